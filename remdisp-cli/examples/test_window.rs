@@ -53,8 +53,11 @@ fn show_frames() -> Result<()> {
         f.read_exact(&mut v_data)?;
 
         let frame = YuvFrame {
+            pts: None,
             y_linesize: meta.y_linesize,
             uv_linesize: meta.uv_linesize,
+            // The samples have no padding
+            width: meta.y_linesize,
             height: meta.height,
             y: &y_data,
             u: &u_data,
@@ -0,0 +1,203 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use crate::prelude::*;
+use crate::proto::control_event::{self, adjust::Power};
+use crate::proto::ControlEvent;
+
+/// Brightness and contrast are percentages of the monitor's range.
+pub const MAX_PERCENT: u32 = 100;
+
+/// Changes the control asks the display to make to its monitor. None leaves a setting as is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Adjustments {
+    pub brightness: Option<u32>,
+    pub contrast: Option<u32>,
+    pub power: Option<PowerMode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerMode {
+    On,
+    /// Show black, but be ready to show the stream again instantly
+    Blank,
+    /// The monitor's power saving mode
+    Sleep,
+}
+
+impl Adjustments {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply `later` on top of these.
+    pub fn merge(&mut self, later: Adjustments) {
+        self.brightness = later.brightness.or(self.brightness);
+        self.contrast = later.contrast.or(self.contrast);
+        self.power = later.power.or(self.power);
+    }
+}
+
+/// Parses commands like `brightness 40`, `contrast 60`, `on`, `blank` or `sleep`.
+impl FromStr for Adjustments {
+    type Err = InvalidAdjustment;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidAdjustment::Command(s.to_string());
+        let mut words = s.split_whitespace();
+        let command = words.next().ok_or_else(invalid)?;
+        let arg = words.next();
+        if words.next().is_some() {
+            return Err(invalid());
+        }
+
+        let percent = || arg.ok_or_else(invalid).and_then(parse_percent);
+        let mut adjustments = Self::default();
+        match (command.to_ascii_lowercase().as_str(), arg) {
+            ("brightness", _) => adjustments.brightness = Some(percent()?),
+            ("contrast", _) => adjustments.contrast = Some(percent()?),
+            (power, None) => adjustments.power = Some(power.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        }
+        Ok(adjustments)
+    }
+}
+
+impl FromStr for PowerMode {
+    type Err = InvalidAdjustment;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "on" => Ok(Self::On),
+            "blank" => Ok(Self::Blank),
+            "sleep" => Ok(Self::Sleep),
+            _ => Err(InvalidAdjustment::Command(s.to_string())),
+        }
+    }
+}
+
+/// Parses a brightness or contrast like `40`.
+pub fn parse_percent(s: &str) -> Result<u32, InvalidAdjustment> {
+    let percent = s
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| InvalidAdjustment::NotPercent(s.to_string()))?;
+    check_percent(percent)
+}
+
+fn check_percent(percent: u32) -> Result<u32, InvalidAdjustment> {
+    if percent > MAX_PERCENT {
+        return Err(InvalidAdjustment::Percent(percent));
+    }
+    Ok(percent)
+}
+
+impl From<Adjustments> for ControlEvent {
+    fn from(adjustments: Adjustments) -> Self {
+        let power = adjustments.power.map(|power| match power {
+            PowerMode::On => Power::On,
+            PowerMode::Blank => Power::Blank,
+            PowerMode::Sleep => Power::Sleep,
+        });
+        ControlEvent {
+            control_event: Some(control_event::ControlEvent::Adjust(control_event::Adjust {
+                brightness: adjustments.brightness,
+                contrast: adjustments.contrast,
+                power: power.map(|power| power as i32),
+            })),
+        }
+    }
+}
+
+impl TryFrom<control_event::Adjust> for Adjustments {
+    type Error = InvalidAdjustment;
+
+    fn try_from(adjust: control_event::Adjust) -> Result<Self, Self::Error> {
+        let power = match adjust.power {
+            Some(power) => Some(match Power::from_i32(power) {
+                Some(Power::On) => PowerMode::On,
+                Some(Power::Blank) => PowerMode::Blank,
+                Some(Power::Sleep) => PowerMode::Sleep,
+                None => return Err(InvalidAdjustment::UnknownPower(power)),
+            }),
+            None => None,
+        };
+        Ok(Self {
+            brightness: adjust.brightness.map(check_percent).transpose()?,
+            contrast: adjust.contrast.map(check_percent).transpose()?,
+            power,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidAdjustment {
+    #[error("Expected brightness PERCENT, contrast PERCENT, on, blank or sleep, got {0:?}")]
+    Command(String),
+    #[error("Expected a percentage, got {0:?}")]
+    NotPercent(String),
+    #[error("{0}% is more than the maximum of 100%")]
+    Percent(u32),
+    #[error("Unknown power mode {0}")]
+    UnknownPower(i32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ltest]
+    fn parses_commands() {
+        assert_eq!(
+            "brightness 40".parse::<Adjustments>().unwrap(),
+            Adjustments {
+                brightness: Some(40),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            " Sleep ".parse::<Adjustments>().unwrap(),
+            Adjustments {
+                power: Some(PowerMode::Sleep),
+                ..Default::default()
+            }
+        );
+        assert_eq!(parse_percent("40%").unwrap(), 40);
+        assert!("contrast 101".parse::<Adjustments>().is_err());
+        assert!("contrast".parse::<Adjustments>().is_err());
+        assert!("blank 1".parse::<Adjustments>().is_err());
+        assert!("dim".parse::<Adjustments>().is_err());
+    }
+
+    #[ltest]
+    fn round_trips_events() {
+        let adjustments = Adjustments {
+            brightness: None,
+            contrast: Some(70),
+            power: Some(PowerMode::Blank),
+        };
+        let adjust = match ControlEvent::from(adjustments).control_event {
+            Some(control_event::ControlEvent::Adjust(adjust)) => adjust,
+            other => panic!("Expected adjust, got {:?}", other),
+        };
+        assert_eq!(Adjustments::try_from(adjust).unwrap(), adjustments);
+    }
+
+    #[ltest]
+    fn merges_later_settings() {
+        let mut adjustments: Adjustments = "brightness 40".parse().unwrap();
+        adjustments.merge("sleep".parse().unwrap());
+        adjustments.merge("brightness 60".parse().unwrap());
+        assert_eq!(
+            adjustments,
+            Adjustments {
+                brightness: Some(60),
+                contrast: None,
+                power: Some(PowerMode::Sleep),
+            }
+        );
+        assert!(!adjustments.is_empty());
+        assert!(Adjustments::default().is_empty());
+    }
+}
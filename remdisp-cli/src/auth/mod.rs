@@ -0,0 +1,384 @@
+//! Who we are and who we trust.
+//!
+//! Every install has a self-signed certificate, and peers are identified by the fingerprint of
+//! theirs. Displays and controls trust each other once they've been paired, which the user does
+//! by checking both show the same short code, see [`pairing_code`].
+//!
+//! Alternatively both sides can be given the same token, see [`token`].
+
+use std::fmt::{self, Debug, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::prelude::*;
+
+pub mod tls;
+pub mod token;
+
+/// How long the user has to compare the codes after pairing starts
+pub const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+/// Digits in a pairing code
+const PAIRING_CODE_LEN: usize = 6;
+/// Bytes in each side's pairing nonce
+pub const PAIRING_NONCE_LEN: usize = 32;
+/// Longest peer name we store, in characters
+const MAX_PEER_NAME_LEN: usize = 64;
+
+const CERT_FILE: &str = "identity.der";
+const KEY_FILE: &str = "identity.key";
+/// Controls a display trusts
+pub const PAIRED_CONTROLS_FILE: &str = "paired-controls";
+/// Displays a control trusts, by the host it was paired with
+pub const PAIRED_DISPLAYS_FILE: &str = "paired-displays";
+
+/// The video stream, which is only encrypted when paired.
+pub trait VideoStream: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Debug> VideoStream for T {}
+
+/// Where identities and paired peers are kept unless overridden.
+pub fn default_config_dir() -> Result<PathBuf, AuthError> {
+    dirs::config_dir()
+        .map(|dir| dir.join("remdisp"))
+        .ok_or(AuthError::NoConfigDir)
+}
+
+/// Our certificate and its private key, both DER encoded.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Identity {
+    cert: Vec<u8>,
+    #[derivative(Debug = "ignore")]
+    key: Vec<u8>,
+}
+
+impl Identity {
+    /// Load the identity in `dir`, creating one if this is the first run.
+    pub fn load_or_create(dir: &Path) -> Result<Self, AuthError> {
+        let cert_path = dir.join(CERT_FILE);
+        let key_path = dir.join(KEY_FILE);
+        if cert_path.exists() && key_path.exists() {
+            return Ok(Self {
+                cert: fs::read(&cert_path).map_err(|err| AuthError::Io(cert_path, err))?,
+                key: fs::read(&key_path).map_err(|err| AuthError::Io(key_path, err))?,
+            });
+        }
+
+        let identity = Self::generate()?;
+        info!(fingerprint = %identity.fingerprint(), "Created new identity");
+        fs::create_dir_all(dir).map_err(|err| AuthError::Io(dir.to_path_buf(), err))?;
+        write_private(&key_path, &identity.key).map_err(|err| AuthError::Io(key_path, err))?;
+        fs::write(&cert_path, &identity.cert).map_err(|err| AuthError::Io(cert_path, err))?;
+        Ok(identity)
+    }
+
+    pub fn generate() -> Result<Self, AuthError> {
+        let cert = rcgen::generate_simple_self_signed(vec![tls::SERVER_NAME.to_string()])?;
+        Ok(Self {
+            cert: cert.serialize_der()?,
+            key: cert.serialize_private_key_der(),
+        })
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of(&self.cert)
+    }
+
+    pub fn cert_der(&self) -> &[u8] {
+        &self.cert
+    }
+
+    pub fn key_der(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(contents)
+}
+
+/// SHA-256 of a DER encoded certificate.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    pub fn of(cert_der: &[u8]) -> Self {
+        Self(Sha256::digest(cert_der).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Fingerprint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Fingerprint({})", self)
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AuthError::InvalidFingerprint(s.to_string());
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairedPeer {
+    pub fingerprint: Fingerprint,
+    /// The control's hostname on a display, the host we paired with on a control
+    pub name: String,
+}
+
+/// Peers we've paired with, saved one per line as the fingerprint followed by the name.
+#[derive(Debug)]
+pub struct PairedPeers {
+    path: PathBuf,
+    peers: Vec<PairedPeer>,
+}
+
+impl PairedPeers {
+    /// Empty if the file doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self, AuthError> {
+        let peers = match fs::read_to_string(path) {
+            Ok(contents) => parse_peers(&contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(AuthError::Io(path.to_path_buf(), err)),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            peers,
+        })
+    }
+
+    pub fn find(&self, fingerprint: &Fingerprint) -> Option<&PairedPeer> {
+        self.peers.iter().find(|peer| &peer.fingerprint == fingerprint)
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<&PairedPeer> {
+        self.peers.iter().find(|peer| peer.name == name)
+    }
+
+    /// Add or replace the peer with the same fingerprint, and save.
+    ///
+    /// The name is only a label, several peers can share one.
+    pub fn add(&mut self, mut peer: PairedPeer) -> Result<(), AuthError> {
+        peer.name = clean_peer_name(&peer.name);
+        self.peers.retain(|existing| existing.fingerprint != peer.fingerprint);
+        self.peers.push(peer);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), AuthError> {
+        let io_err = |err| AuthError::Io(self.path.clone(), err);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(io_err)?;
+        }
+        let contents: String = self
+            .peers
+            .iter()
+            .map(|peer| format!("{} {}\n", peer.fingerprint, peer.name))
+            .collect();
+        fs::write(&self.path, contents).map_err(io_err)
+    }
+}
+
+fn parse_peers(contents: &str) -> Result<Vec<PairedPeer>, AuthError> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (fingerprint, name) = line.split_once(' ').unwrap_or((line, ""));
+            Ok(PairedPeer {
+                fingerprint: fingerprint.parse()?,
+                name: name.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Make a name the peer told us safe to show and to save as one line of the peers file.
+///
+/// Drops control characters, including newlines, trims whitespace and caps the length.
+pub fn clean_peer_name(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_PEER_NAME_LEN)
+        .collect();
+    cleaned.trim().to_string()
+}
+
+pub fn new_pairing_nonce() -> Vec<u8> {
+    let mut nonce = vec![0; PAIRING_NONCE_LEN];
+    rand::thread_rng().fill(&mut nonce[..]);
+    nonce
+}
+
+/// What the display sends before it sees the control's nonce, fixing its own.
+pub fn pairing_commitment(display_nonce: &[u8]) -> Vec<u8> {
+    Sha256::digest(display_nonce).to_vec()
+}
+
+/// The code the user checks is the same on the control and the display.
+///
+/// Covers both fingerprints as each side saw them, so someone relaying between us gets a
+/// different code on each side. They can't steer the codes to match either: the display commits
+/// to its nonce before seeing the control's, and the control sends its nonce before seeing the
+/// display's. That leaves a relay one chance in a million per attempt, and as the code is only
+/// ever compared by the user there's nothing to guess offline.
+pub fn pairing_code(
+    control: &Fingerprint,
+    display: &Fingerprint,
+    control_nonce: &[u8],
+    display_nonce: &[u8],
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(control.as_bytes());
+    hasher.update(display.as_bytes());
+    hasher.update(control_nonce);
+    hasher.update(display_nonce);
+    let hash = hasher.finalize();
+    let value = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+    let max = 10u32.pow(PAIRING_CODE_LEN as u32);
+    format!("{:0width$}", value % max, width = PAIRING_CODE_LEN)
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Couldn't find a config directory, pass one explicitly")]
+    NoConfigDir,
+    #[error("Error accessing {0}")]
+    Io(PathBuf, #[source] io::Error),
+    #[error("Error generating certificate")]
+    Generate(#[from] rcgen::RcgenError),
+    #[error("Invalid fingerprint {0}")]
+    InvalidFingerprint(String),
+    #[error("Token must be a single word of printable ASCII")]
+    InvalidToken,
+    #[error("Error configuring TLS")]
+    Tls(#[from] rustls::TLSError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ltest]
+    fn fingerprint_round_trips() {
+        let fingerprint = Fingerprint::of(b"not really a certificate");
+        let parsed: Fingerprint = fingerprint.to_string().parse().unwrap();
+        assert_eq!(parsed, fingerprint);
+        assert!("abc".parse::<Fingerprint>().is_err());
+    }
+
+    #[ltest]
+    fn parses_paired_peers() {
+        let a = Fingerprint::of(b"a");
+        let b = Fingerprint::of(b"b");
+        let contents = format!("# Paired controls\n{} laptop\n\n{} desk pc\n", a, b);
+
+        let peers = parse_peers(&contents).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].fingerprint, a);
+        assert_eq!(peers[1].name, "desk pc");
+    }
+
+    #[ltest]
+    fn cleans_peer_names() {
+        assert_eq!(clean_peer_name(" desk\npc\u{1b}[2J "), "deskpc[2J");
+        assert_eq!(clean_peer_name(&"a".repeat(1000)).len(), MAX_PEER_NAME_LEN);
+    }
+
+    #[ltest]
+    fn replaces_peers_by_fingerprint() {
+        let path = std::env::temp_dir().join("remdisp_test_paired_peers");
+        let _ = fs::remove_file(&path);
+        let a = Fingerprint::of(b"a");
+        let b = Fingerprint::of(b"b");
+        let peer = |fingerprint: &Fingerprint, name: &str| PairedPeer {
+            fingerprint: fingerprint.clone(),
+            name: name.to_string(),
+        };
+
+        let mut peers = PairedPeers::load(&path).unwrap();
+        peers.add(peer(&a, "laptop")).unwrap();
+        peers.add(peer(&b, "laptop")).unwrap();
+        peers.add(peer(&a, "work\nlaptop")).unwrap();
+
+        let peers = PairedPeers::load(&path).unwrap();
+        assert_eq!(peers.find(&a).unwrap().name, "worklaptop");
+        assert_eq!(peers.find(&b).unwrap().name, "laptop");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[ltest]
+    fn code_depends_on_fingerprints_and_nonces() {
+        let control = Fingerprint::of(b"control");
+        let display = Fingerprint::of(b"display");
+        let relay = Fingerprint::of(b"relay");
+        let (a, b) = (new_pairing_nonce(), new_pairing_nonce());
+
+        let code = pairing_code(&control, &display, &a, &b);
+        assert_eq!(code.len(), PAIRING_CODE_LEN);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(code, pairing_code(&control, &display, &a, &b));
+        // Any of these could collide by chance, but all of them won't
+        let others = [
+            pairing_code(&relay, &display, &a, &b),
+            pairing_code(&control, &relay, &a, &b),
+            pairing_code(&control, &display, &b, &a),
+            pairing_code(&control, &display, &new_pairing_nonce(), &b),
+        ];
+        assert!(others.iter().any(|other| other != &code));
+
+        assert_eq!(pairing_commitment(&a), pairing_commitment(&a));
+        assert_ne!(pairing_commitment(&a), pairing_commitment(&b));
+    }
+}
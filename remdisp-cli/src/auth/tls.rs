@@ -0,0 +1,144 @@
+//! TLS for the control and video channels.
+//!
+//! Certificates are self-signed, so instead of checking them against CAs we check the
+//! fingerprint against the peers we've paired with.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use rustls::{
+    Certificate, ClientCertVerified, ClientCertVerifier, ClientConfig, DistinguishedNames,
+    PrivateKey, RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig, TLSError,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use webpki::{DNSName, DNSNameRef};
+
+use crate::auth::{AuthError, Fingerprint, Identity};
+
+/// The name in every certificate. Never checked as peers are identified by fingerprint.
+pub const SERVER_NAME: &str = "remdisp";
+/// gRPC needs HTTP/2 negotiated
+pub const ALPN_H2: &[u8] = b"h2";
+
+pub type ClientTlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+pub type ServerTlsStream = tokio_rustls::server::TlsStream<TcpStream>;
+
+/// Checks the display's certificate on the control side.
+///
+/// Pinned to the fingerprint we paired with, or when pairing accepts anything and remembers it
+/// so the user can confirm it.
+#[derive(Debug)]
+pub struct DisplayVerifier {
+    expected: Option<Fingerprint>,
+    seen: Mutex<Option<Fingerprint>>,
+}
+
+impl DisplayVerifier {
+    pub fn pinned(expected: Fingerprint) -> Self {
+        Self {
+            expected: Some(expected),
+            seen: Mutex::new(None),
+        }
+    }
+
+    pub fn for_pairing() -> Self {
+        Self {
+            expected: None,
+            seen: Mutex::new(None),
+        }
+    }
+
+    /// The fingerprint of the display we completed a handshake with, if any.
+    pub fn seen(&self) -> Option<Fingerprint> {
+        *self.seen.lock().unwrap()
+    }
+}
+
+impl ServerCertVerifier for DisplayVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let fingerprint = leaf_fingerprint(presented_certs)?;
+        if let Some(expected) = self.expected {
+            if fingerprint != expected {
+                return Err(TLSError::General(format!(
+                    "Display presented certificate {}, but we paired with {}",
+                    fingerprint, expected
+                )));
+            }
+        }
+        *self.seen.lock().unwrap() = Some(fingerprint);
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Requires controls to present a certificate but accepts any, as unpaired controls must be able
+/// to connect to pair. Whether the control is paired is checked per request.
+#[derive(Debug)]
+pub struct AnyControlVerifier;
+
+impl ClientCertVerifier for AnyControlVerifier {
+    fn client_auth_root_subjects(&self, _sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        Some(DistinguishedNames::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        _sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        leaf_fingerprint(presented_certs)?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+fn leaf_fingerprint(certs: &[Certificate]) -> Result<Fingerprint, TLSError> {
+    certs
+        .first()
+        .map(|cert| Fingerprint::of(&cert.0))
+        .ok_or(TLSError::NoCertificatesPresented)
+}
+
+/// The display's side of both channels.
+pub fn server_config(identity: &Identity) -> Result<ServerConfig, AuthError> {
+    let mut config = ServerConfig::new(Arc::new(AnyControlVerifier));
+    config.set_single_cert(
+        vec![Certificate(identity.cert_der().to_vec())],
+        PrivateKey(identity.key_der().to_vec()),
+    )?;
+    Ok(config)
+}
+
+/// The control's side of both channels.
+pub fn client_config(
+    identity: &Identity,
+    verifier: Arc<DisplayVerifier>,
+) -> Result<ClientConfig, AuthError> {
+    let mut config = ClientConfig::new();
+    config.dangerous().set_certificate_verifier(verifier);
+    config.set_single_client_cert(
+        vec![Certificate(identity.cert_der().to_vec())],
+        PrivateKey(identity.key_der().to_vec()),
+    )?;
+    Ok(config)
+}
+
+/// Who is on the other end of a display's side of a video stream.
+pub fn control_fingerprint(stream: &ServerTlsStream) -> Option<Fingerprint> {
+    use rustls::Session;
+
+    let (_, session) = stream.get_ref();
+    let certs = session.get_peer_certificates()?;
+    leaf_fingerprint(&certs).ok()
+}
+
+/// Open the video stream to a display, which the connector's verifier must accept.
+pub async fn connect(connector: &TlsConnector, stream: TcpStream) -> io::Result<ClientTlsStream> {
+    let name = DNSNameRef::try_from_ascii_str(SERVER_NAME).expect("SERVER_NAME is valid");
+    connector.connect(name, stream).await
+}
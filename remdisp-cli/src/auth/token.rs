@@ -0,0 +1,75 @@
+//! A token shared by the display and control, for when pairing is more than a trusted network
+//! needs.
+//!
+//! Nothing is encrypted. The token is sent with every request, and the video stream is only
+//! checked by the nonce each Attach gets, which the control must send first on it.
+
+use std::fs;
+use std::path::Path;
+
+use rand::RngCore;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+use crate::auth::{AuthError, VideoStream};
+
+/// Request metadata holding the token
+pub const TOKEN_METADATA: &str = "x-remdisp-token";
+const NONCE_LEN: usize = 16;
+
+/// Read a token from the first line of a file.
+pub fn load_token(path: &Path) -> Result<String, AuthError> {
+    let contents =
+        fs::read_to_string(path).map_err(|err| AuthError::Io(path.to_path_buf(), err))?;
+    parse_token(&contents)
+}
+
+fn parse_token(contents: &str) -> Result<String, AuthError> {
+    let token = contents.lines().next().unwrap_or("").trim();
+    // It has to fit in request metadata
+    if token.is_empty() || !token.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return Err(AuthError::InvalidToken);
+    }
+    Ok(token.to_string())
+}
+
+/// Compares in time independent of where they differ, so the token can't be guessed byte by byte.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub fn new_nonce() -> Vec<u8> {
+    let mut nonce = vec![0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Whether whoever just connected to the video port sent the nonce we gave the control.
+pub async fn read_nonce(stream: &mut dyn VideoStream, expected: &[u8]) -> io::Result<bool> {
+    let mut nonce = vec![0; expected.len()];
+    stream.read_exact(&mut nonce).await?;
+    Ok(constant_time_eq(&nonce, expected))
+}
+
+pub async fn write_nonce(stream: &mut dyn VideoStream, nonce: &[u8]) -> io::Result<()> {
+    stream.write_all(nonce).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    #[ltest]
+    fn parses_token() {
+        assert_eq!(parse_token("s3cret\n").unwrap(), "s3cret");
+        assert!(parse_token("\n").is_err());
+        assert!(parse_token("has space").is_err());
+    }
+
+    #[ltest]
+    fn compares_tokens() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
@@ -0,0 +1,151 @@
+use std::os::raw::c_int;
+use std::ptr;
+
+use ffmpeg_sys_next as sys;
+
+use crate::av::audio::{AudioPacket, CHANNELS, SAMPLE_RATE};
+use crate::av::{ensure_av_logs_setup, to_av_error, AvError};
+use crate::prelude::*;
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct AudioDecoder {
+    #[derivative(Debug = "ignore")]
+    ctx: ptr::NonNull<sys::AVCodecContext>,
+    #[derivative(Debug = "ignore")]
+    frame: ptr::NonNull<sys::AVFrame>,
+    #[derivative(Debug = "ignore")]
+    pkt: ptr::NonNull<sys::AVPacket>,
+    /// Packet data with the padding the decoder requires
+    #[derivative(Debug = "ignore")]
+    pkt_buf: Vec<u8>,
+}
+
+impl AudioDecoder {
+    // See <https://github.com/FFmpeg/FFmpeg/blob/master/doc/examples/decode_audio.c>
+
+    #[instrument(err)]
+    pub fn new() -> Result<Self, AvError> {
+        ensure_av_logs_setup();
+
+        let codec_id = sys::AVCodecID::AV_CODEC_ID_OPUS;
+        let codec = unsafe {
+            nonnull_or!(
+                sys::avcodec_find_decoder(codec_id),
+                AvError::CodecUnavailable(codec_id)
+            )
+        }?;
+        let ctx = unsafe {
+            nonnull_or!(
+                sys::avcodec_alloc_context3(codec.as_ptr()),
+                AvError::CreateContext
+            )
+        }?;
+
+        unsafe {
+            // There's no container to tell the decoder this
+            let ctx = &mut *ctx.as_ptr();
+            ctx.sample_rate = SAMPLE_RATE as c_int;
+            ctx.channels = CHANNELS as c_int;
+            ctx.channel_layout = sys::av_get_default_channel_layout(CHANNELS as c_int) as u64;
+
+            let status = sys::avcodec_open2(ctx, codec.as_ptr(), ptr::null_mut());
+            if status < 0 {
+                return Err(AvError::OpenContext(status));
+            }
+        }
+
+        let frame = unsafe { nonnull_or!(sys::av_frame_alloc(), AvError::AllocateFrame) }?;
+        let pkt = unsafe { nonnull_or!(sys::av_packet_alloc(), AvError::AllocatePacket) }?;
+
+        Ok(Self {
+            ctx,
+            frame,
+            pkt,
+            pkt_buf: Vec::new(),
+        })
+    }
+
+    /// Decode a packet to interleaved samples.
+    #[instrument(err, skip(packet))]
+    pub fn decode(&mut self, packet: &AudioPacket) -> Result<Vec<i16>, AvError> {
+        self.pkt_buf.clear();
+        self.pkt_buf.extend_from_slice(&packet.data);
+        self.pkt_buf.resize(
+            packet.data.len() + sys::AV_INPUT_BUFFER_PADDING_SIZE as usize,
+            0,
+        );
+
+        unsafe {
+            let pkt = &mut *self.pkt.as_ptr();
+            pkt.data = self.pkt_buf.as_mut_ptr();
+            pkt.size = packet.data.len() as c_int;
+
+            let status = sys::avcodec_send_packet(self.ctx.as_ptr(), pkt);
+            if status < 0 {
+                return Err(AvError::SendForDecoding(status));
+            }
+        }
+
+        let mut samples = vec![];
+        loop {
+            let status =
+                unsafe { sys::avcodec_receive_frame(self.ctx.as_ptr(), self.frame.as_ptr()) };
+            if status == to_av_error(sys::EAGAIN) || status == sys::AVERROR_EOF {
+                return Ok(samples);
+            } else if status < 0 {
+                return Err(AvError::InDecoding(status));
+            }
+
+            unsafe { interleave(self.frame.as_ref(), &mut samples) }?;
+        }
+    }
+}
+
+impl Drop for AudioDecoder {
+    fn drop(&mut self) {
+        unsafe {
+            sys::av_frame_free(&mut self.frame.as_ptr());
+            sys::av_packet_free(&mut self.pkt.as_ptr());
+            sys::avcodec_free_context(&mut self.ctx.as_ptr());
+        }
+    }
+}
+
+/// Append a decoded frame to `out` as interleaved stereo 16 bit samples. Mono is played on both
+/// channels.
+///
+/// Safety: `frame` must be a valid decoded audio frame.
+unsafe fn interleave(frame: &sys::AVFrame, out: &mut Vec<i16>) -> Result<(), AvError> {
+    use sys::AVSampleFormat::*;
+
+    let is = |format: sys::AVSampleFormat| frame.format == format as c_int;
+    let planar = is(AV_SAMPLE_FMT_S16P) || is(AV_SAMPLE_FMT_FLTP);
+    let float = is(AV_SAMPLE_FMT_FLT) || is(AV_SAMPLE_FMT_FLTP);
+    if !(planar || float || is(AV_SAMPLE_FMT_S16)) {
+        return Err(AvError::SampleFormat(frame.format));
+    }
+
+    let len = frame.nb_samples as usize;
+    let channels = frame.channels.max(1) as usize;
+    let float_to_i16 = |sample: f32| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+    out.reserve(len * CHANNELS);
+    for n in 0..len {
+        for channel in 0..CHANNELS {
+            let channel = channel.min(channels - 1);
+            let (plane, index) = if planar {
+                (channel, n)
+            } else {
+                (0, n * channels + channel)
+            };
+            let plane = *frame.extended_data.add(plane);
+            let sample = if float {
+                float_to_i16(*plane.cast::<f32>().add(index))
+            } else {
+                *plane.cast::<i16>().add(index)
+            };
+            out.push(sample);
+        }
+    }
+    Ok(())
+}
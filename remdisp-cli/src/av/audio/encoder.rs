@@ -0,0 +1,175 @@
+use std::os::raw::c_int;
+use std::ptr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use ffmpeg_sys_next as sys;
+
+use crate::av::audio::{AudioPacket, CHANNELS, SAMPLES_TIME_BASE, SAMPLE_RATE};
+use crate::av::{ensure_av_logs_setup, to_av_error, AvError, MICROS_TIME_BASE};
+use crate::prelude::*;
+
+/// Plenty for music, and still tiny next to the video
+const BIT_RATE: i64 = 128_000;
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct AudioEncoder {
+    #[derivative(Debug = "ignore")]
+    ctx: ptr::NonNull<sys::AVCodecContext>,
+    #[derivative(Debug = "ignore")]
+    frame: ptr::NonNull<sys::AVFrame>,
+    #[derivative(Debug = "ignore")]
+    pkt: ptr::NonNull<sys::AVPacket>,
+    /// Samples per channel the codec takes at a time
+    frame_samples: usize,
+    /// Interleaved samples waiting for a full frame
+    #[derivative(Debug = "ignore")]
+    pending: Vec<i16>,
+    /// Per channel, which is also the pts of the next frame
+    samples_sent: i64,
+    /// Stream time of the first sample
+    start: Duration,
+}
+
+impl AudioEncoder {
+    // See <https://github.com/FFmpeg/FFmpeg/blob/master/doc/examples/encode_audio.c>
+
+    /// `start` is when the first sample encoded was captured, relative to the start of the
+    /// stream.
+    #[instrument(err)]
+    pub fn new(start: Duration) -> Result<Self, AvError> {
+        ensure_av_logs_setup();
+
+        let codec_id = sys::AVCodecID::AV_CODEC_ID_OPUS;
+        // ffmpeg's own Opus encoder is experimental and only takes planar floats
+        let codec = unsafe {
+            nonnull_or!(
+                sys::avcodec_find_encoder_by_name(b"libopus\0".as_ptr().cast()),
+                AvError::CodecUnavailable(codec_id)
+            )
+        }?;
+
+        let ctx = unsafe {
+            nonnull_or!(
+                sys::avcodec_alloc_context3(codec.as_ptr()),
+                AvError::CreateContext
+            )
+        }?;
+
+        let frame_samples = unsafe {
+            let ctx = &mut *ctx.as_ptr();
+            ctx.sample_rate = SAMPLE_RATE as c_int;
+            ctx.channels = CHANNELS as c_int;
+            ctx.channel_layout = sys::av_get_default_channel_layout(CHANNELS as c_int) as u64;
+            ctx.sample_fmt = sys::AVSampleFormat::AV_SAMPLE_FMT_S16;
+            ctx.bit_rate = BIT_RATE;
+            ctx.time_base = SAMPLES_TIME_BASE;
+
+            let status = sys::avcodec_open2(ctx, codec.as_ptr(), ptr::null_mut());
+            if status < 0 {
+                return Err(AvError::OpenContext(status));
+            }
+            ctx.frame_size as usize
+        };
+        debug!(frame_samples, "Opened audio codec context");
+
+        let frame = unsafe { nonnull_or!(sys::av_frame_alloc(), AvError::AllocateFrame) }?;
+        unsafe {
+            let frame = &mut *frame.as_ptr();
+            let ctx = ctx.as_ref();
+            frame.nb_samples = frame_samples as c_int;
+            frame.format = ctx.sample_fmt as c_int;
+            frame.channel_layout = ctx.channel_layout;
+            frame.channels = ctx.channels;
+            frame.sample_rate = ctx.sample_rate;
+            if sys::av_frame_get_buffer(frame, 0) < 0 {
+                return Err(AvError::AllocateFrame);
+            }
+        }
+
+        let pkt = unsafe { nonnull_or!(sys::av_packet_alloc(), AvError::AllocatePacket) }?;
+
+        Ok(Self {
+            ctx,
+            frame,
+            pkt,
+            frame_samples,
+            pending: Vec::new(),
+            samples_sent: 0,
+            start,
+        })
+    }
+
+    /// Encode interleaved samples, returning the packets that are ready. Samples that don't
+    /// make up a whole frame are kept for the next call.
+    #[instrument(err, skip(samples))]
+    pub fn encode(&mut self, samples: &[i16]) -> Result<Vec<AudioPacket>, AvError> {
+        self.pending.extend_from_slice(samples);
+
+        let frame_len = self.frame_samples * CHANNELS;
+        let mut packets = vec![];
+        while self.pending.len() >= frame_len {
+            unsafe {
+                // The encoder may still hold a reference to the last frame's buffer
+                if sys::av_frame_make_writable(self.frame.as_ptr()) < 0 {
+                    return Err(AvError::AllocateFrame);
+                }
+                let frame = &mut *self.frame.as_ptr();
+                ptr::copy_nonoverlapping(self.pending.as_ptr(), frame.data[0].cast(), frame_len);
+                frame.pts = self.samples_sent;
+
+                let status = sys::avcodec_send_frame(self.ctx.as_ptr(), frame);
+                if status < 0 {
+                    return Err(AvError::SendForEncoding(status));
+                }
+            }
+            self.pending.drain(..frame_len);
+            self.samples_sent += self.frame_samples as i64;
+
+            while let Some(packet) = self.receive_packet()? {
+                packets.push(packet);
+            }
+        }
+        Ok(packets)
+    }
+
+    fn receive_packet(&mut self) -> Result<Option<AudioPacket>, AvError> {
+        let status = unsafe { sys::avcodec_receive_packet(self.ctx.as_ptr(), self.pkt.as_ptr()) };
+        if status == to_av_error(sys::EAGAIN) || status == sys::AVERROR_EOF {
+            return Ok(None);
+        } else if status < 0 {
+            return Err(AvError::Encode);
+        }
+
+        let pkt_ref = unsafe { self.pkt.as_ref() };
+        let micros = unsafe { sys::av_rescale_q(pkt_ref.pts, SAMPLES_TIME_BASE, MICROS_TIME_BASE) };
+        // The codec's lookahead puts the first packet a little before the first sample
+        let timestamp = if micros < 0 {
+            self.start
+                .saturating_sub(Duration::from_micros(micros.unsigned_abs()))
+        } else {
+            self.start + Duration::from_micros(micros as u64)
+        };
+
+        let data = unsafe { &*ptr::slice_from_raw_parts(pkt_ref.data, pkt_ref.size as usize) };
+        let packet = AudioPacket {
+            timestamp,
+            data: Bytes::copy_from_slice(data),
+        };
+        unsafe { sys::av_packet_unref(self.pkt.as_ptr()) };
+
+        trace!(?packet, "Encoded audio packet");
+        Ok(Some(packet))
+    }
+}
+
+impl Drop for AudioEncoder {
+    fn drop(&mut self) {
+        unsafe {
+            sys::avcodec_free_context(&mut self.ctx.as_ptr());
+            sys::av_frame_free(&mut self.frame.as_ptr());
+            sys::av_packet_free(&mut self.pkt.as_ptr());
+        }
+    }
+}
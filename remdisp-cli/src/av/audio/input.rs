@@ -0,0 +1,376 @@
+use std::convert::Infallible;
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Once;
+use std::time::Instant;
+use std::{fmt, ptr, thread};
+
+use ffmpeg_sys_next as sys;
+
+use crate::av::audio::{duration_of, CHANNELS, FRAME_SAMPLES, SAMPLE_RATE};
+use crate::av::{ensure_av_logs_setup, to_av_error, AvError};
+use crate::prelude::*;
+
+/// PulseAudio's name for the monitor of the default output, i.e. whatever is playing
+pub const DEFAULT_PULSE_SOURCE: &str = "@DEFAULT_MONITOR@";
+/// Asks PulseAudio for a packet's worth of audio at a time, instead of its default of a lot more
+const PULSE_FRAGMENT_BYTES: usize = FRAME_SAMPLES * CHANNELS * 2;
+
+static DEVICES_REGISTERED: Once = Once::new();
+
+/// Where the control gets the audio it sends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioSource {
+    /// A PulseAudio source by name, which also works with PipeWire through its PulseAudio
+    /// server. Monitor sources such as `<sink>.monitor` capture what a sink is playing.
+    Pulse(String),
+    /// A file in any format ffmpeg can read, played in real time
+    File(PathBuf),
+    /// Silence, in real time
+    Null,
+}
+
+/// Parses `pulse` for whatever is playing, `pulse:SOURCE` for a particular source, `null`, or
+/// anything else as a file.
+impl FromStr for AudioSource {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "null" => Self::Null,
+            "pulse" => Self::Pulse(DEFAULT_PULSE_SOURCE.to_string()),
+            _ => match s.strip_prefix("pulse:") {
+                Some(source) => Self::Pulse(source.to_string()),
+                None => Self::File(s.into()),
+            },
+        })
+    }
+}
+
+impl fmt::Display for AudioSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pulse(source) => write!(f, "pulse:{}", source),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Null => write!(f, "null"),
+        }
+    }
+}
+
+/// Reads interleaved samples at our [`SAMPLE_RATE`] from an [`AudioSource`].
+///
+/// Reads block, so this is meant to be used from its own thread.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct AudioInput {
+    source: AudioSource,
+    /// None for [`AudioSource::Null`]
+    #[derivative(Debug = "ignore")]
+    decoding: Option<DecodingInput>,
+    /// Sources that aren't live are slowed down to real time, starting from here
+    paced_from: Option<Instant>,
+    /// Interleaved
+    samples_read: usize,
+}
+
+impl AudioInput {
+    #[instrument(err)]
+    pub fn open(source: &AudioSource) -> Result<Self, AvError> {
+        ensure_av_logs_setup();
+
+        let decoding = match source {
+            AudioSource::Pulse(name) => Some(DecodingInput::open_pulse(name)?),
+            AudioSource::File(path) => {
+                let url = CString::new(path.to_string_lossy().as_bytes())
+                    .map_err(|_| AvError::InvalidPath(path.clone()))?;
+                Some(DecodingInput::open(&url, ptr::null_mut(), ptr::null_mut())?)
+            }
+            AudioSource::Null => None,
+        };
+        debug!(%source, "Opened audio input");
+
+        Ok(Self {
+            source: source.clone(),
+            decoding,
+            paced_from: None,
+            samples_read: 0,
+        })
+    }
+
+    /// The next samples. Returns None at the end of a file.
+    #[instrument(err)]
+    pub fn read(&mut self) -> Result<Option<Vec<i16>>, AvError> {
+        let samples = match &mut self.decoding {
+            Some(decoding) => match decoding.read()? {
+                Some(samples) => samples,
+                None => return Ok(None),
+            },
+            None => vec![0; FRAME_SAMPLES * CHANNELS],
+        };
+
+        if !matches!(self.source, AudioSource::Pulse(_)) {
+            let paced_from = *self.paced_from.get_or_insert_with(Instant::now);
+            // Samples are available once they'd have finished playing
+            let due = paced_from + duration_of(self.samples_read + samples.len());
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+        self.samples_read += samples.len();
+
+        Ok(Some(samples))
+    }
+}
+
+/// Demuxes, decodes and resamples the best audio stream of an input.
+///
+/// Pointers are null until opened, so a partly opened input can be dropped.
+struct DecodingInput {
+    fmt_ctx: *mut sys::AVFormatContext,
+    codec_ctx: *mut sys::AVCodecContext,
+    swr: *mut sys::SwrContext,
+    frame: *mut sys::AVFrame,
+    pkt: *mut sys::AVPacket,
+    stream_index: c_int,
+    /// We've told the decoder there are no more packets
+    flushing: bool,
+}
+
+impl DecodingInput {
+    // See <https://github.com/FFmpeg/FFmpeg/blob/master/doc/examples/transcode_aac.c>
+
+    fn open_pulse(name: &str) -> Result<Self, AvError> {
+        DEVICES_REGISTERED.call_once(|| unsafe { sys::avdevice_register_all() });
+
+        let format = unsafe { sys::av_find_input_format(b"pulse\0".as_ptr().cast()) };
+        if format.is_null() {
+            return Err(AvError::InputFormatUnavailable("pulse"));
+        }
+        let url = CString::new(name).map_err(|_| AvError::InvalidPath(name.into()))?;
+
+        let mut options = ptr::null_mut();
+        let fragment_size = CString::new(PULSE_FRAGMENT_BYTES.to_string()).unwrap();
+        unsafe {
+            sys::av_dict_set(
+                &mut options,
+                b"fragment_size\0".as_ptr().cast(),
+                fragment_size.as_ptr(),
+                0,
+            )
+        };
+        let opened = Self::open(&url, format, &mut options);
+        unsafe { sys::av_dict_free(&mut options) };
+        opened
+    }
+
+    fn open(
+        url: &CString,
+        format: *mut sys::AVInputFormat,
+        options: *mut *mut sys::AVDictionary,
+    ) -> Result<Self, AvError> {
+        let mut input = Self {
+            fmt_ctx: ptr::null_mut(),
+            codec_ctx: ptr::null_mut(),
+            swr: ptr::null_mut(),
+            frame: ptr::null_mut(),
+            pkt: ptr::null_mut(),
+            stream_index: -1,
+            flushing: false,
+        };
+
+        unsafe {
+            let status =
+                sys::avformat_open_input(&mut input.fmt_ctx, url.as_ptr(), format, options);
+            if status < 0 {
+                return Err(AvError::OpenInput(status));
+            }
+            let status = sys::avformat_find_stream_info(input.fmt_ctx, ptr::null_mut());
+            if status < 0 {
+                return Err(AvError::OpenInput(status));
+            }
+
+            let mut codec = ptr::null_mut();
+            input.stream_index = sys::av_find_best_stream(
+                input.fmt_ctx,
+                sys::AVMediaType::AVMEDIA_TYPE_AUDIO,
+                -1,
+                -1,
+                &mut codec,
+                0,
+            );
+            if input.stream_index < 0 || codec.is_null() {
+                return Err(AvError::NoAudioStream);
+            }
+            let stream = *(*input.fmt_ctx).streams.offset(input.stream_index as isize);
+
+            input.codec_ctx = sys::avcodec_alloc_context3(codec);
+            if input.codec_ctx.is_null() {
+                return Err(AvError::CreateContext);
+            }
+            if sys::avcodec_parameters_to_context(input.codec_ctx, (*stream).codecpar) < 0 {
+                return Err(AvError::ConfigureContext);
+            }
+            let status = sys::avcodec_open2(input.codec_ctx, codec, ptr::null_mut());
+            if status < 0 {
+                return Err(AvError::OpenContext(status));
+            }
+
+            let codec_ctx = &*input.codec_ctx;
+            let in_layout = if codec_ctx.channel_layout != 0 {
+                codec_ctx.channel_layout as i64
+            } else {
+                sys::av_get_default_channel_layout(codec_ctx.channels)
+            };
+            debug!(
+                sample_rate = codec_ctx.sample_rate,
+                channels = codec_ctx.channels,
+                sample_fmt = ?codec_ctx.sample_fmt,
+                "Resampling audio input"
+            );
+            input.swr = sys::swr_alloc_set_opts(
+                ptr::null_mut(),
+                sys::av_get_default_channel_layout(CHANNELS as c_int),
+                sys::AVSampleFormat::AV_SAMPLE_FMT_S16,
+                SAMPLE_RATE as c_int,
+                in_layout,
+                codec_ctx.sample_fmt,
+                codec_ctx.sample_rate,
+                0,
+                ptr::null_mut(),
+            );
+            if input.swr.is_null() {
+                return Err(AvError::Resample(0));
+            }
+            let status = sys::swr_init(input.swr);
+            if status < 0 {
+                return Err(AvError::Resample(status));
+            }
+
+            input.frame = sys::av_frame_alloc();
+            if input.frame.is_null() {
+                return Err(AvError::AllocateFrame);
+            }
+            input.pkt = sys::av_packet_alloc();
+            if input.pkt.is_null() {
+                return Err(AvError::AllocatePacket);
+            }
+        }
+
+        Ok(input)
+    }
+
+    /// Returns None at the end of the input.
+    fn read(&mut self) -> Result<Option<Vec<i16>>, AvError> {
+        loop {
+            let status = unsafe { sys::avcodec_receive_frame(self.codec_ctx, self.frame) };
+            if status == 0 {
+                return self.resample().map(Some);
+            } else if status == sys::AVERROR_EOF {
+                return Ok(None);
+            } else if status != to_av_error(sys::EAGAIN) {
+                return Err(AvError::InDecoding(status));
+            }
+
+            // The decoder needs another packet
+            let status = unsafe { sys::av_read_frame(self.fmt_ctx, self.pkt) };
+            if status == sys::AVERROR_EOF && !self.flushing {
+                self.flushing = true;
+                // Null flushes the decoder
+                let status = unsafe { sys::avcodec_send_packet(self.codec_ctx, ptr::null()) };
+                if status < 0 {
+                    return Err(AvError::SendForDecoding(status));
+                }
+                continue;
+            } else if status == sys::AVERROR_EOF {
+                return Ok(None);
+            } else if status < 0 {
+                return Err(AvError::Demux(status));
+            }
+
+            let status = unsafe {
+                if (*self.pkt).stream_index == self.stream_index {
+                    sys::avcodec_send_packet(self.codec_ctx, self.pkt)
+                } else {
+                    0
+                }
+            };
+            unsafe { sys::av_packet_unref(self.pkt) };
+            if status < 0 {
+                return Err(AvError::SendForDecoding(status));
+            }
+        }
+    }
+
+    /// Convert the decoded frame to our format.
+    fn resample(&mut self) -> Result<Vec<i16>, AvError> {
+        unsafe {
+            let frame = &*self.frame;
+            let capacity = sys::swr_get_out_samples(self.swr, frame.nb_samples).max(0);
+            let mut samples = vec![0i16; capacity as usize * CHANNELS];
+            let mut out = samples.as_mut_ptr().cast::<u8>();
+            let converted = sys::swr_convert(
+                self.swr,
+                &mut out,
+                capacity,
+                frame.extended_data as *mut *const u8,
+                frame.nb_samples,
+            );
+            sys::av_frame_unref(self.frame);
+            if converted < 0 {
+                return Err(AvError::Resample(converted));
+            }
+            samples.truncate(converted as usize * CHANNELS);
+            Ok(samples)
+        }
+    }
+}
+
+impl Drop for DecodingInput {
+    fn drop(&mut self) {
+        unsafe {
+            sys::av_packet_free(&mut self.pkt);
+            sys::av_frame_free(&mut self.frame);
+            sys::swr_free(&mut self.swr);
+            sys::avcodec_free_context(&mut self.codec_ctx);
+            sys::avformat_close_input(&mut self.fmt_ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[ltest]
+    fn parses_sources() {
+        assert_eq!("null".parse(), Ok(AudioSource::Null));
+        assert_eq!(
+            "pulse".parse(),
+            Ok(AudioSource::Pulse(DEFAULT_PULSE_SOURCE.to_string()))
+        );
+        assert_eq!(
+            "pulse:alsa_output.usb.monitor".parse(),
+            Ok(AudioSource::Pulse("alsa_output.usb.monitor".to_string()))
+        );
+        assert_eq!(
+            "music.ogg".parse(),
+            Ok(AudioSource::File("music.ogg".into()))
+        );
+    }
+
+    #[ltest]
+    fn paces_null_source() {
+        let mut input = AudioInput::open(&AudioSource::Null).unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            let samples = input.read().unwrap().unwrap();
+            assert_eq!(samples.len(), FRAME_SAMPLES * CHANNELS);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+}
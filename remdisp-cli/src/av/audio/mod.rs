@@ -0,0 +1,175 @@
+//! Audio sent alongside video, encoded with Opus.
+//!
+//! Audio is always 16 bit interleaved stereo at 48kHz, as that's what Opus works in natively.
+//! Packets are timestamped on the same clock as video, so the display can play them in sync.
+
+use std::io;
+use std::time::Duration;
+
+use bytes::Bytes;
+use ffmpeg_sys_next as sys;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::prelude::*;
+
+pub mod decoder;
+pub mod encoder;
+pub mod input;
+
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const CHANNELS: usize = 2;
+/// Samples per channel in each packet, 20ms
+pub const FRAME_SAMPLES: usize = 960;
+
+/// Time base of sample counts
+const SAMPLES_TIME_BASE: sys::AVRational = sys::AVRational {
+    num: 1,
+    den: SAMPLE_RATE as i32,
+};
+
+/// How long `samples` interleaved samples last.
+pub fn duration_of(samples: usize) -> Duration {
+    Duration::from_micros((samples / CHANNELS) as u64 * 1_000_000 / SAMPLE_RATE as u64)
+}
+
+/// How many interleaved samples last `duration`.
+pub fn samples_in(duration: Duration) -> usize {
+    (duration.as_micros() * SAMPLE_RATE as u128 / 1_000_000) as usize * CHANNELS
+}
+
+/// An encoded audio packet.
+///
+/// On the wire each packet is framed as a big-endian header followed by the data:
+/// `len: u32`, `timestamp_micros: u64`.
+#[derive(Derivative, Clone, PartialEq, Eq)]
+#[derivative(Debug)]
+pub struct AudioPacket {
+    /// When the first sample should play, relative to the start of the stream
+    pub timestamp: Duration,
+    #[derivative(Debug = "ignore")]
+    pub data: Bytes,
+}
+
+impl AudioPacket {
+    const HEADER_LEN: usize = 4 + 8;
+    /// Opus packets are at most a few KiB, anything bigger means the stream is corrupt
+    const MAX_DATA_LEN: usize = 64 * 1024;
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, out: &mut W) -> io::Result<()> {
+        let mut header = [0u8; Self::HEADER_LEN];
+        header[0..4].copy_from_slice(&(self.data.len() as u32).to_be_bytes());
+        header[4..12].copy_from_slice(&(self.timestamp.as_micros() as u64).to_be_bytes());
+
+        out.write_all(&header).await?;
+        out.write_all(&self.data).await?;
+        Ok(())
+    }
+
+    /// Returns None if the input ended cleanly between packets.
+    pub async fn read_from<R: AsyncRead + Unpin>(input: &mut R) -> io::Result<Option<Self>> {
+        let mut header = [0u8; Self::HEADER_LEN];
+
+        let first = input.read(&mut header).await?;
+        if first == 0 {
+            return Ok(None);
+        }
+        input.read_exact(&mut header[first..]).await?;
+
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[0..4]);
+        let len = u32::from_be_bytes(len) as usize;
+
+        let mut micros = [0u8; 8];
+        micros.copy_from_slice(&header[4..12]);
+        let micros = u64::from_be_bytes(micros);
+
+        if len > Self::MAX_DATA_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Audio packet of {} bytes exceeds maximum", len),
+            ));
+        }
+
+        let mut data = vec![0u8; len];
+        input.read_exact(&mut data).await?;
+
+        let packet = Self {
+            timestamp: Duration::from_micros(micros),
+            data: data.into(),
+        };
+        trace!(?packet, "Read audio packet");
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decoder::AudioDecoder;
+    use super::encoder::AudioEncoder;
+    use super::input::{AudioInput, AudioSource};
+    use super::*;
+
+    #[ltest(atest)]
+    async fn frames_packets() {
+        let packets = vec![
+            AudioPacket {
+                timestamp: Duration::from_millis(20),
+                data: Bytes::from_static(b"opus"),
+            },
+            AudioPacket {
+                timestamp: Duration::from_millis(40),
+                data: Bytes::new(),
+            },
+        ];
+        let mut buf = vec![];
+        for packet in &packets {
+            packet.write_to(&mut buf).await.unwrap();
+        }
+
+        let mut input = buf.as_slice();
+        let mut read = vec![];
+        while let Some(packet) = AudioPacket::read_from(&mut input).await.unwrap() {
+            read.push(packet);
+        }
+        assert_eq!(read, packets);
+    }
+
+    #[ltest]
+    fn round_trips_null_source() {
+        let mut input = AudioInput::open(&AudioSource::Null).unwrap();
+        let mut encoder = AudioEncoder::new(Duration::from_secs(1)).unwrap();
+        let mut packets = vec![];
+        for _ in 0..5 {
+            let samples = input.read().unwrap().unwrap();
+            packets.extend(encoder.encode(&samples).unwrap());
+        }
+        assert!(!packets.is_empty());
+        assert!(packets[0].timestamp <= Duration::from_secs(1));
+        let interval = duration_of(FRAME_SAMPLES * CHANNELS);
+        assert!(packets
+            .windows(2)
+            .all(|pair| pair[1].timestamp - pair[0].timestamp == interval));
+
+        let mut decoder = AudioDecoder::new().unwrap();
+        let mut decoded = vec![];
+        for packet in &packets {
+            decoded.extend(decoder.decode(packet).unwrap());
+        }
+        assert!(!decoded.is_empty());
+        assert!(decoded.len() <= packets.len() * FRAME_SAMPLES * CHANNELS);
+        // Silence should stay near enough silent
+        assert!(decoded.iter().all(|&sample| sample.abs() < 64));
+    }
+
+    #[ltest]
+    fn converts_durations() {
+        assert_eq!(
+            duration_of(FRAME_SAMPLES * CHANNELS),
+            Duration::from_millis(20)
+        );
+        assert_eq!(
+            samples_in(Duration::from_millis(20)),
+            FRAME_SAMPLES * CHANNELS
+        );
+    }
+}
@@ -5,8 +5,9 @@ use ffmpeg_sys_next as sys;
 use ffmpeg_sys_next::avcodec_receive_frame;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+use crate::av::packet::VideoPacket;
 use crate::av::yuv_frame::YuvFrame;
-use crate::av::{ensure_av_logs_setup, to_av_error, AvError};
+use crate::av::{ensure_av_logs_setup, to_av_error, AvError, MICROS_TIME_BASE};
 use crate::prelude::*;
 
 #[derive(Derivative)]
@@ -16,6 +17,8 @@ pub struct Decoder {
     parser: ptr::NonNull<sys::AVCodecParserContext>,
     frame: ptr::NonNull<sys::AVFrame>,
     pkt: ptr::NonNull<sys::AVPacket>,
+    #[derivative(Debug = "ignore")]
+    pkt_buf: Vec<u8>,
 }
 
 // TODO: Impl debug that looks inside, also for others
@@ -55,6 +58,9 @@ impl Decoder {
         }?;
 
         unsafe {
+            // So frames decoded from VideoPackets carry their timestamps through
+            (*ctx.as_ptr()).pkt_timebase = MICROS_TIME_BASE;
+
             let status = sys::avcodec_open2(ctx.as_ptr(), codec.as_ptr(), ptr::null_mut());
             if status < 0 {
                 return Err(AvError::OpenContext(status));
@@ -70,6 +76,7 @@ impl Decoder {
             parser,
             frame,
             pkt,
+            pkt_buf: Vec::new(),
         })
     }
 
@@ -127,6 +134,42 @@ impl Decoder {
         }
     }
 
+    /// Decode a single already-delimited packet, such as one read from a framed stream.
+    ///
+    /// Frames passed to `on_frame` have their pts set in microseconds.
+    #[instrument(err, skip(packet, on_frame))]
+    pub fn decode_packet<Cb>(
+        &mut self,
+        packet: &VideoPacket,
+        mut on_frame: Cb,
+    ) -> Result<(), DecodeError>
+    where
+        Cb: for<'a> FnMut(YuvFrame<'a>),
+    {
+        // ffmpeg requires padding after the data because some optimized readers overread
+        self.pkt_buf.clear();
+        self.pkt_buf.extend_from_slice(&packet.data);
+        self.pkt_buf
+            .resize(packet.data.len() + sys::AV_INPUT_BUFFER_PADDING_SIZE as usize, 0);
+
+        unsafe {
+            let pkt = self.pkt.as_mut();
+            pkt.data = self.pkt_buf.as_mut_ptr();
+            pkt.size = packet.data.len() as c_int;
+            pkt.pts = packet.timestamp.as_micros() as i64;
+            pkt.dts = sys::AV_NOPTS_VALUE;
+            pkt.flags = if packet.key {
+                sys::AV_PKT_FLAG_KEY as c_int
+            } else {
+                0
+            };
+        }
+
+        // Not refcounted, so ffmpeg copies the data and pkt_buf is free to be reused
+        self.send_for_decoding(self.pkt.as_ptr())?;
+        self.receive_until_empty(&mut on_frame)
+    }
+
     /// Returns if a full packet has been parsed
     #[instrument(err, skip(data))]
     fn parse_into_pkt(&mut self, data: &[u8]) -> Result<usize, DecodeError> {
@@ -153,8 +196,10 @@ impl Decoder {
         Ok(ret as usize)
     }
 
+    /// Receive any frames still buffered in the decoder. Only needed when driving the decoder
+    /// with [`Self::decode_packet`], as [`Self::decode`] flushes when its input ends.
     #[instrument(err, skip(on_frame))]
-    fn flush<Cb>(&mut self, mut on_frame: Cb) -> Result<(), DecodeError>
+    pub fn flush<Cb>(&mut self, mut on_frame: Cb) -> Result<(), DecodeError>
     where
         Cb: for<'a> FnMut(YuvFrame<'a>),
     {
@@ -247,6 +292,38 @@ mod tests {
         decoder_fixture();
     }
 
+    #[ltest]
+    fn decoded_packets_keep_timestamps() {
+        use crate::av::encoder::tests::{framebuf_fixture, mode_fixture};
+        use crate::av::encoder::Encoder;
+
+        let mut encoder = Encoder::new(mode_fixture()).unwrap();
+        let mut packets = vec![];
+        for n in 0..10 {
+            encoder.send_frame(&framebuf_fixture(n)).unwrap();
+            while let Some(packet) = encoder.receive_packet().unwrap() {
+                packets.push(packet);
+            }
+        }
+        encoder.flush().unwrap();
+        while let Some(packet) = encoder.receive_packet().unwrap() {
+            packets.push(packet);
+        }
+
+        let mut decoder = decoder_fixture();
+        let mut timestamps = vec![];
+        for packet in &packets {
+            decoder
+                .decode_packet(packet, |frame| timestamps.push(frame.pts))
+                .unwrap();
+        }
+        decoder.flush(|frame| timestamps.push(frame.pts)).unwrap();
+
+        // The encoder's time base is 1/25
+        let expected: Vec<_> = (0..10).map(|n| Some(n * 40_000)).collect();
+        assert_eq!(timestamps, expected);
+    }
+
     #[ltest(atest)]
     async fn can_decode_sample_data() {
         let data = tokio::fs::File::open("sample_data/sample.h264")
@@ -0,0 +1,334 @@
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use ffmpeg_sys_next as sys;
+
+use crate::av::frame_rate::FrameRate;
+use crate::av::packet::VideoPacket;
+use crate::av::{ensure_av_logs_setup, to_av_error, AvError, MICROS_TIME_BASE};
+use crate::prelude::*;
+
+/// Reads H.264 packets back out of a file, either a container we recorded or a raw Annex B
+/// stream like `sample_data/sample.h264`.
+///
+/// Packets are converted to Annex B, which is what our decoder expects.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Demuxer {
+    path: PathBuf,
+    #[derivative(Debug = "ignore")]
+    ctx: ptr::NonNull<sys::AVFormatContext>,
+    #[derivative(Debug = "ignore")]
+    bsf: ptr::NonNull<sys::AVBSFContext>,
+    #[derivative(Debug = "ignore")]
+    pkt: ptr::NonNull<sys::AVPacket>,
+    stream_index: c_int,
+    time_base: sys::AVRational,
+    width: u32,
+    height: u32,
+    /// Used to make up timestamps for packets that don't have any, as in raw streams
+    fallback_frame_rate: FrameRate,
+    packets_read: u32,
+    /// Timestamps are made relative to this, the first pts seen
+    start_pts: Option<i64>,
+    input_ended: bool,
+}
+
+impl Demuxer {
+    // See <https://github.com/FFmpeg/FFmpeg/blob/master/doc/examples/demuxing_decoding.c>
+
+    #[instrument(err)]
+    pub fn open(path: &Path, fallback_frame_rate: FrameRate) -> Result<Self, AvError> {
+        ensure_av_logs_setup();
+
+        let c_path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| AvError::InvalidPath(path.to_owned()))?;
+
+        let mut ctx = ptr::null_mut();
+        let status = unsafe {
+            sys::avformat_open_input(&mut ctx, c_path.as_ptr(), ptr::null_mut(), ptr::null_mut())
+        };
+        if status < 0 {
+            return Err(AvError::OpenInput(status));
+        }
+        let ctx = nonnull_or!(ctx, AvError::CreateContext)?;
+
+        // Closes ctx if we fail before we've constructed Self
+        let close_ctx = |err| {
+            unsafe { sys::avformat_close_input(&mut ctx.as_ptr()) };
+            Err(err)
+        };
+
+        let status = unsafe { sys::avformat_find_stream_info(ctx.as_ptr(), ptr::null_mut()) };
+        if status < 0 {
+            return close_ctx(AvError::OpenInput(status));
+        }
+
+        let stream_index = unsafe {
+            sys::av_find_best_stream(
+                ctx.as_ptr(),
+                sys::AVMediaType::AVMEDIA_TYPE_VIDEO,
+                -1,
+                -1,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if stream_index < 0 {
+            return close_ctx(AvError::NoVideoStream);
+        }
+
+        let (codecpar, time_base) = unsafe {
+            let stream = *(*ctx.as_ptr()).streams.offset(stream_index as isize);
+            ((*stream).codecpar, (*stream).time_base)
+        };
+
+        let (codec_id, width, height) =
+            unsafe { ((*codecpar).codec_id, (*codecpar).width, (*codecpar).height) };
+        if codec_id != sys::AVCodecID::AV_CODEC_ID_H264 {
+            return close_ctx(AvError::CodecUnavailable(codec_id));
+        }
+
+        let bsf = match Self::create_annexb_filter(codecpar, time_base) {
+            Ok(bsf) => bsf,
+            Err(err) => return close_ctx(err),
+        };
+
+        let pkt = match unsafe { nonnull_or!(sys::av_packet_alloc(), AvError::AllocatePacket) } {
+            Ok(pkt) => pkt,
+            Err(err) => {
+                unsafe { sys::av_bsf_free(&mut bsf.as_ptr()) };
+                return close_ctx(err);
+            }
+        };
+
+        debug!(?path, width, height, "Opened input");
+        Ok(Self {
+            path: path.to_owned(),
+            ctx,
+            bsf,
+            pkt,
+            stream_index,
+            time_base,
+            width: width as u32,
+            height: height as u32,
+            fallback_frame_rate,
+            packets_read: 0,
+            start_pts: None,
+            input_ended: false,
+        })
+    }
+
+    /// Containers store H.264 length-prefixed, but our decoder only understands Annex B.
+    /// Streams that are already Annex B pass through unchanged.
+    fn create_annexb_filter(
+        codecpar: *const sys::AVCodecParameters,
+        time_base: sys::AVRational,
+    ) -> Result<ptr::NonNull<sys::AVBSFContext>, AvError> {
+        unsafe {
+            let filter = sys::av_bsf_get_by_name(b"h264_mp4toannexb\0".as_ptr().cast());
+            if filter.is_null() {
+                return Err(AvError::CreateFilter(0));
+            }
+
+            let mut bsf = ptr::null_mut();
+            let status = sys::av_bsf_alloc(filter, &mut bsf);
+            if status < 0 {
+                return Err(AvError::CreateFilter(status));
+            }
+            let bsf = nonnull_or!(bsf, AvError::CreateFilter(0))?;
+
+            let status = sys::avcodec_parameters_copy((*bsf.as_ptr()).par_in, codecpar);
+            if status < 0 {
+                sys::av_bsf_free(&mut bsf.as_ptr());
+                return Err(AvError::CreateFilter(status));
+            }
+            (*bsf.as_ptr()).time_base_in = time_base;
+
+            let status = sys::av_bsf_init(bsf.as_ptr());
+            if status < 0 {
+                sys::av_bsf_free(&mut bsf.as_ptr());
+                return Err(AvError::CreateFilter(status));
+            }
+
+            Ok(bsf)
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Packets are returned in decode order. Returns None at the end of the input.
+    #[instrument(err)]
+    pub fn read_packet(&mut self) -> Result<Option<VideoPacket>, AvError> {
+        loop {
+            let status = unsafe { sys::av_bsf_receive_packet(self.bsf.as_ptr(), self.pkt.as_ptr()) };
+            if status == 0 {
+                return Ok(Some(self.take_packet()));
+            } else if status == sys::AVERROR_EOF {
+                return Ok(None);
+            } else if status != to_av_error(sys::EAGAIN) {
+                return Err(AvError::Filter(status));
+            }
+
+            // The filter needs more input
+            if self.input_ended {
+                return Ok(None);
+            }
+
+            let status = unsafe { sys::av_read_frame(self.ctx.as_ptr(), self.pkt.as_ptr()) };
+            if status == sys::AVERROR_EOF {
+                debug!(path = ?self.path, "Reached end of input");
+                self.input_ended = true;
+                // Null flushes the filter
+                let status = unsafe { sys::av_bsf_send_packet(self.bsf.as_ptr(), ptr::null_mut()) };
+                if status < 0 {
+                    return Err(AvError::Filter(status));
+                }
+                continue;
+            } else if status < 0 {
+                return Err(AvError::Demux(status));
+            }
+
+            if unsafe { self.pkt.as_ref() }.stream_index != self.stream_index {
+                unsafe { sys::av_packet_unref(self.pkt.as_ptr()) };
+                continue;
+            }
+
+            // Takes ownership of the packet's data and resets it
+            let status = unsafe { sys::av_bsf_send_packet(self.bsf.as_ptr(), self.pkt.as_ptr()) };
+            if status < 0 {
+                unsafe { sys::av_packet_unref(self.pkt.as_ptr()) };
+                return Err(AvError::Filter(status));
+            }
+        }
+    }
+
+    fn take_packet(&mut self) -> VideoPacket {
+        let pkt_ref = unsafe { self.pkt.as_ref() };
+
+        let fallback_pts = || {
+            let interval = self.fallback_frame_rate.interval() * self.packets_read;
+            unsafe {
+                sys::av_rescale_q(interval.as_micros() as i64, MICROS_TIME_BASE, self.time_base)
+            }
+        };
+        let pts = if pkt_ref.pts != sys::AV_NOPTS_VALUE {
+            pkt_ref.pts
+        } else if pkt_ref.dts != sys::AV_NOPTS_VALUE {
+            pkt_ref.dts
+        } else {
+            fallback_pts()
+        };
+        let dts = if pkt_ref.dts != sys::AV_NOPTS_VALUE {
+            pkt_ref.dts
+        } else {
+            pts
+        };
+
+        let start_pts = *self.start_pts.get_or_insert(pts);
+        let micros = unsafe { sys::av_rescale_q(pts - start_pts, self.time_base, MICROS_TIME_BASE) };
+        let dts_offset_micros =
+            unsafe { sys::av_rescale_q(pts - dts, self.time_base, MICROS_TIME_BASE) };
+
+        let data = unsafe { &*ptr::slice_from_raw_parts(pkt_ref.data, pkt_ref.size as usize) };
+
+        let packet = VideoPacket {
+            timestamp: Duration::from_micros(micros.max(0) as u64),
+            dts_offset: Duration::from_micros(dts_offset_micros.max(0) as u64),
+            key: pkt_ref.flags & sys::AV_PKT_FLAG_KEY as c_int != 0,
+            data: Bytes::copy_from_slice(data),
+        };
+
+        unsafe { sys::av_packet_unref(self.pkt.as_ptr()) };
+        self.packets_read += 1;
+
+        trace!(?packet, "Demuxed packet");
+        packet
+    }
+}
+
+impl Drop for Demuxer {
+    fn drop(&mut self) {
+        unsafe {
+            sys::av_packet_free(&mut self.pkt.as_ptr());
+            sys::av_bsf_free(&mut self.bsf.as_ptr());
+            sys::avformat_close_input(&mut self.ctx.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::av::decoder::Decoder;
+    use crate::av::encoder::tests::{framebuf_fixture, mode_fixture};
+    use crate::av::encoder::Encoder;
+    use crate::av::packet::PacketSink;
+    use crate::av::recorder::Recorder;
+
+    fn read_all(path: &Path) -> Vec<VideoPacket> {
+        let mut demuxer = Demuxer::open(path, FrameRate::default()).unwrap();
+        let mut packets = vec![];
+        while let Some(packet) = demuxer.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    fn decode_all(packets: &[VideoPacket]) -> usize {
+        let mut decoder = Decoder::new().unwrap();
+        let mut count = 0;
+        for packet in packets {
+            decoder.decode_packet(packet, |_| count += 1).unwrap();
+        }
+        decoder.flush(|_| count += 1).unwrap();
+        count
+    }
+
+    #[ltest]
+    fn demuxes_raw_sample() {
+        let packets = read_all(Path::new("sample_data/sample.h264"));
+        assert!(!packets.is_empty());
+        assert!(packets[0].key);
+        assert_eq!(decode_all(&packets), packets.len());
+    }
+
+    #[ltest(atest)]
+    async fn round_trips_recording() {
+        let path = std::env::temp_dir().join("remdisp_test_demux.mkv");
+        let mode = mode_fixture();
+
+        let mut encoder = Encoder::new(mode).unwrap();
+        let mut recorder = Recorder::create(&path, mode.width, mode.height).unwrap();
+        for n in 0..10 {
+            encoder.send_frame(&framebuf_fixture(n)).unwrap();
+            while let Some(packet) = encoder.receive_packet().unwrap() {
+                recorder.write_packet(&packet).await.unwrap();
+            }
+        }
+        encoder.flush().unwrap();
+        while let Some(packet) = encoder.receive_packet().unwrap() {
+            recorder.write_packet(&packet).await.unwrap();
+        }
+        recorder.finish().await.unwrap();
+
+        let packets = read_all(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut timestamps: Vec<_> = packets.iter().map(|p| p.timestamp).collect();
+        timestamps.sort();
+        let expected: Vec<_> = (0..10).map(|n| Duration::from_millis(40 * n)).collect();
+        assert_eq!(timestamps, expected);
+        assert_eq!(decode_all(&packets), 10);
+    }
+}
@@ -1,11 +1,15 @@
 use std::ptr;
+use std::time::Duration;
 
+use bytes::Bytes;
 use evdi::prelude::Mode;
 use ffmpeg_sys_next as sys;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::av;
-use crate::av::{converter::Converter, ensure_av_logs_setup, AvError};
+use crate::av::frame_rate::FrameRate;
+use crate::av::packet::VideoPacket;
+use crate::av::{converter::Converter, ensure_av_logs_setup, AvError, MICROS_TIME_BASE};
 use crate::prelude::*;
 
 mod codec_options;
@@ -16,8 +20,12 @@ pub struct Encoder {
     pkt: ptr::NonNull<sys::AVPacket>,
     mode: Mode,
     converter: Converter,
-    /// Presentation timestamp
-    pts: i64,
+    frame_rate: FrameRate,
+    frames_sent: u32,
+    /// Presentation timestamp of the last frame sent, in the codec time base
+    last_pts: Option<i64>,
+    /// Make the next frame a keyframe
+    force_keyframe: bool,
 }
 
 /// Currently re-using after flushing not supported
@@ -25,8 +33,12 @@ impl Encoder {
     // See <https://ffmpeg.org/doxygen/4.0/group__lavc__encdec.html>
     // and <https://github.com/FFmpeg/FFmpeg/blob/master/doc/examples/encode_video.c>
 
-    #[instrument(err)]
     pub fn new(mode: Mode) -> Result<Self, AvError> {
+        Self::with_frame_rate(mode, FrameRate::default())
+    }
+
+    #[instrument(err)]
+    pub fn with_frame_rate(mode: Mode, frame_rate: FrameRate) -> Result<Self, AvError> {
         ensure_av_logs_setup();
 
         let codec_id = sys::AVCodecID::AV_CODEC_ID_H264;
@@ -63,7 +75,12 @@ impl Encoder {
             ctx.width = mode.width as i32;
             ctx.height = mode.height as i32;
             ctx.pix_fmt = target_src_format;
-            ctx.time_base = sys::AVRational { num: 1, den: 25 };
+            ctx.time_base = frame_rate.time_base();
+            // Only a hint for rate control when the frame rate is variable
+            ctx.framerate = sys::AVRational {
+                num: frame_rate.max_fps() as i32,
+                den: 1,
+            };
 
             let options = codec_options::Options::from(ctx.priv_data);
             debug!(?options, "Options supported by codec");
@@ -77,6 +94,8 @@ impl Encoder {
             Self::set_opt(ctx, b"preset\0", b"ultrafast\0")?;
             // Constant rate factor, i.e. quality (0..51.0, lower better quality)
             Self::set_opt(ctx, b"crf\0", b"0\0")?;
+            // So keyframes we ask for let a receiver that lost frames start decoding again
+            Self::set_opt(ctx, b"forced-idr\0", b"1\0")?;
         }
         debug!("Configured codec context");
 
@@ -97,7 +116,10 @@ impl Encoder {
             pkt,
             mode,
             converter,
-            pts: 0,
+            frame_rate,
+            frames_sent: 0,
+            last_pts: None,
+            force_keyframe: false,
         })
     }
 
@@ -132,21 +154,60 @@ impl Encoder {
         formats
     }
 
+    /// Send a frame assuming frames are exactly one frame interval apart.
     #[instrument(err, skip(bytes))]
     pub fn send_frame(&mut self, bytes: &[u8]) -> Result<(), AvError> {
+        let timestamp = self.frame_rate.interval() * self.frames_sent;
+        self.send_frame_at(bytes, timestamp)
+    }
+
+    /// Send a frame captured at `timestamp` after the start of the stream.
+    #[instrument(err, skip(bytes))]
+    pub fn send_frame_at(&mut self, bytes: &[u8], timestamp: Duration) -> Result<(), AvError> {
+        let time_base = self.frame_rate.time_base();
+        let pts = unsafe {
+            sys::av_rescale_q(timestamp.as_micros() as i64, MICROS_TIME_BASE, time_base)
+        };
+        // The encoder rejects timestamps that don't strictly increase
+        let pts = match self.last_pts {
+            Some(last) if pts <= last => {
+                debug!(pts, last, "Bumping non-increasing pts");
+                last + 1
+            }
+            _ => pts,
+        };
+
         let frame = self.converter.convert(bytes);
         unsafe {
-            frame.pts = self.pts;
-            self.pts += 1;
+            frame.pts = pts;
+            // The frame is reused, so this has to be reset after a forced keyframe
+            frame.pict_type = if self.force_keyframe {
+                sys::AVPictureType::AV_PICTURE_TYPE_I
+            } else {
+                sys::AVPictureType::AV_PICTURE_TYPE_NONE
+            };
 
             let status = sys::avcodec_send_frame(self.ctx.as_ptr(), frame);
             if status < 0 {
                 return Err(AvError::SendForEncoding(status));
             }
         }
+
+        self.last_pts = Some(pts);
+        self.frames_sent += 1;
+        self.force_keyframe = false;
         Ok(())
     }
 
+    /// Make the next frame sent a keyframe.
+    pub fn request_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    pub fn frame_rate(&self) -> FrameRate {
+        self.frame_rate
+    }
+
     #[instrument(err)]
     pub fn flush(&mut self) -> Result<(), AvError> {
         unsafe {
@@ -170,41 +231,74 @@ impl Encoder {
         &mut self,
         mut out: W,
     ) -> Result<(), AvError> {
-        loop {
-            unsafe {
-                // NOTE: I'm not sure if this receives packets in order. If not, we have a problem,
-                // since we don't pass through any sort of timestamp (pts, dts, etc).
-                let status = sys::avcodec_receive_packet(self.ctx.as_ptr(), self.pkt.as_ptr());
-                if status == av::to_av_error(sys::EAGAIN) || status == sys::AVERROR_EOF {
-                    return Ok(());
-                } else if status < 0 {
-                    return Err(AvError::Encode);
-                }
-            };
+        while let Some(packet) = self.receive_packet()? {
+            out.write_all(&packet.data).await?;
+        }
+        Ok(())
+    }
 
-            // The docs mention something called "muxing", which is apparently writing packets to
-            // a file. We don't need headers to tell the other side what sort of format, so I don't
-            // think we need that?
-            // See <https://ffmpeg.org/doxygen/3.2/group__lavf__encoding.html#details>
-
-            let pkt_ref = unsafe { self.pkt.as_ref() };
-
-            debug!(
-                pts = pkt_ref.pts,
-                dts = pkt_ref.dts,
-                size = pkt_ref.size,
-                stream_index = pkt_ref.stream_index,
-                flags = pkt_ref.flags,
-                duration = pkt_ref.duration,
-                pos = pkt_ref.pos,
-                convergence_duration = pkt_ref.convergence_duration,
-                "Received packet"
-            );
+    /// Like [`Self::receive_available`], but frames each packet with its timestamp so the
+    /// other side can pace presentation. See [`VideoPacket`].
+    #[instrument(err, skip(out))]
+    pub async fn receive_available_framed<W: AsyncWrite + Unpin>(
+        &mut self,
+        mut out: W,
+    ) -> Result<(), AvError> {
+        while let Some(packet) = self.receive_packet()? {
+            packet.write_to(&mut out).await?;
+        }
+        Ok(())
+    }
 
-            let data = unsafe { &*ptr::slice_from_raw_parts(pkt_ref.data, pkt_ref.size as usize) };
+    /// Returns None if the encoder needs more input (or has been fully flushed).
+    pub fn receive_packet(&mut self) -> Result<Option<VideoPacket>, AvError> {
+        unsafe {
+            let status = sys::avcodec_receive_packet(self.ctx.as_ptr(), self.pkt.as_ptr());
+            if status == av::to_av_error(sys::EAGAIN) || status == sys::AVERROR_EOF {
+                return Ok(None);
+            } else if status < 0 {
+                return Err(AvError::Encode);
+            }
+        };
 
-            out.write_all(data).await?;
-        }
+        // The docs mention something called "muxing", which is apparently writing packets to
+        // a file. We don't need headers to tell the other side what sort of format, so I don't
+        // think we need that?
+        // See <https://ffmpeg.org/doxygen/3.2/group__lavf__encoding.html#details>
+
+        let pkt_ref = unsafe { self.pkt.as_ref() };
+
+        debug!(
+            pts = pkt_ref.pts,
+            dts = pkt_ref.dts,
+            size = pkt_ref.size,
+            stream_index = pkt_ref.stream_index,
+            flags = pkt_ref.flags,
+            duration = pkt_ref.duration,
+            pos = pkt_ref.pos,
+            convergence_duration = pkt_ref.convergence_duration,
+            "Received packet"
+        );
+
+        let data = unsafe { &*ptr::slice_from_raw_parts(pkt_ref.data, pkt_ref.size as usize) };
+
+        let time_base = unsafe { self.ctx.as_ref().time_base };
+        let micros = unsafe { sys::av_rescale_q(pkt_ref.pts, time_base, MICROS_TIME_BASE) };
+        let dts_offset_micros = unsafe {
+            sys::av_rescale_q(pkt_ref.pts - pkt_ref.dts, time_base, MICROS_TIME_BASE)
+        };
+
+        let packet = VideoPacket {
+            // pts is never negative for us because we start counting at zero
+            timestamp: Duration::from_micros(micros.max(0) as u64),
+            dts_offset: Duration::from_micros(dts_offset_micros.max(0) as u64),
+            key: pkt_ref.flags & sys::AV_PKT_FLAG_KEY as i32 != 0,
+            data: Bytes::copy_from_slice(data),
+        };
+
+        unsafe { sys::av_packet_unref(self.pkt.as_ptr()) };
+
+        Ok(Some(packet))
     }
 }
 
@@ -279,6 +373,36 @@ pub mod tests {
         encode_to(&mut out).await;
     }
 
+    #[ltest]
+    fn variable_frame_rate_uses_capture_times() {
+        let frame_rate = FrameRate::Variable { max: 60 };
+        let mut encoder = Encoder::with_frame_rate(mode_fixture(), frame_rate).unwrap();
+
+        let captured_at = [0, 17, 500, 503, 2_000];
+        let mut timestamps = vec![];
+        for (n, millis) in captured_at.iter().enumerate() {
+            let bytes = framebuf_fixture(n as u32);
+            encoder
+                .send_frame_at(&bytes, Duration::from_millis(*millis))
+                .unwrap();
+            while let Some(packet) = encoder.receive_packet().unwrap() {
+                timestamps.push(packet.timestamp);
+            }
+        }
+        encoder.flush().unwrap();
+        while let Some(packet) = encoder.receive_packet().unwrap() {
+            timestamps.push(packet.timestamp);
+        }
+
+        // Packets may come out in decode order, which differs with b-frames
+        timestamps.sort();
+        let expected: Vec<_> = captured_at
+            .iter()
+            .map(|millis| Duration::from_millis(*millis))
+            .collect();
+        assert_eq!(timestamps, expected);
+    }
+
     #[ignore]
     #[ltest(atest)]
     async fn output_video_to_file_for_manual_check() {
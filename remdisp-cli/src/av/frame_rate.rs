@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use ffmpeg_sys_next as sys;
+
+use crate::av::MICROS_TIME_BASE;
+use crate::prelude::*;
+
+/// How often frames are captured and encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameRate {
+    /// Capture at a fixed rate, repeating the previous frame if nothing changed.
+    Constant(u32),
+    /// Only capture when the screen changes, but no more often than `max` frames per second.
+    /// Frames are timestamped with when they were actually captured.
+    Variable { max: u32 },
+}
+
+impl FrameRate {
+    pub const DEFAULT_FPS: u32 = 25;
+    pub const MAX_FPS: u32 = 240;
+
+    /// Fails if the rate is zero or unreasonably high.
+    pub fn new(fps: u32, variable: bool) -> Result<Self, InvalidFrameRate> {
+        if fps == 0 || fps > Self::MAX_FPS {
+            return Err(InvalidFrameRate(fps));
+        }
+
+        Ok(if variable {
+            Self::Variable { max: fps }
+        } else {
+            Self::Constant(fps)
+        })
+    }
+
+    pub fn max_fps(&self) -> u32 {
+        match *self {
+            Self::Constant(fps) => fps,
+            Self::Variable { max } => max,
+        }
+    }
+
+    /// The minimum time between two captures
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.max_fps()
+    }
+
+    pub fn is_variable(&self) -> bool {
+        matches!(self, Self::Variable { .. })
+    }
+
+    pub(crate) fn time_base(&self) -> sys::AVRational {
+        match *self {
+            Self::Constant(fps) => sys::AVRational {
+                num: 1,
+                den: fps as i32,
+            },
+            // Real capture times don't fall on any grid, so keep full precision
+            Self::Variable { .. } => MICROS_TIME_BASE,
+        }
+    }
+}
+
+impl Default for FrameRate {
+    fn default() -> Self {
+        Self::Constant(Self::DEFAULT_FPS)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Frame rate must be between 1 and {max} fps, got {0}", max = FrameRate::MAX_FPS)]
+pub struct InvalidFrameRate(pub u32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ltest]
+    fn rejects_out_of_range() {
+        assert!(FrameRate::new(0, false).is_err());
+        assert!(FrameRate::new(FrameRate::MAX_FPS + 1, true).is_err());
+        assert_eq!(FrameRate::new(60, true).unwrap(), FrameRate::Variable { max: 60 });
+    }
+
+    #[ltest]
+    fn interval_matches_rate() {
+        assert_eq!(FrameRate::Constant(25).interval(), Duration::from_millis(40));
+        assert_eq!(
+            FrameRate::Variable { max: 50 }.interval(),
+            Duration::from_millis(20)
+        );
+    }
+}
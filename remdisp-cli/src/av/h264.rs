@@ -0,0 +1,111 @@
+//! Just enough H.264 bitstream handling to get by without a bitstream filter.
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+const NAL_TYPE_MASK: u8 = 0x1f;
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_PPS: u8 = 8;
+
+/// Iterate over the NAL units (without start codes) of an Annex B byte stream.
+pub fn nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = skip_start_code(data);
+    std::iter::from_fn(move || {
+        let rest_ref = rest?;
+        match find_start_code(rest_ref) {
+            Some((nal_end, next_start)) => {
+                rest = Some(&rest_ref[next_start..]);
+                Some(&rest_ref[..nal_end])
+            }
+            None => {
+                rest = None;
+                Some(rest_ref)
+            }
+        }
+    })
+    .filter(|nal| !nal.is_empty())
+}
+
+/// The SPS and PPS NAL units in `data`, each prefixed with a start code.
+///
+/// Muxers accept this as extradata when the encoder wasn't asked for global headers.
+pub fn parameter_sets(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    for nal in nal_units(data) {
+        let nal_type = nal[0] & NAL_TYPE_MASK;
+        if nal_type == NAL_TYPE_SPS || nal_type == NAL_TYPE_PPS {
+            out.extend_from_slice(&START_CODE);
+            out.extend_from_slice(nal);
+        }
+    }
+    out
+}
+
+fn skip_start_code(data: &[u8]) -> Option<&[u8]> {
+    if data.starts_with(&START_CODE) {
+        Some(&data[4..])
+    } else if data.starts_with(&START_CODE[1..]) {
+        Some(&data[3..])
+    } else if data.is_empty() {
+        None
+    } else {
+        Some(data)
+    }
+}
+
+/// Returns the end of the current NAL and the start of the next.
+fn find_start_code(data: &[u8]) -> Option<(usize, usize)> {
+    let idx = data.windows(3).position(|w| w == &START_CODE[1..])?;
+    // A 4 byte start code is a zero followed by a 3 byte one
+    let nal_end = if idx > 0 && data[idx - 1] == 0 {
+        idx - 1
+    } else {
+        idx
+    };
+    Some((nal_end, idx + 3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    const SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1f];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xe3];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84, 0x00];
+
+    fn stream_fixture() -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&START_CODE);
+        data.extend_from_slice(SPS);
+        data.extend_from_slice(&START_CODE[1..]);
+        data.extend_from_slice(PPS);
+        data.extend_from_slice(&START_CODE);
+        data.extend_from_slice(IDR);
+        data
+    }
+
+    #[ltest]
+    fn splits_nal_units() {
+        let data = stream_fixture();
+        let nals: Vec<_> = nal_units(&data).collect();
+        assert_eq!(nals, vec![SPS, PPS, IDR]);
+    }
+
+    #[ltest]
+    fn extracts_parameter_sets() {
+        let mut expected = vec![];
+        expected.extend_from_slice(&START_CODE);
+        expected.extend_from_slice(SPS);
+        expected.extend_from_slice(&START_CODE);
+        expected.extend_from_slice(PPS);
+
+        assert_eq!(parameter_sets(&stream_fixture()), expected);
+    }
+
+    #[ltest]
+    fn no_parameter_sets_in_non_key_packet() {
+        let mut data = START_CODE.to_vec();
+        data.extend_from_slice(&[0x41, 0x9a, 0x00]);
+        assert!(parameter_sets(&data).is_empty());
+    }
+}
@@ -1,6 +1,7 @@
 use std::ffi::c_void;
 use std::io;
 use std::os::raw::{c_char, c_int};
+use std::path::PathBuf;
 use std::sync::Once;
 
 use ffmpeg_sys_next as sys;
@@ -15,11 +16,24 @@ macro_rules! nonnull_or {
     }};
 }
 
+pub mod audio;
 mod converter;
 pub mod decoder;
+pub mod demuxer;
 pub mod encoder;
+pub mod frame_rate;
+mod h264;
+pub mod packet;
+pub mod recorder;
+pub mod udp;
 pub mod yuv_frame;
 
+/// Time base of [`packet::VideoPacket`] timestamps
+const MICROS_TIME_BASE: sys::AVRational = sys::AVRational {
+    num: 1,
+    den: 1_000_000,
+};
+
 static LOG_SETUP: Once = Once::new();
 pub(crate) fn ensure_av_logs_setup() {
     LOG_SETUP.call_once(|| {
@@ -98,6 +112,34 @@ pub enum AvError {
     AllocateFrame,
     #[error("Error during decoding: AV_ERROR {0}")]
     InDecoding(i32),
+    #[error("Path {0:?} can't be passed to ffmpeg")]
+    InvalidPath(PathBuf),
+    #[error("Couldn't choose a container format for {0:?}, try a .mkv or .mp4 extension")]
+    UnknownContainer(PathBuf),
+    #[error("Failed to open output file: AV_ERROR {0}")]
+    OpenOutput(i32),
+    #[error("Failed to write container header: AV_ERROR {0}")]
+    WriteHeader(i32),
+    #[error("Failed to mux packet: AV_ERROR {0}")]
+    Mux(i32),
+    #[error("Failed to open input file: AV_ERROR {0}")]
+    OpenInput(i32),
+    #[error("Input has no video stream")]
+    NoVideoStream,
+    #[error("Failed to read packet from input: AV_ERROR {0}")]
+    Demux(i32),
+    #[error("Failed to create bitstream filter: AV_ERROR {0}")]
+    CreateFilter(i32),
+    #[error("Error filtering packet: AV_ERROR {0}")]
+    Filter(i32),
+    #[error("Input has no audio stream")]
+    NoAudioStream,
+    #[error("Input format {0} not available, check your ffmpeg installation")]
+    InputFormatUnavailable(&'static str),
+    #[error("Failed to resample audio: AV_ERROR {0}")]
+    Resample(i32),
+    #[error("Can't convert audio from sample format {0}")]
+    SampleFormat(i32),
 }
 
 /// Copy of the macro AVERROR
@@ -0,0 +1,192 @@
+use std::fmt::Debug;
+use std::io;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::av::AvError;
+use crate::prelude::*;
+
+/// An encoded video packet with the timing information the display needs to present it.
+///
+/// On the wire each packet is framed as a big-endian header followed by the data:
+/// `len: u32`, `timestamp_micros: u64`, `dts_offset_micros: u32`, `flags: u8`.
+#[derive(Derivative, Clone, PartialEq, Eq)]
+#[derivative(Debug)]
+pub struct VideoPacket {
+    /// Presentation time relative to the start of the stream
+    pub timestamp: Duration,
+    /// How long before `timestamp` the packet needs to be decoded. Zero unless the encoder
+    /// reorders frames. Only muxers care about this, our decoder doesn't.
+    pub dts_offset: Duration,
+    pub key: bool,
+    #[derivative(Debug = "ignore")]
+    pub data: Bytes,
+}
+
+impl VideoPacket {
+    const HEADER_LEN: usize = 4 + 8 + 4 + 1;
+    const FLAG_KEY: u8 = 1;
+    /// Guards against allocating absurd amounts of memory if the stream is corrupt
+    const MAX_DATA_LEN: usize = 64 * 1024 * 1024;
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, out: &mut W) -> io::Result<()> {
+        let mut header = [0u8; Self::HEADER_LEN];
+        header[0..4].copy_from_slice(&(self.data.len() as u32).to_be_bytes());
+        header[4..12].copy_from_slice(&(self.timestamp.as_micros() as u64).to_be_bytes());
+        header[12..16].copy_from_slice(&(self.dts_offset.as_micros() as u32).to_be_bytes());
+        header[16] = if self.key { Self::FLAG_KEY } else { 0 };
+
+        out.write_all(&header).await?;
+        out.write_all(&self.data).await?;
+        Ok(())
+    }
+
+    /// Returns None if the input ended cleanly between packets.
+    pub async fn read_from<R: AsyncRead + Unpin>(input: &mut R) -> io::Result<Option<Self>> {
+        let mut header = [0u8; Self::HEADER_LEN];
+
+        // Distinguish a clean end of stream from one in the middle of a header
+        let first = input.read(&mut header).await?;
+        if first == 0 {
+            return Ok(None);
+        }
+        input.read_exact(&mut header[first..]).await?;
+
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[0..4]);
+        let len = u32::from_be_bytes(len) as usize;
+
+        let mut micros = [0u8; 8];
+        micros.copy_from_slice(&header[4..12]);
+        let micros = u64::from_be_bytes(micros);
+
+        let mut dts_offset_micros = [0u8; 4];
+        dts_offset_micros.copy_from_slice(&header[12..16]);
+        let dts_offset_micros = u32::from_be_bytes(dts_offset_micros);
+
+        let flags = header[16];
+
+        if len > Self::MAX_DATA_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Video packet of {} bytes exceeds maximum", len),
+            ));
+        }
+
+        let mut data = vec![0u8; len];
+        input.read_exact(&mut data).await?;
+
+        let packet = Self {
+            timestamp: Duration::from_micros(micros),
+            dts_offset: Duration::from_micros(dts_offset_micros as u64),
+            key: flags & Self::FLAG_KEY != 0,
+            data: data.into(),
+        };
+        trace!(?packet, "Read video packet");
+        Ok(Some(packet))
+    }
+
+    /// Whether a packet framed by [`Self::write_to`] is a keyframe, given at least its header.
+    pub fn is_key_framed(framed: &[u8]) -> bool {
+        framed
+            .get(Self::HEADER_LEN - 1)
+            .map_or(false, |flags| flags & Self::FLAG_KEY != 0)
+    }
+
+    /// Decode timestamp in microseconds. May be negative.
+    pub fn dts_micros(&self) -> i64 {
+        self.timestamp.as_micros() as i64 - self.dts_offset.as_micros() as i64
+    }
+}
+
+/// Somewhere encoded video can be sent, such as the display or a recording.
+#[async_trait]
+pub trait PacketSink: Send + Debug {
+    async fn write_packet(&mut self, packet: &VideoPacket) -> Result<(), AvError>;
+
+    /// Whether the receiver lost video and can't carry on until the next keyframe, so one
+    /// should be sent early. Resets the request.
+    fn take_keyframe_request(&mut self) -> bool {
+        false
+    }
+
+    /// Called once after the last packet.
+    async fn finish(&mut self) -> Result<(), AvError> {
+        Ok(())
+    }
+}
+
+/// Sends packets over a byte stream using the framing described on [`VideoPacket`].
+#[derive(Debug)]
+pub struct FramedSink<W>(pub W);
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send + Debug> PacketSink for FramedSink<W> {
+    async fn write_packet(&mut self, packet: &VideoPacket) -> Result<(), AvError> {
+        packet.write_to(&mut self.0).await?;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<(), AvError> {
+        self.0.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet_fixture(n: u64) -> VideoPacket {
+        VideoPacket {
+            timestamp: Duration::from_millis(40 * n),
+            dts_offset: Duration::from_millis(40 * (n % 2)),
+            key: n == 0,
+            data: vec![n as u8; 100 + n as usize].into(),
+        }
+    }
+
+    #[ltest(atest)]
+    async fn round_trips_packets() {
+        let mut buf = vec![];
+        for n in 0..5 {
+            packet_fixture(n).write_to(&mut buf).await.unwrap();
+        }
+
+        let mut input = buf.as_slice();
+        for n in 0..5 {
+            let packet = VideoPacket::read_from(&mut input).await.unwrap();
+            assert_eq!(packet, Some(packet_fixture(n)));
+        }
+        assert_eq!(VideoPacket::read_from(&mut input).await.unwrap(), None);
+    }
+
+    #[ltest(atest)]
+    async fn errors_on_truncated_packet() {
+        let mut buf = vec![];
+        packet_fixture(1).write_to(&mut buf).await.unwrap();
+        buf.truncate(buf.len() - 1);
+
+        let err = VideoPacket::read_from(&mut buf.as_slice())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[ltest(atest)]
+    async fn rejects_oversized_packet() {
+        let mut buf = vec![];
+        buf.extend_from_slice(&u32::MAX.to_be_bytes());
+        buf.extend_from_slice(&0u64.to_be_bytes());
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.push(0);
+
+        let err = VideoPacket::read_from(&mut buf.as_slice())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
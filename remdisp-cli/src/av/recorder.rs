@@ -0,0 +1,293 @@
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use async_trait::async_trait;
+use ffmpeg_sys_next as sys;
+
+use crate::av::packet::{PacketSink, VideoPacket};
+use crate::av::{ensure_av_logs_setup, h264, AvError, MICROS_TIME_BASE};
+use crate::prelude::*;
+
+/// Muxes encoded packets into a container file that ordinary players understand.
+///
+/// The container is chosen from the file extension (e.g. `.mkv` or `.mp4`). Nothing is written
+/// until the first key packet, as that is where we get the codec parameters from. Call
+/// [`PacketSink::finish`] when done, otherwise some containers (notably mp4) won't be playable.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    #[derivative(Debug = "ignore")]
+    ctx: ptr::NonNull<sys::AVFormatContext>,
+    #[derivative(Debug = "ignore")]
+    stream: ptr::NonNull<sys::AVStream>,
+    #[derivative(Debug = "ignore")]
+    pkt: ptr::NonNull<sys::AVPacket>,
+    header_written: bool,
+    finished: bool,
+}
+
+// Safety: We never share the pointers, they're only used through &mut self
+unsafe impl Send for Recorder {}
+
+impl Recorder {
+    // See <https://github.com/FFmpeg/FFmpeg/blob/master/doc/examples/muxing.c>
+
+    #[instrument(err)]
+    pub fn create(path: &Path, width: u32, height: u32) -> Result<Self, AvError> {
+        ensure_av_logs_setup();
+
+        let c_path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| AvError::InvalidPath(path.to_owned()))?;
+
+        let mut ctx = ptr::null_mut();
+        let status = unsafe {
+            sys::avformat_alloc_output_context2(
+                &mut ctx,
+                ptr::null_mut(),
+                ptr::null(),
+                c_path.as_ptr(),
+            )
+        };
+        if status < 0 {
+            return Err(AvError::UnknownContainer(path.to_owned()));
+        }
+        let ctx = nonnull_or!(ctx, AvError::CreateContext)?;
+
+        // Owned by ctx from here
+        let stream = unsafe {
+            nonnull_or!(
+                sys::avformat_new_stream(ctx.as_ptr(), ptr::null()),
+                AvError::CreateContext
+            )
+        };
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                unsafe { sys::avformat_free_context(ctx.as_ptr()) };
+                return Err(err);
+            }
+        };
+
+        let pkt = match unsafe { nonnull_or!(sys::av_packet_alloc(), AvError::AllocatePacket) } {
+            Ok(pkt) => pkt,
+            Err(err) => {
+                unsafe { sys::avformat_free_context(ctx.as_ptr()) };
+                return Err(err);
+            }
+        };
+
+        // From here Drop cleans up
+        let recorder = Self {
+            path: path.to_owned(),
+            ctx,
+            stream,
+            pkt,
+            header_written: false,
+            finished: false,
+        };
+
+        unsafe {
+            let stream = &mut *recorder.stream.as_ptr();
+            // The muxer may pick something else when we write the header
+            stream.time_base = MICROS_TIME_BASE;
+
+            let par = &mut *stream.codecpar;
+            par.codec_type = sys::AVMediaType::AVMEDIA_TYPE_VIDEO;
+            par.codec_id = sys::AVCodecID::AV_CODEC_ID_H264;
+            par.format = sys::AVPixelFormat::AV_PIX_FMT_YUV420P as c_int;
+            par.width = width as c_int;
+            par.height = height as c_int;
+
+            let status = sys::avio_open(
+                &mut (*recorder.ctx.as_ptr()).pb,
+                c_path.as_ptr(),
+                sys::AVIO_FLAG_WRITE as c_int,
+            );
+            if status < 0 {
+                return Err(AvError::OpenOutput(status));
+            }
+        }
+
+        info!(path = ?recorder.path, "Recording");
+        Ok(recorder)
+    }
+
+    fn write_header(&mut self, first_key: &VideoPacket) -> Result<(), AvError> {
+        let extradata = h264::parameter_sets(&first_key.data);
+        if extradata.is_empty() {
+            warn!("First key packet has no parameter sets, recording may not be playable");
+        }
+
+        unsafe {
+            let par = &mut *(*self.stream.as_ptr()).codecpar;
+            let padded_size = extradata.len() + sys::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+            let buf = sys::av_mallocz(padded_size) as *mut u8;
+            if buf.is_null() {
+                return Err(AvError::AllocatePacket);
+            }
+            ptr::copy_nonoverlapping(extradata.as_ptr(), buf, extradata.len());
+            // Freed by ffmpeg along with the stream
+            par.extradata = buf;
+            par.extradata_size = extradata.len() as c_int;
+
+            let status = sys::avformat_write_header(self.ctx.as_ptr(), ptr::null_mut());
+            if status < 0 {
+                return Err(AvError::WriteHeader(status));
+            }
+        }
+
+        self.header_written = true;
+        debug!(path = ?self.path, "Wrote recording header");
+        Ok(())
+    }
+
+    fn write(&mut self, packet: &VideoPacket) -> Result<(), AvError> {
+        if !self.header_written {
+            if !packet.key {
+                debug!("Skipping packet before first key packet");
+                return Ok(());
+            }
+            self.write_header(packet)?;
+        }
+
+        unsafe {
+            let pkt = self.pkt.as_ptr();
+            let status = sys::av_new_packet(pkt, packet.data.len() as c_int);
+            if status < 0 {
+                return Err(AvError::AllocatePacket);
+            }
+            ptr::copy_nonoverlapping(packet.data.as_ptr(), (*pkt).data, packet.data.len());
+
+            let time_base = (*self.stream.as_ptr()).time_base;
+            (*pkt).pts = sys::av_rescale_q(
+                packet.timestamp.as_micros() as i64,
+                MICROS_TIME_BASE,
+                time_base,
+            );
+            (*pkt).dts = sys::av_rescale_q(packet.dts_micros(), MICROS_TIME_BASE, time_base);
+            (*pkt).stream_index = (*self.stream.as_ptr()).index;
+            if packet.key {
+                (*pkt).flags |= sys::AV_PKT_FLAG_KEY as c_int;
+            }
+
+            // Takes ownership of the packet's data and resets it
+            let status = sys::av_interleaved_write_frame(self.ctx.as_ptr(), pkt);
+            if status < 0 {
+                return Err(AvError::Mux(status));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_trailer(&mut self) -> Result<(), AvError> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        if !self.header_written {
+            warn!(path = ?self.path, "Recording finished before any key packet, file is empty");
+            return Ok(());
+        }
+
+        let status = unsafe { sys::av_write_trailer(self.ctx.as_ptr()) };
+        if status < 0 {
+            return Err(AvError::Mux(status));
+        }
+        info!(path = ?self.path, "Finished recording");
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PacketSink for Recorder {
+    async fn write_packet(&mut self, packet: &VideoPacket) -> Result<(), AvError> {
+        self.write(packet)
+    }
+
+    async fn finish(&mut self) -> Result<(), AvError> {
+        self.write_trailer()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if self.header_written && !self.finished {
+            warn!(path = ?self.path, "Recorder dropped without finishing, file may be unplayable");
+        }
+
+        unsafe {
+            sys::av_packet_free(&mut self.pkt.as_ptr());
+            sys::avio_closep(&mut (*self.ctx.as_ptr()).pb);
+            sys::avformat_free_context(self.ctx.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::av::encoder::tests::{framebuf_fixture, mode_fixture};
+    use crate::av::encoder::Encoder;
+
+    async fn record_to(path: &Path) {
+        let mode = mode_fixture();
+        let mut encoder = Encoder::new(mode).unwrap();
+        let mut recorder = Recorder::create(path, mode.width, mode.height).unwrap();
+
+        for n in 0..10 {
+            encoder.send_frame(&framebuf_fixture(n)).unwrap();
+            while let Some(packet) = encoder.receive_packet().unwrap() {
+                recorder.write_packet(&packet).await.unwrap();
+            }
+        }
+        encoder.flush().unwrap();
+        while let Some(packet) = encoder.receive_packet().unwrap() {
+            recorder.write_packet(&packet).await.unwrap();
+        }
+
+        recorder.finish().await.unwrap();
+    }
+
+    #[ltest]
+    fn rejects_unknown_container() {
+        let path = std::env::temp_dir().join("remdisp_test_recording.notacontainer");
+        let err = Recorder::create(&path, 640, 480).unwrap_err();
+        assert!(matches!(err, AvError::UnknownContainer(_)));
+    }
+
+    #[ltest(atest)]
+    async fn records_mkv() {
+        let path = std::env::temp_dir().join("remdisp_test_recording.mkv");
+        record_to(&path).await;
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        // EBML magic
+        assert_eq!(&data[..4], &[0x1a, 0x45, 0xdf, 0xa3]);
+    }
+
+    #[ltest(atest)]
+    async fn records_mp4() {
+        let path = std::env::temp_dir().join("remdisp_test_recording.mp4");
+        record_to(&path).await;
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&data[4..8], b"ftyp");
+    }
+
+    #[ignore]
+    #[ltest(atest)]
+    async fn output_recording_to_file_for_manual_check() {
+        let path = Path::new("TEMP_recording.mkv");
+        record_to(path).await;
+        panic!("Manually check with `ffplay TEMP_recording.mkv`");
+    }
+}
@@ -0,0 +1,845 @@
+//! Video over UDP, so a lost packet only costs the frames that depend on it instead of stalling
+//! everything behind it like TCP does.
+//!
+//! Each [`VideoPacket`] is framed as for TCP and split into fragments small enough for a
+//! datagram. Every fragment has a sequence number, which the display uses to ask for ones it
+//! missed. If a frame still isn't complete in time the display skips ahead to the next keyframe
+//! and asks the control for one sooner than the encoder would send it.
+//!
+//! Datagrams start with a kind byte:
+//! - `HELLO nonce`, control to display until acknowledged with `HELLO_ACK`
+//! - `DATA seq: u32, frame: u32, index: u16, count: u16, payload`
+//! - `NACK seq: u32...`, display to control
+//! - `KEYFRAME`, display to control
+//! - `END frames: u32, next_seq: u32`, control to display
+//!
+//! Nothing is encrypted, even when the control channel is.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+use crate::auth::token::constant_time_eq;
+use crate::av::packet::{PacketSink, VideoPacket};
+use crate::av::AvError;
+use crate::prelude::*;
+
+const HELLO: u8 = 0;
+const HELLO_ACK: u8 = 1;
+const DATA: u8 = 2;
+const NACK: u8 = 3;
+const KEYFRAME: u8 = 4;
+const END: u8 = 5;
+
+/// Small enough to not be fragmented by IP on typical links
+const MAX_PAYLOAD: usize = 1200;
+const DATA_HEADER_LEN: usize = 1 + 4 + 4 + 2 + 2;
+const MAX_DATAGRAM: usize = DATA_HEADER_LEN + MAX_PAYLOAD;
+/// Fragments the control keeps to resend
+const RESEND_BUFFER: usize = 4096;
+const HELLO_INTERVAL: Duration = Duration::from_millis(200);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the display checks for missing fragments
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+/// Between asking for the same fragment again
+const NACK_INTERVAL: Duration = Duration::from_millis(40);
+/// How long the display waits for a frame it's missing parts of before skipping it
+const GIVE_UP_AFTER: Duration = Duration::from_millis(250);
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+/// Gaps in sequence numbers larger than this aren't asked for, as they can't be worth it
+const MAX_GAP: u32 = 1024;
+/// Frames further ahead of the one being waited on are dropped, bounding memory use
+const MAX_FRAMES_AHEAD: u32 = 256;
+/// END is sent a few times in case some are lost
+const END_REPEATS: usize = 3;
+/// How long the control keeps answering NACKs after END, so the last frames can be completed
+const END_LINGER: Duration = GIVE_UP_AFTER;
+
+/// One piece of a framed [`VideoPacket`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fragment<'a> {
+    seq: u32,
+    frame: u32,
+    index: u16,
+    count: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    fn encode(&self) -> Bytes {
+        let mut datagram = Vec::with_capacity(DATA_HEADER_LEN + self.payload.len());
+        datagram.push(DATA);
+        datagram.extend_from_slice(&self.seq.to_be_bytes());
+        datagram.extend_from_slice(&self.frame.to_be_bytes());
+        datagram.extend_from_slice(&self.index.to_be_bytes());
+        datagram.extend_from_slice(&self.count.to_be_bytes());
+        datagram.extend_from_slice(self.payload);
+        datagram.into()
+    }
+
+    fn decode(datagram: &'a [u8]) -> Option<Self> {
+        if datagram.len() < DATA_HEADER_LEN || datagram[0] != DATA {
+            return None;
+        }
+        Some(Self {
+            seq: u32::from_be_bytes(datagram[1..5].try_into().unwrap()),
+            frame: u32::from_be_bytes(datagram[5..9].try_into().unwrap()),
+            index: u16::from_be_bytes(datagram[9..11].try_into().unwrap()),
+            count: u16::from_be_bytes(datagram[11..13].try_into().unwrap()),
+            payload: &datagram[DATA_HEADER_LEN..],
+        })
+    }
+}
+
+/// Sends packets to the display over UDP, resending fragments it reports missing.
+#[derive(Debug)]
+pub struct UdpSink {
+    socket: Arc<UdpSocket>,
+    sent: Arc<Mutex<SentFragments>>,
+    keyframe_requested: Arc<AtomicBool>,
+    next_seq: u32,
+    next_frame: u32,
+    feedback: JoinHandle<()>,
+}
+
+impl UdpSink {
+    /// Say hello to the display with the nonce from its Attach, until it acknowledges.
+    pub async fn connect(display: SocketAddr, nonce: &[u8]) -> io::Result<Self> {
+        let unspecified: IpAddr = match display {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((unspecified, 0)).await?;
+        socket.connect(display).await?;
+
+        let mut hello = vec![HELLO];
+        hello.extend_from_slice(nonce);
+        let handshake = async {
+            let mut buf = [0u8; MAX_DATAGRAM];
+            loop {
+                socket.send(&hello).await?;
+                match timeout(HELLO_INTERVAL, socket.recv(&mut buf)).await {
+                    Ok(Ok(len)) if len > 0 && buf[0] == HELLO_ACK => return Ok(()),
+                    Ok(Ok(_)) | Err(_) => (),
+                    // The display may not be listening yet
+                    Ok(Err(err)) if err.kind() == io::ErrorKind::ConnectionRefused => (),
+                    Ok(Err(err)) => return Err(err),
+                }
+            }
+        };
+        timeout(HANDSHAKE_TIMEOUT, handshake).await.map_err(|_| {
+            io::Error::new(io::ErrorKind::TimedOut, "Display didn't answer video hello")
+        })??;
+        debug!(%display, "Video stream connected over UDP");
+
+        let socket = Arc::new(socket);
+        let sent = Arc::new(Mutex::new(SentFragments::default()));
+        let keyframe_requested = Arc::new(AtomicBool::new(false));
+        let feedback = tokio::spawn(handle_feedback(
+            socket.clone(),
+            sent.clone(),
+            keyframe_requested.clone(),
+        ));
+        Ok(Self {
+            socket,
+            sent,
+            keyframe_requested,
+            next_seq: 0,
+            next_frame: 0,
+            feedback,
+        })
+    }
+}
+
+#[async_trait]
+impl PacketSink for UdpSink {
+    async fn write_packet(&mut self, packet: &VideoPacket) -> Result<(), AvError> {
+        let mut framed = vec![];
+        packet.write_to(&mut framed).await?;
+        let count: u16 = ((framed.len() + MAX_PAYLOAD - 1) / MAX_PAYLOAD)
+            .try_into()
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Packet too large to send over UDP",
+                )
+            })?;
+
+        let frame = self.next_frame;
+        self.next_frame = self.next_frame.wrapping_add(1);
+        for (index, payload) in framed.chunks(MAX_PAYLOAD).enumerate() {
+            let seq = self.next_seq;
+            self.next_seq = self.next_seq.wrapping_add(1);
+            let datagram = Fragment {
+                seq,
+                frame,
+                index: index as u16,
+                count,
+                payload,
+            }
+            .encode();
+            self.sent.lock().unwrap().push(seq, datagram.clone());
+            self.socket.send(&datagram).await?;
+        }
+        Ok(())
+    }
+
+    fn take_keyframe_request(&mut self) -> bool {
+        self.keyframe_requested.swap(false, Ordering::Relaxed)
+    }
+
+    async fn finish(&mut self) -> Result<(), AvError> {
+        let mut end = vec![END];
+        end.extend_from_slice(&self.next_frame.to_be_bytes());
+        end.extend_from_slice(&self.next_seq.to_be_bytes());
+        for _ in 0..END_REPEATS {
+            self.socket.send(&end).await?;
+        }
+        sleep(END_LINGER).await;
+        Ok(())
+    }
+}
+
+impl Drop for UdpSink {
+    fn drop(&mut self) {
+        self.feedback.abort();
+    }
+}
+
+/// The most recently sent fragments, by sequence number.
+#[derive(Debug, Default)]
+struct SentFragments(VecDeque<(u32, Bytes)>);
+
+impl SentFragments {
+    fn push(&mut self, seq: u32, datagram: Bytes) {
+        if self.0.len() == RESEND_BUFFER {
+            self.0.pop_front();
+        }
+        self.0.push_back((seq, datagram));
+    }
+
+    fn get(&self, seq: u32) -> Option<&Bytes> {
+        // Sequence numbers are consecutive, so we can index instead of searching
+        let first = self.0.front()?.0;
+        self.0
+            .get(seq.wrapping_sub(first) as usize)
+            .filter(|(sent, _)| *sent == seq)
+            .map(|(_, datagram)| datagram)
+    }
+}
+
+/// Resend what the display asks for and note when it wants a keyframe.
+async fn handle_feedback(
+    socket: Arc<UdpSocket>,
+    sent: Arc<Mutex<SentFragments>>,
+    keyframe_requested: Arc<AtomicBool>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let len = match socket.recv(&mut buf).await {
+            Ok(len) => len,
+            // Usually the display going away, which the control channel will notice
+            Err(err) => {
+                trace!(?err, "Error receiving video feedback");
+                continue;
+            }
+        };
+        match buf[..len].split_first() {
+            Some((&NACK, seqs)) => {
+                let resend: Vec<Bytes> = {
+                    let sent = sent.lock().unwrap();
+                    seqs.chunks_exact(4)
+                        .filter_map(|seq| sent.get(u32::from_be_bytes(seq.try_into().unwrap())))
+                        .cloned()
+                        .collect()
+                };
+                trace!(
+                    asked = seqs.len() / 4,
+                    resending = resend.len(),
+                    "Display sent NACK"
+                );
+                for datagram in resend {
+                    if let Err(err) = socket.send(&datagram).await {
+                        trace!(?err, "Failed to resend fragment");
+                    }
+                }
+            }
+            Some((&KEYFRAME, _)) => {
+                debug!("Display asked for a keyframe");
+                keyframe_requested.store(true, Ordering::Relaxed);
+            }
+            // A duplicate from the handshake
+            Some((&HELLO_ACK, _)) => (),
+            other => debug!(kind = ?other.map(|(kind, _)| kind), "Unexpected video datagram"),
+        }
+    }
+}
+
+/// Wait for the control to say hello with the session's nonce from a host `is_control`
+/// accepts, and connect to it.
+pub async fn accept(
+    socket: UdpSocket,
+    nonce: &[u8],
+    is_control: impl Fn(IpAddr) -> bool,
+) -> io::Result<(UdpSocket, SocketAddr)> {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let hello = match buf[..len].split_first() {
+            Some((&HELLO, hello)) => hello,
+            _ => {
+                debug!(%from, "Ignoring video datagram before hello");
+                continue;
+            }
+        };
+        if !is_control(from.ip()) {
+            warn!(%from, "Rejecting video hello from another host");
+            continue;
+        }
+        if !constant_time_eq(hello, nonce) {
+            warn!(%from, "Rejecting video hello without the right nonce");
+            continue;
+        }
+
+        socket.connect(from).await?;
+        socket.send(&[HELLO_ACK]).await?;
+        return Ok((socket, from));
+    }
+}
+
+/// Read packets from the control in order, in the background like the TCP reader.
+pub fn spawn_packet_reader(
+    socket: UdpSocket,
+    queue: usize,
+) -> mpsc::Receiver<io::Result<VideoPacket>> {
+    let (tx, rx) = mpsc::channel(queue);
+    tokio::spawn(async move {
+        if let Err(err) = read_packets(&socket, &tx).await {
+            let _ = tx.send(Err(err)).await;
+        }
+    });
+    rx
+}
+
+async fn read_packets(
+    socket: &UdpSocket,
+    tx: &mpsc::Sender<io::Result<VideoPacket>>,
+) -> io::Result<()> {
+    let mut reassembler = Reassembler::default();
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            received = socket.recv(&mut buf) => match received {
+                // The control missed our acknowledgement
+                Ok(len) if buf[..len].first() == Some(&HELLO) => {
+                    send_feedback(socket, &[HELLO_ACK]).await;
+                }
+                Ok(len) => reassembler.receive(&buf[..len], Instant::now()),
+                // Left over from feedback the control didn't receive
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    trace!(?err, "Control refused video feedback");
+                }
+                Err(err) => return Err(err),
+            },
+            _ = tick.tick() => (),
+        }
+
+        let now = Instant::now();
+        while let Some(framed) = reassembler.pop(now) {
+            let packet = VideoPacket::read_from(&mut framed.as_ref())
+                .await?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Empty video packet"))?;
+            if tx.send(Ok(packet)).await.is_err() {
+                return Ok(());
+            }
+        }
+        if reassembler.is_finished() {
+            return Ok(());
+        }
+
+        for seqs in reassembler.nacks(now).chunks(MAX_PAYLOAD / 4) {
+            let mut nack = vec![NACK];
+            for seq in seqs {
+                nack.extend_from_slice(&seq.to_be_bytes());
+            }
+            send_feedback(socket, &nack).await;
+        }
+        if reassembler.wants_keyframe(now) {
+            debug!("Asking control for a keyframe");
+            send_feedback(socket, &[KEYFRAME]).await;
+        }
+    }
+}
+
+/// Feedback is best effort, losing some only slows down recovery.
+async fn send_feedback(socket: &UdpSocket, datagram: &[u8]) {
+    if let Err(err) = socket.send(datagram).await {
+        trace!(?err, "Failed to send video feedback");
+    }
+}
+
+/// Puts fragments back together into framed packets, in order, deciding what to ask for again
+/// and when to give up.
+#[derive(Debug, Default)]
+struct Reassembler {
+    frames: BTreeMap<u32, PartialFrame>,
+    /// The frame to return next
+    next_frame: u32,
+    highest_seq: Option<u32>,
+    /// Fragments noticed missing, with when they were noticed and last asked for
+    missing: BTreeMap<u32, (Instant, Option<Instant>)>,
+    /// When we started waiting on `next_frame` despite having later ones
+    stalled_since: Option<Instant>,
+    /// Set after skipping a frame, as what follows can't be decoded until a keyframe
+    awaiting_keyframe: bool,
+    last_keyframe_request: Option<Instant>,
+    /// Number of frames the control sent, once it's finished
+    end: Option<u32>,
+}
+
+#[derive(Debug)]
+struct PartialFrame {
+    fragments: Vec<Option<Bytes>>,
+    received: usize,
+}
+
+impl PartialFrame {
+    fn is_complete(&self) -> bool {
+        self.received == self.fragments.len()
+    }
+
+    /// Known once the first fragment, holding the header, has arrived.
+    fn is_key(&self) -> Option<bool> {
+        self.fragments[0]
+            .as_ref()
+            .map(|first| VideoPacket::is_key_framed(first))
+    }
+
+    fn assemble(self) -> Bytes {
+        let mut framed = Vec::new();
+        for fragment in self.fragments.into_iter().flatten() {
+            framed.extend_from_slice(&fragment);
+        }
+        framed.into()
+    }
+}
+
+impl Reassembler {
+    fn receive(&mut self, datagram: &[u8], now: Instant) {
+        match datagram.first() {
+            Some(&DATA) => match Fragment::decode(datagram) {
+                Some(fragment) => self.receive_fragment(fragment, now),
+                None => debug!(len = datagram.len(), "Ignoring malformed video fragment"),
+            },
+            Some(&END) if datagram.len() == 9 => {
+                let frames = u32::from_be_bytes(datagram[1..5].try_into().unwrap());
+                let next_seq = u32::from_be_bytes(datagram[5..9].try_into().unwrap());
+                if self.end.is_none() {
+                    debug!(frames, "Control finished sending video");
+                }
+                self.end = Some(frames);
+                // Anything after what we've seen is missing off the end
+                self.saw_seq(next_seq, now);
+            }
+            kind => debug!(?kind, "Ignoring unexpected video datagram"),
+        }
+    }
+
+    fn receive_fragment(&mut self, fragment: Fragment<'_>, now: Instant) {
+        self.saw_seq(fragment.seq, now);
+        self.missing.remove(&fragment.seq);
+
+        let Fragment {
+            frame,
+            index,
+            count,
+            payload,
+            ..
+        } = fragment;
+        // Behind the one we're waiting for wraps around to far ahead
+        if frame.wrapping_sub(self.next_frame) > MAX_FRAMES_AHEAD || index >= count {
+            trace!(
+                frame,
+                index,
+                "Ignoring fragment of a frame we aren't waiting for"
+            );
+            return;
+        }
+
+        let partial = self.frames.entry(frame).or_insert_with(|| PartialFrame {
+            fragments: vec![None; count as usize],
+            received: 0,
+        });
+        if partial.fragments.len() != count as usize {
+            debug!(
+                frame,
+                "Ignoring fragment inconsistent with the rest of its frame"
+            );
+            return;
+        }
+        let slot = &mut partial.fragments[index as usize];
+        if slot.is_none() {
+            *slot = Some(Bytes::copy_from_slice(payload));
+            partial.received += 1;
+        }
+    }
+
+    /// Note every fragment between the highest seen and `seq` is missing.
+    fn saw_seq(&mut self, seq: u32, now: Instant) {
+        let first_missing = match self.highest_seq {
+            Some(highest) if !is_after(seq, highest) => return,
+            Some(highest) => highest.wrapping_add(1),
+            None => seq,
+        };
+        let gap = seq.wrapping_sub(first_missing);
+        if gap <= MAX_GAP {
+            for offset in 0..gap {
+                self.missing
+                    .insert(first_missing.wrapping_add(offset), (now, None));
+            }
+        }
+        self.highest_seq = Some(seq);
+    }
+
+    /// The next framed packet, if it's ready or we've given up on the ones before it.
+    fn pop(&mut self, now: Instant) -> Option<Bytes> {
+        loop {
+            if self.is_finished() {
+                return None;
+            }
+
+            let next = self.frames.get(&self.next_frame);
+            if next.map_or(false, PartialFrame::is_complete) {
+                let framed = self.frames.remove(&self.next_frame).unwrap().assemble();
+                self.next_frame = self.next_frame.wrapping_add(1);
+                self.stalled_since = None;
+                if self.awaiting_keyframe {
+                    if !VideoPacket::is_key_framed(&framed) {
+                        trace!("Dropping frame that can't be decoded until a keyframe");
+                        continue;
+                    }
+                    info!("Recovered from lost video at keyframe");
+                    self.awaiting_keyframe = false;
+                }
+                return Some(framed);
+            }
+
+            // Only stalled once something after it arrives, otherwise it may not be sent yet
+            let behind = self
+                .frames
+                .keys()
+                .any(|&frame| is_after(frame, self.next_frame))
+                || self.end.map_or(false, |end| is_after(end, self.next_frame));
+            if !behind {
+                return None;
+            }
+            // No point waiting for a frame we'd drop anyway
+            let useless =
+                self.awaiting_keyframe && next.and_then(PartialFrame::is_key) == Some(false);
+            let since = *self.stalled_since.get_or_insert(now);
+            if !useless && now.saturating_duration_since(since) < GIVE_UP_AFTER {
+                return None;
+            }
+
+            if !self.awaiting_keyframe {
+                warn!(
+                    frame = self.next_frame,
+                    "Lost video, skipping to the next keyframe"
+                );
+            }
+            self.frames.remove(&self.next_frame);
+            self.next_frame = self.next_frame.wrapping_add(1);
+            self.awaiting_keyframe = true;
+            self.stalled_since = None;
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.end
+            .map_or(false, |end| !is_after(end, self.next_frame))
+    }
+
+    /// Missing fragments to ask for now.
+    fn nacks(&mut self, now: Instant) -> Vec<u32> {
+        self.missing
+            .retain(|_, (noticed, _)| now.saturating_duration_since(*noticed) < GIVE_UP_AFTER);
+        let mut due = vec![];
+        for (&seq, (_, last_asked)) in self.missing.iter_mut() {
+            let is_due = last_asked.map_or(true, |last| {
+                now.saturating_duration_since(last) >= NACK_INTERVAL
+            });
+            if is_due {
+                *last_asked = Some(now);
+                due.push(seq);
+            }
+        }
+        due
+    }
+
+    /// Whether to ask for a keyframe now.
+    fn wants_keyframe(&mut self, now: Instant) -> bool {
+        let recently = self.last_keyframe_request.map_or(false, |last| {
+            now.saturating_duration_since(last) < KEYFRAME_REQUEST_INTERVAL
+        });
+        if !self.awaiting_keyframe || recently {
+            return false;
+        }
+        self.last_keyframe_request = Some(now);
+        true
+    }
+}
+
+/// Whether sequence or frame number `a` comes after `b`, allowing for them wrapping around.
+fn is_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impair::{Impairment, UdpProxy};
+    use std::collections::HashSet;
+
+    fn packet_fixture(n: u64, key: bool) -> VideoPacket {
+        VideoPacket {
+            timestamp: Duration::from_millis(40 * n),
+            dts_offset: Duration::ZERO,
+            key,
+            data: vec![n as u8; 3000].into(),
+        }
+    }
+
+    /// Datagrams for a packet, starting at `seq`.
+    async fn fragments(packet: &VideoPacket, frame: u32, seq: u32) -> Vec<Bytes> {
+        let mut framed = vec![];
+        packet.write_to(&mut framed).await.unwrap();
+        let count = (framed.len() + MAX_PAYLOAD - 1) / MAX_PAYLOAD;
+        framed
+            .chunks(MAX_PAYLOAD)
+            .enumerate()
+            .map(|(index, payload)| {
+                Fragment {
+                    seq: seq.wrapping_add(index as u32),
+                    frame,
+                    index: index as u16,
+                    count: count as u16,
+                    payload,
+                }
+                .encode()
+            })
+            .collect()
+    }
+
+    #[ltest]
+    fn round_trips_fragments() {
+        let fragment = Fragment {
+            seq: 7,
+            frame: 3,
+            index: 1,
+            count: 2,
+            payload: b"data",
+        };
+        let datagram = fragment.encode();
+        assert_eq!(Fragment::decode(&datagram), Some(fragment));
+        assert_eq!(Fragment::decode(&datagram[..5]), None);
+    }
+
+    #[ltest(atest)]
+    async fn skips_lost_frames_to_keyframe() {
+        let start = Instant::now();
+        let mut reassembler = Reassembler::default();
+        // Each packet is 3 fragments
+        let key = fragments(&packet_fixture(0, true), 0, 0).await;
+        let lost = fragments(&packet_fixture(1, false), 1, 3).await;
+        let after = fragments(&packet_fixture(2, false), 2, 6).await;
+        for datagram in key.iter().chain(&lost[..2]).chain(&after) {
+            reassembler.receive(datagram, start);
+        }
+
+        assert!(reassembler.pop(start).is_some());
+        assert_eq!(reassembler.pop(start), None);
+        // Asks for the fragment after the gap
+        assert_eq!(reassembler.nacks(start), vec![5]);
+        assert_eq!(reassembler.nacks(start), Vec::<u32>::new());
+
+        let later = start + GIVE_UP_AFTER;
+        assert_eq!(reassembler.pop(later), None);
+        assert!(reassembler.wants_keyframe(later));
+        assert!(!reassembler.wants_keyframe(later));
+
+        for datagram in fragments(&packet_fixture(3, true), 3, 9).await {
+            reassembler.receive(&datagram, later);
+        }
+        let framed = reassembler.pop(later).unwrap();
+        let packet = VideoPacket::read_from(&mut framed.as_ref()).await.unwrap();
+        assert_eq!(packet, Some(packet_fixture(3, true)));
+    }
+
+    #[ltest(atest)]
+    async fn reassembles_across_wrap_around() {
+        let start = Instant::now();
+        let mut reassembler = Reassembler {
+            next_frame: u32::MAX,
+            ..Reassembler::default()
+        };
+        // Frame numbers wrap after the first frame, sequence numbers within the second
+        let first = fragments(&packet_fixture(0, true), u32::MAX, u32::MAX - 4).await;
+        let second = fragments(&packet_fixture(1, false), 0, u32::MAX - 1).await;
+        let third = fragments(&packet_fixture(2, false), 1, 2).await;
+        for datagram in first.iter().chain(&second[..2]).chain(&third) {
+            reassembler.receive(datagram, start);
+        }
+
+        let framed = reassembler.pop(start).unwrap();
+        let packet = VideoPacket::read_from(&mut framed.as_ref()).await.unwrap();
+        assert_eq!(packet, Some(packet_fixture(0, true)));
+        assert_eq!(reassembler.pop(start), None);
+        // Only the one fragment lost, not everything from before the wrap
+        assert_eq!(reassembler.nacks(start), vec![0]);
+
+        reassembler.receive(&second[2], start);
+        for n in 1..=2 {
+            let framed = reassembler.pop(start).unwrap();
+            let packet = VideoPacket::read_from(&mut framed.as_ref()).await.unwrap();
+            assert_eq!(packet, Some(packet_fixture(n, false)));
+        }
+        assert_eq!(reassembler.pop(start), None);
+        assert!(!reassembler.wants_keyframe(start));
+    }
+
+    /// Forwards between a control and display on loopback, dropping the first attempt at
+    /// sending some fragments.
+    async fn lossy_proxy(display: SocketAddr) -> SocketAddr {
+        let control_side = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let display_side = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        display_side.connect(display).await.unwrap();
+        let addr = control_side.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut control = None;
+            let mut dropped = HashSet::new();
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            let mut back = vec![0u8; MAX_DATAGRAM];
+            loop {
+                tokio::select! {
+                    Ok((len, from)) = control_side.recv_from(&mut buf) => {
+                        control = Some(from);
+                        if let Some(fragment) = Fragment::decode(&buf[..len]) {
+                            if fragment.seq % 7 == 3 && dropped.insert(fragment.seq) {
+                                continue;
+                            }
+                        }
+                        let _ = display_side.send(&buf[..len]).await;
+                    }
+                    Ok(len) = display_side.recv(&mut back) => {
+                        if let Some(control) = control {
+                            let _ = control_side.send_to(&back[..len], control).await;
+                        }
+                    }
+                }
+            }
+        });
+        addr
+    }
+
+    #[ltest(atest)]
+    async fn recovers_lost_fragments_over_loopback() {
+        let nonce = b"nonce";
+        let display_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = lossy_proxy(display_socket.local_addr().unwrap()).await;
+        let display = tokio::spawn(async move {
+            let (socket, _) = accept(display_socket, nonce, |ip| ip.is_loopback())
+                .await
+                .unwrap();
+            let mut packets = spawn_packet_reader(socket, 4);
+            let mut received = vec![];
+            while let Some(packet) = packets.recv().await {
+                received.push(packet.unwrap());
+            }
+            received
+        });
+
+        let mut sink = UdpSink::connect(proxy, nonce).await.unwrap();
+        let sent: Vec<_> = (0..30).map(|n| packet_fixture(n, n == 0)).collect();
+        for packet in &sent {
+            sink.write_packet(packet).await.unwrap();
+        }
+        sink.finish().await.unwrap();
+
+        let received = timeout(TIMEOUT, display).await.unwrap().unwrap();
+        assert_eq!(received, sent);
+    }
+
+    #[ltest(atest)]
+    async fn survives_impaired_link() {
+        let nonce = b"nonce";
+        let display_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let impairment = Impairment {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+            loss: 0.1,
+            ..Default::default()
+        };
+        let proxy = UdpProxy::start(
+            display_socket.local_addr().unwrap(),
+            impairment,
+            Impairment {
+                seed: 1,
+                ..impairment
+            },
+        )
+        .await
+        .unwrap();
+        let display = tokio::spawn(async move {
+            let (socket, _) = accept(display_socket, nonce, |ip| ip.is_loopback())
+                .await
+                .unwrap();
+            let mut packets = spawn_packet_reader(socket, 4);
+            let mut received = vec![];
+            while let Some(packet) = packets.recv().await {
+                received.push(packet.unwrap());
+            }
+            received
+        });
+
+        let mut sink = UdpSink::connect(proxy.addr(), nonce).await.unwrap();
+        let mut sent = vec![];
+        for n in 0..60 {
+            // Like the capture loop, answer requests with a keyframe
+            let key = n == 0 || sink.take_keyframe_request();
+            let packet = packet_fixture(n, key);
+            sink.write_packet(&packet).await.unwrap();
+            sent.push(packet);
+            sleep(Duration::from_millis(10)).await;
+        }
+        sink.finish().await.unwrap();
+
+        let received = timeout(TIMEOUT, display).await.unwrap().unwrap();
+        assert!(
+            received.len() > sent.len() / 2,
+            "Only got {}",
+            received.len()
+        );
+        assert_eq!(received[0], sent[0]);
+        let mut remaining = sent.iter();
+        for packet in &received {
+            assert!(
+                remaining.any(|sent| sent == packet),
+                "Out of order {:?}",
+                packet
+            );
+        }
+    }
+}
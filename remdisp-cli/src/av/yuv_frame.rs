@@ -6,8 +6,11 @@ use std::slice;
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct YuvFrame<'a> {
+    /// Presentation timestamp, in microseconds for frames from [`crate::av::packet::VideoPacket`]s
+    pub pts: Option<i64>,
     pub y_linesize: usize,
     pub uv_linesize: usize,
+    pub width: usize,
     pub height: usize,
     #[derivative(Debug = "ignore")]
     pub y: &'a [u8],
@@ -43,16 +46,25 @@ impl<'a> YuvFrame<'a> {
         debug_assert_eq!(u_linesize, v_linesize);
         let uv_linesize = u_linesize;
 
+        let width: usize = sys.width.try_into().expect("Can fit width in usize");
         let height: usize = sys.height.try_into().expect("Can fit height in usize");
 
+        let pts = if sys.best_effort_timestamp == ffmpeg_sys_next::AV_NOPTS_VALUE {
+            None
+        } else {
+            Some(sys.best_effort_timestamp)
+        };
+
         // Safety: Lifetime is constrained to lifetime of borrow of frame
         let y = slice::from_raw_parts(sys.data[0], y_linesize * height);
         let u = slice::from_raw_parts(sys.data[1], uv_linesize * height / 2);
         let v = slice::from_raw_parts(sys.data[2], uv_linesize * height / 2);
 
         Self {
+            pts,
             y_linesize,
             uv_linesize,
+            width,
             height,
             y,
             u,
@@ -60,3 +72,50 @@ impl<'a> YuvFrame<'a> {
         }
     }
 }
+
+/// A copy of a [`YuvFrame`] that can outlive the decoder's buffers.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct OwnedYuvFrame {
+    pub pts: Option<i64>,
+    pub y_linesize: usize,
+    pub uv_linesize: usize,
+    pub width: usize,
+    pub height: usize,
+    #[derivative(Debug = "ignore")]
+    pub y: Vec<u8>,
+    #[derivative(Debug = "ignore")]
+    pub u: Vec<u8>,
+    #[derivative(Debug = "ignore")]
+    pub v: Vec<u8>,
+}
+
+impl<'a> YuvFrame<'a> {
+    pub fn to_owned(&self) -> OwnedYuvFrame {
+        OwnedYuvFrame {
+            pts: self.pts,
+            y_linesize: self.y_linesize,
+            uv_linesize: self.uv_linesize,
+            width: self.width,
+            height: self.height,
+            y: self.y.to_vec(),
+            u: self.u.to_vec(),
+            v: self.v.to_vec(),
+        }
+    }
+}
+
+impl OwnedYuvFrame {
+    pub fn as_frame(&self) -> YuvFrame {
+        YuvFrame {
+            pts: self.pts,
+            y_linesize: self.y_linesize,
+            uv_linesize: self.uv_linesize,
+            width: self.width,
+            height: self.height,
+            y: &self.y,
+            u: &self.u,
+            v: &self.v,
+        }
+    }
+}
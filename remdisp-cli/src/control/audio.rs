@@ -0,0 +1,82 @@
+use std::io;
+use std::thread;
+use std::time::Instant;
+
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc;
+
+use crate::av::audio::encoder::AudioEncoder;
+use crate::av::audio::input::{AudioInput, AudioSource};
+use crate::av::audio::{duration_of, AudioPacket};
+use crate::av::AvError;
+use crate::prelude::*;
+
+/// Encoded packets waiting to be sent, a third of a second
+const PACKET_QUEUE: usize = 16;
+
+/// Capture audio from `source` and send it to `out` until the input ends or sending fails.
+/// Dropping the future stops capture.
+///
+/// Timestamps are relative to `start`, like those of video captured for the same session, so
+/// the display can play the two in sync.
+#[instrument(skip(out, start), err)]
+pub async fn send_audio<W: AsyncWrite + Unpin>(
+    source: AudioSource,
+    mut out: W,
+    start: Instant,
+) -> Result<(), AudioError> {
+    // Reading the input blocks, so it gets a thread of its own
+    let (tx, mut rx) = mpsc::channel(PACKET_QUEUE);
+    thread::spawn(move || capture_audio(&source, start, tx));
+
+    while let Some(packet) = rx.recv().await {
+        packet?.write_to(&mut out).await?;
+    }
+    Ok(())
+}
+
+fn capture_audio(
+    source: &AudioSource,
+    start: Instant,
+    tx: mpsc::Sender<Result<AudioPacket, AvError>>,
+) {
+    let send = |packet| tx.blocking_send(packet).is_ok();
+    let result = (|| -> Result<(), AvError> {
+        let mut input = AudioInput::open(source)?;
+        let mut samples = match input.read()? {
+            Some(samples) => samples,
+            None => return Ok(()),
+        };
+        // The first samples were captured over the time it took to read them
+        let first_at = start.elapsed().saturating_sub(duration_of(samples.len()));
+        let mut encoder = AudioEncoder::new(first_at)?;
+        debug!(?first_at, "Capturing audio");
+
+        loop {
+            for packet in encoder.encode(&samples)? {
+                if !send(Ok(packet)) {
+                    debug!("Stopping audio capture as the session ended");
+                    return Ok(());
+                }
+            }
+            samples = match input.read()? {
+                Some(samples) => samples,
+                None => {
+                    info!("Audio input ended");
+                    return Ok(());
+                }
+            };
+        }
+    })();
+    if let Err(err) = result {
+        send(Err(err));
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AudioError {
+    #[error("Error capturing audio")]
+    Capture(#[from] AvError),
+    #[error("Error sending audio")]
+    Io(#[from] io::Error),
+}
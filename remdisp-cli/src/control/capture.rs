@@ -0,0 +1,234 @@
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use evdi::prelude::*;
+use tokio::sync::mpsc;
+use tokio::time::sleep_until;
+
+use crate::av::encoder::Encoder;
+use crate::av::frame_rate::FrameRate;
+use crate::av::packet::PacketSink;
+use crate::av::recorder::Recorder;
+use crate::av::AvError;
+use crate::control::cursor;
+use crate::control::pacer::CapturePacer;
+use crate::prelude::*;
+use crate::proto::ControlEvent;
+
+const EVDI_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default)]
+pub struct CaptureOptions {
+    pub frame_rate: FrameRate,
+    /// Also record everything captured to this file
+    pub record_to: Option<PathBuf>,
+}
+
+impl CaptureOptions {
+    /// Options for the nth capture of a session that is restarted when the display resizes.
+    ///
+    /// Later captures record to numbered files alongside the first, as a recording can't
+    /// change size.
+    pub fn for_segment(&self, n: u32) -> Self {
+        Self {
+            record_to: self.record_to.as_deref().map(|path| segment_path(path, n)),
+            ..self.clone()
+        }
+    }
+}
+
+fn segment_path(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_owned();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+/// The virtual monitor's mode and a buffer to capture it into.
+///
+/// evdi only reports the mode once after connecting, so this lasts as long as the handle stays
+/// connected rather than being set up for each capture.
+#[derive(Debug)]
+pub struct CaptureSource {
+    mode: Mode,
+    buf_id: BufferId,
+}
+
+impl CaptureSource {
+    pub async fn new(handle: &mut Handle) -> Result<Self, CaptureError> {
+        let mode = handle
+            .events
+            .await_mode(EVDI_TIMEOUT)
+            .await
+            .map_err(|err| CaptureError::Evdi(format!("Error awaiting mode: {:?}", err)))?;
+        let buf_id = handle.new_buffer(&mode);
+        Ok(Self { mode, buf_id })
+    }
+}
+
+/// Capture from the virtual monitor and write the encoded video to every sink, until `stop`
+/// completes or a sink fails.
+///
+/// If `cursor_tx` is provided the cursor is sent there instead of being drawn into the video.
+/// Timestamps are relative to `start`, so they carry on if the same sinks are used for
+/// several captures.
+///
+/// Finishing `sinks` is left to the caller. The recording made if `options.record_to` is set
+/// is always finished, even on error, so it stays playable.
+#[instrument(err, skip(handle, sinks, cursor_tx, stop))]
+pub async fn capture(
+    handle: &mut Handle,
+    source: &CaptureSource,
+    options: &CaptureOptions,
+    sinks: &mut [Box<dyn PacketSink>],
+    cursor_tx: Option<mpsc::Sender<ControlEvent>>,
+    start: Instant,
+    stop: impl Future<Output = ()>,
+) -> Result<(), CaptureError> {
+    let CaptureSource { mode, buf_id } = *source;
+
+    let mut encoder = Encoder::with_frame_rate(mode, options.frame_rate)?;
+
+    let mut sinks: Vec<&mut dyn PacketSink> = sinks.iter_mut().map(|sink| sink.as_mut()).collect();
+    let mut recorder = match &options.record_to {
+        Some(path) => Some(Recorder::create(path, mode.width, mode.height)?),
+        None => None,
+    };
+    if let Some(recorder) = recorder.as_mut() {
+        sinks.push(recorder);
+    }
+
+    let cursor_events = cursor_tx.as_ref().map(|_| cursor::subscribe(handle));
+    let forward_cursor = async move {
+        if let (Some(events), Some(tx)) = (cursor_events, cursor_tx) {
+            cursor::forward_cursor(events, tx).await;
+        }
+        // Capture carries on without cursor updates
+        futures::future::pending::<()>().await
+    };
+
+    let result = tokio::select! {
+        result = capture_loop(handle, buf_id, options.frame_rate, start, &mut encoder, &mut sinks) => result,
+        _ = forward_cursor => unreachable!("Never completes"),
+        _ = stop => {
+            info!("Stopping capture");
+            Ok(())
+        }
+    };
+
+    // Get out anything still buffered in the encoder before finishing
+    let result = match result {
+        Ok(()) => drain(&mut encoder, &mut sinks).await,
+        Err(err) => Err(err),
+    };
+
+    drop(sinks);
+    if let Some(mut recorder) = recorder {
+        if let Err(err) = recorder.finish().await {
+            warn!(?recorder, ?err, "Failed to finish recording");
+        }
+    }
+
+    result
+}
+
+async fn capture_loop(
+    handle: &mut Handle,
+    buf_id: BufferId,
+    frame_rate: FrameRate,
+    start: Instant,
+    encoder: &mut Encoder,
+    sinks: &mut [&mut dyn PacketSink],
+) -> Result<(), CaptureError> {
+    let mut pacer = CapturePacer::resume(frame_rate, start, Instant::now());
+    loop {
+        sleep_until(pacer.next_capture().into()).await;
+
+        // With a constant frame rate we only wait a little for damage, and resend the stale
+        // buffer if nothing changed. With a variable frame rate we only send on damage.
+        let wait_for_update = match frame_rate {
+            FrameRate::Constant(_) => frame_rate.interval() / 2,
+            FrameRate::Variable { .. } => EVDI_TIMEOUT,
+        };
+        if let Err(err) = handle.request_update(buf_id, wait_for_update).await {
+            trace!(?err, "No update from kernel");
+            if frame_rate.is_variable() {
+                continue;
+            }
+        }
+
+        // Every sink has to be asked so none are left with a stale request
+        let keyframe_requested = sinks.iter_mut().fold(false, |requested, sink| {
+            sink.take_keyframe_request() || requested
+        });
+        if keyframe_requested {
+            encoder.request_keyframe();
+        }
+
+        let timestamp = pacer.captured(Instant::now());
+        let buf = handle.get_buffer(buf_id).expect("Buffer exists");
+        encoder.send_frame_at(buf.bytes(), timestamp)?;
+
+        write_available(encoder, sinks).await?;
+    }
+}
+
+async fn drain(
+    encoder: &mut Encoder,
+    sinks: &mut [&mut dyn PacketSink],
+) -> Result<(), CaptureError> {
+    encoder.flush()?;
+    write_available(encoder, sinks).await
+}
+
+async fn write_available(
+    encoder: &mut Encoder,
+    sinks: &mut [&mut dyn PacketSink],
+) -> Result<(), CaptureError> {
+    while let Some(packet) = encoder.receive_packet()? {
+        for sink in sinks.iter_mut() {
+            // We currently exit when any sink can't be written to.
+            sink.write_packet(&packet).await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("Evdi error: {0}")]
+    Evdi(String),
+    #[error("Video error")]
+    Av(#[from] AvError),
+    #[error("IO error")]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ltest]
+    fn numbers_later_segments() {
+        let options = CaptureOptions {
+            record_to: Some(PathBuf::from("/tmp/out.mkv")),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.for_segment(0).record_to,
+            Some(PathBuf::from("/tmp/out.mkv"))
+        );
+        assert_eq!(
+            options.for_segment(2).record_to,
+            Some(PathBuf::from("/tmp/out.2.mkv"))
+        );
+        assert_eq!(CaptureOptions::default().for_segment(1).record_to, None);
+    }
+}
@@ -0,0 +1,64 @@
+use evdi::prelude::*;
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::cursor::{CursorShape, CursorUpdate};
+use crate::prelude::*;
+use crate::proto::ControlEvent;
+
+/// Start receiving cursor events from the kernel instead of having the cursor drawn into the
+/// framebuffer.
+pub fn subscribe(handle: &mut Handle) -> impl Stream<Item = CursorEvent> + Unpin {
+    handle.enable_cursor_events(true);
+    handle.events.cursor_stream()
+}
+
+/// Send cursor changes to the display until `events` ends.
+///
+/// Moves are dropped rather than waited on if the display is behind, as only the latest
+/// position matters.
+pub async fn forward_cursor(
+    mut events: impl Stream<Item = CursorEvent> + Unpin,
+    tx: mpsc::Sender<ControlEvent>,
+) {
+    while let Some(event) = events.next().await {
+        let update = match to_update(event) {
+            Some(update) => update,
+            None => continue,
+        };
+        trace!(?update, "Forwarding cursor");
+
+        let result = match update {
+            CursorUpdate::Shape(_) => tx.send(update.into()).await.map_err(|_| ()),
+            CursorUpdate::Move { .. } => match tx.try_send(update.into()) {
+                Err(mpsc::error::TrySendError::Closed(_)) => Err(()),
+                _ => Ok(()),
+            },
+        };
+        if result.is_err() {
+            debug!("Display stopped receiving cursor updates");
+            return;
+        }
+    }
+}
+
+fn to_update(event: CursorEvent) -> Option<CursorUpdate> {
+    match event {
+        CursorEvent::Set(set) if !set.enabled => Some(CursorUpdate::Shape(CursorShape::hidden())),
+        CursorEvent::Set(set) => match CursorShape::from_strided(
+            set.width,
+            set.height,
+            set.hot_x,
+            set.hot_y,
+            set.stride as usize,
+            &set.buffer,
+        ) {
+            Ok(shape) => Some(CursorUpdate::Shape(shape)),
+            Err(err) => {
+                warn!(?err, "Ignoring invalid cursor from kernel");
+                None
+            }
+        },
+        CursorEvent::Move(CursorMove { x, y }) => Some(CursorUpdate::Move { x, y }),
+    }
+}
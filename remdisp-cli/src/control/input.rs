@@ -0,0 +1,628 @@
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::io;
+use std::sync::Arc;
+
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
+use tonic::Status;
+
+use crate::input::InputEvent;
+use crate::prelude::*;
+use crate::proto::{display_event, DisplayEvent};
+
+/// Somewhere to inject input forwarded from the display.
+pub trait InputSink: Send + Debug {
+    /// Called with the size of the stream before any events, and again if it changes.
+    fn set_stream_size(&mut self, _width: u32, _height: u32) {}
+
+    /// Called with the EDID of the virtual monitor showing the stream, before any events and
+    /// again if the display resizes.
+    fn set_edid(&mut self, _edid: &[u8]) {}
+
+    fn inject(&mut self, event: InputEvent) -> Result<(), InputError>;
+}
+
+/// Discards input, for when forwarding is disabled.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullInputSink;
+
+impl InputSink for NullInputSink {
+    fn inject(&mut self, _event: InputEvent) -> Result<(), InputError> {
+        Ok(())
+    }
+}
+
+/// Keeps every event it is given, for tests.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingInputSink {
+    pub events: Arc<Mutex<Vec<InputEvent>>>,
+    pub stream_size: Arc<Mutex<Option<(u32, u32)>>>,
+    pub edid: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl InputSink for RecordingInputSink {
+    fn set_stream_size(&mut self, width: u32, height: u32) {
+        *self.stream_size.lock() = Some((width, height));
+    }
+
+    fn set_edid(&mut self, edid: &[u8]) {
+        *self.edid.lock() = Some(edid.to_vec());
+    }
+
+    fn inject(&mut self, event: InputEvent) -> Result<(), InputError> {
+        self.events.lock().push(event);
+        Ok(())
+    }
+}
+
+/// Inject input events from the display until its event stream ends.
+///
+/// Errors injecting individual events are logged rather than ending the session. Other events
+/// are passed to `on_other`, after resizes have updated the sink's stream size and EDID.
+pub async fn forward_display_events<S>(
+    mut events: S,
+    sink: &mut dyn InputSink,
+    mut on_other: impl FnMut(display_event::DisplayEvent),
+) -> Result<(), Status>
+where
+    S: Stream<Item = Result<DisplayEvent, Status>> + Unpin,
+{
+    while let Some(event) = events.next().await {
+        match event?.display_event {
+            Some(display_event::DisplayEvent::Input(input)) => {
+                let event = match InputEvent::try_from(input) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!(?err, "Ignoring invalid input event from display");
+                        continue;
+                    }
+                };
+                trace!(?event, "Injecting input");
+                if let Err(err) = sink.inject(event) {
+                    warn!(?err, ?event, "Failed to inject input");
+                }
+            }
+            Some(display_event::DisplayEvent::Resize(resize)) => {
+                sink.set_stream_size(resize.width_pixels, resize.height_pixels);
+                sink.set_edid(&resize.edid);
+                on_other(display_event::DisplayEvent::Resize(resize));
+            }
+            Some(other) => on_other(other),
+            None => debug!("Ignoring empty display event"),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum InputError {
+    #[error("IO error injecting input")]
+    Io(#[from] io::Error),
+    #[error("No mapping for USB HID usage {0:#x}")]
+    UnmappedKey(u32),
+}
+
+/// Maps USB HID keyboard usages (as sent by the display) to Linux evdev key codes.
+///
+/// Based on `hid_keyboard` in the kernel's `drivers/hid/hid-input.c`.
+pub fn hid_usage_to_evdev(usage: u32) -> Option<u16> {
+    #[rustfmt::skip]
+    const USAGES: [u16; 0x66] = [
+          0,   0,   0,   0,  30,  48,  46,  32,  18,  33,  34,  35,  23,  36,  37,  38,
+         50,  49,  24,  25,  16,  19,  31,  20,  22,  47,  17,  45,  21,  44,   2,   3,
+          4,   5,   6,   7,   8,   9,  10,  11,  28,   1,  14,  15,  57,  12,  13,  26,
+         27,  43,  43,  39,  40,  41,  51,  52,  53,  58,  59,  60,  61,  62,  63,  64,
+         65,  66,  67,  68,  87,  88,  99,  70, 119, 110, 102, 104, 111, 107, 109, 106,
+        105, 108, 103,  69,  98,  55,  74,  78,  96,  79,  80,  81,  75,  76,  77,  71,
+         72,  73,  82,  83,  86, 127,
+    ];
+    // Left ctrl, shift, alt, meta then right ctrl, shift, alt, meta
+    const MODIFIERS: [u16; 8] = [29, 42, 56, 125, 97, 54, 100, 126];
+
+    let code = match usage {
+        0..=0x65 => USAGES[usage as usize],
+        0xe0..=0xe7 => MODIFIERS[(usage - 0xe0) as usize],
+        _ => 0,
+    };
+    if code == 0 {
+        None
+    } else {
+        Some(code)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use uinput::UinputSink;
+
+#[cfg(target_os = "linux")]
+mod uinput {
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::time::{Duration, Instant};
+    use std::{mem, slice};
+
+    use xrandr::{XHandle, XrandrError};
+
+    use super::{hid_usage_to_evdev, InputError, InputSink};
+    use crate::input::{InputEvent, MouseButton};
+    use crate::prelude::*;
+
+    // From linux/uinput.h and linux/input-event-codes.h
+    const UI_SET_EVBIT: u64 = 0x4004_5564;
+    const UI_SET_KEYBIT: u64 = 0x4004_5565;
+    const UI_SET_RELBIT: u64 = 0x4004_5566;
+    const UI_SET_ABSBIT: u64 = 0x4004_5567;
+    const UI_DEV_SETUP: u64 = 0x405c_5503;
+    const UI_ABS_SETUP: u64 = 0x401c_5504;
+    const UI_DEV_CREATE: u64 = 0x5501;
+    const UI_DEV_DESTROY: u64 = 0x5502;
+
+    const EV_SYN: u16 = 0x00;
+    const EV_KEY: u16 = 0x01;
+    const EV_REL: u16 = 0x02;
+    const EV_ABS: u16 = 0x03;
+    const SYN_REPORT: u16 = 0;
+    const REL_HWHEEL: u16 = 0x06;
+    const REL_WHEEL: u16 = 0x08;
+    const ABS_X: u16 = 0x00;
+    const ABS_Y: u16 = 0x01;
+    const BTN_LEFT: u16 = 0x110;
+    const BTN_RIGHT: u16 = 0x111;
+    const BTN_MIDDLE: u16 = 0x112;
+    const BTN_SIDE: u16 = 0x113;
+    const BTN_EXTRA: u16 = 0x114;
+    const KEY_MAX_USED: u16 = 255;
+    const BUS_VIRTUAL: u16 = 0x06;
+
+    /// Absolute positions are sent in this range and scaled by the compositor
+    const ABS_MAX: i32 = u16::MAX as i32;
+    /// How often to look for the virtual monitor again, as it takes a moment to show up after
+    /// connecting and can be moved around the desktop
+    const OUTPUT_REFRESH: Duration = Duration::from_secs(2);
+
+    pub const DEVICE_NAME: &str = "remdisp virtual input";
+
+    #[repr(C)]
+    struct UinputSetup {
+        bustype: u16,
+        vendor: u16,
+        product: u16,
+        version: u16,
+        name: [u8; 80],
+        ff_effects_max: u32,
+    }
+
+    #[repr(C)]
+    struct UinputAbsSetup {
+        code: u16,
+        absinfo: libc::input_absinfo,
+    }
+
+    /// Injects input through a virtual device created with `/dev/uinput`.
+    ///
+    /// The pointer is an absolute device, which X maps onto the whole desktop. So the pointer
+    /// lands on the virtual monitor, positions are scaled into where X put the output with the
+    /// EDID from [`InputSink::set_edid`]. Until that output is found they cover the whole
+    /// desktop. When streaming to several displays give each device its own name with
+    /// [`Self::with_name`] so they can be told apart.
+    #[derive(Debug)]
+    pub struct UinputSink {
+        file: File,
+        stream_width: u32,
+        stream_height: u32,
+        edid: Vec<u8>,
+        output: Option<OutputArea>,
+        /// When we last looked for the output
+        output_checked: Option<Instant>,
+    }
+
+    /// Where the virtual monitor is on the X screen, in pixels.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct OutputArea {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        screen_width: u32,
+        screen_height: u32,
+    }
+
+    impl OutputArea {
+        /// Finds the monitor one of whose outputs has `edid`.
+        fn find(edid: &[u8]) -> Result<Option<Self>, XrandrError> {
+            let monitors = XHandle::open()?.monitors()?;
+            let screen_width = monitors
+                .iter()
+                .map(|monitor| monitor.x + monitor.width_px)
+                .max()
+                .unwrap_or(0);
+            let screen_height = monitors
+                .iter()
+                .map(|monitor| monitor.y + monitor.height_px)
+                .max()
+                .unwrap_or(0);
+
+            let monitor = monitors.iter().find(|monitor| {
+                monitor
+                    .outputs
+                    .iter()
+                    .any(|output| output.edid().as_deref() == Some(edid))
+            });
+            Ok(monitor.map(|monitor| Self {
+                x: monitor.x.max(0) as u32,
+                y: monitor.y.max(0) as u32,
+                width: monitor.width_px.max(0) as u32,
+                height: monitor.height_px.max(0) as u32,
+                screen_width: screen_width.max(0) as u32,
+                screen_height: screen_height.max(0) as u32,
+            }))
+        }
+
+        /// The whole screen, for when we don't know where the output is.
+        fn whole(width: u32, height: u32) -> Self {
+            Self {
+                x: 0,
+                y: 0,
+                width,
+                height,
+                screen_width: width,
+                screen_height: height,
+            }
+        }
+    }
+
+    impl UinputSink {
+        pub fn new() -> Result<Self, InputError> {
+            Self::with_name(DEVICE_NAME)
+        }
+
+        /// Names longer than the kernel allows are truncated.
+        pub fn with_name(name: &str) -> Result<Self, InputError> {
+            let file = OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open("/dev/uinput")?;
+            let fd = file.as_raw_fd();
+
+            unsafe {
+                for ev in [EV_SYN, EV_KEY, EV_REL, EV_ABS].iter() {
+                    ioctl_int(fd, UI_SET_EVBIT, *ev)?;
+                }
+                for key in 1..=KEY_MAX_USED {
+                    ioctl_int(fd, UI_SET_KEYBIT, key)?;
+                }
+                for btn in [BTN_LEFT, BTN_RIGHT, BTN_MIDDLE, BTN_SIDE, BTN_EXTRA].iter() {
+                    ioctl_int(fd, UI_SET_KEYBIT, *btn)?;
+                }
+                for rel in [REL_WHEEL, REL_HWHEEL].iter() {
+                    ioctl_int(fd, UI_SET_RELBIT, *rel)?;
+                }
+                for abs in [ABS_X, ABS_Y].iter() {
+                    ioctl_int(fd, UI_SET_ABSBIT, *abs)?;
+
+                    let mut setup: UinputAbsSetup = mem::zeroed();
+                    setup.code = *abs;
+                    setup.absinfo.maximum = ABS_MAX;
+                    ioctl_ptr(fd, UI_ABS_SETUP, &setup)?;
+                }
+
+                let mut setup = UinputSetup {
+                    bustype: BUS_VIRTUAL,
+                    vendor: 0,
+                    product: 0,
+                    version: 1,
+                    name: [0; 80],
+                    ff_effects_max: 0,
+                };
+                // Leaves at least one nul terminator
+                let name_bytes: Vec<u8> = name
+                    .bytes()
+                    .filter(|b| *b != 0)
+                    .take(setup.name.len() - 1)
+                    .collect();
+                setup.name[..name_bytes.len()].copy_from_slice(&name_bytes);
+                ioctl_ptr(fd, UI_DEV_SETUP, &setup)?;
+
+                if libc::ioctl(fd, UI_DEV_CREATE as _) < 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+            }
+
+            info!(name, "Created virtual input device");
+            Ok(Self {
+                file,
+                stream_width: 1,
+                stream_height: 1,
+                edid: vec![],
+                output: None,
+                output_checked: None,
+            })
+        }
+
+        fn emit(&mut self, events: &[(u16, u16, i32)]) -> Result<(), InputError> {
+            let mut raw: Vec<libc::input_event> = events
+                .iter()
+                .chain(Some(&(EV_SYN, SYN_REPORT, 0)))
+                .map(|&(type_, code, value)| libc::input_event {
+                    time: libc::timeval {
+                        tv_sec: 0,
+                        tv_usec: 0,
+                    },
+                    type_,
+                    code,
+                    value,
+                })
+                .collect();
+
+            let bytes = unsafe {
+                slice::from_raw_parts_mut(
+                    raw.as_mut_ptr().cast::<u8>(),
+                    raw.len() * mem::size_of::<libc::input_event>(),
+                )
+            };
+            self.file.write_all(bytes)?;
+            Ok(())
+        }
+
+        fn scale(v: u32, size: u32) -> i32 {
+            if size <= 1 {
+                return 0;
+            }
+            (v.min(size - 1) as u64 * ABS_MAX as u64 / (size - 1) as u64) as i32
+        }
+
+        /// Scales a position in the stream to the absolute range, placing it within `area`.
+        fn scale_into(x: u32, y: u32, stream: (u32, u32), area: OutputArea) -> (i32, i32) {
+            fn place(v: u32, stream: u32, offset: u32, extent: u32) -> u32 {
+                if stream <= 1 || extent == 0 {
+                    return offset;
+                }
+                let v = v.min(stream - 1) as u64 * (extent - 1) as u64 / (stream - 1) as u64;
+                offset + v as u32
+            }
+            let x = place(x, stream.0, area.x, area.width);
+            let y = place(y, stream.1, area.y, area.height);
+            (
+                Self::scale(x, area.screen_width),
+                Self::scale(y, area.screen_height),
+            )
+        }
+
+        fn refresh_output(&mut self, now: Instant) {
+            let due = self
+                .output_checked
+                .map_or(true, |checked| now - checked >= OUTPUT_REFRESH);
+            if self.edid.is_empty() || !due {
+                return;
+            }
+            self.output_checked = Some(now);
+
+            let output = match OutputArea::find(&self.edid) {
+                Ok(output) => output,
+                Err(err) => {
+                    debug!(?err, "Failed to look for virtual monitor");
+                    None
+                }
+            };
+            if output != self.output {
+                match output {
+                    Some(output) => debug!(?output, "Mapping pointer onto virtual monitor"),
+                    None => warn!(
+                        "Can't find the virtual monitor on the X screen, so the pointer will \
+                         cover the whole desktop"
+                    ),
+                }
+                self.output = output;
+            }
+        }
+    }
+
+    impl InputSink for UinputSink {
+        fn set_stream_size(&mut self, width: u32, height: u32) {
+            self.stream_width = width;
+            self.stream_height = height;
+        }
+
+        fn set_edid(&mut self, edid: &[u8]) {
+            if edid != self.edid.as_slice() {
+                self.edid = edid.to_vec();
+                self.output = None;
+                self.output_checked = None;
+            }
+        }
+
+        fn inject(&mut self, event: InputEvent) -> Result<(), InputError> {
+            match event {
+                InputEvent::Key { usage, pressed } => {
+                    let code = hid_usage_to_evdev(usage).ok_or(InputError::UnmappedKey(usage))?;
+                    self.emit(&[(EV_KEY, code, pressed as i32)])
+                }
+                InputEvent::MouseMove { x, y } => {
+                    self.refresh_output(Instant::now());
+                    let stream = (self.stream_width, self.stream_height);
+                    let area = self
+                        .output
+                        .unwrap_or_else(|| OutputArea::whole(stream.0, stream.1));
+                    let (x, y) = Self::scale_into(x, y, stream, area);
+                    self.emit(&[(EV_ABS, ABS_X, x), (EV_ABS, ABS_Y, y)])
+                }
+                InputEvent::MouseButton { button, pressed } => {
+                    let code = match button {
+                        MouseButton::Left => BTN_LEFT,
+                        MouseButton::Middle => BTN_MIDDLE,
+                        MouseButton::Right => BTN_RIGHT,
+                        MouseButton::X1 => BTN_SIDE,
+                        MouseButton::X2 => BTN_EXTRA,
+                    };
+                    self.emit(&[(EV_KEY, code, pressed as i32)])
+                }
+                InputEvent::Scroll { x, y } => {
+                    self.emit(&[(EV_REL, REL_HWHEEL, x), (EV_REL, REL_WHEEL, y)])
+                }
+            }
+        }
+    }
+
+    impl Drop for UinputSink {
+        fn drop(&mut self) {
+            unsafe {
+                libc::ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY as _);
+            }
+        }
+    }
+
+    unsafe fn ioctl_int(fd: i32, request: u64, value: u16) -> io::Result<()> {
+        if libc::ioctl(fd, request as _, value as libc::c_int) < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    unsafe fn ioctl_ptr<T>(fd: i32, request: u64, value: &T) -> io::Result<()> {
+        if libc::ioctl(fd, request as _, value as *const T) < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[ltest]
+        fn scales_to_abs_range() {
+            assert_eq!(UinputSink::scale(0, 1920), 0);
+            assert_eq!(UinputSink::scale(1919, 1920), ABS_MAX);
+            assert_eq!(UinputSink::scale(5000, 1920), ABS_MAX);
+        }
+
+        #[ltest]
+        fn scales_into_output() {
+            // A 1280x720 output to the right of a 1920x1080 one
+            let area = OutputArea {
+                x: 1920,
+                y: 0,
+                width: 1280,
+                height: 720,
+                screen_width: 3200,
+                screen_height: 1080,
+            };
+            let stream = (1280, 720);
+
+            assert_eq!(
+                UinputSink::scale_into(0, 0, stream, area),
+                (UinputSink::scale(1920, 3200), 0)
+            );
+            assert_eq!(
+                UinputSink::scale_into(1279, 719, stream, area),
+                (ABS_MAX, UinputSink::scale(719, 1080))
+            );
+            // Without an output the stream covers everything
+            assert_eq!(
+                UinputSink::scale_into(1279, 719, stream, OutputArea::whole(1280, 720)),
+                (ABS_MAX, ABS_MAX)
+            );
+        }
+
+        #[ltest]
+        fn setup_structs_match_kernel_layout() {
+            assert_eq!(mem::size_of::<UinputSetup>(), 92);
+            assert_eq!(mem::size_of::<UinputAbsSetup>(), 28);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::MouseButton;
+    use crate::proto::display_event::Input;
+
+    fn input_event(event: InputEvent) -> Result<DisplayEvent, Status> {
+        Ok(DisplayEvent {
+            display_event: Some(display_event::DisplayEvent::Input(Input::from(event))),
+        })
+    }
+
+    #[ltest]
+    fn maps_common_keys() {
+        assert_eq!(hid_usage_to_evdev(0x04), Some(30)); // A
+        assert_eq!(hid_usage_to_evdev(0x27), Some(11)); // 0
+        assert_eq!(hid_usage_to_evdev(0x28), Some(28)); // Enter
+        assert_eq!(hid_usage_to_evdev(0x52), Some(103)); // Up
+        assert_eq!(hid_usage_to_evdev(0xe1), Some(42)); // Left shift
+        assert_eq!(hid_usage_to_evdev(0x00), None);
+        assert_eq!(hid_usage_to_evdev(0x1000), None);
+    }
+
+    #[ltest(atest)]
+    async fn forwards_input_to_sink() {
+        let events = vec![
+            input_event(InputEvent::MouseMove { x: 1, y: 2 }),
+            input_event(InputEvent::MouseButton {
+                button: MouseButton::Left,
+                pressed: true,
+            }),
+        ];
+        let mut sink = RecordingInputSink::default();
+
+        forward_display_events(tokio_stream::iter(events), &mut sink, |_| ())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *sink.events.lock(),
+            vec![
+                InputEvent::MouseMove { x: 1, y: 2 },
+                InputEvent::MouseButton {
+                    button: MouseButton::Left,
+                    pressed: true
+                },
+            ]
+        );
+    }
+
+    #[ltest(atest)]
+    async fn updates_stream_size_on_resize() {
+        let resize = display_event::Resize {
+            edid: vec![1, 2, 3],
+            width_pixels: 1280,
+            height_pixels: 720,
+        };
+        let events = vec![Ok(DisplayEvent {
+            display_event: Some(display_event::DisplayEvent::Resize(resize.clone())),
+        })];
+        let mut sink = RecordingInputSink::default();
+        let mut others = vec![];
+
+        forward_display_events(tokio_stream::iter(events), &mut sink, |event| {
+            others.push(event)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(*sink.stream_size.lock(), Some((1280, 720)));
+        assert_eq!(*sink.edid.lock(), Some(vec![1, 2, 3]));
+        assert_eq!(others, vec![display_event::DisplayEvent::Resize(resize)]);
+    }
+
+    #[ltest(atest)]
+    async fn stops_on_stream_error() {
+        let events = vec![
+            input_event(InputEvent::Scroll { x: 0, y: 1 }),
+            Err(Status::unavailable("gone")),
+            input_event(InputEvent::Scroll { x: 0, y: -1 }),
+        ];
+        let mut sink = RecordingInputSink::default();
+
+        let result = forward_display_events(tokio_stream::iter(events), &mut sink, |_| ()).await;
+
+        assert!(result.is_err());
+        assert_eq!(sink.events.lock().len(), 1);
+    }
+}
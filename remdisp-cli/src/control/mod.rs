@@ -1,115 +1,822 @@
 use std::fmt::Debug;
+use std::future::Future;
 use std::io;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use anyhow::Result;
 
 use evdi::prelude::*;
+use futures::future::{FusedFuture, FutureExt};
+use futures::StreamExt;
 
+use tokio::net::{lookup_host, TcpStream};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::SendError;
-use tokio::time::timeout;
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, timeout};
+use tokio_rustls::TlsConnector;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::transport::{Channel, Endpoint, Uri};
-use tonic::{Request, Status, Streaming};
+use tonic::metadata::AsciiMetadataValue;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint, Uri};
+use tonic::{Code, Request, Status, Streaming};
 
 use proto::{display_control_client::DisplayControlClient as GeneratedDisplayControlClient, *};
 
-use crate::av::encoder::Encoder;
+use crate::adjust::Adjustments;
+use crate::auth::tls::{self, DisplayVerifier};
+use crate::auth::token::{write_nonce, TOKEN_METADATA};
+use crate::auth::{Fingerprint, Identity, VideoStream};
+use crate::av::audio::input::AudioSource;
+use crate::av::packet::{FramedSink, PacketSink};
+use crate::av::udp::UdpSink;
+use crate::control::audio::send_audio;
+use crate::control::capture::{capture, CaptureError, CaptureOptions, CaptureSource};
+use crate::control::input::{forward_display_events, InputSink, NullInputSink};
+use crate::heartbeat::{HeartbeatOptions, Liveness};
+use crate::net;
 use crate::prelude::*;
 
 use super::proto;
 
+pub mod audio;
+pub mod capture;
+pub mod cursor;
+pub mod input;
+pub mod pacer;
+pub mod pair;
+pub mod replay;
+
 const CONTROL_MSG_TIMEOUT: Duration = Duration::from_secs(15);
 const CONTROL_CONNECT_TIMEOUT: Duration = CONTROL_MSG_TIMEOUT;
+pub const DEFAULT_RECONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(10);
 
 pub struct ControlClient {
     client: GeneratedDisplayControlClient<Channel>,
+    host: String,
+    port: u16,
+    auth: ControlAuth,
+    /// None when using a token
+    video_tls: Option<TlsConnector>,
+    outputs: Vec<hello_reply::Output>,
+    /// Id of the output to attach to
+    output: u32,
+    /// Transports the display accepts, as well as TCP which they all do
+    video_transports: Vec<i32>,
+    video_transport: VideoTransport,
+    /// Whether the display can play audio
+    display_audio: bool,
+    audio: Option<AudioSource>,
+    force: bool,
+    heartbeat: HeartbeatOptions,
+    reconnect_timeout: Duration,
+    /// From the display's Attach, to resume with if we lose the connection
+    session: Option<String>,
+    adjuster: Adjuster,
 }
 
-impl ControlClient {
-    /// Connect and perform hello to verify compatibility
-    pub async fn connect(host: &str, port: u16) -> anyhow::Result<Self> {
-        let display_uri = Uri::builder()
-            .scheme("http")
-            .authority(format!("{}:{}", host, port.to_string()).as_str())
-            .path_and_query("/")
-            .build()?;
-
-        let endpoint = Endpoint::new(display_uri)?.timeout(CONTROL_MSG_TIMEOUT);
-
-        // NOTE: We need to use a generic tokio timeout fn because tonic doesn't support setting the
-        //  connect timeout. See <https://github.com/hyperium/tonic/issues/498>
-        let mut client = timeout(
-            CONTROL_CONNECT_TIMEOUT,
-            GeneratedDisplayControlClient::connect(endpoint),
-        )
+/// How we authenticate to displays, and they to us.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub enum ControlAuth {
+    /// TLS with our identity, trusting only the display we paired with
+    Paired {
+        identity: Arc<Identity>,
+        display: Fingerprint,
+    },
+    /// A token shared with the display, without encryption
+    Token(#[derivative(Debug = "ignore")] String),
+}
+
+/// Adjusts the display's monitor, including while attached. Adjustments are sent again after
+/// reconnecting, so the display ends up how it was asked to be.
+#[derive(Debug, Clone)]
+pub struct Adjuster {
+    tx: Arc<watch::Sender<Adjustments>>,
+    rx: watch::Receiver<Adjustments>,
+}
+
+impl Adjuster {
+    fn new() -> Self {
+        let (tx, rx) = watch::channel(Adjustments::default());
+        Self {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+
+    /// Apply `adjustments` on top of any made before.
+    pub fn adjust(&self, adjustments: Adjustments) {
+        let mut merged = *self.rx.borrow();
+        merged.merge(adjustments);
+        // We hold a receiver, so this can't fail
+        let _ = self.tx.send(merged);
+    }
+
+    /// Everything asked for so far.
+    pub fn adjustments(&self) -> Adjustments {
+        *self.rx.borrow()
+    }
+}
+
+/// How to secure the control channel.
+enum ChannelAuth<'a> {
+    /// TLS, checking the display's certificate with the verifier
+    Tls(&'a Identity, Arc<DisplayVerifier>),
+    /// Plain HTTP, sending the token with every request
+    Token(&'a str),
+}
+
+/// Connect to the display's control channel.
+async fn open_channel(
+    host: &str,
+    port: u16,
+    auth: ChannelAuth<'_>,
+) -> anyhow::Result<GeneratedDisplayControlClient<Channel>> {
+    let scheme = match auth {
+        ChannelAuth::Tls(..) => "https",
+        ChannelAuth::Token(_) => "http",
+    };
+    let display_uri = Uri::builder()
+        .scheme(scheme)
+        .authority(net::authority(host, port).as_str())
+        .path_and_query("/")
+        .build()?;
+
+    let mut endpoint = Endpoint::new(display_uri)?.timeout(CONTROL_MSG_TIMEOUT);
+    if let ChannelAuth::Tls(identity, verifier) = &auth {
+        let mut config = tls::client_config(identity, verifier.clone())?;
+        config.set_protocols(&[tls::ALPN_H2.to_vec()]);
+        let tls_config = ClientTlsConfig::new()
+            .domain_name(tls::SERVER_NAME)
+            .rustls_client_config(config);
+        endpoint = endpoint.tls_config(tls_config)?;
+    }
+
+    // NOTE: We need to use a generic tokio timeout fn because tonic doesn't support setting the
+    //  connect timeout. See <https://github.com/hyperium/tonic/issues/498>
+    let channel = timeout(CONTROL_CONNECT_TIMEOUT, endpoint.connect())
         .await
         .context("Timed out trying to connect to host")?
         .context("Error connecting to host")?;
 
-        client
-            .hello(HelloRequest {
-                version: VERSION.to_string(),
+    let client = match auth {
+        ChannelAuth::Tls(..) => GeneratedDisplayControlClient::new(channel),
+        ChannelAuth::Token(token) => {
+            let token: AsciiMetadataValue = token.parse().context("Invalid token")?;
+            GeneratedDisplayControlClient::with_interceptor(channel, move |mut req: Request<()>| {
+                req.metadata_mut().insert(TOKEN_METADATA, token.clone());
+                Ok(req)
             })
-            .await?;
+        }
+    };
+    Ok(client)
+}
+
+/// Connect to the display and perform hello.
+async fn dial(
+    host: &str,
+    port: u16,
+    auth: &ControlAuth,
+) -> anyhow::Result<(GeneratedDisplayControlClient<Channel>, HelloReply)> {
+    let channel_auth = match auth {
+        ControlAuth::Paired { identity, display } => {
+            ChannelAuth::Tls(identity, Arc::new(DisplayVerifier::pinned(*display)))
+        }
+        ControlAuth::Token(token) => ChannelAuth::Token(token),
+    };
+    let mut client = open_channel(host, port, channel_auth).await?;
+
+    let hello = client
+        .hello(HelloRequest {
+            version: VERSION.to_string(),
+        })
+        .await?
+        .into_inner();
+    debug!(outputs = ?hello.outputs, "Display outputs");
 
-        Ok(Self { client })
+    Ok((client, hello))
+}
+
+impl ControlClient {
+    /// Connect and perform hello to verify compatibility.
+    ///
+    /// To connect with [`ControlAuth::Paired`] we must have paired first, see [`pair::pair`].
+    pub async fn connect(host: &str, port: u16, auth: ControlAuth) -> anyhow::Result<Self> {
+        let (client, hello) = dial(host, port, &auth).await?;
+        let video_tls = match &auth {
+            ControlAuth::Paired { identity, display } => {
+                let verifier = Arc::new(DisplayVerifier::pinned(*display));
+                let config = tls::client_config(identity, verifier)?;
+                Some(TlsConnector::from(Arc::new(config)))
+            }
+            ControlAuth::Token(_) => None,
+        };
+        let output = hello
+            .outputs
+            .first()
+            .map(|output| output.id)
+            .ok_or_else(|| anyhow!("Display has no outputs"))?;
+
+        Ok(Self {
+            client,
+            host: net::unbracket(host).to_string(),
+            port,
+            auth,
+            video_tls,
+            outputs: hello.outputs,
+            output,
+            video_transports: hello.video_transports,
+            video_transport: VideoTransport::Tcp,
+            display_audio: hello.audio,
+            audio: None,
+            force: false,
+            heartbeat: HeartbeatOptions::default(),
+            reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+            session: None,
+            adjuster: Adjuster::new(),
+        })
+    }
+
+    /// Connect to the display again after losing it, keeping our choice of output.
+    async fn redial(&mut self) -> anyhow::Result<()> {
+        let (client, hello) = dial(&self.host, self.port, &self.auth).await?;
+        if !hello.outputs.iter().any(|output| output.id == self.output) {
+            return Err(anyhow!("Display no longer has output {}", self.output));
+        }
+        self.client = client;
+        self.outputs = hello.outputs;
+        self.video_transports = hello.video_transports;
+        self.display_audio = hello.audio;
+        Ok(())
+    }
+
+    /// The monitors on the display, as of connecting.
+    pub fn outputs(&self) -> &[hello_reply::Output] {
+        &self.outputs
     }
 
-    pub async fn attach(&mut self, handle: UnconnectedHandle) -> Result<(), AttachedError> {
-        let (_tx, display_recv) = mpsc::channel::<ControlEvent>(16);
-        let mut recv = self
+    /// Attach to the output with this id or name instead of the first.
+    pub fn select_output(&mut self, selector: &str) -> anyhow::Result<&hello_reply::Output> {
+        let output = find_output(&self.outputs, selector).ok_or_else(|| {
+            let available: Vec<String> = self
+                .outputs
+                .iter()
+                .map(|output| format!("{} ({})", output.id, output.name))
+                .collect();
+            anyhow!(
+                "Display has no output {}, it has {}",
+                selector,
+                available.join(", ")
+            )
+        })?;
+        self.output = output.id;
+        Ok(output)
+    }
+
+    /// Send video over UDP instead of the default TCP, if the display supports it.
+    ///
+    /// UDP copes better with packet loss, but isn't encrypted even when paired.
+    pub fn set_video_transport(&mut self, video_transport: VideoTransport) -> anyhow::Result<()> {
+        let supported = video_transport == VideoTransport::Tcp
+            || self.video_transports.contains(&(video_transport as i32));
+        if !supported {
+            return Err(anyhow!(
+                "Display doesn't support {:?} video",
+                video_transport
+            ));
+        }
+        if video_transport == VideoTransport::Udp {
+            if let ControlAuth::Paired { .. } = self.auth {
+                warn!("Video over UDP isn't encrypted, only the control channel is");
+            }
+        }
+        self.video_transport = video_transport;
+        Ok(())
+    }
+
+    /// Also send audio from `source`, if the display can play it.
+    pub fn set_audio(&mut self, source: Option<AudioSource>) -> anyhow::Result<()> {
+        if source.is_some() && !self.display_audio {
+            return Err(anyhow!("Display can't play audio"));
+        }
+        self.audio = source;
+        Ok(())
+    }
+
+    /// Take over the output when attaching even if another control is using it.
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+
+    pub fn heartbeat(&self) -> HeartbeatOptions {
+        self.heartbeat
+    }
+
+    pub fn set_heartbeat(&mut self, heartbeat: HeartbeatOptions) {
+        self.heartbeat = heartbeat;
+    }
+
+    /// For changing the display's brightness, contrast or power, even while attached.
+    pub fn adjuster(&self) -> Adjuster {
+        self.adjuster.clone()
+    }
+
+    /// How long to keep trying to get back to the display after losing the connection. Zero
+    /// gives up immediately.
+    pub fn set_reconnect_timeout(&mut self, reconnect_timeout: Duration) {
+        self.reconnect_timeout = reconnect_timeout;
+    }
+
+    /// Attach to the display and stream to it until `stop` completes, the display detaches, or
+    /// an error occurs.
+    ///
+    /// Input from the display is injected into `input` if provided. When the display resizes the
+    /// virtual monitor is reconnected with the new size and capture restarts on the same stream.
+    ///
+    /// If the connection to the display is lost we keep the virtual monitor and retry with
+    /// backoff for up to the reconnect timeout, resuming the same session on the display.
+    pub async fn attach(
+        &mut self,
+        handle: UnconnectedHandle,
+        options: &CaptureOptions,
+        input: Option<Box<dyn InputSink>>,
+        stop: impl Future<Output = ()>,
+    ) -> Result<(), AttachedError> {
+        let mut input = input.unwrap_or_else(|| Box::new(NullInputSink));
+        let stop = stop.fuse();
+        tokio::pin!(stop);
+
+        self.session = None;
+        // Waiting in the display's queue lasts as long as the other control stays attached
+        let mut attached = tokio::select! {
+            attached = self.attach_stream() => attached,
+            _ = &mut stop => return Ok(()),
+        };
+        let mut monitor = VirtualMonitor::new(handle);
+        let mut lost_at: Option<Instant> = None;
+        let mut attempt = 0;
+
+        let result = loop {
+            let err = match attached {
+                Ok(stream) => {
+                    lost_at = None;
+                    attempt = 0;
+                    let session = self.stream_session(
+                        stream,
+                        &mut monitor,
+                        input.as_mut(),
+                        options,
+                        &mut stop,
+                    );
+                    match session.await {
+                        Ok(()) => break Ok(()),
+                        Err(err) => err,
+                    }
+                }
+                Err(err) => err,
+            };
+
+            let lost_at = *lost_at.get_or_insert_with(Instant::now);
+            // Only resume sessions that got going, so mistakes like a wrong output fail fast
+            if self.session.is_none()
+                || !err.is_connection_lost()
+                || lost_at.elapsed() >= self.reconnect_timeout
+            {
+                break Err(err);
+            }
+
+            let delay = reconnect_backoff(attempt);
+            attempt += 1;
+            warn!(?err, ?delay, "Lost display, reconnecting");
+            tokio::select! {
+                _ = sleep(delay) => (),
+                _ = &mut stop => break Ok(()),
+            }
+
+            let reattach = async {
+                match self.redial().await {
+                    Ok(()) => self.attach_stream().await,
+                    Err(err) => Err(AttachedError::Reconnect(err)),
+                }
+            };
+            attached = tokio::select! {
+                attached = reattach => attached,
+                _ = &mut stop => break Ok(()),
+            };
+        };
+
+        monitor.disconnect();
+        result
+    }
+
+    /// Stream to one Attach of the display until `stop` completes or the session ends.
+    async fn stream_session(
+        &self,
+        attached: AttachedStream,
+        monitor: &mut VirtualMonitor,
+        input: &mut dyn InputSink,
+        options: &CaptureOptions,
+        stop: &mut (impl FusedFuture<Output = ()> + Unpin),
+    ) -> Result<(), AttachedError> {
+        let AttachedStream {
+            display,
+            video,
+            audio,
+            events_tx,
+            events,
+        } = attached;
+
+        input.set_stream_size(display.width_pixels, display.height_pixels);
+        input.set_edid(&display.edid);
+
+        let (resize_tx, mut resize_rx) = watch::channel(None);
+        // Set if the display side ended the session
+        let ended = Mutex::new(None);
+        let ended_ref = &ended;
+        let liveness = Mutex::new(Liveness::new(self.heartbeat.timeout, Instant::now()));
+        let liveness_ref = &liveness;
+
+        let events = events.inspect(move |_| liveness_ref.lock().unwrap().seen(Instant::now()));
+        let display_events = async move {
+            let result = forward_display_events(events, input, |event| match event {
+                display_event::DisplayEvent::Resize(resize) => {
+                    let _ = resize_tx.send(Some(resize));
+                }
+                display_event::DisplayEvent::Preempted(preempted) => {
+                    warn!(
+                        controller = %preempted.controller,
+                        "Another control took over the display"
+                    );
+                    let err = AttachedError::Preempted(preempted.controller);
+                    *ended_ref.lock().unwrap() = Some(err);
+                }
+                // Already counted towards liveness
+                display_event::DisplayEvent::Heartbeat(_) => (),
+                other => debug!(event = ?other, "Ignoring unexpected display event"),
+            })
+            .await;
+            match result {
+                Ok(()) => info!("Display detached"),
+                // The display ends sessions it finished normally with an OK status
+                Err(status) if status.code() == Code::Ok => info!("Display detached"),
+                Err(status) => {
+                    warn!(?status, "Error receiving events from display");
+                    let mut ended = ended_ref.lock().unwrap();
+                    if ended.is_none() {
+                        *ended = Some(AttachedError::Remote(status));
+                    }
+                }
+            }
+        };
+
+        let heartbeat_tx = events_tx.clone();
+        let heartbeat_interval = self.heartbeat.interval;
+        let heartbeats = async move {
+            let mut interval = tokio::time::interval(heartbeat_interval);
+            loop {
+                interval.tick().await;
+                if liveness_ref.lock().unwrap().is_dead(Instant::now()) {
+                    warn!("Display stopped sending heartbeats, detaching");
+                    *ended_ref.lock().unwrap() = Some(AttachedError::HeartbeatTimeout);
+                    return;
+                }
+                // If the queue is full the display isn't reading, so there's no point waiting
+                let _ = heartbeat_tx.try_send(Heartbeat {}.into());
+            }
+        };
+
+        let adjust_tx = events_tx.clone();
+        let mut adjustments = self.adjuster.rx.clone();
+        let adjust = async move {
+            loop {
+                let current = *adjustments.borrow();
+                if !current.is_empty() {
+                    debug!(adjustments = ?current, "Adjusting display");
+                    if adjust_tx.send(current.into()).await.is_err() {
+                        break;
+                    }
+                }
+                if adjustments.changed().await.is_err() {
+                    break;
+                }
+            }
+            // Ending the session is up to the others
+            futures::future::pending::<()>().await
+        };
+
+        let audio = match (audio, &self.audio) {
+            (Some(stream), Some(source)) => {
+                info!(%source, "Streaming audio to display");
+                let audio = send_audio(source.clone(), stream, monitor.start);
+                Some(tokio::spawn(async move {
+                    if let Err(err) = audio.await {
+                        warn!(?err, "Stopped streaming audio, carrying on without it");
+                    }
+                }))
+            }
+            _ => None,
+        };
+
+        let session_end = async move {
+            tokio::select! {
+                _ = stop => (),
+                _ = display_events => (),
+                _ = heartbeats => (),
+                _ = adjust => (),
+            }
+        };
+        tokio::pin!(session_end);
+
+        let mut sinks = vec![video];
+        let mut config = MonitorConfig {
+            edid: display.edid,
+            width: display.width_pixels,
+            height: display.height_pixels,
+        };
+        let mut result = Ok(());
+
+        loop {
+            let mut resized = false;
+            let stop_or_resize = async {
+                tokio::select! {
+                    _ = &mut session_end => (),
+                    changed = resize_rx.changed() => resized = changed.is_ok(),
+                }
+            };
+
+            let segment = monitor.captures;
+            let start = monitor.start;
+            let (handle, source) = match monitor.connect(&config).await {
+                Ok(connected) => connected,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+
+            info!(
+                ?options,
+                width = config.width,
+                height = config.height,
+                "Streaming to display"
+            );
+            result = capture(
+                handle,
+                source,
+                &options.for_segment(segment),
+                &mut sinks,
+                Some(events_tx.clone()),
+                start,
+                stop_or_resize,
+            )
+            .await;
+            monitor.captures += 1;
+
+            if result.is_err() || !resized {
+                break;
+            }
+            if let Some(resize) = resize_rx.borrow().clone() {
+                info!(
+                    width = resize.width_pixels,
+                    height = resize.height_pixels,
+                    "Display resized, reconnecting virtual monitor"
+                );
+                config = MonitorConfig {
+                    edid: resize.edid,
+                    width: resize.width_pixels,
+                    height: resize.height_pixels,
+                };
+            }
+        }
+
+        for sink in &mut sinks {
+            if let Err(err) = sink.finish().await {
+                warn!(?sink, ?err, "Failed to finish sink");
+            }
+        }
+        if let Some(audio) = audio {
+            audio.abort();
+        }
+        drop(events_tx);
+
+        result?;
+        let ended = ended.lock().unwrap().take();
+        match ended {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Perform the Attach handshake and open the video stream, without touching evdi.
+    pub async fn attach_stream(&mut self) -> Result<AttachedStream, AttachedError> {
+        let (events_tx, display_recv) = mpsc::channel::<ControlEvent>(16);
+        events_tx
+            .send(ControlEvent {
+                control_event: Some(control_event::ControlEvent::Attach(control_event::Attach {
+                    output: self.output,
+                    force: self.force,
+                    resume: self.session.clone().unwrap_or_default(),
+                    video_transport: self.video_transport as i32,
+                    audio: self.audio.is_some(),
+                })),
+            })
+            .await?;
+        let mut events = self
             .client
             .attach(ReceiverStream::new(display_recv))
             .await?
             .into_inner();
 
-        let display_attach = if let Some(event) = recv.message().await? {
-            match event.display_event.expect("Must provide event") {
-                display_event::DisplayEvent::Attach(attach) => attach,
+        // We may be queued for a while, so exchange heartbeats like an attached session
+        let mut heartbeats = tokio::time::interval(self.heartbeat.interval);
+        let mut liveness = Liveness::new(self.heartbeat.timeout, Instant::now());
+        let display = loop {
+            let event = tokio::select! {
+                event = events.message() => event?.ok_or(AttachedError::Protocol)?,
+                _ = heartbeats.tick() => {
+                    let _ = events_tx.try_send(Heartbeat {}.into());
+                    continue;
+                }
+                _ = sleep_until(liveness.deadline().into()) => {
+                    warn!("Display stopped sending heartbeats before attaching");
+                    return Err(AttachedError::HeartbeatTimeout);
+                }
+            };
+            liveness.seen(Instant::now());
+            match event.display_event {
+                Some(display_event::DisplayEvent::Attach(attach)) => break attach,
+                Some(display_event::DisplayEvent::Queued(queued)) => {
+                    info!(
+                        controller = %queued.controller,
+                        "Display is busy, waiting for it to detach"
+                    );
+                }
+                Some(display_event::DisplayEvent::Heartbeat(_)) => (),
+                Some(display_event::DisplayEvent::Preempted(preempted)) => {
+                    return Err(AttachedError::Preempted(preempted.controller));
+                }
+                other => {
+                    warn!(event = ?other, "Expected Attach as first display event");
+                    return Err(AttachedError::Protocol);
+                }
+            }
+        };
+
+        let video_port = display.video_port as u16;
+        let video: Box<dyn PacketSink> = match self.video_transport {
+            VideoTransport::Tcp => {
+                let video = self.open_stream(video_port, &display.video_nonce).await?;
+                Box::new(FramedSink(video))
+            }
+            VideoTransport::Udp => {
+                let addr = lookup_host((self.host.as_str(), video_port))
+                    .await?
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Host not found"))?;
+                Box::new(UdpSink::connect(addr, &display.video_nonce).await?)
             }
-        } else {
-            return Err(AttachedError::Protocol);
         };
+        let audio = match (&self.audio, display.audio_port as u16) {
+            (Some(_), 0) => {
+                warn!("Display can't play audio at the moment, streaming without it");
+                None
+            }
+            (Some(_), audio_port) => {
+                Some(self.open_stream(audio_port, &display.video_nonce).await?)
+            }
+            (None, _) => None,
+        };
+        debug!(?display, video_port, transport = ?self.video_transport, "Attached");
+        if !display.session.is_empty() {
+            self.session = Some(display.session.clone());
+        }
 
-        let config = DeviceConfig::new(
-            display_attach.edid,
-            display_attach.width_pixels,
-            display_attach.height_pixels,
-        );
+        Ok(AttachedStream {
+            display,
+            video,
+            audio,
+            events_tx,
+            events,
+        })
+    }
 
-        let mut handle = handle.connect(&config);
+    /// Open a TCP stream to the display and prove it's from us, with TLS when paired and the
+    /// nonce from its Attach.
+    async fn open_stream(&self, port: u16, nonce: &[u8]) -> io::Result<Box<dyn VideoStream>> {
+        let stream = TcpStream::connect((self.host.as_str(), port)).await?;
+        let mut stream: Box<dyn VideoStream> = match &self.video_tls {
+            Some(connector) => Box::new(tls::connect(connector, stream).await?),
+            None => Box::new(stream),
+        };
+        if !nonce.is_empty() {
+            write_nonce(stream.as_mut(), nonce).await?;
+        }
+        Ok(stream)
+    }
+}
 
-        let mode = handle
-            .events
-            .await_mode(EVDI_TIMEOUT)
-            .await
-            .map_err(|err| unavailable!("Error awaiting mode: {:?}", err))?;
+/// Delay before the nth attempt to reconnect, doubling up to a limit.
+fn reconnect_backoff(attempt: u32) -> Duration {
+    RECONNECT_INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(RECONNECT_MAX_BACKOFF)
+}
 
-        let _buf_id = handle.new_buffer(&mode);
+/// The evdi virtual monitor, kept connected across reconnects to the display so windows on it
+/// don't jump to other screens while the network recovers.
+struct VirtualMonitor {
+    unconnected: Option<UnconnectedHandle>,
+    connected: Option<(Handle, CaptureSource, MonitorConfig)>,
+    /// Capture timestamps are relative to this so they carry on across captures
+    start: Instant,
+    /// Captures so far, for numbering recordings
+    captures: u32,
+}
 
-        let _encoder = Encoder::new(mode)
-            .map_err(|err| unavailable!("Failed to create encoder: {:?}", err))?;
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MonitorConfig {
+    edid: Vec<u8>,
+    width: u32,
+    height: u32,
+}
 
-        // let mut video_stream = TcpStream::connect(display_attach.video_addr).await?;
+impl VirtualMonitor {
+    fn new(handle: UnconnectedHandle) -> Self {
+        Self {
+            unconnected: Some(handle),
+            connected: None,
+            start: Instant::now(),
+            captures: 0,
+        }
+    }
 
-        // loop {
-        //     handle.request_update(buf_id, EVDI_TIMEOUT).await
-        //         .map_err(|err| unavailable!("Failed requesting update from kernel: {:?}", err))?;
-        //
-        //     let buf = handle.get_buffer(buf_id).expect("Buffer exists");
-        //     encoder.send_frame(buf.bytes())
-        //         .map_err(|err| unavailable!("Error sending frame to video encoder: {:?}", err))?;
-        //
-        //     // We currently exit when this call can't write to the stream.
-        //     encoder.receive_available(&mut video_stream).await
-        //         .map_err(|err| unavailable!("Failed to write video: {:?}", err))?;
-        // }
+    /// Connect with `config`, reconnecting only if it differs from the current one.
+    async fn connect(
+        &mut self,
+        config: &MonitorConfig,
+    ) -> Result<(&mut Handle, &CaptureSource), CaptureError> {
+        if matches!(&self.connected, Some((_, _, current)) if current != config) {
+            self.disconnect();
+        }
 
-        Ok(())
+        if self.connected.is_none() {
+            if config.edid.is_empty() {
+                warn!("Display didn't send an EDID, the virtual monitor may not be recognized");
+            }
+            let unconnected = self.unconnected.take().expect("Either connected or unconnected");
+            let device_config = DeviceConfig::new(config.edid.clone(), config.width, config.height);
+            let mut handle = unconnected.connect(&device_config);
+            match CaptureSource::new(&mut handle).await {
+                Ok(source) => self.connected = Some((handle, source, config.clone())),
+                Err(err) => {
+                    self.unconnected = Some(handle.disconnect());
+                    return Err(err);
+                }
+            }
+        }
+
+        let (handle, source, _) = self.connected.as_mut().expect("Just connected");
+        Ok((handle, source))
     }
+
+    fn disconnect(&mut self) {
+        if let Some((handle, _, _)) = self.connected.take() {
+            self.unconnected = Some(handle.disconnect());
+        }
+    }
+}
+
+/// Find an output by id, or failing that case-insensitively by name.
+fn find_output<'a>(
+    outputs: &'a [hello_reply::Output],
+    selector: &str,
+) -> Option<&'a hello_reply::Output> {
+    let by_id = selector
+        .parse::<u32>()
+        .ok()
+        .and_then(|id| outputs.iter().find(|output| output.id == id));
+    by_id.or_else(|| {
+        outputs
+            .iter()
+            .find(|output| output.name.eq_ignore_ascii_case(selector))
+    })
+}
+
+/// An attached display ready to be sent video.
+#[derive(Debug)]
+pub struct AttachedStream {
+    pub display: display_event::Attach,
+    pub video: Box<dyn PacketSink>,
+    /// If we asked for audio and the display can play it
+    pub audio: Option<Box<dyn VideoStream>>,
+    /// Dropping this ends the Attach call
+    pub events_tx: mpsc::Sender<ControlEvent>,
+    /// Further events from the display, such as input
+    pub events: Streaming<DisplayEvent>,
 }
 
 #[derive(Debug, Error)]
@@ -124,6 +831,30 @@ pub enum AttachedError {
     IO(#[from] io::Error),
     #[error("Error sending to other side")]
     Send,
+    #[error("Error capturing video")]
+    Capture(#[from] CaptureError),
+    #[error("Another control took over the display: {0}")]
+    Preempted(String),
+    #[error("Display stopped sending heartbeats")]
+    HeartbeatTimeout,
+    #[error("Error reconnecting to display")]
+    Reconnect(#[source] anyhow::Error),
+}
+
+impl AttachedError {
+    /// Whether reconnecting might help, as opposed to the session being ended on purpose or
+    /// failing for reasons that would just happen again.
+    pub fn is_connection_lost(&self) -> bool {
+        match self {
+            Self::IO(_) | Self::Send | Self::HeartbeatTimeout | Self::Reconnect(_) => true,
+            Self::Capture(CaptureError::Io(_)) => true,
+            Self::Remote(status) => matches!(
+                status.code(),
+                Code::Unavailable | Code::Unknown | Code::Cancelled | Code::DeadlineExceeded
+            ),
+            _ => false,
+        }
+    }
 }
 
 impl<T> From<mpsc::error::SendError<T>> for AttachedError {
@@ -132,4 +863,35 @@ impl<T> From<mpsc::error::SendError<T>> for AttachedError {
     }
 }
 
-const EVDI_TIMEOUT: Duration = Duration::from_secs(10);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(id: u32, name: &str) -> hello_reply::Output {
+        hello_reply::Output {
+            id,
+            name: name.to_string(),
+            width_pixels: 1920,
+            height_pixels: 1080,
+        }
+    }
+
+    #[ltest]
+    fn finds_output_by_id_or_name() {
+        let outputs = vec![output(0, "DP-1"), output(1, "HDMI-1")];
+
+        assert_eq!(find_output(&outputs, "1"), Some(&outputs[1]));
+        assert_eq!(find_output(&outputs, "dp-1"), Some(&outputs[0]));
+        assert_eq!(find_output(&outputs, "2"), None);
+        assert_eq!(find_output(&outputs, "VGA-1"), None);
+    }
+
+    #[ltest]
+    fn backs_off_exponentially_up_to_limit() {
+        assert_eq!(reconnect_backoff(0), Duration::from_millis(500));
+        assert_eq!(reconnect_backoff(1), Duration::from_secs(1));
+        assert_eq!(reconnect_backoff(3), Duration::from_secs(4));
+        assert_eq!(reconnect_backoff(5), RECONNECT_MAX_BACKOFF);
+        assert_eq!(reconnect_backoff(100), RECONNECT_MAX_BACKOFF);
+    }
+}
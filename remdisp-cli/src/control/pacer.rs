@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+use crate::av::frame_rate::FrameRate;
+
+/// Decides when to capture frames and what timestamp to encode them with.
+#[derive(Debug)]
+pub struct CapturePacer {
+    frame_rate: FrameRate,
+    start: Instant,
+    next: Instant,
+}
+
+impl CapturePacer {
+    pub fn new(frame_rate: FrameRate, start: Instant) -> Self {
+        Self {
+            frame_rate,
+            start,
+            next: start,
+        }
+    }
+
+    /// For a capture beginning at `now` whose timestamps carry on from an earlier one that
+    /// began at `start`.
+    pub fn resume(frame_rate: FrameRate, start: Instant, now: Instant) -> Self {
+        let interval = frame_rate.interval().as_nanos();
+        let elapsed = now.saturating_duration_since(start).as_nanos();
+        let ticks = ((elapsed + interval - 1) / interval) as u32;
+        Self {
+            frame_rate,
+            start,
+            next: start + frame_rate.interval() * ticks,
+        }
+    }
+
+    /// Don't capture before this
+    pub fn next_capture(&self) -> Instant {
+        self.next
+    }
+
+    /// Record that a frame was captured at `now`, returning its timestamp.
+    pub fn captured(&mut self, now: Instant) -> Duration {
+        let interval = self.frame_rate.interval();
+
+        match self.frame_rate {
+            FrameRate::Constant(_) => {
+                // Keep timestamps on the grid even if we capture a little late
+                let timestamp = self.next - self.start;
+
+                // If we fell behind skip the ticks we missed instead of bursting to catch up
+                let behind = now.saturating_duration_since(self.next);
+                let missed = (behind.as_nanos() / interval.as_nanos()) as u32;
+                self.next += interval * (missed + 1);
+
+                timestamp
+            }
+            FrameRate::Variable { .. } => {
+                self.next = now + interval;
+                now - self.start
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    const INTERVAL: Duration = Duration::from_millis(40);
+
+    #[ltest]
+    fn constant_stays_on_grid() {
+        let start = Instant::now();
+        let mut pacer = CapturePacer::new(FrameRate::Constant(25), start);
+
+        assert_eq!(pacer.captured(start), Duration::ZERO);
+        assert_eq!(pacer.next_capture(), start + INTERVAL);
+
+        let late = start + INTERVAL + Duration::from_millis(5);
+        assert_eq!(pacer.captured(late), INTERVAL);
+        assert_eq!(pacer.next_capture(), start + INTERVAL * 2);
+    }
+
+    #[ltest]
+    fn constant_skips_missed_ticks() {
+        let start = Instant::now();
+        let mut pacer = CapturePacer::new(FrameRate::Constant(25), start);
+
+        pacer.captured(start + INTERVAL * 3 + Duration::from_millis(1));
+        assert_eq!(pacer.next_capture(), start + INTERVAL * 4);
+        assert_eq!(pacer.captured(start + INTERVAL * 4), INTERVAL * 4);
+    }
+
+    #[ltest]
+    fn variable_uses_capture_time() {
+        let start = Instant::now();
+        let mut pacer = CapturePacer::new(FrameRate::Variable { max: 25 }, start);
+
+        let at = start + Duration::from_millis(123);
+        assert_eq!(pacer.captured(at), Duration::from_millis(123));
+        assert_eq!(pacer.next_capture(), at + INTERVAL);
+    }
+
+    #[ltest]
+    fn resume_continues_timestamps() {
+        let start = Instant::now();
+        let now = start + INTERVAL * 10 + Duration::from_millis(1);
+        let mut pacer = CapturePacer::resume(FrameRate::Constant(25), start, now);
+
+        assert_eq!(pacer.next_capture(), start + INTERVAL * 11);
+        assert_eq!(pacer.captured(start + INTERVAL * 11), INTERVAL * 11);
+    }
+}
@@ -0,0 +1,76 @@
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+
+use crate::auth::tls::DisplayVerifier;
+use crate::auth::{
+    new_pairing_nonce, pairing_code, pairing_commitment, Fingerprint, Identity, PAIRING_NONCE_LEN,
+};
+use crate::control::{open_channel, ChannelAuth};
+use crate::prelude::*;
+use crate::proto::{ConfirmPairingRequest, FinishPairingRequest, StartPairingRequest};
+
+/// Pair with the display at `host`, returning its fingerprint to pass to
+/// [`super::ControlClient::connect`].
+///
+/// Both sides show a code. `confirm_code` should show the user ours and ask whether the display
+/// shows the same one, and they confirm on the display as well. `name` is how the display will
+/// list us.
+pub async fn pair<F, Fut>(
+    host: &str,
+    port: u16,
+    identity: &Identity,
+    name: &str,
+    confirm_code: F,
+) -> anyhow::Result<Fingerprint>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = io::Result<bool>>,
+{
+    let verifier = Arc::new(DisplayVerifier::for_pairing());
+    let auth = ChannelAuth::Tls(identity, verifier.clone());
+    let mut client = open_channel(host, port, auth).await?;
+    let display = verifier
+        .seen()
+        .ok_or_else(|| anyhow!("Display didn't present a certificate"))?;
+    info!(%display, "Connected to display, starting pairing");
+
+    let commitment = client
+        .start_pairing(StartPairingRequest {
+            name: name.to_string(),
+        })
+        .await?
+        .into_inner()
+        .commitment;
+    let nonce = new_pairing_nonce();
+    let display_nonce = client
+        .finish_pairing(FinishPairingRequest {
+            nonce: nonce.clone(),
+        })
+        .await?
+        .into_inner()
+        .nonce;
+    let kept_commitment = display_nonce.len() == PAIRING_NONCE_LEN
+        && pairing_commitment(&display_nonce) == commitment;
+    if !kept_commitment {
+        return Err(anyhow!("Display changed its nonce after committing to it"));
+    }
+
+    let code = pairing_code(&identity.fingerprint(), &display, &nonce, &display_nonce);
+    let accepted = confirm_code(code)
+        .await
+        .context("Failed to ask whether the codes match")?;
+    // Sent either way, so the display stops showing its code
+    let confirmed = client
+        .confirm_pairing(ConfirmPairingRequest { accepted })
+        .await;
+    if !accepted {
+        return Err(anyhow!("The codes didn't match, not pairing"));
+    }
+    confirmed.context("Display rejected pairing")?;
+
+    info!(%display, "Paired with display");
+    Ok(display)
+}
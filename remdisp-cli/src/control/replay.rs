@@ -0,0 +1,98 @@
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tokio::time::sleep_until;
+
+use crate::av::demuxer::Demuxer;
+use crate::av::frame_rate::FrameRate;
+use crate::av::packet::PacketSink;
+use crate::av::AvError;
+use crate::control::{AttachedError, ControlClient};
+use crate::prelude::*;
+use crate::proto::Heartbeat;
+
+/// Stream a recorded file to the display as if it were being captured live.
+///
+/// Packets are sent at the pace they were originally encoded at. `fallback_frame_rate` is used
+/// for files without timestamps, such as raw `.h264` streams.
+#[instrument(err, skip(client, stop))]
+pub async fn replay(
+    client: &mut ControlClient,
+    path: &Path,
+    fallback_frame_rate: FrameRate,
+    stop: impl Future<Output = ()>,
+) -> Result<(), ReplayError> {
+    // Open first so we don't attach to the display if the file is bad
+    let mut demuxer = Demuxer::open(path, fallback_frame_rate)?;
+
+    let attached = client.attach_stream().await?;
+    if (demuxer.width(), demuxer.height())
+        != (
+            attached.display.width_pixels,
+            attached.display.height_pixels,
+        )
+    {
+        warn!(
+            file_width = demuxer.width(),
+            file_height = demuxer.height(),
+            display_width = attached.display.width_pixels,
+            display_height = attached.display.height_pixels,
+            "Recording size doesn't match display"
+        );
+    }
+
+    // We don't need anything from the display, but it needs to know we're still here
+    let heartbeat_interval = client.heartbeat().interval;
+    let events_tx = &attached.events_tx;
+    let heartbeats = async move {
+        let mut interval = tokio::time::interval(heartbeat_interval);
+        loop {
+            interval.tick().await;
+            if events_tx.send(Heartbeat {}.into()).await.is_err() {
+                return;
+            }
+        }
+    };
+
+    let mut sink = attached.video;
+    let result = tokio::select! {
+        result = send_paced(&mut demuxer, sink.as_mut()) => result,
+        _ = stop => {
+            info!("Stopping replay");
+            Ok(())
+        }
+        _ = heartbeats => {
+            info!("Display detached");
+            Ok(())
+        }
+    };
+    sink.finish().await?;
+
+    result
+}
+
+async fn send_paced(demuxer: &mut Demuxer, sink: &mut dyn PacketSink) -> Result<(), ReplayError> {
+    let start = Instant::now();
+    let mut count = 0;
+
+    while let Some(packet) = demuxer.read_packet()? {
+        // Packets come in decode order, so pace by when they would have been decoded
+        let due = start + Duration::from_micros(packet.dts_micros().max(0) as u64);
+        sleep_until(due.into()).await;
+
+        sink.write_packet(&packet).await?;
+        count += 1;
+    }
+
+    info!(count, "Finished replaying");
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("Error reading recording")]
+    Av(#[from] AvError),
+    #[error("Error attaching to display")]
+    Attach(#[from] AttachedError),
+}
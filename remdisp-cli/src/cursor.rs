@@ -0,0 +1,202 @@
+use std::convert::TryFrom;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::prelude::*;
+use crate::proto::control_event::{self, CursorMove};
+use crate::proto::ControlEvent;
+
+/// Cursors larger than this are rejected, as no real cursor is this large.
+pub const MAX_CURSOR_SIZE: u32 = 512;
+
+/// The image of the control's cursor.
+#[derive(Clone, PartialEq, Eq, Derivative)]
+#[derivative(Debug)]
+pub struct CursorShape {
+    pub visible: bool,
+    pub width: u32,
+    pub height: u32,
+    pub hot_x: i32,
+    pub hot_y: i32,
+    /// ARGB8888 in native byte order, rows packed without padding
+    #[derivative(Debug = "ignore")]
+    pub argb: Bytes,
+}
+
+impl CursorShape {
+    pub fn hidden() -> Self {
+        Self {
+            visible: false,
+            width: 0,
+            height: 0,
+            hot_x: 0,
+            hot_y: 0,
+            argb: Bytes::new(),
+        }
+    }
+
+    /// Copy an image whose rows are `stride` bytes apart, dropping the padding.
+    pub fn from_strided(
+        width: u32,
+        height: u32,
+        hot_x: i32,
+        hot_y: i32,
+        stride: usize,
+        data: &[u8],
+    ) -> Result<Self, InvalidCursorUpdate> {
+        let row_len = width as usize * 4;
+        if stride < row_len || data.len() < stride * height as usize {
+            return Err(InvalidCursorUpdate::ImageSize { width, height });
+        }
+
+        let mut argb = BytesMut::with_capacity(row_len * height as usize);
+        for row in data.chunks(stride).take(height as usize) {
+            argb.extend_from_slice(&row[..row_len]);
+        }
+
+        let shape = Self {
+            visible: true,
+            width,
+            height,
+            hot_x,
+            hot_y,
+            argb: argb.freeze(),
+        };
+        shape.validate()?;
+        Ok(shape)
+    }
+
+    fn validate(&self) -> Result<(), InvalidCursorUpdate> {
+        if !self.visible {
+            return Ok(());
+        }
+        let size_err = InvalidCursorUpdate::ImageSize {
+            width: self.width,
+            height: self.height,
+        };
+        if self.width > MAX_CURSOR_SIZE || self.height > MAX_CURSOR_SIZE {
+            return Err(size_err);
+        }
+        if self.argb.len() != self.width as usize * self.height as usize * 4 {
+            return Err(size_err);
+        }
+        Ok(())
+    }
+}
+
+/// A change to the control's cursor, sent separately from video so it can be drawn at the
+/// display's refresh rate rather than the capture frame rate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CursorUpdate {
+    Shape(CursorShape),
+    /// Position of the top left of the cursor image, in stream pixels. May be negative when
+    /// the hotspot is near the top or left edge.
+    Move { x: i32, y: i32 },
+}
+
+impl From<CursorUpdate> for ControlEvent {
+    fn from(update: CursorUpdate) -> Self {
+        let event = match update {
+            CursorUpdate::Shape(shape) => {
+                control_event::ControlEvent::CursorShape(control_event::CursorShape {
+                    visible: shape.visible,
+                    width: shape.width,
+                    height: shape.height,
+                    hot_x: shape.hot_x,
+                    hot_y: shape.hot_y,
+                    argb: shape.argb.to_vec(),
+                })
+            }
+            CursorUpdate::Move { x, y } => {
+                control_event::ControlEvent::CursorMove(CursorMove { x, y })
+            }
+        };
+        ControlEvent {
+            control_event: Some(event),
+        }
+    }
+}
+
+impl TryFrom<control_event::ControlEvent> for CursorUpdate {
+    type Error = InvalidCursorUpdate;
+
+    fn try_from(event: control_event::ControlEvent) -> Result<Self, Self::Error> {
+        match event {
+            control_event::ControlEvent::CursorShape(shape) => {
+                let shape = CursorShape {
+                    visible: shape.visible,
+                    width: shape.width,
+                    height: shape.height,
+                    hot_x: shape.hot_x,
+                    hot_y: shape.hot_y,
+                    argb: shape.argb.into(),
+                };
+                shape.validate()?;
+                Ok(CursorUpdate::Shape(shape))
+            }
+            control_event::ControlEvent::CursorMove(CursorMove { x, y }) => {
+                Ok(CursorUpdate::Move { x, y })
+            }
+            _ => Err(InvalidCursorUpdate::NotCursor),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidCursorUpdate {
+    #[error("Cursor image doesn't match its size of {width}x{height}")]
+    ImageSize { width: u32, height: u32 },
+    #[error("Event isn't a cursor update")]
+    NotCursor,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ltest]
+    fn strips_stride_padding() {
+        #[rustfmt::skip]
+        let data = [
+            1, 1, 1, 1,  2, 2, 2, 2,  0, 0,
+            3, 3, 3, 3,  4, 4, 4, 4,  0, 0,
+        ];
+        let shape = CursorShape::from_strided(2, 2, 1, 0, 10, &data).unwrap();
+        assert_eq!(
+            &shape.argb[..],
+            &[1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4]
+        );
+    }
+
+    #[ltest]
+    fn rejects_short_image() {
+        assert!(CursorShape::from_strided(2, 2, 0, 0, 8, &[0; 12]).is_err());
+    }
+
+    #[ltest]
+    fn round_trips_through_proto() {
+        let updates = vec![
+            CursorUpdate::Shape(CursorShape::from_strided(1, 1, 0, 0, 4, &[9; 4]).unwrap()),
+            CursorUpdate::Shape(CursorShape::hidden()),
+            CursorUpdate::Move { x: -3, y: 100 },
+        ];
+
+        for update in updates {
+            let event = ControlEvent::from(update.clone()).control_event.unwrap();
+            assert_eq!(CursorUpdate::try_from(event).unwrap(), update);
+        }
+    }
+
+    #[ltest]
+    fn rejects_mismatched_proto_image() {
+        let event = control_event::ControlEvent::CursorShape(control_event::CursorShape {
+            visible: true,
+            width: 4,
+            height: 4,
+            hot_x: 0,
+            hot_y: 0,
+            argb: vec![0; 4],
+        });
+        assert!(CursorUpdate::try_from(event).is_err());
+    }
+}
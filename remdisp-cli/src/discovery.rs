@@ -0,0 +1,283 @@
+//! Finding displays on the LAN.
+//!
+//! Displays periodically send a [`Beacon`] over UDP to the broadcast address, on the same port
+//! number they serve on. Controls listen on that port to see which displays are around.
+
+use std::collections::HashMap;
+use std::fmt::{self, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use prost::Message;
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout_at, Instant};
+
+use crate::prelude::*;
+use crate::proto::Beacon;
+
+/// Time between beacons
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(2);
+/// How long to listen for beacons to be fairly sure every display has sent one
+pub const DEFAULT_DISCOVER_WAIT: Duration = Duration::from_secs(3);
+/// Prefixes beacons so we can ignore anything else sent to the port
+const BEACON_MAGIC: &[u8] = b"remdisp-beacon\0";
+const MAX_BEACON_LEN: usize = 4096;
+
+/// Where a display on `port` sends beacons. Loopback is included for controls on the same
+/// machine when there's no network to broadcast on. Controls can hear both, see
+/// [`DiscoveredDisplay::identity`].
+pub fn beacon_targets(port: u16) -> Vec<SocketAddr> {
+    vec![
+        (Ipv4Addr::BROADCAST, port).into(),
+        (Ipv4Addr::LOCALHOST, port).into(),
+    ]
+}
+
+/// Send `beacon` to `targets` every [`BEACON_INTERVAL`] until dropped.
+#[instrument(skip(beacon))]
+pub async fn announce(beacon: Beacon, targets: Vec<SocketAddr>) -> io::Result<!> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let packet = encode_beacon(&beacon);
+    loop {
+        for target in &targets {
+            // Normal for some targets to be unreachable, such as broadcast without a network
+            if let Err(err) = socket.send_to(&packet, target).await {
+                trace!(%target, ?err, "Failed to send beacon");
+            }
+        }
+        sleep(BEACON_INTERVAL).await;
+    }
+}
+
+fn encode_beacon(beacon: &Beacon) -> Vec<u8> {
+    let mut packet = BEACON_MAGIC.to_vec();
+    beacon
+        .encode(&mut packet)
+        .expect("Vec grows to fit the beacon");
+    packet
+}
+
+fn decode_beacon(packet: &[u8]) -> Option<Beacon> {
+    if !packet.starts_with(BEACON_MAGIC) {
+        return None;
+    }
+    Beacon::decode(&packet[BEACON_MAGIC.len()..]).ok()
+}
+
+/// A display that sent us a beacon.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredDisplay {
+    pub ip: IpAddr,
+    pub beacon: Beacon,
+}
+
+impl DiscoveredDisplay {
+    /// Where to connect to the display.
+    pub fn addr(&self) -> SocketAddr {
+        (self.ip, self.beacon.port as u16).into()
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.beacon.version == VERSION
+    }
+
+    /// Tells displays apart regardless of which address we heard them on. The fingerprint if
+    /// they sent one, otherwise the name and port.
+    pub fn identity(&self) -> (String, u32) {
+        if self.beacon.fingerprint.is_empty() {
+            (self.beacon.name.clone(), self.beacon.port)
+        } else {
+            (self.beacon.fingerprint.clone(), 0)
+        }
+    }
+}
+
+impl fmt::Display for DiscoveredDisplay {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {} (version {}",
+            self.beacon.name,
+            self.addr(),
+            self.beacon.version
+        )?;
+        if !self.is_compatible() {
+            write!(f, ", incompatible")?;
+        }
+        write!(f, ")")?;
+        for output in &self.beacon.outputs {
+            write!(
+                f,
+                "\n  {}: {} {}x{}",
+                output.id, output.name, output.width_pixels, output.height_pixels
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Listens for beacons.
+#[derive(Debug)]
+pub struct Discoverer {
+    socket: UdpSocket,
+}
+
+impl Discoverer {
+    /// Listen on `port` on every interface.
+    pub async fn new(port: u16) -> io::Result<Self> {
+        Self::bind((Ipv4Addr::UNSPECIFIED, port).into()).await
+    }
+
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Self { socket })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Every display heard from within `wait`, ordered by address.
+    ///
+    /// Each display is listed once, by its LAN address if we heard it on both that and
+    /// loopback.
+    pub async fn collect(&self, wait: Duration) -> io::Result<Vec<DiscoveredDisplay>> {
+        let deadline = Instant::now() + wait;
+        let mut displays = HashMap::new();
+        let mut buf = vec![0; MAX_BEACON_LEN];
+        while let Ok(received) = timeout_at(deadline, self.socket.recv_from(&mut buf)).await {
+            let (len, from) = received?;
+            match decode_beacon(&buf[..len]) {
+                Some(beacon) => {
+                    let display = DiscoveredDisplay {
+                        ip: from.ip(),
+                        beacon,
+                    };
+                    add_display(&mut displays, display);
+                }
+                None => debug!(%from, "Ignoring packet that isn't a beacon"),
+            }
+        }
+
+        let mut displays: Vec<_> = displays.into_iter().map(|(_, display)| display).collect();
+        displays.sort_by_key(DiscoveredDisplay::addr);
+        Ok(displays)
+    }
+}
+
+/// Keep the first address we heard a display on, unless that was loopback.
+fn add_display(
+    displays: &mut HashMap<(String, u32), DiscoveredDisplay>,
+    display: DiscoveredDisplay,
+) {
+    let known = displays.get(&display.identity());
+    if known.map_or(true, |known| known.ip.is_loopback()) {
+        displays.insert(display.identity(), display);
+    }
+}
+
+/// Displays sending beacons to `port` within `wait`.
+pub async fn discover(port: u16, wait: Duration) -> io::Result<Vec<DiscoveredDisplay>> {
+    Discoverer::new(port).await?.collect(wait).await
+}
+
+/// This machine's hostname, for peers to list us by.
+#[cfg(unix)]
+pub fn local_name() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: The buffer is valid for its length. The name may be truncated without a nul.
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        warn!(err = ?io::Error::last_os_error(), "Failed to get hostname");
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&byte| byte == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// This machine's name, for peers to list us by. Windows keeps it in the environment.
+#[cfg(not(unix))]
+pub fn local_name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|err| {
+        warn!(?err, "Failed to get computer name");
+        "unknown".to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::hello_reply;
+
+    fn sample_beacon() -> Beacon {
+        Beacon {
+            name: "living-room".to_string(),
+            version: VERSION.to_string(),
+            port: 48611,
+            outputs: vec![hello_reply::Output {
+                id: 0,
+                name: "HDMI-1".to_string(),
+                width_pixels: 1920,
+                height_pixels: 1080,
+            }],
+            fingerprint: String::new(),
+        }
+    }
+
+    #[ltest]
+    fn ignores_other_packets() {
+        let beacon = sample_beacon();
+        assert_eq!(decode_beacon(&encode_beacon(&beacon)), Some(beacon));
+        assert_eq!(decode_beacon(b"hello"), None);
+    }
+
+    #[ltest(atest)]
+    async fn discovers_on_loopback() {
+        let discoverer = Discoverer::bind((Ipv4Addr::LOCALHOST, 0).into())
+            .await
+            .unwrap();
+        let target = discoverer.local_addr().unwrap();
+        let announcer = tokio::spawn(announce(sample_beacon(), vec![target]));
+
+        let displays = discoverer.collect(Duration::from_millis(500)).await.unwrap();
+        announcer.abort();
+
+        assert_eq!(
+            displays,
+            vec![DiscoveredDisplay {
+                ip: Ipv4Addr::LOCALHOST.into(),
+                beacon: sample_beacon(),
+            }]
+        );
+        assert_eq!(displays[0].addr(), "127.0.0.1:48611".parse().unwrap());
+        assert!(displays[0].is_compatible());
+    }
+
+    #[ltest]
+    fn lists_display_heard_twice_once() {
+        let heard = |ip: [u8; 4], name: &str| DiscoveredDisplay {
+            ip: Ipv4Addr::from(ip).into(),
+            beacon: Beacon {
+                name: name.to_string(),
+                ..sample_beacon()
+            },
+        };
+        let mut displays = HashMap::new();
+        add_display(&mut displays, heard([127, 0, 0, 1], "living-room"));
+        add_display(&mut displays, heard([192, 168, 1, 5], "living-room"));
+        add_display(&mut displays, heard([127, 0, 0, 1], "living-room"));
+        add_display(&mut displays, heard([192, 168, 1, 6], "kitchen"));
+
+        let mut displays: Vec<_> = displays.into_iter().map(|(_, display)| display).collect();
+        displays.sort_by_key(DiscoveredDisplay::addr);
+        assert_eq!(
+            displays,
+            vec![
+                heard([192, 168, 1, 5], "living-room"),
+                heard([192, 168, 1, 6], "kitchen"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,393 @@
+//! Carrying out the control's [`Adjustments`] to the monitor a window is on.
+//!
+//! We ask the monitor itself first, as that's what the user would do with its buttons. Where we
+//! can't, the window fakes it: brightness and contrast with a gamma ramp, and sleep by blanking.
+
+use std::error::Error;
+use std::fmt::Debug;
+
+use cfg_if::cfg_if;
+
+use crate::adjust::{Adjustments, PowerMode, MAX_PERCENT};
+use crate::display::info::PlatformWindowHandle;
+use crate::prelude::*;
+
+/// Controls a monitor in hardware.
+pub trait MonitorBackend: Debug {
+    fn set_brightness(&mut self, percent: u32) -> Result<(), MonitorError>;
+
+    fn set_contrast(&mut self, percent: u32) -> Result<(), MonitorError>;
+
+    /// Only asked for [`PowerMode::On`] and [`PowerMode::Sleep`]. Blanking is always up to the
+    /// window.
+    fn set_power(&mut self, power: PowerMode) -> Result<(), MonitorError>;
+}
+
+/// Find the backend for the monitor the window is on, if the platform has one.
+///
+/// Only Windows has one, through DDC/CI. The xrandr bindings can't set gamma or DPMS, so on X11
+/// everything is done by the window.
+pub fn monitor_backend(handle: PlatformWindowHandle) -> Option<Box<dyn MonitorBackend>> {
+    cfg_if! {
+        if #[cfg(target_os = "windows")] {
+            match ddc::DdcMonitor::for_window(handle) {
+                Ok(monitor) => Some(Box::new(monitor)),
+                Err(err) => {
+                    debug!(?err, "Can't control monitor, falling back to software");
+                    None
+                }
+            }
+        } else {
+            let _ = handle;
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod ddc {
+    use std::collections::HashMap;
+
+    use monitor_control_win::Monitor;
+
+    use super::*;
+
+    /// VESA Monitor Control Command Set codes
+    const VCP_BRIGHTNESS: u8 = 0x10;
+    const VCP_CONTRAST: u8 = 0x12;
+    const VCP_POWER_MODE: u8 = 0xD6;
+    const POWER_ON: u32 = 0x01;
+    const POWER_STANDBY: u32 = 0x02;
+
+    #[derive(Derivative)]
+    #[derivative(Debug)]
+    pub struct DdcMonitor {
+        #[derivative(Debug = "ignore")]
+        monitor: Monitor,
+        /// Each control's maximum, which monitors choose themselves, by VCP code
+        maxima: HashMap<u8, u32>,
+    }
+
+    impl DdcMonitor {
+        pub fn for_window(handle: PlatformWindowHandle) -> Result<Self, MonitorError> {
+            let hwnd = handle.expect_windows().0.cast();
+            let monitor = Monitor::intersecting(hwnd)
+                .map_err(MonitorError::platform)?
+                .into_iter()
+                .next()
+                .ok_or(MonitorError::Unsupported)?;
+            Ok(Self {
+                monitor,
+                maxima: HashMap::new(),
+            })
+        }
+
+        fn set(&mut self, code: u8, value: u32) -> Result<(), MonitorError> {
+            self.monitor
+                .set_vcp_feature(code, value)
+                .map_err(MonitorError::platform)
+        }
+
+        /// Set a continuous control to `percent` of its maximum.
+        fn set_percent(&mut self, code: u8, percent: u32) -> Result<(), MonitorError> {
+            let max = match self.maxima.get(&code) {
+                Some(&max) => max,
+                None => {
+                    let max = self
+                        .monitor
+                        .get_vcp_feature(code)
+                        .map_err(MonitorError::platform)?
+                        .maximum();
+                    self.maxima.insert(code, max);
+                    max
+                }
+            };
+            self.set(code, scale_to_vcp(percent, max))
+        }
+    }
+
+    /// `percent` of `max`, rounded to the nearest step the monitor has.
+    pub fn scale_to_vcp(percent: u32, max: u32) -> u32 {
+        let percent = percent.min(MAX_PERCENT) as u64;
+        ((percent * max as u64 + MAX_PERCENT as u64 / 2) / MAX_PERCENT as u64) as u32
+    }
+
+    impl MonitorBackend for DdcMonitor {
+        fn set_brightness(&mut self, percent: u32) -> Result<(), MonitorError> {
+            self.set_percent(VCP_BRIGHTNESS, percent)
+        }
+
+        fn set_contrast(&mut self, percent: u32) -> Result<(), MonitorError> {
+            self.set_percent(VCP_CONTRAST, percent)
+        }
+
+        fn set_power(&mut self, power: PowerMode) -> Result<(), MonitorError> {
+            let mode = match power {
+                PowerMode::Sleep => POWER_STANDBY,
+                _ => POWER_ON,
+            };
+            self.set(VCP_POWER_MODE, mode)
+        }
+    }
+}
+
+/// What the window has to do itself because the monitor couldn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoftwareAdjustments {
+    pub brightness: u32,
+    pub contrast: u32,
+    pub blank: bool,
+}
+
+impl Default for SoftwareAdjustments {
+    fn default() -> Self {
+        Self {
+            brightness: MAX_PERCENT,
+            contrast: MAX_PERCENT,
+            blank: false,
+        }
+    }
+}
+
+/// Applies adjustments with the monitor's backend where possible, keeping track of what's left
+/// for the window.
+#[derive(Debug)]
+pub struct MonitorAdjuster {
+    backend: Option<Box<dyn MonitorBackend>>,
+    software: SoftwareAdjustments,
+}
+
+impl MonitorAdjuster {
+    pub fn new(backend: Option<Box<dyn MonitorBackend>>) -> Self {
+        Self {
+            backend,
+            software: SoftwareAdjustments::default(),
+        }
+    }
+
+    pub fn software(&self) -> SoftwareAdjustments {
+        self.software
+    }
+
+    /// Returns whether the window needs to redraw because [`Self::software`] changed.
+    pub fn apply(&mut self, adjustments: Adjustments) -> bool {
+        let before = self.software;
+
+        if let Some(percent) = adjustments.brightness {
+            self.software.brightness =
+                self.in_hardware(percent, |backend| backend.set_brightness(percent));
+        }
+        if let Some(percent) = adjustments.contrast {
+            self.software.contrast =
+                self.in_hardware(percent, |backend| backend.set_contrast(percent));
+        }
+        match adjustments.power {
+            Some(PowerMode::On) => {
+                // Fine to fail if the monitor never slept
+                if let Some(Err(err)) = self
+                    .backend
+                    .as_mut()
+                    .map(|backend| backend.set_power(PowerMode::On))
+                {
+                    debug!(?err, "Failed to wake monitor");
+                }
+                self.software.blank = false;
+            }
+            Some(PowerMode::Blank) => self.software.blank = true,
+            Some(PowerMode::Sleep) => {
+                if let Some(Err(err)) = self
+                    .backend
+                    .as_mut()
+                    .map(|backend| backend.set_power(PowerMode::Sleep))
+                {
+                    warn!(?err, "Failed to sleep monitor, blanking instead");
+                }
+                // So nothing shows if the monitor ignores us, and it's black when it wakes
+                self.software.blank = true;
+            }
+            None => {}
+        }
+
+        self.software != before
+    }
+
+    /// Returns the percentage left for software.
+    fn in_hardware(
+        &mut self,
+        percent: u32,
+        set: impl FnOnce(&mut dyn MonitorBackend) -> Result<(), MonitorError>,
+    ) -> u32 {
+        let backend = match &mut self.backend {
+            Some(backend) => backend,
+            None => return percent,
+        };
+        match set(backend.as_mut()) {
+            Ok(()) => MAX_PERCENT,
+            Err(err) => {
+                warn!(
+                    ?err,
+                    "Failed to adjust monitor, adjusting the window instead"
+                );
+                percent
+            }
+        }
+    }
+}
+
+/// Gamma ramp for one channel. Contrast squeezes values towards mid grey, then brightness
+/// scales them.
+pub fn gamma_ramp(brightness: u32, contrast: u32) -> [u16; 256] {
+    let brightness = brightness.min(MAX_PERCENT) as f64 / MAX_PERCENT as f64;
+    let contrast = contrast.min(MAX_PERCENT) as f64 / MAX_PERCENT as f64;
+    let mut ramp = [0u16; 256];
+    for (i, entry) in ramp.iter_mut().enumerate() {
+        let value = i as f64 / 255.0;
+        let value = (0.5 + (value - 0.5) * contrast) * brightness;
+        *entry = (value.clamp(0.0, 1.0) * u16::MAX as f64).round() as u16;
+    }
+    ramp
+}
+
+#[derive(Debug, Error)]
+pub enum MonitorError {
+    #[error("The monitor can't be controlled")]
+    Unsupported,
+    #[error("Error controlling monitor")]
+    Platform(#[source] Box<dyn Error + Send + Sync + 'static>),
+}
+
+impl MonitorError {
+    #[cfg(target_os = "windows")]
+    fn platform(err: impl Error + Send + Sync + 'static) -> Self {
+        Self::Platform(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Call {
+        Brightness(u32),
+        Contrast(u32),
+        Power(PowerMode),
+    }
+
+    /// Records what it's asked to do, failing if it doesn't support it.
+    #[derive(Debug, Default)]
+    struct MockMonitor {
+        calls: Rc<RefCell<Vec<Call>>>,
+        picture_unsupported: bool,
+        power_unsupported: bool,
+    }
+
+    impl MockMonitor {
+        fn call(&mut self, call: Call, unsupported: bool) -> Result<(), MonitorError> {
+            self.calls.borrow_mut().push(call);
+            if unsupported {
+                Err(MonitorError::Unsupported)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl MonitorBackend for MockMonitor {
+        fn set_brightness(&mut self, percent: u32) -> Result<(), MonitorError> {
+            self.call(Call::Brightness(percent), self.picture_unsupported)
+        }
+
+        fn set_contrast(&mut self, percent: u32) -> Result<(), MonitorError> {
+            self.call(Call::Contrast(percent), self.picture_unsupported)
+        }
+
+        fn set_power(&mut self, power: PowerMode) -> Result<(), MonitorError> {
+            self.call(Call::Power(power), self.power_unsupported)
+        }
+    }
+
+    fn adjust(command: &str) -> Adjustments {
+        command.parse().unwrap()
+    }
+
+    #[ltest]
+    fn prefers_hardware() {
+        let monitor = MockMonitor::default();
+        let calls = monitor.calls.clone();
+        let mut adjuster = MonitorAdjuster::new(Some(Box::new(monitor)));
+
+        assert!(!adjuster.apply(adjust("brightness 30")));
+        assert!(!adjuster.apply(adjust("contrast 70")));
+        // Blanking the window makes sure nothing shows whatever the monitor does
+        assert!(adjuster.apply(adjust("sleep")));
+        assert!(adjuster.apply(adjust("on")));
+
+        assert_eq!(adjuster.software(), SoftwareAdjustments::default());
+        assert_eq!(
+            *calls.borrow(),
+            vec![
+                Call::Brightness(30),
+                Call::Contrast(70),
+                Call::Power(PowerMode::Sleep),
+                Call::Power(PowerMode::On),
+            ]
+        );
+    }
+
+    #[ltest]
+    fn falls_back_to_software() {
+        let monitor = MockMonitor {
+            picture_unsupported: true,
+            power_unsupported: true,
+            ..Default::default()
+        };
+        let mut adjuster = MonitorAdjuster::new(Some(Box::new(monitor)));
+        assert!(adjuster.apply(adjust("brightness 30")));
+        assert!(adjuster.apply(adjust("sleep")));
+        assert_eq!(
+            adjuster.software(),
+            SoftwareAdjustments {
+                brightness: 30,
+                contrast: MAX_PERCENT,
+                blank: true,
+            }
+        );
+
+        let mut adjuster = MonitorAdjuster::new(None);
+        assert!(adjuster.apply(adjust("contrast 50")));
+        assert!(adjuster.apply(adjust("blank")));
+        assert!(!adjuster.apply(adjust("blank")));
+        assert!(adjuster.apply(adjust("on")));
+        assert_eq!(adjuster.software().contrast, 50);
+        assert!(!adjuster.software().blank);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[ltest]
+    fn scales_to_vcp_maximum() {
+        use ddc::scale_to_vcp;
+        assert_eq!(scale_to_vcp(30, 100), 30);
+        assert_eq!(scale_to_vcp(50, 255), 128);
+        assert_eq!(scale_to_vcp(MAX_PERCENT, 80), 80);
+        assert_eq!(scale_to_vcp(MAX_PERCENT + 1, 80), 80);
+        assert_eq!(scale_to_vcp(0, 80), 0);
+    }
+
+    #[ltest]
+    fn builds_gamma_ramps() {
+        let identity = gamma_ramp(MAX_PERCENT, MAX_PERCENT);
+        assert_eq!(identity[0], 0);
+        assert_eq!(identity[255], u16::MAX);
+        assert!(identity.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let dim = gamma_ramp(50, MAX_PERCENT);
+        assert_eq!(dim[255], u16::MAX / 2 + 1);
+
+        let flat = gamma_ramp(MAX_PERCENT, 0);
+        assert!(flat.iter().all(|&entry| entry == flat[0]));
+    }
+}
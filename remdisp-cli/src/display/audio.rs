@@ -0,0 +1,209 @@
+//! Playing the control's audio in sync with its video.
+//!
+//! Audio packets are due at the same local time as video frames with the same timestamp, see
+//! [`PresentationScheduler::due_at`](crate::display::scheduler::PresentationScheduler::due_at).
+//! When what's already queued would make a packet play too early or late we pad with silence or
+//! skip samples, which also absorbs drift between the control's clock and our sound card's.
+
+use std::fmt::Debug;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+use crate::av::audio::{duration_of, samples_in, CHANNELS, FRAME_SAMPLES, SAMPLE_RATE};
+use crate::display::window::{SdlContext, WindowError};
+use crate::prelude::*;
+
+/// Audio this far out of sync is brought back in line. Anything less is left alone, so network
+/// jitter doesn't make playback stutter.
+pub const SYNC_TOLERANCE: Duration = Duration::from_millis(40);
+/// Never pad more than this at once, in case timestamps jump
+const MAX_PADDING: Duration = Duration::from_secs(1);
+
+/// Somewhere to play interleaved samples at [`SAMPLE_RATE`].
+pub trait AudioOutput: Debug {
+    fn queue(&mut self, samples: &[i16]) -> Result<(), WindowError>;
+
+    /// How long until everything queued so far has played.
+    fn queued(&self) -> Duration;
+}
+
+/// The default audio device, through SDL.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct SdlAudioOutput {
+    #[derivative(Debug = "ignore")]
+    queue: AudioQueue<i16>,
+    /// Last so the device is closed before SDL is shut down
+    #[derivative(Debug = "ignore")]
+    _sdl: Rc<SdlContext>,
+}
+
+impl SdlAudioOutput {
+    /// Must be called from the thread windows are created on.
+    pub fn open() -> Result<Self, WindowError> {
+        let sdl = SdlContext::get()?;
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(CHANNELS as u8),
+            samples: Some(FRAME_SAMPLES as u16),
+        };
+        let queue = sdl.audio()?.open_queue(None, &spec)?;
+        queue.resume();
+        debug!(spec = ?queue.spec(), "Opened audio device");
+        Ok(Self { queue, _sdl: sdl })
+    }
+}
+
+impl AudioOutput for SdlAudioOutput {
+    fn queue(&mut self, samples: &[i16]) -> Result<(), WindowError> {
+        if self.queue.queue(samples) {
+            Ok(())
+        } else {
+            Err(WindowError::Sdl(sdl2::get_error()))
+        }
+    }
+
+    fn queued(&self) -> Duration {
+        duration_of(self.queue.size() as usize / std::mem::size_of::<i16>())
+    }
+}
+
+/// Queues decoded audio so it plays when it's due.
+#[derive(Debug)]
+pub struct AudioPlayer<O> {
+    output: O,
+    stats: AudioStats,
+}
+
+/// Counts of interleaved samples.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AudioStats {
+    pub played: u64,
+    /// Silence added because audio arrived ahead of when it was due
+    pub padded: u64,
+    /// Audio dropped because it arrived too late
+    pub skipped: u64,
+}
+
+impl<O: AudioOutput> AudioPlayer<O> {
+    pub fn new(output: O) -> Self {
+        Self {
+            output,
+            stats: AudioStats::default(),
+        }
+    }
+
+    /// Queue `samples` to start playing at `due`.
+    pub fn play(&mut self, samples: &[i16], due: Instant, now: Instant) -> Result<(), WindowError> {
+        let starts = now + self.output.queued();
+        let mut samples = samples;
+
+        if due > starts + SYNC_TOLERANCE {
+            let padding = vec![0; samples_in((due - starts).min(MAX_PADDING))];
+            trace!(len = padding.len(), "Padding audio");
+            self.output.queue(&padding)?;
+            self.stats.padded += padding.len() as u64;
+        } else if starts > due + SYNC_TOLERANCE {
+            let skip = samples_in(starts - due).min(samples.len());
+            trace!(len = skip, "Skipping late audio");
+            samples = &samples[skip..];
+            self.stats.skipped += skip as u64;
+        }
+
+        if !samples.is_empty() {
+            self.output.queue(samples)?;
+            self.stats.played += samples.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> AudioStats {
+        self.stats
+    }
+}
+
+impl AudioStats {
+    pub fn log(&self) {
+        info!(
+            played = self.played,
+            padded = self.padded,
+            skipped = self.skipped,
+            "Audio stats"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays nothing, so whatever is queued stays queued.
+    #[derive(Debug, Default)]
+    struct MockOutput {
+        samples: Vec<i16>,
+    }
+
+    impl AudioOutput for MockOutput {
+        fn queue(&mut self, samples: &[i16]) -> Result<(), WindowError> {
+            self.samples.extend_from_slice(samples);
+            Ok(())
+        }
+
+        fn queued(&self) -> Duration {
+            duration_of(self.samples.len())
+        }
+    }
+
+    fn packet(duration: Duration) -> Vec<i16> {
+        vec![1; samples_in(duration)]
+    }
+
+    #[ltest]
+    fn plays_audio_in_sync() {
+        let mut player = AudioPlayer::new(MockOutput::default());
+        let now = Instant::now();
+        let len = Duration::from_millis(20);
+
+        player.play(&packet(len), now, now).unwrap();
+        // Within the tolerance either way
+        player.play(&packet(len), now + len * 2, now).unwrap();
+        player.play(&packet(len), now, now).unwrap();
+
+        assert_eq!(player.output.samples, packet(len * 3));
+        assert_eq!(player.stats().played, samples_in(len * 3) as u64);
+    }
+
+    #[ltest]
+    fn pads_early_audio() {
+        let mut player = AudioPlayer::new(MockOutput::default());
+        let now = Instant::now();
+        let early = Duration::from_millis(100);
+
+        player
+            .play(&packet(Duration::from_millis(20)), now + early, now)
+            .unwrap();
+
+        let samples = &player.output.samples;
+        assert!(samples[..samples_in(early)]
+            .iter()
+            .all(|&sample| sample == 0));
+        assert_eq!(samples.len(), samples_in(early + Duration::from_millis(20)));
+        assert_eq!(player.stats().padded, samples_in(early) as u64);
+    }
+
+    #[ltest]
+    fn skips_late_audio() {
+        let mut player = AudioPlayer::new(MockOutput::default());
+        let now = Instant::now();
+        let queued = Duration::from_millis(100);
+        player.play(&packet(queued), now, now).unwrap();
+
+        // Due now, but there's already 100ms to play before it
+        player.play(&packet(queued * 2), now, now).unwrap();
+
+        assert_eq!(player.output.samples.len(), samples_in(queued * 2));
+        assert_eq!(player.stats().skipped, samples_in(queued) as u64);
+    }
+}
@@ -1,61 +1,400 @@
-use crate::av::AvError;
-use crate::av::{self, decoder::Decoder};
-use crate::display::window::{SdlWindow, Window, WindowError};
-use crate::prelude::*;
-use crate::proto::{display_event, ControlEvent, DisplayEvent};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::fmt::Debug;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::mpsc as std_mpsc;
+use std::time::{Duration, Instant};
 use std::{io, thread};
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
+
+use futures::future::{join, join_all, FutureExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep_until, timeout};
+use tokio_rustls::TlsAcceptor;
 use tonic::{Status, Streaming};
+use tracing::{Instrument, Level};
 
-#[derive(Debug)]
+use crate::adjust::Adjustments;
+use crate::auth::token::{new_nonce, read_nonce};
+use crate::auth::{tls, Fingerprint, VideoStream};
+use crate::av::audio::decoder::AudioDecoder;
+use crate::av::audio::AudioPacket;
+use crate::av::packet::VideoPacket;
+use crate::av::udp;
+use crate::av::yuv_frame::{OwnedYuvFrame, YuvFrame};
+use crate::av::AvError;
+use crate::av::{self, decoder::Decoder};
+use crate::cursor::CursorUpdate;
+use crate::display::audio::{AudioPlayer, SdlAudioOutput};
+use crate::display::info::DisplayInfo;
+use crate::display::pairing::{run_pairing_window, PairingPrompt};
+use crate::display::scheduler::PresentationScheduler;
+use crate::display::window::{
+    list_monitors, MonitorInfo, MonitorSelector, SdlWindow, Window, WindowError,
+};
+use crate::display::DisplayOptions;
+use crate::heartbeat::{HeartbeatOptions, Liveness};
+use crate::net::PortRange;
+use crate::prelude::*;
+use crate::proto::{
+    control_event, display_event, ControlEvent, DisplayEvent, Heartbeat, VideoTransport,
+};
+
+/// Used if the window can't tell us the refresh rate of the display
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_micros(1_000_000 / 60);
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(4);
+/// Packets read ahead of the decoder. Small because the scheduler does the buffering.
+const PACKET_QUEUE: usize = 4;
+/// How long a connection to the video port has to complete its handshake, so one that stalls
+/// can't keep the control out
+const VIDEO_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Derivative)]
+#[derivative(Debug)]
 pub struct EventChans {
     pub tx: mpsc::Sender<Result<DisplayEvent, Status>>,
     pub recv: Streaming<ControlEvent>,
+    /// Who is attaching, for telling other controllers who has the output
+    pub controller: String,
+    /// Take over the output even if it's busy
+    pub force: bool,
+    /// Where the control connected from, if known. The video stream must come from the same
+    /// host.
+    pub control_ip: Option<IpAddr>,
+    /// Token of the session being resumed, empty for a new session
+    pub resume: String,
+    pub video_auth: VideoAuth,
+    pub video_transport: VideoTransport,
+    /// The control wants to send audio too
+    pub audio: bool,
+}
+
+/// How the control proves it's the one that opened the video stream.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub enum VideoAuth {
+    /// A TLS handshake with the certificate of the paired control that attached
+    Tls {
+        peer: Fingerprint,
+        #[derivative(Debug = "ignore")]
+        acceptor: TlsAcceptor,
+    },
+    /// Sending the nonce from Attach first, when authenticating with a shared token
+    Nonce,
+}
+
+impl VideoAuth {
+    fn peer(&self) -> Option<Fingerprint> {
+        match self {
+            Self::Tls { peer, .. } => Some(*peer),
+            Self::Nonce => None,
+        }
+    }
+}
+
+/// A monitor the control can attach to.
+#[derive(Debug)]
+pub struct Output {
+    pub monitor: MonitorInfo,
+    pub attach: mpsc::Sender<EventChans>,
+}
+
+/// What the displayer thread serves.
+#[derive(Debug)]
+pub struct Displayer {
+    pub outputs: Vec<Output>,
+    /// Whether an audio device opened when the thread started
+    pub audio: bool,
+}
+
+/// What to do when a controller attaches to an output that is already in use, without forcing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusyPolicy {
+    /// Fail the attach, naming the current controller
+    Reject,
+    /// Attach once the current controller and any queued before detach
+    Queue,
+}
+
+impl Default for BusyPolicy {
+    fn default() -> Self {
+        Self::Reject
+    }
+}
+
+impl FromStr for BusyPolicy {
+    type Err = UnknownBusyPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "queue" => Ok(Self::Queue),
+            _ => Err(UnknownBusyPolicy(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown busy policy {0}, expected reject or queue")]
+pub struct UnknownBusyPolicy(String);
+
+/// Start the thread that shows windows, with an output for each monitor in the options.
+///
+/// All windows share one thread because SDL can only be used from the thread that initialized
+/// it. That includes the window showing `pairing_prompt` whenever it's set.
+pub fn spawn_displayer(
+    options: DisplayOptions,
+    pairing_prompt: watch::Receiver<Option<PairingPrompt>>,
+) -> Result<Displayer, WindowError> {
+    let runtime = tokio::runtime::Handle::current();
+    let (outputs_tx, outputs_rx) = std_mpsc::channel();
+
+    thread::spawn(move || {
+        let selected = match select_monitors(&options.monitors) {
+            Ok(selected) => selected,
+            Err(err) => {
+                let _ = outputs_tx.send(Err(err));
+                return;
+            }
+        };
+
+        let mut outputs = vec![];
+        let mut loops = vec![];
+        for monitor in selected {
+            let (attach_tx, attach_rx) = mpsc::channel(16);
+            let mut options = options.clone();
+            options.window.monitor = Some(MonitorSelector::Index(monitor.index));
+            let span = span!(Level::INFO, "output", index = monitor.index, name = %monitor.name);
+            let window = SdlWindow::with_options(options.window.clone());
+            loops.push(run_output(attach_rx, window, options).instrument(span));
+            outputs.push(Output {
+                monitor,
+                attach: attach_tx,
+            });
+        }
+        info!(count = outputs.len(), "Serving outputs");
+
+        // Each session opens the device again, this is only so controls know whether to ask
+        let audio = match SdlAudioOutput::open() {
+            Ok(_) => true,
+            Err(err) => {
+                warn!(?err, "Can't play audio, controls won't be able to send any");
+                false
+            }
+        };
+        if outputs_tx.send(Ok(Displayer { outputs, audio })).is_err() {
+            return;
+        }
+
+        runtime.block_on(join(join_all(loops), run_pairing_window(pairing_prompt)));
+    });
+
+    outputs_rx
+        .recv()
+        .expect("Displayer thread exited before listing outputs")
 }
 
-pub fn spawn_displayer(mut chan: mpsc::Receiver<EventChans>) {
-    thread::spawn(|| {
-        tokio::runtime::Handle::current().block_on(async move {
-            let mut event_chans: Option<EventChans> = None;
-            let mut window = SdlWindow::new();
+fn select_monitors(selectors: &[MonitorSelector]) -> Result<Vec<MonitorInfo>, WindowError> {
+    let monitors = list_monitors()?;
+    if selectors.is_empty() {
+        return Ok(monitors);
+    }
+    let mut selected: Vec<MonitorInfo> = vec![];
+    for selector in selectors {
+        let monitor = selector
+            .select(&monitors)
+            .ok_or_else(|| WindowError::NoSuchMonitor(selector.clone()))?;
+        if !selected.contains(monitor) {
+            selected.push(monitor.clone());
+        }
+    }
+    Ok(selected)
+}
+
+/// A session whose control went away without detaching, kept in case it reconnects.
+#[derive(Debug)]
+struct LostSession {
+    token: String,
+    controller: String,
+    /// None if controls aren't paired
+    peer: Option<Fingerprint>,
+    display_info: DisplayInfo,
+    until: Instant,
+}
+
+impl LostSession {
+    /// Only the control that lost the session can resume it.
+    fn is_resumed_by(&self, attach: &EventChans) -> bool {
+        attach.resume == self.token && attach.video_auth.peer() == self.peer
+    }
+}
+
+enum Resumed {
+    Attach(EventChans),
+    TimedOut,
+    ChanClosed,
+}
+
+/// Controls waiting for a busy output, oldest first.
+///
+/// Nothing else talks to them while they wait, so we exchange heartbeats with them here and
+/// drop any that stop answering.
+#[derive(Debug)]
+struct AttachQueue {
+    waiting: VecDeque<(EventChans, Liveness)>,
+    heartbeat: HeartbeatOptions,
+    interval: tokio::time::Interval,
+}
+
+impl AttachQueue {
+    fn new(heartbeat: HeartbeatOptions) -> Self {
+        Self {
+            waiting: VecDeque::new(),
+            heartbeat,
+            interval: tokio::time::interval(heartbeat.interval),
+        }
+    }
+
+    fn push(&mut self, chans: EventChans) {
+        let liveness = Liveness::new(self.heartbeat.timeout, Instant::now());
+        self.waiting.push_back((chans, liveness));
+    }
+
+    fn pop(&mut self) -> Option<EventChans> {
+        self.waiting.pop_front().map(|(chans, _)| chans)
+    }
+
+    /// Wait for the next heartbeat, then send it to the waiting controls and drop any that went
+    /// away.
+    async fn keep_alive(&mut self) {
+        self.interval.tick().await;
+        let now = Instant::now();
+        for (mut chans, mut liveness) in std::mem::take(&mut self.waiting) {
+            if Self::is_alive(&mut chans, &mut liveness, now) {
+                // If the queue is full the control isn't reading, so there's no point waiting
+                let _ = chans.tx.try_send(Ok(Heartbeat {}.into()));
+                self.waiting.push_back((chans, liveness));
+            } else {
+                info!(controller = %chans.controller, "Dropping queued control that went away");
+            }
+        }
+    }
+
+    fn is_alive(chans: &mut EventChans, liveness: &mut Liveness, now: Instant) -> bool {
+        if chans.tx.is_closed() {
+            return false;
+        }
+        // Only heartbeats are expected before attaching, so anything else is dropped too
+        while let Some(event) = chans.recv.message().now_or_never() {
+            match event {
+                Ok(Some(_)) => liveness.seen(now),
+                Ok(None) | Err(_) => return false,
+            }
+        }
+        !liveness.is_dead(now)
+    }
+}
+
+async fn run_output<W: Window>(
+    mut chan: mpsc::Receiver<EventChans>,
+    mut window: W,
+    options: DisplayOptions,
+) {
+    let mut queue = AttachQueue::new(options.heartbeat);
+    let mut forced: Option<EventChans> = None;
+    let mut lost: Option<LostSession> = None;
+
+    loop {
+        let next = if let Some(forced) = forced.take() {
+            Some(forced)
+        } else if let Some(lost_session) = &lost {
+            let policy = options.busy_policy;
+            match await_resume(&mut chan, &mut window, lost_session, policy, &mut queue).await {
+                Resumed::Attach(resumer) => Some(resumer),
+                Resumed::TimedOut => {
+                    info!("Lost control didn't come back");
+                    lost = None;
+                    if let Err(err) = window.close() {
+                        warn!(?err, "Error closing window");
+                    }
+                    continue;
+                }
+                Resumed::ChanClosed => {
+                    info!("Window actor exiting as input chan closed");
+                    return;
+                }
+            }
+        } else {
+            queue.pop()
+        };
+        let mut curr_event_chans = match next {
+            // Queued controllers may have given up waiting
+            Some(next) if next.tx.is_closed() => continue,
+            Some(next) => next,
+            None => match chan.recv().await {
+                Some(new_attached) => new_attached,
+                None => {
+                    info!("Window actor exiting as input chan closed");
+                    return;
+                }
+            },
+        };
+        let controller = curr_event_chans.controller.clone();
+
+        let resumed = lost
+            .take()
+            .filter(|lost| lost.is_resumed_by(&curr_event_chans));
+        let (token, mut display_info) = match resumed {
+            Some(lost) => {
+                info!(%controller, "Window actor resuming lost session");
+                (lost.token, lost.display_info)
+            }
+            None => {
+                info!(%controller, "Window actor attaching");
+                // We may have been holding the window for a lost session
+                if let Err(err) = window.close() {
+                    warn!(?err, "Error closing window");
+                }
+                match window.create() {
+                    Ok(display_info) => (new_session_token(), display_info),
+                    Err(err) => {
+                        let status = ShowWindowError::from(err).into();
+                        curr_event_chans.tx.send_or_log(Err(status)).await;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let exit_status = {
+            let session = show_window(
+                &mut curr_event_chans,
+                &mut window,
+                &mut display_info,
+                &token,
+                options.video_addr,
+                options.video_ports,
+                options.heartbeat,
+            );
+            tokio::pin!(session);
 
             loop {
-                match event_chans {
-                    Some(curr_event_chans) => tokio::select! {
-                        new_attached = chan.recv() => {
-                            match new_attached {
-                                Some(new_attached) => {
-                                    info!("Window actor detached to attach to new");
-                                    event_chans = Some(new_attached)
-                                },
-                                None => {
-                                    info!("Window actor exiting as input chan closed");
-                                    return
-                                },
-                            }
-                        },
-
-                        exit_status = show_window(&curr_event_chans, &mut window) => {
-                            warn!(?exit_status, "show_window exited early");
-
-                            let status = match exit_status {
-                                Ok(_) => Status::ok("Done"),
-                                Err(err) => err,
-                            };
-                            curr_event_chans.tx.send_or_log(Err(status)).await;
-
-                            event_chans = None;
-                        }
-                    },
+                tokio::select! {
+                    exit_status = &mut session => break Some(exit_status),
+                    _ = queue.keep_alive() => {},
 
-                    None => match chan.recv().await {
+                    new_attached = chan.recv() => match new_attached {
+                        Some(new_attached) if new_attached.force => {
+                            info!(by = %new_attached.controller, "Window actor preempted");
+                            forced = Some(new_attached);
+                            break None;
+                        }
                         Some(new_attached) => {
-                            info!("Window actor attaching");
-                            event_chans = Some(new_attached)
+                            on_busy(options.busy_policy, new_attached, &controller, &mut queue)
+                                .await
                         }
                         None => {
                             info!("Window actor exiting as input chan closed");
@@ -64,53 +403,598 @@ pub fn spawn_displayer(mut chan: mpsc::Receiver<EventChans>) {
                     },
                 }
             }
-        });
-    });
+        };
+
+        match exit_status {
+            Some(Err(err)) if err.is_connection_lost() && !options.resume_timeout.is_zero() => {
+                warn!(?err, "Lost control, waiting for it to reconnect");
+                if let Err(err) = window.show_reconnecting() {
+                    warn!(?err, "Error showing reconnecting");
+                }
+                lost = Some(LostSession {
+                    token,
+                    controller,
+                    peer: curr_event_chans.video_auth.peer(),
+                    display_info,
+                    until: Instant::now() + options.resume_timeout,
+                });
+                // In case it's only the video stream that broke
+                curr_event_chans.tx.send_or_log(Err(err.into())).await;
+            }
+            Some(exit_status) => {
+                warn!(?exit_status, "show_window exited early");
+                if let Err(err) = window.close() {
+                    warn!(?err, "Error closing window");
+                }
+
+                let status = match exit_status {
+                    Ok(_) => Status::ok("Done"),
+                    Err(err) => err.into(),
+                };
+                curr_event_chans.tx.send_or_log(Err(status)).await;
+            }
+            None => {
+                if let Err(err) = window.close() {
+                    warn!(?err, "Error closing window");
+                }
+
+                let by = forced
+                    .as_ref()
+                    .map(|forced| forced.controller.clone())
+                    .unwrap_or_default();
+                let event = display_event::DisplayEvent::Preempted(display_event::Preempted {
+                    controller: by,
+                });
+                curr_event_chans
+                    .tx
+                    .send_or_log(Ok(DisplayEvent {
+                        display_event: Some(event),
+                    }))
+                    .await;
+            }
+        }
+    }
 }
 
-#[instrument]
-async fn show_window<W: Window>(chans: &EventChans, window: &mut W) -> Result<(), Status> {
-    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?; // 0 means OS chooses
+/// Keep the window of a lost session open until its control comes back, another control forces
+/// its way in, or we give up on it. Other controls are treated as if the output were busy.
+async fn await_resume<W: Window>(
+    chan: &mut mpsc::Receiver<EventChans>,
+    window: &mut W,
+    lost: &LostSession,
+    policy: BusyPolicy,
+    queue: &mut AttachQueue,
+) -> Resumed {
+    let mut input_interval = tokio::time::interval(INPUT_POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = sleep_until(lost.until.into()) => return Resumed::TimedOut,
+            _ = queue.keep_alive() => {},
+            // Keeps the window responsive. There's nobody to send the input to.
+            _ = input_interval.tick() => {
+                window.poll_input();
+            },
+            new_attached = chan.recv() => match new_attached {
+                Some(new_attached) if lost.is_resumed_by(&new_attached) || new_attached.force => {
+                    return Resumed::Attach(new_attached)
+                }
+                Some(new_attached) => {
+                    on_busy(policy, new_attached, &lost.controller, queue).await
+                }
+                None => return Resumed::ChanClosed,
+            },
+        }
+    }
+}
+
+fn new_session_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Handle an attach to an output that `controller` is using.
+async fn on_busy(
+    policy: BusyPolicy,
+    new_attached: EventChans,
+    controller: &str,
+    queue: &mut AttachQueue,
+) {
+    match policy {
+        BusyPolicy::Reject => {
+            info!(rejected = %new_attached.controller, "Rejecting attach as output is busy");
+            let status = Status::unavailable(format!("Output is in use by {}", controller));
+            new_attached.tx.send_or_log(Err(status)).await;
+        }
+        BusyPolicy::Queue => {
+            info!(queued = %new_attached.controller, "Queueing attach as output is busy");
+            let event = display_event::DisplayEvent::Queued(display_event::Queued {
+                controller: controller.to_string(),
+            });
+            new_attached
+                .tx
+                .send_or_log(Ok(DisplayEvent {
+                    display_event: Some(event),
+                }))
+                .await;
+            queue.push(new_attached);
+        }
+    }
+}
+
+/// Stream to an already created window until the control detaches or the session fails.
+///
+/// `display_info` is kept up to date with resizes.
+#[instrument(skip(display_info))]
+async fn show_window<W: Window>(
+    chans: &mut EventChans,
+    window: &mut W,
+    display_info: &mut DisplayInfo,
+    token: &str,
+    video_addr: SocketAddr,
+    video_ports: Option<PortRange>,
+    heartbeat: HeartbeatOptions,
+) -> Result<(), ShowWindowError> {
+    let listener = bind_video(video_addr, video_ports, chans.video_transport).await?;
     let port = listener.local_addr()?.port();
+    let nonce = new_nonce();
 
-    let display_info = window.create().map_err(ShowWindowError::from)?;
+    let audio_listener = if chans.audio {
+        bind_audio(video_addr, video_ports).await
+    } else {
+        None
+    };
+    let audio_port = match &audio_listener {
+        Some((listener, _)) => listener.local_addr()?.port(),
+        None => 0,
+    };
+
+    let refresh_interval = display_info
+        .refresh_rate_hz
+        .map(|hz| Duration::from_secs(1) / hz)
+        .unwrap_or(DEFAULT_REFRESH_INTERVAL);
 
     chans
         .tx
         .send(Ok(DisplayEvent {
             display_event: Some(display_event::DisplayEvent::Attach(display_event::Attach {
-                edid: display_info.edid,
+                edid: display_info.edid.clone(),
                 width_pixels: display_info.width_pixels,
                 height_pixels: display_info.height_pixels,
                 video_port: port as u32,
+                session: token.to_string(),
+                video_nonce: nonce.clone(),
+                audio_port: audio_port as u32,
             })),
         }))
-        .await
-        .map_err(ShowWindowError::from)?;
+        .await?;
     debug!("Sent attach event to control");
 
-    let (stream, control_addr) = listener.accept().await?;
-    info!(?control_addr, "Control accepted stream");
+    // Nothing reads the control's heartbeats until we're streaming, so give it as long
+    let open = open_video(listener, chans, &nonce);
+    let (mut packets, control_addr) = timeout(heartbeat.timeout, open)
+        .await
+        .map_err(|_| ShowWindowError::VideoTimeout)??;
+    info!(?control_addr, transport = ?chans.video_transport, "Control accepted stream");
+
+    let mut audio = match audio_listener {
+        Some((listener, output)) => {
+            let accept = accept_video(&listener, chans.control_ip, &chans.video_auth, &nonce);
+            let (stream, _) = timeout(heartbeat.timeout, accept)
+                .await
+                .map_err(|_| ShowWindowError::AudioTimeout)??;
+            debug!("Control opened audio stream");
+            Some(SessionAudio {
+                packets: spawn_audio_reader(stream),
+                decoder: AudioDecoder::new()?,
+                player: AudioPlayer::new(output),
+            })
+        }
+        None => None,
+    };
 
-    let mut decoder = Decoder::new().map_err(ShowWindowError::from)?;
+    let mut decoder = Decoder::new()?;
     debug!(?decoder, "Created decoder");
 
-    decoder
-        .decode(stream, |frame| {
-            debug!(?frame, "Received frame from decoder");
-            if let Err(err) = window.update(frame) {
-                warn!("Error updating window: {:?}", err);
-                let tx = chans.tx.clone();
-                tokio::spawn(async move {
-                    tx.send_or_log(Err(err.into())).await;
-                });
+    let mut scheduler = PresentationScheduler::new(refresh_interval);
+    let mut stats_interval = tokio::time::interval(STATS_LOG_INTERVAL);
+    let mut input_interval = tokio::time::interval(INPUT_POLL_INTERVAL);
+    let mut heartbeat_interval = tokio::time::interval(heartbeat.interval);
+    let mut liveness = Liveness::new(heartbeat.timeout, Instant::now());
+    let mut control_open = true;
+
+    loop {
+        // Sleep forever-ish if there is nothing to wait for, as select! evaluates every branch
+        let deadline = scheduler
+            .next_deadline()
+            .unwrap_or_else(|| Instant::now() + STATS_LOG_INTERVAL);
+
+        tokio::select! {
+            packet = packets.recv() => match packet {
+                Some(Ok(packet)) => {
+                    decoder.decode_packet(&packet, |frame| {
+                        schedule_frame(&mut scheduler, frame)
+                    })?;
+                }
+                Some(Err(err)) => return Err(err.into()),
+                None => {
+                    debug!("Video stream ended, flushing decoder");
+                    decoder.flush(|frame| schedule_frame(&mut scheduler, frame))?;
+                    break;
+                }
+            },
+            packet = recv_audio(&mut audio) => match packet {
+                Some(Ok(packet)) => {
+                    if let Some(audio) = &mut audio {
+                        audio.play(&packet, &mut scheduler);
+                    }
+                }
+                Some(Err(err)) => {
+                    warn!(?err, "Error receiving audio, carrying on without it");
+                    audio = None;
+                }
+                None => {
+                    debug!("Audio stream ended");
+                    audio = None;
+                }
+            },
+            _ = sleep_until(deadline.into()) => {},
+            _ = stats_interval.tick() => {
+                scheduler.stats().log();
+                if let Some(audio) = &audio {
+                    audio.player.stats().log();
+                }
+            },
+            _ = input_interval.tick() => {
+                forward_input(chans, window);
+                if let Some(info) = window.take_resize()? {
+                    *display_info = info.clone();
+                    request_resize(chans, info);
+                }
+            },
+            event = chans.recv.message(), if control_open => match event? {
+                Some(event) => {
+                    liveness.seen(Instant::now());
+                    handle_control_event(window, event);
+                }
+                None => {
+                    debug!("Control stopped sending events");
+                    control_open = false;
+                }
+            },
+            _ = heartbeat_interval.tick() => {
+                // If the queue is full the control isn't reading, so there's no point waiting
+                let _ = chans.tx.try_send(Ok(Heartbeat {}.into()));
+            },
+            _ = sleep_until(liveness.deadline().into()), if control_open => {
+                warn!("Control stopped sending heartbeats, detaching");
+                return Err(ShowWindowError::HeartbeatTimeout);
+            },
+        }
+
+        if let Some(frame) = scheduler.pop_due(Instant::now()) {
+            present(chans, window, &frame);
+        }
+    }
+
+    // Present whatever was still waiting, at its proper time
+    while let Some(deadline) = scheduler.next_deadline() {
+        sleep_until(deadline.into()).await;
+        if let Some(frame) = scheduler.pop_due(Instant::now()) {
+            present(chans, window, &frame);
+        }
+    }
+    scheduler.stats().log();
+
+    Ok(())
+}
+
+/// The audio stream of a session and what plays it.
+#[derive(Debug)]
+struct SessionAudio {
+    packets: mpsc::Receiver<io::Result<AudioPacket>>,
+    decoder: AudioDecoder,
+    player: AudioPlayer<SdlAudioOutput>,
+}
+
+impl SessionAudio {
+    /// Play a packet alongside the video frame with the same timestamp. Audio isn't worth
+    /// ending the session over, so errors are only logged.
+    fn play(&mut self, packet: &AudioPacket, scheduler: &mut PresentationScheduler<OwnedYuvFrame>) {
+        let samples = match self.decoder.decode(packet) {
+            Ok(samples) => samples,
+            Err(err) => {
+                warn!(?err, "Dropping audio packet that failed to decode");
+                return;
             }
+        };
+        let now = Instant::now();
+        let due = scheduler.due_at(packet.timestamp, now);
+        if let Err(err) = self.player.play(&samples, due, now) {
+            warn!(?err, "Error playing audio");
+        }
+    }
+}
+
+/// The next audio packet, or never if there's no audio.
+async fn recv_audio(audio: &mut Option<SessionAudio>) -> Option<io::Result<AudioPacket>> {
+    match audio {
+        Some(audio) => audio.packets.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Open the audio device and listen for the audio stream like a TCP video stream. Returns None
+/// if we can't play audio or have no port left to listen on, so the control knows not to send
+/// any and the session carries on with video only.
+async fn bind_audio(
+    addr: SocketAddr,
+    ports: Option<PortRange>,
+) -> Option<(TcpListener, SdlAudioOutput)> {
+    let output = match SdlAudioOutput::open() {
+        Ok(output) => output,
+        Err(err) => {
+            warn!(?err, "Can't play audio, so the control won't send any");
+            return None;
+        }
+    };
+    match bind_video(addr, ports, VideoTransport::Tcp).await {
+        Ok(VideoListener::Tcp(listener)) => Some((listener, output)),
+        Ok(VideoListener::Udp(_)) => unreachable!("Bound with TCP"),
+        Err(err) => {
+            warn!(
+                ?err,
+                "Can't listen for audio, so the control won't send any. Give --video-port a \
+                 range with a port for audio as well."
+            );
+            None
+        }
+    }
+}
+
+/// Where the control sends video, depending on the transport it picked.
+#[derive(Debug)]
+enum VideoListener {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl VideoListener {
+    async fn bind(addr: SocketAddr, transport: VideoTransport) -> io::Result<Self> {
+        Ok(match transport {
+            VideoTransport::Tcp => Self::Tcp(TcpListener::bind(addr).await?),
+            VideoTransport::Udp => Self::Udp(UdpSocket::bind(addr).await?),
         })
-        .await?;
+    }
 
-    window.close()?;
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            Self::Udp(socket) => socket.local_addr(),
+        }
+    }
+}
 
-    Ok(())
+/// Listen for the video stream on the first free port in `ports`, or any the OS chooses.
+async fn bind_video(
+    mut addr: SocketAddr,
+    ports: Option<PortRange>,
+    transport: VideoTransport,
+) -> Result<VideoListener, ShowWindowError> {
+    let ports = match ports {
+        Some(ports) => ports,
+        None => {
+            addr.set_port(0);
+            return Ok(VideoListener::bind(addr, transport).await?);
+        }
+    };
+    for port in ports.ports() {
+        addr.set_port(port);
+        match VideoListener::bind(addr, transport).await {
+            Ok(listener) => return Ok(listener),
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                debug!(port, "Video port in use");
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Err(ShowWindowError::NoVideoPort(ports))
+}
+
+/// Wait for the control to open the video stream and start reading packets from it.
+async fn open_video(
+    listener: VideoListener,
+    chans: &EventChans,
+    nonce: &[u8],
+) -> Result<(mpsc::Receiver<io::Result<VideoPacket>>, SocketAddr), ShowWindowError> {
+    match listener {
+        VideoListener::Tcp(listener) => {
+            let (stream, addr) =
+                accept_video(&listener, chans.control_ip, &chans.video_auth, nonce).await?;
+            Ok((spawn_packet_reader(stream), addr))
+        }
+        VideoListener::Udp(socket) => {
+            let control_ip = chans.control_ip;
+            let is_control =
+                |ip| control_ip.map_or(true, |control_ip| is_same_host(ip, control_ip));
+            let (socket, addr) = udp::accept(socket, nonce, is_control).await?;
+            Ok((udp::spawn_packet_reader(socket, PACKET_QUEUE), addr))
+        }
+    }
+}
+
+/// Accept the video stream from the control that attached, rejecting anyone else who connects
+/// first.
+async fn accept_video(
+    listener: &TcpListener,
+    control_ip: Option<IpAddr>,
+    auth: &VideoAuth,
+    nonce: &[u8],
+) -> Result<(Box<dyn VideoStream>, SocketAddr), ShowWindowError> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        if let Some(control_ip) = control_ip {
+            if !is_same_host(addr.ip(), control_ip) {
+                warn!(?addr, %control_ip, "Rejecting video stream from another host");
+                continue;
+            }
+        }
+
+        match timeout(VIDEO_HANDSHAKE_TIMEOUT, video_handshake(stream, auth, nonce)).await {
+            Ok(Ok(stream)) => return Ok((stream, addr)),
+            Ok(Err(err)) => warn!(?addr, ?err, "Rejecting video stream"),
+            Err(_) => warn!(?addr, "Rejecting video stream that didn't complete handshake"),
+        }
+    }
+}
+
+/// Check the stream is from the control that attached, which sends the session's nonce first.
+async fn video_handshake(
+    stream: TcpStream,
+    auth: &VideoAuth,
+    nonce: &[u8],
+) -> Result<Box<dyn VideoStream>, ShowWindowError> {
+    let mut stream: Box<dyn VideoStream> = match auth {
+        VideoAuth::Tls { peer, acceptor } => {
+            let stream = acceptor.accept(stream).await?;
+            if tls::control_fingerprint(&stream).as_ref() != Some(peer) {
+                return Err(ShowWindowError::WrongPeer);
+            }
+            Box::new(stream)
+        }
+        VideoAuth::Nonce => Box::new(stream),
+    };
+    if !read_nonce(stream.as_mut(), nonce).await? {
+        return Err(ShowWindowError::WrongPeer);
+    }
+    Ok(stream)
+}
+
+/// Whether the addresses are the same, treating IPv4 and IPv4-mapped IPv6 addresses as equal.
+fn is_same_host(a: IpAddr, b: IpAddr) -> bool {
+    let canonical = |ip: IpAddr| match ip {
+        IpAddr::V6(v6) => v6.to_ipv4().filter(|_| v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff]),
+        IpAddr::V4(v4) => Some(v4),
+    };
+    match (canonical(a), canonical(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn schedule_frame(scheduler: &mut PresentationScheduler<OwnedYuvFrame>, frame: YuvFrame) {
+    let now = Instant::now();
+    // Frames should always have a pts, but if one doesn't present it as soon as we can
+    let timestamp = match frame.pts {
+        Some(pts) => Duration::from_micros(pts.max(0) as u64),
+        None => {
+            warn!("Decoded frame without pts");
+            Duration::ZERO
+        }
+    };
+    scheduler.push(timestamp, frame.to_owned(), now);
+}
+
+fn handle_control_event<W: Window>(window: &mut W, event: ControlEvent) {
+    let event = match event.control_event {
+        Some(control_event::ControlEvent::Attach(attach)) => {
+            warn!(?attach, "Ignoring Attach after the session started");
+            return;
+        }
+        Some(control_event::ControlEvent::Heartbeat(_)) => return,
+        Some(control_event::ControlEvent::Adjust(adjust)) => {
+            match Adjustments::try_from(adjust) {
+                Ok(adjustments) => {
+                    debug!(?adjustments, "Adjusting monitor");
+                    if let Err(err) = window.adjust(adjustments) {
+                        warn!(?err, "Error adjusting monitor");
+                    }
+                }
+                Err(err) => warn!(?err, "Ignoring invalid adjustment"),
+            }
+            return;
+        }
+        Some(event) => event,
+        None => return,
+    };
+    match CursorUpdate::try_from(event) {
+        Ok(update) => {
+            trace!(?update, "Updating cursor");
+            if let Err(err) = window.update_cursor(update) {
+                warn!(?err, "Error updating cursor");
+            }
+        }
+        Err(err) => warn!(?err, "Ignoring invalid cursor update"),
+    }
+}
+
+fn forward_input<W: Window>(chans: &EventChans, window: &mut W) {
+    for input in window.poll_input() {
+        trace!(?input, "Forwarding input");
+        let event = DisplayEvent {
+            display_event: Some(display_event::DisplayEvent::Input(input.into())),
+        };
+        // Better to drop input than stall presentation if control isn't keeping up
+        if let Err(err) = chans.tx.try_send(Ok(event)) {
+            warn!(?err, "Dropped input event");
+        }
+    }
+}
+
+fn request_resize(chans: &EventChans, info: DisplayInfo) {
+    debug!(width = info.width_pixels, height = info.height_pixels, "Requesting resize");
+    let event = DisplayEvent {
+        display_event: Some(display_event::DisplayEvent::Resize(display_event::Resize {
+            edid: info.edid,
+            width_pixels: info.width_pixels,
+            height_pixels: info.height_pixels,
+        })),
+    };
+    // Unlike input this mustn't be dropped, but we still can't wait on the control here
+    let tx = chans.tx.clone();
+    tokio::spawn(async move {
+        tx.send_or_log(Ok(event)).await;
+    });
+}
+
+fn present<W: Window>(chans: &EventChans, window: &mut W, frame: &OwnedYuvFrame) {
+    debug!(?frame, "Presenting frame");
+    if let Err(err) = window.update(frame.as_frame()) {
+        warn!("Error updating window: {:?}", err);
+        let tx = chans.tx.clone();
+        tokio::spawn(async move {
+            tx.send_or_log(Err(err.into())).await;
+        });
+    }
+}
+
+/// Reading happens in a separate task because reads aren't cancel safe, and we need to be
+/// able to wake up to present frames while waiting on the network.
+fn spawn_packet_reader(
+    mut stream: Box<dyn VideoStream>,
+) -> mpsc::Receiver<io::Result<VideoPacket>> {
+    let (tx, rx) = mpsc::channel(PACKET_QUEUE);
+    tokio::spawn(async move {
+        while let Some(result) = VideoPacket::read_from(&mut stream).await.transpose() {
+            let failed = result.is_err();
+            if tx.send(result).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+/// Like [`spawn_packet_reader`], for the audio stream.
+fn spawn_audio_reader(mut stream: Box<dyn VideoStream>) -> mpsc::Receiver<io::Result<AudioPacket>> {
+    let (tx, rx) = mpsc::channel(PACKET_QUEUE);
+    tokio::spawn(async move {
+        while let Some(result) = AudioPacket::read_from(&mut stream).await.transpose() {
+            let failed = result.is_err();
+            if tx.send(result).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    rx
 }
 
 #[derive(Error, Debug)]
@@ -119,12 +1003,41 @@ enum ShowWindowError {
     Window(#[from] WindowError),
     #[error("Error decoding stream")]
     Decode(#[from] AvError),
+    #[error("Error decoding stream")]
+    DecodePacket(#[from] av::decoder::DecodeError),
     #[error("Error communicating with client")]
     ClientCom,
+    #[error("Error receiving events from client")]
+    Control(#[from] Status),
+    #[error("Control stopped sending heartbeats")]
+    HeartbeatTimeout,
+    #[error("Video stream wasn't opened by the control that attached")]
+    WrongPeer,
+    #[error("Control never opened the video stream")]
+    VideoTimeout,
+    #[error("Control never opened the audio stream")]
+    AudioTimeout,
+    #[error("Every video port in {0} is in use, allow more with --video-port")]
+    NoVideoPort(PortRange),
     #[error("Error performing stream IO")]
     StreamIo(#[from] io::Error),
 }
 
+impl ShowWindowError {
+    /// Whether the control might come back, as opposed to the session failing on our side.
+    fn is_connection_lost(&self) -> bool {
+        matches!(
+            self,
+            Self::ClientCom
+                | Self::Control(_)
+                | Self::HeartbeatTimeout
+                | Self::VideoTimeout
+                | Self::AudioTimeout
+                | Self::StreamIo(_)
+        )
+    }
+}
+
 impl<T> From<mpsc::error::SendError<T>> for ShowWindowError {
     fn from(_: SendError<T>) -> Self {
         Self::ClientCom
@@ -133,7 +1046,15 @@ impl<T> From<mpsc::error::SendError<T>> for ShowWindowError {
 
 impl From<ShowWindowError> for Status {
     fn from(err: ShowWindowError) -> Self {
-        Status::unavailable(format!("{}", err))
+        match err {
+            ShowWindowError::Control(status) => status,
+            ShowWindowError::HeartbeatTimeout
+            | ShowWindowError::VideoTimeout
+            | ShowWindowError::AudioTimeout => Status::deadline_exceeded(format!("{}", err)),
+            ShowWindowError::WrongPeer => Status::permission_denied(format!("{}", err)),
+            ShowWindowError::NoVideoPort(_) => Status::resource_exhausted(format!("{}", err)),
+            err => Status::unavailable(format!("{}", err)),
+        }
     }
 }
 
@@ -142,3 +1063,301 @@ impl From<av::decoder::DecodeError> for Status {
         Status::unavailable(format!("{}", err))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::token::write_nonce;
+    use bytes::Bytes;
+    use prost::Message;
+    use std::future::Future;
+    use tokio::time::sleep;
+    use tonic::codec::{Codec, ProstCodec};
+    use tonic::transport::Body;
+
+    #[derive(Debug)]
+    struct MockWindow;
+
+    impl Window for MockWindow {
+        fn create(&mut self) -> Result<DisplayInfo, WindowError> {
+            Ok(DisplayInfo {
+                edid: vec![],
+                width_pixels: 1280,
+                height_pixels: 720,
+                refresh_rate_hz: Some(60),
+            })
+        }
+
+        fn update(&mut self, _frame: YuvFrame) -> Result<(), WindowError> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<(), WindowError> {
+            Ok(())
+        }
+
+        fn update_cursor(&mut self, _update: CursorUpdate) -> Result<(), WindowError> {
+            Ok(())
+        }
+
+        fn show_reconnecting(&mut self) -> Result<(), WindowError> {
+            Ok(())
+        }
+
+        fn adjust(&mut self, _adjustments: Adjustments) -> Result<(), WindowError> {
+            Ok(())
+        }
+
+        fn poll_input(&mut self) -> Vec<crate::input::InputEvent> {
+            vec![]
+        }
+
+        fn take_resize(&mut self) -> Result<Option<DisplayInfo>, WindowError> {
+            Ok(None)
+        }
+    }
+
+    /// The far end of an Attach call.
+    struct MockControl {
+        events: mpsc::Receiver<Result<DisplayEvent, Status>>,
+        /// Dropping this ends our event stream
+        send: mpsc::Sender<ControlEvent>,
+        video: Option<TcpStream>,
+    }
+
+    impl MockControl {
+        async fn attach(output: &mpsc::Sender<EventChans>, controller: &str, force: bool) -> Self {
+            let (tx, events) = mpsc::channel(16);
+            let (send, mut to_send) = mpsc::channel::<ControlEvent>(16);
+            let (mut body_tx, body) = Body::channel();
+            tokio::spawn(async move {
+                while let Some(event) = to_send.recv().await {
+                    // gRPC framing: uncompressed, then the length
+                    let mut frame = vec![0; 5];
+                    event.encode(&mut frame).unwrap();
+                    let len = (frame.len() - 5) as u32;
+                    frame[1..5].copy_from_slice(&len.to_be_bytes());
+                    if body_tx.send_data(Bytes::from(frame)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            let decoder = ProstCodec::<DisplayEvent, ControlEvent>::default().decoder();
+            let chans = EventChans {
+                tx,
+                recv: Streaming::new_request(decoder, body),
+                controller: controller.to_string(),
+                force,
+                control_ip: Some("127.0.0.1".parse().unwrap()),
+                resume: String::new(),
+                video_auth: VideoAuth::Nonce,
+                video_transport: VideoTransport::Tcp,
+                audio: false,
+            };
+            output.send(chans).await.unwrap();
+            Self {
+                events,
+                send,
+                video: None,
+            }
+        }
+
+        /// Skips heartbeats.
+        async fn next_event(&mut self) -> Option<Result<display_event::DisplayEvent, Status>> {
+            loop {
+                let event = timeout(TIMEOUT, self.events.recv()).await.unwrap()?;
+                match event.map(|event| event.display_event.unwrap()) {
+                    Ok(display_event::DisplayEvent::Heartbeat(_)) => continue,
+                    event => return Some(event),
+                }
+            }
+        }
+
+        /// Wait for the display to attach and open the video stream.
+        async fn expect_attach(&mut self) {
+            let attach = match self.next_event().await {
+                Some(Ok(display_event::DisplayEvent::Attach(attach))) => attach,
+                other => panic!("Expected Attach, got {:?}", other),
+            };
+            let addr = ("127.0.0.1", attach.video_port as u16);
+            let mut video = TcpStream::connect(addr).await.unwrap();
+            write_nonce(&mut video, &attach.video_nonce).await.unwrap();
+            self.video = Some(video);
+        }
+
+        async fn heartbeat(&self) {
+            self.send.send(Heartbeat {}.into()).await.unwrap();
+        }
+    }
+
+    async fn with_output<F, Fut>(options: DisplayOptions, test: F)
+    where
+        F: FnOnce(mpsc::Sender<EventChans>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let (tx, rx) = mpsc::channel(16);
+        let options = DisplayOptions {
+            video_addr: "127.0.0.1:0".parse().unwrap(),
+            ..options
+        };
+        tokio::select! {
+            _ = run_output(rx, MockWindow, options) => panic!("Output exited"),
+            _ = test(tx) => (),
+        }
+    }
+
+    #[ltest]
+    fn parses_busy_policy() {
+        assert_eq!("reject".parse::<BusyPolicy>().unwrap(), BusyPolicy::Reject);
+        assert_eq!("queue".parse::<BusyPolicy>().unwrap(), BusyPolicy::Queue);
+        assert!("replace".parse::<BusyPolicy>().is_err());
+    }
+
+    #[ltest(atest)]
+    async fn rejects_attach_while_busy() {
+        with_output(DisplayOptions::default(), |output| async move {
+            let mut current = MockControl::attach(&output, "current", false).await;
+            current.expect_attach().await;
+
+            let mut other = MockControl::attach(&output, "other", false).await;
+            let status = other.next_event().await.unwrap().unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unavailable);
+            assert!(status.message().contains("current"));
+        })
+        .await;
+    }
+
+    #[ltest(atest)]
+    async fn queues_attach_until_current_detaches() {
+        let options = DisplayOptions {
+            busy_policy: BusyPolicy::Queue,
+            ..Default::default()
+        };
+        with_output(options, |output| async move {
+            let mut current = MockControl::attach(&output, "current", false).await;
+            current.expect_attach().await;
+
+            let mut queued = MockControl::attach(&output, "queued", false).await;
+            match queued.next_event().await {
+                Some(Ok(display_event::DisplayEvent::Queued(queued))) => {
+                    assert_eq!(queued.controller, "current")
+                }
+                other => panic!("Expected Queued, got {:?}", other),
+            }
+
+            // Ending the video stream ends the session
+            current.video = None;
+            let status = current.next_event().await.unwrap().unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Ok);
+
+            queued.expect_attach().await;
+        })
+        .await;
+    }
+
+    #[ltest(atest)]
+    async fn forced_attach_preempts_current() {
+        with_output(DisplayOptions::default(), |output| async move {
+            let mut current = MockControl::attach(&output, "current", false).await;
+            current.expect_attach().await;
+
+            let mut forced = MockControl::attach(&output, "forced", true).await;
+            match current.next_event().await {
+                Some(Ok(display_event::DisplayEvent::Preempted(preempted))) => {
+                    assert_eq!(preempted.controller, "forced")
+                }
+                other => panic!("Expected Preempted, got {:?}", other),
+            }
+            forced.expect_attach().await;
+        })
+        .await;
+    }
+
+    #[ltest(atest)]
+    async fn drops_silent_queued_control() {
+        let options = DisplayOptions {
+            busy_policy: BusyPolicy::Queue,
+            heartbeat: HeartbeatOptions {
+                interval: Duration::from_millis(50),
+                timeout: Duration::from_millis(300),
+            },
+            ..Default::default()
+        };
+        with_output(options, |output| async move {
+            let mut current = MockControl::attach(&output, "current", false).await;
+            current.expect_attach().await;
+            let mut queued = MockControl::attach(&output, "queued", false).await;
+
+            // The current control keeps heartbeating while the queued one says nothing
+            let until_dropped = async {
+                let mut events = vec![];
+                while let Some(event) = queued.events.recv().await {
+                    events.push(event.unwrap().display_event.unwrap());
+                }
+                events
+            };
+            let keep_current_alive = async {
+                loop {
+                    sleep(Duration::from_millis(50)).await;
+                    current.heartbeat().await;
+                }
+            };
+            let events = timeout(TIMEOUT, async {
+                tokio::select! {
+                    events = until_dropped => events,
+                    _ = keep_current_alive => unreachable!(),
+                }
+            })
+            .await
+            .expect("Queued control wasn't dropped");
+
+            assert!(matches!(events[0], display_event::DisplayEvent::Queued(_)));
+            assert!(events.len() > 1, "Queued control got no heartbeats");
+            assert!(events[1..]
+                .iter()
+                .all(|event| matches!(event, display_event::DisplayEvent::Heartbeat(_))));
+            while let Some(event) = current.events.recv().now_or_never() {
+                let event = event.expect("Current control was detached").unwrap();
+                assert!(matches!(
+                    event.display_event,
+                    Some(display_event::DisplayEvent::Heartbeat(_))
+                ));
+            }
+        })
+        .await;
+    }
+
+    #[ltest(atest)]
+    async fn binds_video_ports() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let taken = bind_video(addr, None, VideoTransport::Tcp).await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        let ports: PortRange = port.to_string().parse().unwrap();
+        let err = bind_video(addr, Some(ports), VideoTransport::Tcp)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ShowWindowError::NoVideoPort(_)));
+
+        drop(taken);
+        let listener = bind_video(addr, Some(ports), VideoTransport::Tcp)
+            .await
+            .unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+    }
+
+    #[ltest]
+    fn compares_hosts() {
+        let v4: IpAddr = "192.168.1.2".parse().unwrap();
+        let mapped: IpAddr = "::ffff:192.168.1.2".parse().unwrap();
+        let other: IpAddr = "192.168.1.3".parse().unwrap();
+        let v6: IpAddr = "fe80::1".parse().unwrap();
+
+        assert!(is_same_host(v4, v4));
+        assert!(is_same_host(v4, mapped));
+        assert!(is_same_host(mapped, v4));
+        assert!(!is_same_host(v4, other));
+        assert!(!is_same_host(v6, v4));
+        assert!(is_same_host(v6, v6));
+    }
+}
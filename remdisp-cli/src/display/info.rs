@@ -2,62 +2,149 @@ use crate::prelude::*;
 use cfg_if::cfg_if;
 use std::error::Error;
 
+pub mod edid;
+
+pub use edid::{Edid, EdidBuilder, EdidError};
+
+/// Assumed when synthesizing an EDID for a display that didn't report its refresh rate
+pub const DEFAULT_REFRESH_RATE_HZ: u32 = 60;
+
 #[derive(Debug)]
 pub struct DisplayInfo {
     pub edid: Vec<u8>,
     pub width_pixels: u32,
     pub height_pixels: u32,
+    /// None if the platform didn't report one
+    pub refresh_rate_hz: Option<u32>,
+}
+
+/// Where a window is on the desktop, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ScreenRect {
+    fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x
+            && y >= self.y
+            && (x as i64) < self.x as i64 + self.width as i64
+            && (y as i64) < self.y as i64 + self.height as i64
+    }
+
+    fn center(&self) -> (i32, i32) {
+        (
+            self.x + (self.width / 2) as i32,
+            self.y + (self.height / 2) as i32,
+        )
+    }
+
+    /// Index of the rect equal to self, or failing that the one containing our center.
+    pub fn find_in(&self, candidates: &[ScreenRect]) -> Option<usize> {
+        candidates.iter().position(|c| c == self).or_else(|| {
+            let (x, y) = self.center();
+            candidates.iter().position(|c| c.contains(x, y))
+        })
+    }
 }
 
 impl DisplayInfo {
-    pub fn read_edid(handle: PlatformWindowHandle) -> Result<Vec<u8>, ReadEdidPlatformError> {
+    /// Read the EDID of the monitor the window is on.
+    ///
+    /// `bounds` are the bounds of the monitor as the windowing library reports them, which is
+    /// how the output is found on platforms where we can't ask using the window handle.
+    pub fn read_edid(
+        handle: PlatformWindowHandle,
+        bounds: ScreenRect,
+    ) -> Result<Vec<u8>, ReadEdidError> {
         cfg_if! {
             if #[cfg(target_os = "windows")] {
+                let _ = bounds;
                 Self::read_edid_windows(handle.expect_windows())
             } else if #[cfg(target_os = "linux")] {
-                Self::read_edid_x11(handle.expect_linux())
+                Self::read_edid_x11(handle.expect_linux(), bounds)
             } else {
-                panic!("Only windows and linux supported")
+                let _ = (handle, bounds);
+                Err(ReadEdidError::Unsupported)
             }
         }
     }
 
     #[cfg(target_os = "windows")]
-    fn read_edid_windows(
-        handle: WindowsWindowHandle,
-    ) -> Result<Vec<u8>, ReadEdidErrorPlatformError> {
-        use monitor_control_win::{Monitor, WinError};
-        fn helper(handle: WindowsWindowHandle) -> Result<Vec<u8>, WinError> {
-            // TODO: Gracefully handle len != 1
-            let monitors = Monitor::intersecting(handle.0.cast())?;
-            let monitor = monitors.first().expect("TODO: Fixme");
-            monitor.edid()
-        }
+    fn read_edid_windows(handle: WindowsWindowHandle) -> Result<Vec<u8>, ReadEdidError> {
+        use monitor_control_win::Monitor;
 
-        helper(handle).map_err(|err| ReadEdidPlatformError(Box::new(err)))
+        let monitors = Monitor::intersecting(handle.0.cast()).map_err(ReadEdidPlatformError::new)?;
+        if monitors.len() > 1 {
+            debug!(count = monitors.len(), "Window intersects several monitors, using the first");
+        }
+        let monitor = monitors.first().ok_or(ReadEdidError::NoOutput)?;
+        let edid = monitor.edid().map_err(ReadEdidPlatformError::new)?;
+        Ok(edid)
     }
 
     #[cfg(target_os = "linux")]
-    fn read_edid_x11(handle: LinuxWindowHandle) -> Result<Vec<u8>, ReadEdidPlatformError> {
-        use xrandr::{XHandle, XrandrError};
-        fn helper(_handle: LinuxWindowHandle) -> Result<Vec<u8>, XrandrError> {
-            // TODO: Get the output from the window instead of getting the first
-            //  monitor's first output
-            let monitors = XHandle::open()?.monitors()?;
-            let monitor = monitors.first().expect("TODO: Fixme");
-            let output = monitor.outputs.first().expect("TODO: Fixme");
-            let edid = output.edid().expect("TODO: Fixme");
-            Ok(edid)
+    fn read_edid_x11(
+        _handle: LinuxWindowHandle,
+        bounds: ScreenRect,
+    ) -> Result<Vec<u8>, ReadEdidError> {
+        use xrandr::{Monitor, XHandle, XrandrError};
+        fn monitors() -> Result<Vec<Monitor>, XrandrError> {
+            XHandle::open()?.monitors()
         }
 
-        helper(handle).map_err(|err| ReadEdidPlatformError(Box::new(err)))
+        let monitors = monitors().map_err(ReadEdidPlatformError::new)?;
+
+        let rects: Vec<_> = monitors
+            .iter()
+            .map(|monitor| ScreenRect {
+                x: monitor.x,
+                y: monitor.y,
+                width: monitor.width_px as u32,
+                height: monitor.height_px as u32,
+            })
+            .collect();
+        let monitor = bounds
+            .find_in(&rects)
+            .map(|index| &monitors[index])
+            .ok_or(ReadEdidError::NoOutput)?;
+        debug!(monitor = ?monitor.name, ?bounds, "Found monitor window is on");
+
+        // A monitor can be made of several outputs, any of which will do
+        monitor
+            .outputs
+            .iter()
+            .find_map(|output| output.edid())
+            .ok_or_else(|| ReadEdidError::NoEdid(monitor.name.clone()))
     }
 }
 
+#[derive(Debug, Error)]
+pub enum ReadEdidError {
+    #[error("No output found for the window")]
+    NoOutput,
+    #[error("Output {0} has no EDID")]
+    NoEdid(String),
+    #[error("Reading EDIDs isn't supported on this platform")]
+    Unsupported,
+    #[error("Error reading EDID")]
+    Platform(#[from] ReadEdidPlatformError),
+}
+
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct ReadEdidPlatformError(Box<dyn Error + Send + Sync + 'static>);
 
+impl ReadEdidPlatformError {
+    #[cfg(any(target_os = "windows", target_os = "linux"))]
+    fn new(err: impl Error + Send + Sync + 'static) -> Self {
+        Self(Box::new(err))
+    }
+}
+
 pub enum PlatformWindowHandle {
     Windows(WindowsWindowHandle),
     Linux(LinuxWindowHandle),
@@ -89,3 +176,36 @@ impl PlatformWindowHandle {
 
 pub struct WindowsWindowHandle(pub *mut libc::c_void);
 pub struct LinuxWindowHandle(pub *mut libc::c_void);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, y: i32, width: u32, height: u32) -> ScreenRect {
+        ScreenRect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[ltest]
+    fn finds_exact_match() {
+        let monitors = [rect(0, 0, 1920, 1080), rect(1920, 0, 1920, 1080)];
+        assert_eq!(rect(1920, 0, 1920, 1080).find_in(&monitors), Some(1));
+    }
+
+    #[ltest]
+    fn finds_monitor_containing_center() {
+        // e.g. if the windowing library excludes a panel from the bounds
+        let monitors = [rect(0, 0, 2560, 1440), rect(2560, 0, 1920, 1080)];
+        assert_eq!(rect(2560, 32, 1920, 1048).find_in(&monitors), Some(1));
+    }
+
+    #[ltest]
+    fn finds_nothing_off_screen() {
+        let monitors = [rect(0, 0, 1920, 1080)];
+        assert_eq!(rect(5000, 5000, 10, 10).find_in(&monitors), None);
+    }
+}
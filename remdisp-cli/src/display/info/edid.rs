@@ -0,0 +1,497 @@
+//! Building and parsing EDID 1.4 base blocks.
+//!
+//! See the VESA E-EDID standard, release A revision 2, and the CVT standard for the reduced
+//! blanking timings we generate.
+
+use std::fmt;
+
+use crate::prelude::*;
+
+pub const BLOCK_LEN: usize = 128;
+
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+const DESCRIPTORS_START: usize = 54;
+const DESCRIPTOR_LEN: usize = 18;
+const DESCRIPTOR_NAME: u8 = 0xfc;
+const DESCRIPTOR_DUMMY: u8 = 0x10;
+const MAX_NAME_LEN: usize = 13;
+
+/// sRGB primaries and white point, which we claim so colors aren't managed on the control
+const SRGB_CHROMATICITY: [u8; 10] = [0xee, 0x91, 0xa3, 0x54, 0x4c, 0x99, 0x26, 0x0f, 0x50, 0x54];
+
+// CVT reduced blanking, in pixels and lines
+const RB_H_BLANK: u32 = 160;
+const RB_H_FRONT_PORCH: u32 = 48;
+const RB_H_SYNC: u32 = 32;
+const RB_V_FRONT_PORCH: u32 = 3;
+const RB_MIN_V_BACK_PORCH: u32 = 6;
+const RB_MIN_V_BLANK_MICROS: u64 = 460;
+
+/// Assumed when the physical size isn't given
+const DEFAULT_DPI: u32 = 96;
+
+/// Builds a single block EDID describing a monitor with one preferred mode.
+#[derive(Debug, Clone)]
+pub struct EdidBuilder {
+    width: u32,
+    height: u32,
+    refresh_rate_hz: u32,
+    name: String,
+    manufacturer: [u8; 3],
+    product_code: u16,
+    serial: u32,
+    size_mm: Option<(u32, u32)>,
+}
+
+impl EdidBuilder {
+    pub fn new(width: u32, height: u32, refresh_rate_hz: u32) -> Self {
+        Self {
+            width,
+            height,
+            refresh_rate_hz,
+            name: "remdisp".to_string(),
+            manufacturer: *b"RMD",
+            product_code: 0,
+            serial: 0,
+            size_mm: None,
+        }
+    }
+
+    /// Truncated to the 13 characters EDIDs allow.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn product_code(mut self, product_code: u16) -> Self {
+        self.product_code = product_code;
+        self
+    }
+
+    pub fn serial(mut self, serial: u32) -> Self {
+        self.serial = serial;
+        self
+    }
+
+    pub fn physical_size_mm(mut self, width: u32, height: u32) -> Self {
+        self.size_mm = Some((width, height));
+        self
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>, EdidError> {
+        let timing = self.timing()?;
+        let mut edid = vec![0u8; BLOCK_LEN];
+
+        edid[0..8].copy_from_slice(&HEADER);
+        edid[8..10].copy_from_slice(&encode_manufacturer(self.manufacturer).to_be_bytes());
+        edid[10..12].copy_from_slice(&self.product_code.to_le_bytes());
+        edid[12..16].copy_from_slice(&self.serial.to_le_bytes());
+        edid[16] = 0; // Week unspecified
+        edid[17] = 2021 - 1990;
+        edid[18] = 1;
+        edid[19] = 4;
+        // Digital, 8 bits per color, interface undefined
+        edid[20] = 0x80 | 0x20;
+        edid[21] = ((timing.width_mm + 5) / 10).min(255) as u8;
+        edid[22] = ((timing.height_mm + 5) / 10).min(255) as u8;
+        edid[23] = 120; // Gamma 2.2
+        // sRGB default and preferred timing is native
+        edid[24] = 0x04 | 0x02;
+        edid[25..35].copy_from_slice(&SRGB_CHROMATICITY);
+        // No established timings, and standard timings all unused
+        for slot in edid[38..54].chunks_mut(2) {
+            slot.copy_from_slice(&[0x01, 0x01]);
+        }
+
+        let descriptors = [
+            timing.encode(),
+            text_descriptor(DESCRIPTOR_NAME, &self.name),
+            dummy_descriptor(),
+            dummy_descriptor(),
+        ];
+        for (n, descriptor) in descriptors.iter().enumerate() {
+            let start = DESCRIPTORS_START + n * DESCRIPTOR_LEN;
+            edid[start..start + DESCRIPTOR_LEN].copy_from_slice(descriptor);
+        }
+
+        edid[126] = 0; // No extensions
+        edid[127] = checksum(&edid[..127]);
+        Ok(edid)
+    }
+
+    /// CVT reduced blanking timings, which suit digital displays and keep the pixel clock low.
+    fn timing(&self) -> Result<DetailedTiming, EdidError> {
+        let invalid = || EdidError::UnsupportedMode {
+            width: self.width,
+            height: self.height,
+            refresh_rate_hz: self.refresh_rate_hz,
+        };
+        if self.width == 0 || self.height == 0 || self.refresh_rate_hz == 0 {
+            return Err(invalid());
+        }
+        if self.width > 0xfff || self.height > 0xfff {
+            return Err(invalid());
+        }
+
+        let h_total = (self.width + RB_H_BLANK) as u64;
+
+        // Enough lines of blanking to take at least the minimum time
+        let frame_micros = 1_000_000 / self.refresh_rate_hz as u64;
+        let h_period_nanos = (frame_micros.saturating_sub(RB_MIN_V_BLANK_MICROS) * 1000)
+            / self.height as u64;
+        if h_period_nanos == 0 {
+            return Err(invalid());
+        }
+        let v_sync = v_sync_width(self.width, self.height);
+        let vbi_lines = (RB_MIN_V_BLANK_MICROS * 1000 / h_period_nanos) as u32 + 1;
+        let v_blank = vbi_lines.max(RB_V_FRONT_PORCH + v_sync + RB_MIN_V_BACK_PORCH);
+        let v_total = (self.height + v_blank) as u64;
+
+        let pixel_clock_khz = h_total * v_total * self.refresh_rate_hz as u64 / 1000;
+        // The descriptor stores the clock in units of 10kHz
+        if pixel_clock_khz / 10 > u16::MAX as u64 {
+            return Err(invalid());
+        }
+
+        let (width_mm, height_mm) = self.size_mm.unwrap_or_else(|| {
+            let to_mm = |px: u32| px * 254 / (DEFAULT_DPI * 10);
+            (to_mm(self.width), to_mm(self.height))
+        });
+
+        Ok(DetailedTiming {
+            pixel_clock_khz: pixel_clock_khz as u32,
+            h_active: self.width,
+            h_blank: RB_H_BLANK,
+            h_front_porch: RB_H_FRONT_PORCH,
+            h_sync_width: RB_H_SYNC,
+            v_active: self.height,
+            v_blank,
+            v_front_porch: RB_V_FRONT_PORCH,
+            v_sync_width: v_sync,
+            width_mm,
+            height_mm,
+            h_sync_positive: true,
+            v_sync_positive: false,
+        })
+    }
+}
+
+/// CVT encodes the aspect ratio in the vertical sync width.
+fn v_sync_width(width: u32, height: u32) -> u32 {
+    let is = |w: u32, h: u32| width * h == height * w;
+    if is(4, 3) {
+        4
+    } else if is(16, 9) {
+        5
+    } else if is(16, 10) {
+        6
+    } else if is(5, 4) || is(15, 9) {
+        7
+    } else {
+        10
+    }
+}
+
+/// The parts of an EDID we care about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edid {
+    pub manufacturer: String,
+    pub product_code: u16,
+    pub serial: u32,
+    pub version: u8,
+    pub revision: u8,
+    pub name: Option<String>,
+    pub preferred_timing: Option<DetailedTiming>,
+    pub extensions: u8,
+}
+
+impl Edid {
+    /// Only the base block is parsed, extensions are counted but otherwise ignored.
+    pub fn parse(bytes: &[u8]) -> Result<Self, EdidError> {
+        if bytes.len() < BLOCK_LEN {
+            return Err(EdidError::TooShort(bytes.len()));
+        }
+        let block = &bytes[..BLOCK_LEN];
+        if block[..8] != HEADER {
+            return Err(EdidError::BadHeader);
+        }
+        if checksum(&block[..127]) != block[127] {
+            return Err(EdidError::BadChecksum);
+        }
+
+        let mut name = None;
+        let mut preferred_timing = None;
+        for descriptor in block[DESCRIPTORS_START..126].chunks(DESCRIPTOR_LEN) {
+            if descriptor[0] != 0 || descriptor[1] != 0 {
+                if preferred_timing.is_none() {
+                    preferred_timing = Some(DetailedTiming::decode(descriptor));
+                }
+            } else if descriptor[3] == DESCRIPTOR_NAME {
+                name = Some(decode_text(&descriptor[5..]));
+            }
+        }
+
+        Ok(Self {
+            manufacturer: decode_manufacturer(u16::from_be_bytes([block[8], block[9]])),
+            product_code: u16::from_le_bytes([block[10], block[11]]),
+            serial: u32::from_le_bytes([block[12], block[13], block[14], block[15]]),
+            version: block[18],
+            revision: block[19],
+            name,
+            preferred_timing,
+            extensions: block[126],
+        })
+    }
+}
+
+impl fmt::Display for Edid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({:04x}) EDID {}.{}",
+            self.manufacturer,
+            self.name.as_deref().unwrap_or("unnamed"),
+            self.product_code,
+            self.version,
+            self.revision
+        )?;
+        if let Some(timing) = &self.preferred_timing {
+            write!(
+                f,
+                ", preferred {}x{}@{:.2}Hz",
+                timing.h_active,
+                timing.v_active,
+                timing.refresh_rate_hz()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A detailed timing descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetailedTiming {
+    /// Rounded down to a multiple of 10kHz
+    pub pixel_clock_khz: u32,
+    pub h_active: u32,
+    pub h_blank: u32,
+    pub h_front_porch: u32,
+    pub h_sync_width: u32,
+    pub v_active: u32,
+    pub v_blank: u32,
+    pub v_front_porch: u32,
+    pub v_sync_width: u32,
+    pub width_mm: u32,
+    pub height_mm: u32,
+    pub h_sync_positive: bool,
+    pub v_sync_positive: bool,
+}
+
+impl DetailedTiming {
+    pub fn refresh_rate_hz(&self) -> f64 {
+        let total = (self.h_active + self.h_blank) as f64 * (self.v_active + self.v_blank) as f64;
+        self.pixel_clock_khz as f64 * 1000.0 / total
+    }
+
+    fn encode(&self) -> [u8; DESCRIPTOR_LEN] {
+        let lo = |v: u32| (v & 0xff) as u8;
+        let hi4 = |v: u32| ((v >> 8) & 0xf) as u8;
+        let mut d = [0u8; DESCRIPTOR_LEN];
+
+        d[0..2].copy_from_slice(&((self.pixel_clock_khz / 10) as u16).to_le_bytes());
+        d[2] = lo(self.h_active);
+        d[3] = lo(self.h_blank);
+        d[4] = hi4(self.h_active) << 4 | hi4(self.h_blank);
+        d[5] = lo(self.v_active);
+        d[6] = lo(self.v_blank);
+        d[7] = hi4(self.v_active) << 4 | hi4(self.v_blank);
+        d[8] = lo(self.h_front_porch);
+        d[9] = lo(self.h_sync_width);
+        d[10] = ((self.v_front_porch & 0xf) << 4 | (self.v_sync_width & 0xf)) as u8;
+        d[11] = (((self.h_front_porch >> 8) & 0x3) << 6
+            | ((self.h_sync_width >> 8) & 0x3) << 4
+            | ((self.v_front_porch >> 4) & 0x3) << 2
+            | ((self.v_sync_width >> 4) & 0x3)) as u8;
+        d[12] = lo(self.width_mm);
+        d[13] = lo(self.height_mm);
+        d[14] = hi4(self.width_mm) << 4 | hi4(self.height_mm);
+        // Digital separate sync
+        d[17] = 0x18
+            | if self.v_sync_positive { 0x04 } else { 0 }
+            | if self.h_sync_positive { 0x02 } else { 0 };
+        d
+    }
+
+    fn decode(d: &[u8]) -> Self {
+        let join = |lo: u8, hi: u8| lo as u32 | (hi as u32) << 8;
+        Self {
+            pixel_clock_khz: u16::from_le_bytes([d[0], d[1]]) as u32 * 10,
+            h_active: join(d[2], d[4] >> 4),
+            h_blank: join(d[3], d[4] & 0xf),
+            v_active: join(d[5], d[7] >> 4),
+            v_blank: join(d[6], d[7] & 0xf),
+            h_front_porch: join(d[8], d[11] >> 6),
+            h_sync_width: join(d[9], (d[11] >> 4) & 0x3),
+            v_front_porch: (d[10] >> 4) as u32 | ((d[11] as u32 >> 2) & 0x3) << 4,
+            v_sync_width: (d[10] & 0xf) as u32 | (d[11] as u32 & 0x3) << 4,
+            width_mm: join(d[12], d[14] >> 4),
+            height_mm: join(d[13], d[14] & 0xf),
+            h_sync_positive: d[17] & 0x02 != 0,
+            v_sync_positive: d[17] & 0x04 != 0,
+        }
+    }
+}
+
+fn text_descriptor(tag: u8, text: &str) -> [u8; DESCRIPTOR_LEN] {
+    let mut d = [0u8; DESCRIPTOR_LEN];
+    d[3] = tag;
+
+    // Text is ASCII, terminated by a newline if short and padded with spaces
+    let field = &mut d[5..];
+    for b in field.iter_mut() {
+        *b = b' ';
+    }
+    let text: Vec<u8> = text
+        .bytes()
+        .filter(|b| b.is_ascii_graphic() || *b == b' ')
+        .take(MAX_NAME_LEN)
+        .collect();
+    field[..text.len()].copy_from_slice(&text);
+    if text.len() < MAX_NAME_LEN {
+        field[text.len()] = b'\n';
+    }
+    d
+}
+
+fn dummy_descriptor() -> [u8; DESCRIPTOR_LEN] {
+    let mut d = [0u8; DESCRIPTOR_LEN];
+    d[3] = DESCRIPTOR_DUMMY;
+    d
+}
+
+fn decode_text(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == b'\n').unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).trim_end().to_string()
+}
+
+/// Three letters packed as five bit offsets from 'A' - 1.
+fn encode_manufacturer(id: [u8; 3]) -> u16 {
+    id.iter()
+        .fold(0, |acc, c| acc << 5 | (c.to_ascii_uppercase() - b'@') as u16 & 0x1f)
+}
+
+fn decode_manufacturer(packed: u16) -> String {
+    [10, 5, 0]
+        .iter()
+        .map(|shift| (b'@' + ((packed >> shift) & 0x1f) as u8) as char)
+        .collect()
+}
+
+/// The byte that makes the block sum to zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_add(*b))
+        .wrapping_neg()
+}
+
+#[derive(Debug, Error)]
+pub enum EdidError {
+    #[error("EDID is {0} bytes, shorter than a block")]
+    TooShort(usize),
+    #[error("EDID header is invalid")]
+    BadHeader,
+    #[error("EDID checksum is invalid")]
+    BadChecksum,
+    #[error("Can't describe {width}x{height}@{refresh_rate_hz}Hz in an EDID")]
+    UnsupportedMode {
+        width: u32,
+        height: u32,
+        refresh_rate_hz: u32,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ltest]
+    fn builds_valid_block() {
+        let edid = EdidBuilder::new(1920, 1080, 60).build().unwrap();
+        assert_eq!(edid.len(), BLOCK_LEN);
+        assert_eq!(edid[..8], HEADER);
+        let sum = edid.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        assert_eq!(sum, 0);
+    }
+
+    #[ltest]
+    fn round_trips() {
+        let edid = EdidBuilder::new(2560, 1440, 75)
+            .name("Test monitor")
+            .product_code(0x1234)
+            .serial(42)
+            .physical_size_mm(597, 336)
+            .build()
+            .unwrap();
+        let parsed = Edid::parse(&edid).unwrap();
+
+        assert_eq!(parsed.manufacturer, "RMD");
+        assert_eq!(parsed.product_code, 0x1234);
+        assert_eq!(parsed.serial, 42);
+        assert_eq!((parsed.version, parsed.revision), (1, 4));
+        assert_eq!(parsed.name.as_deref(), Some("Test monitor"));
+        assert_eq!(parsed.extensions, 0);
+
+        let timing = parsed.preferred_timing.unwrap();
+        assert_eq!((timing.h_active, timing.v_active), (2560, 1440));
+        assert_eq!((timing.width_mm, timing.height_mm), (597, 336));
+        assert!((timing.refresh_rate_hz() - 75.0).abs() < 0.1);
+    }
+
+    #[ltest]
+    fn round_trips_timing() {
+        let timing = EdidBuilder::new(3840, 2160, 60).timing().unwrap();
+        assert_eq!(DetailedTiming::decode(&timing.encode()), timing);
+    }
+
+    #[ltest]
+    fn matches_cvt_reduced_blanking() {
+        // 1920x1080@60 CVT-RB is 2080x1111 total
+        let timing = EdidBuilder::new(1920, 1080, 60).timing().unwrap();
+        assert_eq!(timing.h_active + timing.h_blank, 2080);
+        assert_eq!(timing.v_active + timing.v_blank, 1111);
+        assert_eq!(timing.v_sync_width, 5);
+    }
+
+    #[ltest]
+    fn truncates_long_names() {
+        let edid = EdidBuilder::new(800, 600, 60)
+            .name("A very long monitor name")
+            .build()
+            .unwrap();
+        assert_eq!(
+            Edid::parse(&edid).unwrap().name.as_deref(),
+            Some("A very long m")
+        );
+    }
+
+    #[ltest]
+    fn rejects_unrepresentable_modes() {
+        assert!(EdidBuilder::new(0, 1080, 60).build().is_err());
+        assert!(EdidBuilder::new(8192, 4320, 60).build().is_err());
+        assert!(EdidBuilder::new(3840, 2160, 240).build().is_err());
+    }
+
+    #[ltest]
+    fn rejects_corrupt() {
+        let mut edid = EdidBuilder::new(1920, 1080, 60).build().unwrap();
+        assert!(matches!(
+            Edid::parse(&edid[..100]),
+            Err(EdidError::TooShort(100))
+        ));
+        edid[60] ^= 1;
+        assert!(matches!(Edid::parse(&edid), Err(EdidError::BadChecksum)));
+        edid[0] = 1;
+        assert!(matches!(Edid::parse(&edid), Err(EdidError::BadHeader)));
+    }
+}
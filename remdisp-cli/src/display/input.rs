@@ -0,0 +1,168 @@
+use sdl2::event::Event;
+use sdl2::mouse::MouseWheelDirection;
+
+use crate::input::{InputEvent, MouseButton};
+
+/// Maps window coordinates to stream coordinates.
+///
+/// The window size is in the points SDL reports mouse positions in, which on HiDPI aren't
+/// pixels, and differs from the stream's while the control catches up with a resize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputMapper {
+    pub window_width: u32,
+    pub window_height: u32,
+    pub stream_width: u32,
+    pub stream_height: u32,
+}
+
+impl InputMapper {
+    pub fn new(
+        window_width: u32,
+        window_height: u32,
+        stream_width: u32,
+        stream_height: u32,
+    ) -> Self {
+        Self {
+            window_width,
+            window_height,
+            stream_width,
+            stream_height,
+        }
+    }
+
+    /// Scales and clamps a point into the stream.
+    pub fn map_point(&self, x: i32, y: i32) -> (u32, u32) {
+        fn map(v: i32, from: u32, to: u32) -> u32 {
+            if from == 0 || to == 0 {
+                return 0;
+            }
+            let v = v.max(0) as u64;
+            let scaled = v * to as u64 / from as u64;
+            scaled.min(to as u64 - 1) as u32
+        }
+
+        (
+            map(x, self.window_width, self.stream_width),
+            map(y, self.window_height, self.stream_height),
+        )
+    }
+
+    /// Translate an SDL event, if it is input we forward.
+    pub fn translate(&self, event: &Event) -> Option<InputEvent> {
+        match *event {
+            // The control machine's own key repeat handles held keys
+            Event::KeyDown {
+                scancode: Some(scancode),
+                repeat: false,
+                ..
+            } => Some(InputEvent::Key {
+                usage: scancode as u32,
+                pressed: true,
+            }),
+            Event::KeyUp {
+                scancode: Some(scancode),
+                ..
+            } => Some(InputEvent::Key {
+                usage: scancode as u32,
+                pressed: false,
+            }),
+            Event::MouseMotion { x, y, .. } => {
+                let (x, y) = self.map_point(x, y);
+                Some(InputEvent::MouseMove { x, y })
+            }
+            Event::MouseButtonDown { mouse_btn, .. } => Some(InputEvent::MouseButton {
+                button: Self::translate_button(mouse_btn)?,
+                pressed: true,
+            }),
+            Event::MouseButtonUp { mouse_btn, .. } => Some(InputEvent::MouseButton {
+                button: Self::translate_button(mouse_btn)?,
+                pressed: false,
+            }),
+            Event::MouseWheel {
+                x, y, direction, ..
+            } => {
+                let (x, y) = match direction {
+                    MouseWheelDirection::Flipped => (-x, -y),
+                    _ => (x, y),
+                };
+                Some(InputEvent::Scroll { x, y })
+            }
+            _ => None,
+        }
+    }
+
+    fn translate_button(button: sdl2::mouse::MouseButton) -> Option<MouseButton> {
+        use sdl2::mouse::MouseButton as Sdl;
+        match button {
+            Sdl::Left => Some(MouseButton::Left),
+            Sdl::Middle => Some(MouseButton::Middle),
+            Sdl::Right => Some(MouseButton::Right),
+            Sdl::X1 => Some(MouseButton::X1),
+            Sdl::X2 => Some(MouseButton::X2),
+            Sdl::Unknown => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use sdl2::keyboard::{Mod, Scancode};
+    use sdl2::mouse::MouseState;
+
+    #[ltest]
+    fn identity_when_sizes_match() {
+        let mapper = InputMapper::new(1920, 1080, 1920, 1080);
+        assert_eq!(mapper.map_point(0, 0), (0, 0));
+        assert_eq!(mapper.map_point(1919, 1079), (1919, 1079));
+    }
+
+    #[ltest]
+    fn scales_and_clamps() {
+        let mapper = InputMapper::new(960, 540, 1920, 1080);
+        assert_eq!(mapper.map_point(480, 270), (960, 540));
+        assert_eq!(mapper.map_point(-5, 2000), (0, 1079));
+    }
+
+    #[ltest]
+    fn translates_keys_without_repeats() {
+        let mapper = InputMapper::new(1, 1, 1, 1);
+        let key_down = |repeat| Event::KeyDown {
+            timestamp: 0,
+            window_id: 0,
+            keycode: None,
+            scancode: Some(Scancode::A),
+            keymod: Mod::NOMOD,
+            repeat,
+        };
+
+        assert_eq!(
+            mapper.translate(&key_down(false)),
+            Some(InputEvent::Key {
+                usage: 0x04,
+                pressed: true
+            })
+        );
+        assert_eq!(mapper.translate(&key_down(true)), None);
+    }
+
+    #[ltest]
+    fn translates_mouse_motion() {
+        let mapper = InputMapper::new(100, 100, 200, 200);
+        let event = Event::MouseMotion {
+            timestamp: 0,
+            window_id: 0,
+            which: 0,
+            mousestate: MouseState::from_sdl_state(0),
+            x: 50,
+            y: 25,
+            xrel: 0,
+            yrel: 0,
+        };
+        assert_eq!(
+            mapper.translate(&event),
+            Some(InputEvent::MouseMove { x: 100, y: 50 })
+        );
+    }
+}
@@ -1,27 +1,46 @@
 use std::fmt::Debug;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use futures::{Stream, TryStreamExt};
 
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::sleep_until;
+use tokio_rustls::TlsAcceptor;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::transport;
+use tonic::transport::{self, ServerTlsConfig};
 use tonic::{Request, Response, Status, Streaming};
 
 use proto::display_control_server::DisplayControlServer as GenDisplayControlServer;
 use proto::*;
 
-use crate::display::displayer::spawn_displayer;
+use crate::auth::token::{constant_time_eq, TOKEN_METADATA};
+use crate::auth::{
+    clean_peer_name, new_pairing_nonce, pairing_code, pairing_commitment, tls, AuthError,
+    Fingerprint, Identity, PairedPeer, PairedPeers, PAIRING_NONCE_LEN, PAIRING_TIMEOUT,
+};
+use crate::discovery::{announce, beacon_targets, local_name};
+use crate::display::displayer::{spawn_displayer, BusyPolicy, VideoAuth};
 use crate::display::info::DisplayInfo;
+use crate::display::pairing::PairingPrompt;
+use crate::display::window::{MonitorSelector, WindowError, WindowOptions};
+use crate::heartbeat::HeartbeatOptions;
+use crate::net::PortRange;
 use crate::prelude::*;
 
 use super::proto;
 
+pub mod adjust;
+pub mod audio;
 pub mod displayer;
 pub mod info;
+pub mod input;
+pub mod pairing;
+pub mod scheduler;
 pub mod window;
 
 #[async_trait]
@@ -29,18 +48,115 @@ pub trait StreamDisplayer: Send + Sync + Debug {
     async fn display(&self, info: DisplayInfo, stream: TcpStream) -> Result<(), Status>;
 }
 
+/// How long an output waits for a control that went away without detaching to come back
+pub const DEFAULT_RESUME_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Configuration for a [`DisplayServer`].
+#[derive(Debug, Clone)]
+pub struct DisplayOptions {
+    /// Monitors to offer as outputs, every monitor if empty
+    pub monitors: Vec<MonitorSelector>,
+    /// Applied to the window of every output
+    pub window: WindowOptions,
+    pub busy_policy: BusyPolicy,
+    pub heartbeat: HeartbeatOptions,
+    /// Zero to close the window as soon as the control is lost
+    pub resume_timeout: Duration,
+    /// Send beacons so controls on the LAN can find us
+    pub announce: bool,
+    /// Where to listen for video streams. The port is replaced with one from `video_ports`.
+    pub video_addr: SocketAddr,
+    /// Ports to try listening for each session's video stream on, any the OS chooses if unset
+    pub video_ports: Option<PortRange>,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        Self {
+            monitors: Vec::new(),
+            window: WindowOptions::default(),
+            busy_policy: BusyPolicy::default(),
+            heartbeat: HeartbeatOptions::default(),
+            resume_timeout: DEFAULT_RESUME_TIMEOUT,
+            announce: true,
+            video_addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into(),
+            video_ports: None,
+        }
+    }
+}
+
+/// How controls authenticate to a [`DisplayServer`].
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub enum DisplayAuth {
+    /// Controls in `paired` can attach over TLS, and others can pair to be added to it
+    Pairing {
+        identity: Identity,
+        paired: PairedPeers,
+    },
+    /// Controls with the same token can attach, without encryption
+    Token(#[derivative(Debug = "ignore")] String),
+}
+
 #[derive(Debug)]
 pub struct DisplayServer {
-    window: mpsc::Sender<displayer::EventChans>,
+    outputs: Vec<displayer::Output>,
+    auth: ServerAuth,
+    /// Shown on the display while set
+    pairing_prompt: watch::Sender<Option<PairingPrompt>>,
+    announce: bool,
+    /// Whether we could open an audio device
+    audio: bool,
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+enum ServerAuth {
+    Pairing(Pairing),
+    Token(#[derivative(Debug = "ignore")] String),
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct Pairing {
+    identity: Identity,
+    paired: Mutex<PairedPeers>,
+    pending: Mutex<Option<PendingPairing>>,
+    #[derivative(Debug = "ignore")]
+    video_tls: TlsAcceptor,
+}
+
+/// A control allowed to make a request.
+struct Authorized {
+    /// For a paired control, what it was called when it paired
+    name: Option<String>,
+    video: VideoAuth,
+}
+
+/// A control pairing with us, which we've sent the commitment to our nonce.
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct PendingPairing {
+    control: Fingerprint,
+    name: String,
+    #[derivative(Debug = "ignore")]
+    nonce: Vec<u8>,
+    /// The user's answer on the display, once we're showing the code
+    answer: Option<watch::Receiver<Option<bool>>>,
+    expires: Instant,
 }
 
 #[tonic::async_trait]
 impl display_control_server::DisplayControl for DisplayServer {
     async fn hello(&self, req: Request<HelloRequest>) -> Result<Response<HelloReply>, Status> {
+        self.authorize(&req)?;
         let requester_version = req.into_inner().version;
         return if requester_version == VERSION {
             Ok(Response::new(HelloReply {
                 version: VERSION.to_string(),
+                outputs: self.output_list(),
+                video_transports: vec![VideoTransport::Tcp as i32, VideoTransport::Udp as i32],
+                audio: self.audio,
             }))
         } else {
             Err(Status::failed_precondition("Incompatible version"))
@@ -54,32 +170,324 @@ impl display_control_server::DisplayControl for DisplayServer {
         &self,
         request: Request<Streaming<ControlEvent>>,
     ) -> Result<Response<Self::AttachStream>, Status> {
-        let recv = request.into_inner();
-        let (tx, control_recv) = mpsc::channel::<Result<DisplayEvent, Status>>(16);
+        let authorized = self.authorize(&request)?;
+        let addr = request.remote_addr();
+        let controller = match (authorized.name, addr) {
+            (Some(name), Some(addr)) => format!("{} ({})", name, addr),
+            (Some(name), None) => name,
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => "an unknown controller".to_string(),
+        };
+        let mut recv = request.into_inner();
+        let attach = match recv.message().await? {
+            Some(ControlEvent {
+                control_event: Some(control_event::ControlEvent::Attach(attach)),
+            }) => attach,
+            _ => return Err(Status::invalid_argument("First event must be Attach")),
+        };
+        let video_transport = VideoTransport::from_i32(attach.video_transport)
+            .ok_or_else(|| Status::invalid_argument("Unknown video transport"))?;
+        let id = attach.output;
+        let output = self
+            .outputs
+            .iter()
+            .find(|output| output.monitor.index == id)
+            .ok_or_else(|| Status::not_found(format!("No output {}", id)))?;
+        info!(
+            id,
+            name = %output.monitor.name,
+            %controller,
+            force = attach.force,
+            transport = ?video_transport,
+            audio = attach.audio,
+            "Attaching to output"
+        );
 
-        self.window
-            .send(displayer::EventChans { tx, recv })
+        let (tx, control_recv) = mpsc::channel::<Result<DisplayEvent, Status>>(16);
+        output
+            .attach
+            .send(displayer::EventChans {
+                tx,
+                recv,
+                controller,
+                force: attach.force,
+                resume: attach.resume,
+                video_auth: authorized.video,
+                control_ip: addr.map(|addr| addr.ip()),
+                video_transport,
+                audio: attach.audio,
+            })
             .await
             .map_err(|_| Status::unavailable("Could not connect to window actor"))?;
 
         Ok(Response::new(Box::pin(ReceiverStream::new(control_recv))))
     }
+
+    async fn start_pairing(
+        &self,
+        request: Request<StartPairingRequest>,
+    ) -> Result<Response<StartPairingReply>, Status> {
+        let pairing = self.pairing()?;
+        let control = peer_fingerprint(&request)?;
+        let name = clean_peer_name(&request.into_inner().name);
+        info!(%name, %control, "Starting pairing");
+
+        let nonce = new_pairing_nonce();
+        let commitment = pairing_commitment(&nonce);
+        // Replaces any earlier attempt, so only the latest code shown works
+        *pairing.pending.lock().unwrap() = Some(PendingPairing {
+            control,
+            name,
+            nonce,
+            answer: None,
+            expires: Instant::now() + PAIRING_TIMEOUT,
+        });
+        let _ = self.pairing_prompt.send(None);
+
+        Ok(Response::new(StartPairingReply { commitment }))
+    }
+
+    async fn finish_pairing(
+        &self,
+        request: Request<FinishPairingRequest>,
+    ) -> Result<Response<FinishPairingReply>, Status> {
+        let pairing = self.pairing()?;
+        let control = peer_fingerprint(&request)?;
+        let control_nonce = request.into_inner().nonce;
+        if control_nonce.len() != PAIRING_NONCE_LEN {
+            return Err(Status::invalid_argument("Wrong length of pairing nonce"));
+        }
+
+        let mut pending = pairing.pending.lock().unwrap();
+        // Only once per commitment, so the control can't try nonces until the codes match
+        let pending = pending
+            .as_mut()
+            .filter(|pending| {
+                pending.control == control
+                    && pending.answer.is_none()
+                    && Instant::now() < pending.expires
+            })
+            .ok_or_else(|| Status::failed_precondition("No pairing in progress"))?;
+        let code = pairing_code(
+            &control,
+            &pairing.identity.fingerprint(),
+            &control_nonce,
+            &pending.nonce,
+        );
+        let (answer_tx, answer_rx) = watch::channel(None);
+        pending.answer = Some(answer_rx);
+        info!(name = %pending.name, %control, "Showing pairing code");
+        let _ = self.pairing_prompt.send(Some(PairingPrompt {
+            code,
+            answer: Arc::new(answer_tx),
+        }));
+
+        Ok(Response::new(FinishPairingReply {
+            nonce: pending.nonce.clone(),
+        }))
+    }
+
+    async fn confirm_pairing(
+        &self,
+        request: Request<ConfirmPairingRequest>,
+    ) -> Result<Response<ConfirmPairingReply>, Status> {
+        let pairing = self.pairing()?;
+        let control = peer_fingerprint(&request)?;
+        let accepted = request.into_inner().accepted;
+
+        let pending = {
+            let mut pending = pairing.pending.lock().unwrap();
+            let showing = pending.as_ref().map_or(false, |pending| {
+                pending.control == control && pending.answer.is_some()
+            });
+            if showing {
+                pending.take()
+            } else {
+                None
+            }
+        };
+        let pending =
+            pending.ok_or_else(|| Status::failed_precondition("No pairing in progress"))?;
+        let mut answer = pending.answer.expect("Only taken while showing the code");
+
+        let accepted = accepted && {
+            // The user has to say the codes match on the display too
+            let answered = tokio::select! {
+                changed = answer.changed() => changed.is_ok(),
+                _ = sleep_until(pending.expires.into()) => false,
+            };
+            answered && *answer.borrow() == Some(true)
+        };
+        let _ = self.pairing_prompt.send(None);
+        if !accepted {
+            warn!(name = %pending.name, %control, "Pairing failed, codes weren't confirmed");
+            return Err(Status::permission_denied(
+                "The codes weren't confirmed to match",
+            ));
+        }
+
+        info!(name = %pending.name, %control, "Paired with control");
+        pairing
+            .paired
+            .lock()
+            .unwrap()
+            .add(PairedPeer {
+                fingerprint: control,
+                name: pending.name,
+            })
+            .map_err(|err| Status::internal(format!("Error saving paired control: {}", err)))?;
+
+        Ok(Response::new(ConfirmPairingReply {}))
+    }
+}
+
+/// The fingerprint of the certificate the control presented.
+fn peer_fingerprint<T>(request: &Request<T>) -> Result<Fingerprint, Status> {
+    request
+        .peer_certs()
+        .and_then(|certs| certs.first().map(|cert| Fingerprint::of(cert.get_ref())))
+        .ok_or_else(|| Status::unauthenticated("No client certificate"))
 }
 
 impl DisplayServer {
-    pub async fn serve(self, addr: SocketAddr) -> Result<(), transport::Error> {
-        transport::Server::builder()
+    pub fn new(options: DisplayOptions, auth: DisplayAuth) -> Result<Self, DisplayServerError> {
+        let auth = match auth {
+            DisplayAuth::Pairing { identity, paired } => {
+                info!(fingerprint = %identity.fingerprint(), "Display identity");
+                let video_tls = TlsAcceptor::from(Arc::new(tls::server_config(&identity)?));
+                ServerAuth::Pairing(Pairing {
+                    identity,
+                    paired: Mutex::new(paired),
+                    pending: Mutex::new(None),
+                    video_tls,
+                })
+            }
+            DisplayAuth::Token(token) => {
+                warn!("Authenticating with a shared token, connections won't be encrypted");
+                ServerAuth::Token(token)
+            }
+        };
+        let (pairing_prompt, pairing_prompt_rx) = watch::channel(None);
+        let announce = options.announce;
+        let displayer = spawn_displayer(options, pairing_prompt_rx)?;
+        Ok(Self {
+            outputs: displayer.outputs,
+            auth,
+            pairing_prompt,
+            announce,
+            audio: displayer.audio,
+        })
+    }
+
+    fn output_list(&self) -> Vec<hello_reply::Output> {
+        self.outputs
+            .iter()
+            .map(|output| hello_reply::Output {
+                id: output.monitor.index,
+                name: output.monitor.name.clone(),
+                width_pixels: output.monitor.bounds.width,
+                height_pixels: output.monitor.bounds.height,
+            })
+            .collect()
+    }
+
+    fn beacon(&self, port: u16) -> Beacon {
+        Beacon {
+            name: local_name(),
+            version: VERSION.to_string(),
+            port: port.into(),
+            outputs: self.output_list(),
+            fingerprint: match &self.auth {
+                ServerAuth::Pairing(pairing) => pairing.identity.fingerprint().to_string(),
+                ServerAuth::Token(_) => String::new(),
+            },
+        }
+    }
+
+    /// Check the control making the request is paired or has the token.
+    fn authorize<T>(&self, request: &Request<T>) -> Result<Authorized, Status> {
+        match &self.auth {
+            ServerAuth::Pairing(pairing) => {
+                let fingerprint = peer_fingerprint(request)?;
+                let peer = pairing
+                    .paired
+                    .lock()
+                    .unwrap()
+                    .find(&fingerprint)
+                    .cloned()
+                    .ok_or_else(|| {
+                        Status::unauthenticated(
+                            "Not paired with this display, run `remdisp pair` first",
+                        )
+                    })?;
+                Ok(Authorized {
+                    name: Some(peer.name),
+                    video: VideoAuth::Tls {
+                        peer: fingerprint,
+                        acceptor: pairing.video_tls.clone(),
+                    },
+                })
+            }
+            ServerAuth::Token(token) => {
+                let given = request
+                    .metadata()
+                    .get(TOKEN_METADATA)
+                    .map(|given| given.as_bytes())
+                    .unwrap_or_default();
+                if !constant_time_eq(given, token.as_bytes()) {
+                    return Err(Status::unauthenticated("Missing or wrong token"));
+                }
+                Ok(Authorized {
+                    name: None,
+                    video: VideoAuth::Nonce,
+                })
+            }
+        }
+    }
+
+    fn pairing(&self) -> Result<&Pairing, Status> {
+        match &self.auth {
+            ServerAuth::Pairing(pairing) => Ok(pairing),
+            ServerAuth::Token(_) => Err(Status::failed_precondition(
+                "Display uses a shared token instead of pairing",
+            )),
+        }
+    }
+
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), DisplayServerError> {
+        if self.announce {
+            let port = addr.port();
+            let beacon = self.beacon(port);
+            tokio::spawn(async move {
+                if let Err(err) = announce(beacon, beacon_targets(port)).await {
+                    warn!(?err, "Stopped announcing display, controls will need its address");
+                }
+            });
+        }
+
+        let mut builder = transport::Server::builder();
+        if let ServerAuth::Pairing(pairing) = &self.auth {
+            let mut config = tls::server_config(&pairing.identity)?;
+            config.set_protocols(&[tls::ALPN_H2.to_vec()]);
+            let mut tls_config = ServerTlsConfig::new();
+            tls_config.rustls_server_config(config);
+            builder = builder.tls_config(tls_config);
+        }
+
+        builder
             .add_service(GenDisplayControlServer::new(self))
             .serve(addr)
-            .await
+            .await?;
+        Ok(())
     }
 }
 
-impl Default for DisplayServer {
-    fn default() -> Self {
-        let (window_tx, window_recv) = mpsc::channel(16);
-        spawn_displayer(window_recv);
-
-        Self { window: window_tx }
-    }
+#[derive(Debug, Error)]
+pub enum DisplayServerError {
+    #[error("Error creating windows")]
+    Window(#[from] WindowError),
+    #[error("Error setting up TLS")]
+    Auth(#[from] AuthError),
+    #[error("Error serving")]
+    Transport(#[from] transport::Error),
 }
@@ -0,0 +1,208 @@
+//! Showing the pairing code, so the user can check the control shows the same one.
+//!
+//! Drawn as seven segment digits as we don't have a font renderer. The user answers with Enter
+//! if the codes match and Escape if not, as the window title says.
+
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use tokio::sync::watch;
+use tokio::time::sleep_until;
+
+use crate::auth::PAIRING_TIMEOUT;
+use crate::display::window::{SdlContext, WindowError};
+use crate::prelude::*;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const DIGIT_WIDTH: u32 = 60;
+const DIGIT_HEIGHT: u32 = 120;
+const SEGMENT_THICKNESS: u32 = 12;
+const DIGIT_GAP: u32 = 30;
+const MARGIN: u32 = 60;
+const BACKGROUND: Color = Color::RGB(32, 32, 32);
+const FOREGROUND: Color = Color::RGB(240, 240, 240);
+
+/// Segments lit for each digit, bit 0 is the top (a) going clockwise to f, then the middle (g)
+const DIGIT_SEGMENTS: [u8; 10] = [0x3f, 0x06, 0x5b, 0x4f, 0x66, 0x6d, 0x7d, 0x07, 0x7f, 0x6f];
+
+/// A code for the user to compare with the control's, and where their answer goes.
+#[derive(Debug, Clone)]
+pub struct PairingPrompt {
+    pub code: String,
+    /// Set to whether the user said the codes match
+    pub answer: Arc<watch::Sender<Option<bool>>>,
+}
+
+/// Show the prompt in the channel whenever there is one, until it's answered, cleared or
+/// expires.
+///
+/// Must run on the displayer thread.
+pub async fn run_pairing_window(mut prompt: watch::Receiver<Option<PairingPrompt>>) {
+    loop {
+        let shown = prompt.borrow().clone();
+        if let Some(shown) = shown {
+            match show_until_changed(&shown, &mut prompt).await {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(err) => warn!(?err, "Error showing pairing code"),
+            }
+        }
+
+        if prompt.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Returns whether the prompt changed, as opposed to being answered or expiring.
+async fn show_until_changed(
+    prompt: &PairingPrompt,
+    changes: &mut watch::Receiver<Option<PairingPrompt>>,
+) -> Result<bool, WindowError> {
+    let mut window = PairingWindow::show(&prompt.code)?;
+    let expires = Instant::now() + PAIRING_TIMEOUT;
+    let mut poll_interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            changed = changes.changed() => return Ok(changed.is_ok()),
+            _ = sleep_until(expires.into()) => {
+                info!("Pairing code expired");
+                return Ok(false);
+            },
+            _ = poll_interval.tick() => {
+                if let Some(accepted) = window.poll()? {
+                    info!(accepted, "User answered whether the pairing codes match");
+                    let _ = prompt.answer.send(Some(accepted));
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
+
+struct PairingWindow {
+    canvas: sdl2::render::WindowCanvas,
+    window_id: u32,
+    code: String,
+    /// Last so the window is destroyed before SDL is shut down
+    sdl: Rc<SdlContext>,
+}
+
+impl PairingWindow {
+    fn show(code: &str) -> Result<Self, WindowError> {
+        let sdl = SdlContext::get()?;
+        let digits = code.len() as u32;
+        let width = MARGIN * 2 + digits * DIGIT_WIDTH + digits.saturating_sub(1) * DIGIT_GAP;
+        let height = MARGIN * 2 + DIGIT_HEIGHT;
+
+        let mut window = sdl
+            .video
+            .window(
+                "Pairing: Enter if the control shows this code, Escape if not",
+                width,
+                height,
+            )
+            .position_centered()
+            .build()?;
+        window.show();
+        let window_id = window.id();
+        let canvas = window.into_canvas().build()?;
+        sdl.register(window_id);
+
+        let mut created = Self {
+            canvas,
+            window_id,
+            code: code.to_string(),
+            sdl,
+        };
+        created.draw()?;
+        Ok(created)
+    }
+
+    /// Keep the window responsive, redrawing in case it was covered. Returns the user's answer
+    /// once they give one.
+    fn poll(&mut self) -> Result<Option<bool>, WindowError> {
+        let events = self.sdl.poll(self.window_id);
+        if events.is_empty() {
+            return Ok(None);
+        }
+        self.draw()?;
+
+        Ok(events.iter().find_map(|event| match event {
+            Event::KeyDown {
+                keycode: Some(keycode),
+                ..
+            } => match keycode {
+                Keycode::Return | Keycode::KpEnter => Some(true),
+                Keycode::Escape => Some(false),
+                _ => None,
+            },
+            _ => None,
+        }))
+    }
+
+    fn draw(&mut self) -> Result<(), WindowError> {
+        self.canvas.set_draw_color(BACKGROUND);
+        self.canvas.clear();
+        self.canvas.set_draw_color(FOREGROUND);
+        for (i, digit) in self.code.chars().enumerate() {
+            let x = MARGIN + i as u32 * (DIGIT_WIDTH + DIGIT_GAP);
+            let rects = digit_segments(digit, x as i32, MARGIN as i32);
+            self.canvas.fill_rects(&rects)?;
+        }
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+impl Drop for PairingWindow {
+    fn drop(&mut self) {
+        self.sdl.unregister(self.window_id);
+    }
+}
+
+/// The rectangles to fill to draw a digit with its top left at x, y. Nothing for non-digits.
+fn digit_segments(digit: char, x: i32, y: i32) -> Vec<Rect> {
+    let lit = match digit.to_digit(10) {
+        Some(digit) => DIGIT_SEGMENTS[digit as usize],
+        None => return vec![],
+    };
+    let (w, h, t) = (DIGIT_WIDTH, DIGIT_HEIGHT, SEGMENT_THICKNESS);
+    let right = x + (w - t) as i32;
+    let middle = y + (h / 2) as i32;
+    let segments = [
+        Rect::new(x, y, w, t), // a
+        Rect::new(right, y, t, h / 2), // b
+        Rect::new(right, middle, t, h / 2), // c
+        Rect::new(x, y + (h - t) as i32, w, t), // d
+        Rect::new(x, middle, t, h / 2), // e
+        Rect::new(x, y, t, h / 2), // f
+        Rect::new(x, middle - (t / 2) as i32, w, t), // g
+    ];
+    segments
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| lit & (1 << i) != 0)
+        .map(|(_, rect)| *rect)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ltest]
+    fn draws_digits() {
+        assert_eq!(digit_segments('8', 0, 0).len(), 7);
+        assert_eq!(
+            digit_segments('1', 0, 0),
+            vec![Rect::new(48, 0, 12, 60), Rect::new(48, 60, 12, 60)]
+        );
+        assert!(digit_segments('x', 0, 0).is_empty());
+    }
+}
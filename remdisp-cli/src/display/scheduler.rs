@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::prelude::*;
+
+/// Decides when decoded frames should be presented.
+///
+/// Frames are paced by their stream timestamps relative to the first frame, and never more
+/// often than once per display refresh. When we fall behind (for example because a burst of
+/// packets arrives after a network stall) every frame that is already due except the newest
+/// is dropped, so playback catches up instead of fast-forwarding.
+#[derive(Debug)]
+pub struct PresentationScheduler<T> {
+    refresh_interval: Duration,
+    max_queued: usize,
+    /// Maps stream time to local time. Set by the first frame.
+    anchor: Option<(Duration, Instant)>,
+    last_presented: Option<Instant>,
+    queue: VecDeque<(Instant, T)>,
+    stats: PresentationStats,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PresentationStats {
+    pub presented: u64,
+    /// Frames skipped because a newer frame was already due
+    pub dropped_late: u64,
+    /// Frames discarded because too many were waiting to be presented
+    pub dropped_overflow: u64,
+}
+
+impl<T> PresentationScheduler<T> {
+    pub const DEFAULT_MAX_QUEUED: usize = 8;
+
+    pub fn new(refresh_interval: Duration) -> Self {
+        Self::with_max_queued(refresh_interval, Self::DEFAULT_MAX_QUEUED)
+    }
+
+    pub fn with_max_queued(refresh_interval: Duration, max_queued: usize) -> Self {
+        assert!(max_queued > 0, "Must be able to queue at least one frame");
+        Self {
+            refresh_interval,
+            max_queued,
+            anchor: None,
+            last_presented: None,
+            queue: VecDeque::new(),
+            stats: PresentationStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, timestamp: Duration, frame: T, now: Instant) {
+        let due = self.due_at(timestamp, now);
+
+        // Timestamps should be increasing, but keep the queue sorted if they aren't
+        let idx = self.queue.iter().take_while(|(at, _)| *at <= due).count();
+        self.queue.insert(idx, (due, frame));
+
+        while self.queue.len() > self.max_queued {
+            self.queue.pop_front();
+            self.stats.dropped_overflow += 1;
+        }
+    }
+
+    /// When something stamped `timestamp` should be presented, such as audio to go with the
+    /// frames. The first call, here or through [`Self::push`], sets the mapping to local time.
+    pub fn due_at(&mut self, timestamp: Duration, now: Instant) -> Instant {
+        let (anchor_ts, anchor_at) = *self.anchor.get_or_insert((timestamp, now));
+
+        // Frames stamped before the anchor (e.g. reordered around it) are due immediately
+        anchor_at + timestamp.checked_sub(anchor_ts).unwrap_or_default()
+    }
+
+    /// When [`Self::pop_due`] will next return a frame, if any are queued.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let (due, _) = self.queue.front()?;
+        Some(match self.last_presented {
+            Some(last) => (*due).max(last + self.refresh_interval),
+            None => *due,
+        })
+    }
+
+    /// The newest frame due at `now`, if any. Older due frames are dropped.
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        if now < self.next_deadline()? {
+            return None;
+        }
+
+        let mut newest = None;
+        while let Some((due, _)) = self.queue.front() {
+            if *due > now {
+                break;
+            }
+            let (_, frame) = self.queue.pop_front().unwrap();
+            if newest.replace(frame).is_some() {
+                self.stats.dropped_late += 1;
+            }
+        }
+
+        if newest.is_some() {
+            self.stats.presented += 1;
+            self.last_presented = Some(now);
+        }
+        newest
+    }
+
+    pub fn stats(&self) -> PresentationStats {
+        self.stats
+    }
+}
+
+impl PresentationStats {
+    pub fn dropped(&self) -> u64 {
+        self.dropped_late + self.dropped_overflow
+    }
+
+    pub fn log(&self) {
+        info!(
+            presented = self.presented,
+            dropped_late = self.dropped_late,
+            dropped_overflow = self.dropped_overflow,
+            "Presentation stats"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFRESH: Duration = Duration::from_millis(10);
+    const FRAME: Duration = Duration::from_millis(40);
+
+    fn scheduler_fixture() -> PresentationScheduler<u32> {
+        PresentationScheduler::new(REFRESH)
+    }
+
+    #[ltest]
+    fn presents_frames_at_their_timestamps() {
+        let mut scheduler = scheduler_fixture();
+        let start = Instant::now();
+
+        scheduler.push(Duration::ZERO, 0, start);
+        scheduler.push(FRAME, 1, start);
+
+        assert_eq!(scheduler.pop_due(start), Some(0));
+        assert_eq!(scheduler.next_deadline(), Some(start + FRAME));
+        assert_eq!(scheduler.pop_due(start + FRAME / 2), None);
+        assert_eq!(scheduler.pop_due(start + FRAME), Some(1));
+        assert_eq!(scheduler.next_deadline(), None);
+        assert_eq!(scheduler.stats().dropped(), 0);
+    }
+
+    #[ltest]
+    fn drops_stale_frames_after_burst() {
+        let mut scheduler = scheduler_fixture();
+        let start = Instant::now();
+        scheduler.push(Duration::ZERO, 0, start);
+        assert_eq!(scheduler.pop_due(start), Some(0));
+
+        // Stall, then everything arrives at once
+        let late = start + FRAME * 5;
+        for n in 1..5 {
+            scheduler.push(FRAME * n, n, late);
+        }
+        scheduler.push(FRAME * 6, 6, late);
+
+        assert_eq!(scheduler.pop_due(late), Some(4));
+        assert_eq!(scheduler.stats().dropped_late, 3);
+        assert_eq!(scheduler.next_deadline(), Some(start + FRAME * 6));
+    }
+
+    #[ltest]
+    fn presents_at_most_once_per_refresh() {
+        let mut scheduler = scheduler_fixture();
+        let start = Instant::now();
+
+        scheduler.push(Duration::ZERO, 0, start);
+        assert_eq!(scheduler.pop_due(start), Some(0));
+
+        scheduler.push(REFRESH / 2, 1, start);
+        assert_eq!(scheduler.next_deadline(), Some(start + REFRESH));
+        assert_eq!(scheduler.pop_due(start + REFRESH / 2), None);
+        assert_eq!(scheduler.pop_due(start + REFRESH), Some(1));
+    }
+
+    #[ltest]
+    fn shares_anchor_with_audio() {
+        let mut scheduler = scheduler_fixture();
+        let start = Instant::now();
+
+        // Audio arriving first anchors the stream, and frames follow the same clock
+        assert_eq!(scheduler.due_at(FRAME, start), start);
+        scheduler.push(FRAME * 2, 0, start + FRAME / 2);
+        assert_eq!(scheduler.next_deadline(), Some(start + FRAME));
+        assert_eq!(
+            scheduler.due_at(FRAME * 3, start + FRAME),
+            start + FRAME * 2
+        );
+    }
+
+    #[ltest]
+    fn drops_oldest_when_queue_full() {
+        let mut scheduler = PresentationScheduler::with_max_queued(REFRESH, 2);
+        let start = Instant::now();
+
+        for n in 0..4 {
+            scheduler.push(FRAME * n, n, start);
+        }
+
+        assert_eq!(scheduler.stats().dropped_overflow, 2);
+        assert_eq!(scheduler.pop_due(start + FRAME * 2), Some(2));
+    }
+}
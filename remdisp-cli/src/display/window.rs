@@ -2,10 +2,35 @@ use sdl2::render::TextureValueError;
 use sdl2::video::WindowBuildError;
 use sdl2::IntegerOrSdlError;
 
+use crate::adjust::Adjustments;
 use crate::av::yuv_frame::YuvFrame;
-use crate::display::info::DisplayInfo;
+use crate::cursor::{CursorShape, CursorUpdate};
+use crate::display::adjust::{gamma_ramp, monitor_backend, MonitorAdjuster};
+use crate::display::info::{
+    DisplayInfo, Edid, EdidBuilder, EdidError, PlatformWindowHandle, ScreenRect,
+    DEFAULT_REFRESH_RATE_HZ,
+};
+use crate::display::input::InputMapper;
+use crate::input::InputEvent;
 use crate::prelude::*;
-use std::fmt::{Debug, Formatter};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::video::FullscreenType;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::{self, Debug, Formatter};
+use std::rc::{Rc, Weak};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Used for windowed mode if no size is given, shrunk to fit the monitor
+pub const DEFAULT_WINDOW_SIZE: (u32, u32) = (1280, 720);
+/// How long the window size must be stable before we ask the control to match it, so dragging
+/// the window edge doesn't renegotiate on every frame
+const RESIZE_SETTLE: Duration = Duration::from_millis(500);
+/// Brightness of the last frame while waiting for the control to reconnect, out of 255
+const RECONNECTING_DIM: u8 = 80;
 
 /// Permitted flow
 /// - create
@@ -18,30 +43,263 @@ pub trait Window: Debug {
     /// Update the window with new pixels. Must be called after create.
     fn update(&mut self, frame: YuvFrame) -> Result<(), WindowError>;
 
+    /// Does nothing if the window isn't created, so a session that ended early can be cleaned
+    /// up without knowing how far it got.
     fn close(&mut self) -> Result<(), WindowError>;
+
+    /// Draw the control's cursor over the last frame. Doesn't wait for the next frame.
+    fn update_cursor(&mut self, update: CursorUpdate) -> Result<(), WindowError>;
+
+    /// Show that we lost the control and are waiting for it to come back, by dimming the last
+    /// frame. Cleared by the next update.
+    fn show_reconnecting(&mut self) -> Result<(), WindowError>;
+
+    /// Carry out the control's adjustments to the monitor the window is on, or where the
+    /// monitor can't, fake them in how frames are drawn. Must be called after create.
+    fn adjust(&mut self, adjustments: Adjustments) -> Result<(), WindowError>;
+
+    /// Keyboard and mouse input since the last call, in stream coordinates. Must be called
+    /// after create, and often, as this is also what keeps the window responsive.
+    fn poll_input(&mut self) -> Vec<InputEvent>;
+
+    /// What to advertise to the control if the window has been resized since the last call.
+    ///
+    /// Updated by [`Self::poll_input`]. Frames of the old size are still shown, scaled, until
+    /// the control sends frames of the new size.
+    fn take_resize(&mut self) -> Result<Option<DisplayInfo>, WindowError>;
 }
 
 pub struct SdlWindow {
+    options: WindowOptions,
     created: Option<CreatedSdlWindow>,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct WindowOptions {
+    /// The first monitor if None
+    pub monitor: Option<MonitorSelector>,
+    /// Start in a resizable window instead of fullscreen. Ctrl+Alt+F toggles between them.
+    pub windowed: bool,
+    /// Initial size when windowed, [`DEFAULT_WINDOW_SIZE`] if None
+    pub window_size: Option<(u32, u32)>,
+}
+
+/// Which physical monitor to show the window on, as given by the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MonitorSelector {
+    Index(u32),
+    /// Matched case-insensitively against the names in [`list_monitors`]
+    Name(String),
+}
+
+impl MonitorSelector {
+    pub fn select<'a>(&self, monitors: &'a [MonitorInfo]) -> Option<&'a MonitorInfo> {
+        match self {
+            Self::Index(index) => monitors.iter().find(|m| m.index == *index),
+            Self::Name(name) => monitors.iter().find(|m| m.name.eq_ignore_ascii_case(name)),
+        }
+    }
+}
+
+impl FromStr for MonitorSelector {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => Self::Index(index),
+            Err(_) => Self::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for MonitorSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{}", index),
+            Self::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonitorInfo {
+    pub index: u32,
+    pub name: String,
+    pub bounds: ScreenRect,
+    /// None if the platform didn't report one
+    pub refresh_rate_hz: Option<u32>,
+}
+
+impl fmt::Display for MonitorInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {}x{} at ({}, {})",
+            self.index,
+            self.name,
+            self.bounds.width,
+            self.bounds.height,
+            self.bounds.x,
+            self.bounds.y
+        )?;
+        if let Some(hz) = self.refresh_rate_hz {
+            write!(f, " {}Hz", hz)?;
+        }
+        Ok(())
+    }
+}
+
+/// The monitors the window could be shown on.
+///
+/// Like windows this must be called from the thread that shows them.
+pub fn list_monitors() -> Result<Vec<MonitorInfo>, WindowError> {
+    monitors(&SdlContext::get()?.video)
+}
+
+fn monitors(video: &sdl2::VideoSubsystem) -> Result<Vec<MonitorInfo>, WindowError> {
+    (0..video.num_video_displays()?)
+        .map(|index| {
+            let bounds = video.display_bounds(index)?;
+            let refresh_rate_hz = match video.current_display_mode(index)?.refresh_rate {
+                0 => None, // SDL reports 0 for unspecified
+                rate => Some(rate as u32),
+            };
+            Ok(MonitorInfo {
+                index: index as u32,
+                name: video.display_name(index)?,
+                bounds: ScreenRect {
+                    x: bounds.x(),
+                    y: bounds.y(),
+                    width: bounds.width(),
+                    height: bounds.height(),
+                },
+                refresh_rate_hz,
+            })
+        })
+        .collect()
+}
+
 #[derive(Error, Debug)]
 pub enum WindowError {
     #[error("Sdl error: {0}")]
     Sdl(String),
+    #[error("Error creating EDID for display")]
+    Edid(#[from] EdidError),
+    #[error("No monitor {0}, see --list-monitors")]
+    NoSuchMonitor(MonitorSelector),
 }
 
-struct CreatedSdlWindow {
+/// SDL can only be used from the thread that initialized it and has a single event queue, so
+/// every window on a thread shares one of these.
+pub(super) struct SdlContext {
     ctx: sdl2::Sdl,
-    video: sdl2::VideoSubsystem,
+    pub(super) video: sdl2::VideoSubsystem,
+    event_pump: RefCell<sdl2::EventPump>,
+    /// Events polled but not yet taken, by window id. Only windows that are still open have an
+    /// entry, so events for closed windows are dropped.
+    pending: RefCell<HashMap<u32, Vec<Event>>>,
+}
+
+thread_local! {
+    // Weak so SDL shuts down once the last window closes, as it did before windows shared it
+    static SDL_CONTEXT: RefCell<Weak<SdlContext>> = RefCell::new(Weak::new());
+}
+
+impl SdlContext {
+    pub(super) fn get() -> Result<Rc<Self>, WindowError> {
+        SDL_CONTEXT.with(|cell| {
+            if let Some(existing) = cell.borrow().upgrade() {
+                return Ok(existing);
+            }
+
+            let ctx = sdl2::init()?;
+            let video = ctx.video()?;
+            let event_pump = ctx.event_pump()?;
+            let created = Rc::new(Self {
+                ctx,
+                video,
+                event_pump: RefCell::new(event_pump),
+                pending: RefCell::new(HashMap::new()),
+            });
+            *cell.borrow_mut() = Rc::downgrade(&created);
+            Ok(created)
+        })
+    }
+
+    pub(super) fn audio(&self) -> Result<sdl2::AudioSubsystem, WindowError> {
+        Ok(self.ctx.audio()?)
+    }
+
+    pub(super) fn register(&self, window_id: u32) {
+        self.pending.borrow_mut().insert(window_id, Vec::new());
+    }
+
+    pub(super) fn unregister(&self, window_id: u32) {
+        self.pending.borrow_mut().remove(&window_id);
+    }
+
+    /// Pump SDL's queue and take the events for one window. Events not for any window, such
+    /// as quit, are dropped.
+    pub(super) fn poll(&self, window_id: u32) -> Vec<Event> {
+        let mut pending = self.pending.borrow_mut();
+        for event in self.event_pump.borrow_mut().poll_iter() {
+            if let Some(queue) = event.get_window_id().and_then(|id| pending.get_mut(&id)) {
+                queue.push(event);
+            }
+        }
+        pending
+            .get_mut(&window_id)
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+struct CreatedSdlWindow {
     canvas: sdl2::render::WindowCanvas,
     texture: sdl2::render::Texture,
     pixel_buf: Vec<u8>,
+    window_id: u32,
+    input_mapper: InputMapper,
+    cursor: Option<CursorOverlay>,
+    cursor_pos: (i32, i32),
+    /// Until then the frame texture is uninitialized
+    has_frame: bool,
+    texture_size: (u32, u32),
+    monitor: MonitorInfo,
+    /// None if it couldn't be read
+    monitor_edid: Option<Vec<u8>>,
+    /// What we last advertised to the control
+    stream_size: (u32, u32),
+    /// When the window size last changed, if we haven't advertised it yet
+    resized_at: Option<Instant>,
+    reconnecting: bool,
+    adjuster: MonitorAdjuster,
+    /// Last so the window is destroyed before SDL is shut down
+    sdl: Rc<SdlContext>,
+}
+
+impl Drop for CreatedSdlWindow {
+    fn drop(&mut self) {
+        self.sdl.unregister(self.window_id);
+    }
+}
+
+struct CursorOverlay {
+    texture: sdl2::render::Texture,
+    width: u32,
+    height: u32,
 }
 
 impl SdlWindow {
     pub fn new() -> Self {
-        Self { created: None }
+        Self::with_options(WindowOptions::default())
+    }
+
+    pub fn with_options(options: WindowOptions) -> Self {
+        Self {
+            options,
+            created: None,
+        }
     }
 
     fn expect_created(&mut self) -> &mut CreatedSdlWindow {
@@ -51,6 +309,92 @@ impl SdlWindow {
     }
 }
 
+impl SdlWindow {
+    /// The EDID of the monitor we're on, if it can be read and is valid.
+    fn read_edid(video: &sdl2::VideoSubsystem, window: &sdl2::video::Window) -> Option<Vec<u8>> {
+        let bounds = match window
+            .display_index()
+            .and_then(|index| video.display_bounds(index))
+        {
+            Ok(bounds) => ScreenRect {
+                x: bounds.x(),
+                y: bounds.y(),
+                width: bounds.width(),
+                height: bounds.height(),
+            },
+            Err(err) => {
+                warn!(?err, "Failed to get the monitor the window is on");
+                return None;
+            }
+        };
+
+        let handle = match platform_handle(window) {
+            Some(handle) => handle,
+            None => {
+                warn!("Window system not supported for reading EDIDs");
+                return None;
+            }
+        };
+
+        let edid = match DisplayInfo::read_edid(handle, bounds) {
+            Ok(edid) => edid,
+            Err(err) => {
+                warn!(?err, "Failed to read EDID");
+                return None;
+            }
+        };
+
+        match Edid::parse(&edid) {
+            Ok(parsed) => {
+                info!(edid = %parsed, "Read EDID of monitor");
+                Some(edid)
+            }
+            Err(err) => {
+                warn!(?err, "Monitor has an invalid EDID");
+                None
+            }
+        }
+    }
+
+    /// Describes our mode so the control can still create a matching virtual monitor.
+    fn synthesize_edid(
+        width: u32,
+        height: u32,
+        refresh_rate_hz: Option<u32>,
+    ) -> Result<Vec<u8>, EdidError> {
+        let refresh_rate_hz = refresh_rate_hz.unwrap_or(DEFAULT_REFRESH_RATE_HZ);
+        let edid = EdidBuilder::new(width, height, refresh_rate_hz).build()?;
+        info!(width, height, refresh_rate_hz, "Using synthesized EDID");
+        Ok(edid)
+    }
+}
+
+fn platform_handle(window: &sdl2::video::Window) -> Option<PlatformWindowHandle> {
+    use sdl2::sys;
+
+    let mut info: sys::SDL_SysWMinfo = unsafe { std::mem::zeroed() };
+    info.version = sys::SDL_version {
+        major: sys::SDL_MAJOR_VERSION as u8,
+        minor: sys::SDL_MINOR_VERSION as u8,
+        patch: sys::SDL_PATCHLEVEL as u8,
+    };
+    if unsafe { sys::SDL_GetWindowWMInfo(window.raw(), &mut info) } != sys::SDL_bool::SDL_TRUE {
+        return None;
+    }
+
+    match info.subsystem {
+        #[cfg(target_os = "linux")]
+        sys::SDL_SYSWM_TYPE::SDL_SYSWM_X11 => Some(PlatformWindowHandle::new_linux(
+            unsafe { info.info.x11.window } as *mut libc::c_void,
+        )),
+        #[cfg(target_os = "windows")]
+        sys::SDL_SYSWM_TYPE::SDL_SYSWM_WINDOWS => Some(PlatformWindowHandle::new_windows(
+            unsafe { info.info.win.window }.cast(),
+        )),
+        _ => None,
+    }
+}
+
 impl Default for SdlWindow {
     fn default() -> Self {
         Self::new()
@@ -65,55 +409,105 @@ impl Window for SdlWindow {
     fn create(&mut self) -> Result<DisplayInfo, WindowError> {
         assert!(self.created.is_none(), "Already created");
 
-        let ctx = sdl2::init()?;
-        let video = ctx.video()?;
+        let sdl = SdlContext::get()?;
+        let video = &sdl.video;
+
+        let monitors = monitors(video)?;
+        info!("{} displays connected", monitors.len());
+
+        let monitor = match &self.options.monitor {
+            Some(selector) => selector
+                .select(&monitors)
+                .ok_or_else(|| WindowError::NoSuchMonitor(selector.clone()))?,
+            None => monitors
+                .first()
+                .ok_or_else(|| WindowError::NoSuchMonitor(MonitorSelector::Index(0)))?,
+        };
+        info!(%monitor, "Using monitor");
+
+        let monitor = monitor.clone();
+
+        let mut builder = if self.options.windowed {
+            let (width, height) = self.options.window_size.unwrap_or(DEFAULT_WINDOW_SIZE);
+            let width = width.min(monitor.bounds.width);
+            let height = height.min(monitor.bounds.height);
+            let mut builder = video.window("Remote Display", width, height);
+            builder
+                .position(
+                    monitor.bounds.x + ((monitor.bounds.width - width) / 2) as i32,
+                    monitor.bounds.y + ((monitor.bounds.height - height) / 2) as i32,
+                )
+                .resizable()
+                .allow_highdpi();
+            builder
+        } else {
+            // Fullscreen uses whichever monitor the window starts on
+            let mut builder =
+                video.window("Remote Display", monitor.bounds.width, monitor.bounds.height);
+            builder
+                .position(monitor.bounds.x, monitor.bounds.y)
+                .fullscreen_desktop();
+            builder
+        };
+        let mut window = builder.build()?;
+        window.show();
 
-        let num_displays = video.num_video_displays()?;
-        info!("{} displays connected", num_displays);
+        let monitor_edid = Self::read_edid(video, &window);
+        let adjuster = MonitorAdjuster::new(platform_handle(&window).and_then(monitor_backend));
+        let window_id = window.id();
 
-        let bounds = video.display_bounds(0)?;
-        let width = bounds.w as u32;
-        let height = bounds.h as u32;
+        let mut canvas = window.into_canvas().build()?;
+        let (width, height) = if self.options.windowed {
+            stream_size(&canvas)
+        } else {
+            (monitor.bounds.width, monitor.bounds.height)
+        };
 
-        let mut window = video
-            .window("Remote Display", 0, 0)
-            .fullscreen_desktop()
-            .build()?;
-        window.show();
+        let texture = create_frame_texture(&mut canvas, width, height)?;
 
-        let mut canvas = window.into_canvas().build()?;
-        canvas.set_logical_size(width, height)?;
-
-        // Format corresponds to AV YUV240P
-        // See <https://github.com/FFmpeg/FFmpeg/blob/master/fftools/ffplay.c#L391>
-        let format = sdl2::pixels::PixelFormatEnum::IYUV;
-
-        let texture_creator = canvas.texture_creator();
-        let texture = texture_creator.create_texture(
-            Some(format),
-            sdl2::render::TextureAccess::Streaming,
-            width,
-            height,
-        )?;
+        // The control's cursor is drawn as an overlay instead
+        sdl.ctx.mouse().show_cursor(false);
+
+        let input_mapper = input_mapper(&canvas, (width, height));
 
-        self.created = Some(CreatedSdlWindow {
-            ctx,
-            video,
+        sdl.register(window_id);
+        let created = CreatedSdlWindow {
             canvas,
             texture,
             pixel_buf: Vec::new(),
-        });
-
-        Ok(DisplayInfo {
-            edid: vec![],
-            width_pixels: width,
-            height_pixels: height,
-        })
+            window_id,
+            input_mapper,
+            cursor: None,
+            cursor_pos: (0, 0),
+            has_frame: false,
+            texture_size: (width, height),
+            monitor,
+            monitor_edid,
+            stream_size: (width, height),
+            resized_at: None,
+            reconnecting: false,
+            adjuster,
+            sdl,
+        };
+        let info = created.display_info()?;
+        self.created = Some(created);
+
+        Ok(info)
     }
 
     fn update(&mut self, frame: YuvFrame) -> Result<(), WindowError> {
         let this = self.expect_created();
 
+        let frame_size = (frame.width as u32, frame.height as u32);
+        if frame_size != this.texture_size {
+            info!(?frame_size, "Stream size changed");
+            let texture = create_frame_texture(&mut this.canvas, frame_size.0, frame_size.1)?;
+            let old = std::mem::replace(&mut this.texture, texture);
+            unsafe { old.destroy() };
+            this.texture_size = frame_size;
+            this.input_mapper = input_mapper(&this.canvas, frame_size);
+        }
+
         let uv_pitch = frame.uv_linesize;
         // let uv_pitch = frame.uv_linesize * 2;
 
@@ -126,24 +520,251 @@ impl Window for SdlWindow {
             &frame.v,
             uv_pitch,
         )?;
+        this.has_frame = true;
+        if this.reconnecting {
+            this.texture.set_color_mod(255, 255, 255);
+            this.reconnecting = false;
+        }
 
-        this.canvas.clear();
-        this.canvas.copy(&this.texture, None, None)?; // None means entire window
-        this.canvas.present();
-
-        Ok(())
+        this.redraw()
     }
 
     fn close(&mut self) -> Result<(), WindowError> {
         // Dropping closes window
-        self.created.take().expect("Must be created to destroy");
+        self.created = None;
+        Ok(())
+    }
+
+    fn update_cursor(&mut self, update: CursorUpdate) -> Result<(), WindowError> {
+        let this = self.expect_created();
+        match update {
+            CursorUpdate::Shape(shape) => this.set_cursor_shape(&shape)?,
+            CursorUpdate::Move { x, y } => this.cursor_pos = (x, y),
+        }
+        if this.has_frame {
+            this.redraw()?;
+        }
+        Ok(())
+    }
+
+    fn show_reconnecting(&mut self) -> Result<(), WindowError> {
+        let this = self.expect_created();
+        this.reconnecting = true;
+        if this.has_frame {
+            this.texture.set_color_mod(RECONNECTING_DIM, RECONNECTING_DIM, RECONNECTING_DIM);
+            this.redraw()
+        } else {
+            this.canvas.set_draw_color(sdl2::pixels::Color::RGB(32, 32, 32));
+            this.canvas.clear();
+            this.canvas.present();
+            this.canvas.set_draw_color(sdl2::pixels::Color::BLACK);
+            Ok(())
+        }
+    }
+
+    fn adjust(&mut self, adjustments: Adjustments) -> Result<(), WindowError> {
+        let this = self.expect_created();
+        if !this.adjuster.apply(adjustments) {
+            return Ok(());
+        }
+        let software = this.adjuster.software();
+        info!(?software, "Adjusting how the stream is drawn");
+
+        // Changes the whole monitor, which is what the control asked for. SDL puts it back when
+        // the window closes.
+        let ramp = gamma_ramp(software.brightness, software.contrast);
+        if let Err(err) = this.canvas.window().set_gamma_ramp(&ramp, &ramp, &ramp) {
+            warn!(%err, "Failed to set gamma, brightness and contrast won't change");
+        }
+
+        if this.has_frame || software.blank {
+            this.redraw()?;
+        }
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> Vec<InputEvent> {
+        let this = self.expect_created();
+        let mut mapper = this.input_mapper;
+
+        let mut input = vec![];
+        let mut toggle_fullscreen = false;
+        for event in this.sdl.poll(this.window_id) {
+            match event {
+                Event::KeyDown {
+                    keycode: Some(Keycode::F),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if is_hotkey_mod(keymod) => toggle_fullscreen = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::F),
+                    keymod,
+                    ..
+                } if is_hotkey_mod(keymod) => (),
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                } => {
+                    this.resized_at = Some(Instant::now());
+                    // Until the control catches up the old stream is stretched over the window
+                    mapper = input_mapper(&this.canvas, this.texture_size);
+                    this.input_mapper = mapper;
+                }
+                event => input.extend(mapper.translate(&event)),
+            }
+        }
+
+        if toggle_fullscreen {
+            if let Err(err) = this.toggle_fullscreen() {
+                warn!(?err, "Failed to toggle fullscreen");
+            }
+        }
+
+        input
+    }
+
+    fn take_resize(&mut self) -> Result<Option<DisplayInfo>, WindowError> {
+        let this = self.expect_created();
+        match this.resized_at {
+            Some(at) if at.elapsed() >= RESIZE_SETTLE => this.resized_at = None,
+            _ => return Ok(None),
+        }
+
+        let size = stream_size(&this.canvas);
+        if size == this.stream_size {
+            return Ok(None);
+        }
+        info!(from = ?this.stream_size, to = ?size, "Window resized");
+        this.stream_size = size;
+        this.display_info().map(Some)
+    }
+}
+
+fn is_hotkey_mod(keymod: Mod) -> bool {
+    keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD)
+        && keymod.intersects(Mod::LALTMOD | Mod::RALTMOD)
+}
+
+/// The size of the window in pixels, rounded down to what encoders are happy with.
+fn stream_size(canvas: &sdl2::render::WindowCanvas) -> (u32, u32) {
+    let (width, height) = canvas.window().drawable_size();
+    ((width & !7).max(8), (height & !1).max(2))
+}
+
+/// Maps mouse positions, which SDL gives in window points, into a stream of `stream` pixels
+/// stretched over the window.
+fn input_mapper(canvas: &sdl2::render::WindowCanvas, stream: (u32, u32)) -> InputMapper {
+    let (width, height) = canvas.window().size();
+    InputMapper::new(width, height, stream.0, stream.1)
+}
+
+fn create_frame_texture(
+    canvas: &mut sdl2::render::WindowCanvas,
+    width: u32,
+    height: u32,
+) -> Result<sdl2::render::Texture, WindowError> {
+    // Format corresponds to AV YUV240P
+    // See <https://github.com/FFmpeg/FFmpeg/blob/master/fftools/ffplay.c#L391>
+    let texture = canvas.texture_creator().create_texture(
+        Some(sdl2::pixels::PixelFormatEnum::IYUV),
+        sdl2::render::TextureAccess::Streaming,
+        width,
+        height,
+    )?;
+    Ok(texture)
+}
+
+impl CreatedSdlWindow {
+    fn toggle_fullscreen(&mut self) -> Result<(), WindowError> {
+        let window = self.canvas.window_mut();
+        let to = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        info!(?to, "Toggling fullscreen");
+        window.set_fullscreen(to)?;
+        // SizeChanged isn't always sent when leaving fullscreen
+        self.resized_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// The monitor's own EDID if we fill it, otherwise a made up one for our size.
+    fn display_info(&self) -> Result<DisplayInfo, WindowError> {
+        let (width, height) = self.stream_size;
+        let fills_monitor = (width, height) == (self.monitor.bounds.width, self.monitor.bounds.height);
+        let edid = match &self.monitor_edid {
+            Some(edid) if fills_monitor => edid.clone(),
+            _ => SdlWindow::synthesize_edid(width, height, self.monitor.refresh_rate_hz)?,
+        };
+
+        Ok(DisplayInfo {
+            edid,
+            width_pixels: width,
+            height_pixels: height,
+            refresh_rate_hz: self.monitor.refresh_rate_hz,
+        })
+    }
+
+    /// The frame texture keeps the last frame, so this can be called whenever the cursor changes.
+    /// Only draws black while blanked.
+    fn redraw(&mut self) -> Result<(), WindowError> {
+        self.canvas.clear();
+        if self.adjuster.software().blank {
+            self.canvas.present();
+            return Ok(());
+        }
+        self.canvas.copy(&self.texture, None, None)?; // None means entire window
+
+        if let Some(cursor) = &self.cursor {
+            // The cursor is in stream pixels, and the stream is stretched over the window
+            let (output_width, output_height) = self.canvas.output_size()?;
+            let (stream_width, stream_height) = self.texture_size;
+            let scale = |v: i64, output: u32, stream: u32| v * output as i64 / stream.max(1) as i64;
+            let (x, y) = self.cursor_pos;
+            let dest = sdl2::rect::Rect::new(
+                scale(x.into(), output_width, stream_width) as i32,
+                scale(y.into(), output_height, stream_height) as i32,
+                scale(cursor.width.into(), output_width, stream_width) as u32,
+                scale(cursor.height.into(), output_height, stream_height) as u32,
+            );
+            self.canvas.copy(&cursor.texture, None, dest)?;
+        }
+
+        self.canvas.present();
+        Ok(())
+    }
+
+    fn set_cursor_shape(&mut self, shape: &CursorShape) -> Result<(), WindowError> {
+        if let Some(old) = self.cursor.take() {
+            unsafe { old.texture.destroy() };
+        }
+        if !shape.visible || shape.width == 0 || shape.height == 0 {
+            return Ok(());
+        }
+
+        let mut texture = self.canvas.texture_creator().create_texture_static(
+            Some(sdl2::pixels::PixelFormatEnum::ARGB8888),
+            shape.width,
+            shape.height,
+        )?;
+        texture.set_blend_mode(sdl2::render::BlendMode::Blend);
+        texture.update(None, &shape.argb, shape.width as usize * 4)?;
+
+        self.cursor = Some(CursorOverlay {
+            texture,
+            width: shape.width,
+            height: shape.height,
+        });
         Ok(())
     }
 }
 
 impl Debug for SdlWindow {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("SdlWindow").finish_non_exhaustive()
+        f.debug_struct("SdlWindow")
+            .field("options", &self.options)
+            .finish_non_exhaustive()
     }
 }
 
@@ -171,6 +792,12 @@ impl From<sdl2::render::UpdateTextureYUVError> for WindowError {
     }
 }
 
+impl From<sdl2::render::UpdateTextureError> for WindowError {
+    fn from(err: sdl2::render::UpdateTextureError) -> Self {
+        Self::Sdl(format!("{}", err))
+    }
+}
+
 impl From<sdl2::video::WindowBuildError> for WindowError {
     fn from(err: WindowBuildError) -> Self {
         match err {
@@ -205,3 +832,45 @@ impl From<sdl2::render::TextureValueError> for WindowError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(index: u32, name: &str) -> MonitorInfo {
+        MonitorInfo {
+            index,
+            name: name.to_string(),
+            bounds: ScreenRect {
+                x: 1920 * index as i32,
+                y: 0,
+                width: 1920,
+                height: 1080,
+            },
+            refresh_rate_hz: Some(60),
+        }
+    }
+
+    #[ltest]
+    fn parses_selector() {
+        assert_eq!("1".parse(), Ok(MonitorSelector::Index(1)));
+        assert_eq!(
+            "DP-2".parse(),
+            Ok(MonitorSelector::Name("DP-2".to_string()))
+        );
+    }
+
+    #[ltest]
+    fn selects_monitor() {
+        let monitors = [monitor(0, "eDP-1"), monitor(1, "DP-2")];
+        assert_eq!(
+            MonitorSelector::Index(1).select(&monitors),
+            Some(&monitors[1])
+        );
+        assert_eq!(
+            MonitorSelector::Name("edp-1".to_string()).select(&monitors),
+            Some(&monitors[0])
+        );
+        assert_eq!(MonitorSelector::Index(2).select(&monitors), None);
+    }
+}
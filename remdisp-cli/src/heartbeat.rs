@@ -0,0 +1,147 @@
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::proto::{control_event, display_event, ControlEvent, DisplayEvent, Heartbeat};
+
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Both sides of an Attach send heartbeats so they notice a peer that silently went away long
+/// before TCP would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatOptions {
+    /// How often we send heartbeats
+    pub interval: Duration,
+    /// How long the peer can go without sending anything before we give up on it. Should be
+    /// several of the peer's intervals.
+    pub timeout: Duration,
+}
+
+impl HeartbeatOptions {
+    pub fn new(interval: Duration, timeout: Duration) -> Result<Self, InvalidHeartbeat> {
+        if interval.is_zero() {
+            return Err(InvalidHeartbeat::ZeroInterval);
+        }
+        // Otherwise a peer sending on time could still look dead
+        if timeout <= interval {
+            return Err(InvalidHeartbeat::TimeoutTooShort { interval, timeout });
+        }
+        Ok(Self { interval, timeout })
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum InvalidHeartbeat {
+    #[error("Heartbeat interval must be more than zero")]
+    ZeroInterval,
+    #[error("Heartbeat timeout {timeout:?} must be longer than the interval {interval:?}")]
+    TimeoutTooShort {
+        interval: Duration,
+        timeout: Duration,
+    },
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_HEARTBEAT_INTERVAL,
+            timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+        }
+    }
+}
+
+/// When the peer was last heard from. Any message counts, not just heartbeats.
+#[derive(Debug, Clone, Copy)]
+pub struct Liveness {
+    timeout: Duration,
+    last_seen: Instant,
+}
+
+impl Liveness {
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            timeout,
+            last_seen: now,
+        }
+    }
+
+    pub fn seen(&mut self, now: Instant) {
+        self.last_seen = self.last_seen.max(now);
+    }
+
+    /// When the peer will be considered dead if nothing more is heard.
+    pub fn deadline(&self) -> Instant {
+        self.last_seen + self.timeout
+    }
+
+    pub fn is_dead(&self, now: Instant) -> bool {
+        now >= self.deadline()
+    }
+}
+
+impl From<Heartbeat> for ControlEvent {
+    fn from(heartbeat: Heartbeat) -> Self {
+        ControlEvent {
+            control_event: Some(control_event::ControlEvent::Heartbeat(heartbeat)),
+        }
+    }
+}
+
+impl From<Heartbeat> for DisplayEvent {
+    fn from(heartbeat: Heartbeat) -> Self {
+        DisplayEvent {
+            display_event: Some(display_event::DisplayEvent::Heartbeat(heartbeat)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    #[ltest]
+    fn dies_after_timeout() {
+        let start = Instant::now();
+        let liveness = Liveness::new(Duration::from_secs(10), start);
+
+        assert!(!liveness.is_dead(start + Duration::from_secs(9)));
+        assert!(liveness.is_dead(start + Duration::from_secs(10)));
+    }
+
+    #[ltest]
+    fn seen_extends_deadline() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new(Duration::from_secs(10), start);
+
+        liveness.seen(start + Duration::from_secs(8));
+        assert!(!liveness.is_dead(start + Duration::from_secs(12)));
+        assert_eq!(liveness.deadline(), start + Duration::from_secs(18));
+
+        // Out of order observations don't move the deadline back
+        liveness.seen(start + Duration::from_secs(1));
+        assert_eq!(liveness.deadline(), start + Duration::from_secs(18));
+    }
+
+    #[ltest]
+    fn validates_options() {
+        let secs = Duration::from_secs;
+        assert_eq!(
+            HeartbeatOptions::new(secs(2), secs(10)),
+            Ok(HeartbeatOptions {
+                interval: secs(2),
+                timeout: secs(10)
+            })
+        );
+        assert_eq!(
+            HeartbeatOptions::new(secs(0), secs(10)),
+            Err(InvalidHeartbeat::ZeroInterval)
+        );
+        assert!(matches!(
+            HeartbeatOptions::new(secs(2), secs(2)),
+            Err(InvalidHeartbeat::TimeoutTooShort { .. })
+        ));
+        assert!(HeartbeatOptions::new(secs(2), secs(0)).is_err());
+    }
+}
@@ -0,0 +1,609 @@
+//! Simulated bad networks for tests, so we can check how sessions cope with latency, jitter,
+//! limited bandwidth and loss without real hardware.
+//!
+//! - [`ImpairedSink`] goes between the encoder's packets and any [`PacketSink`], losing whole
+//!   packets like a datagram network would.
+//! - [`impaired_pipe`] is an in-memory byte stream, such as from
+//!   [`Encoder::receive_available`](crate::av::encoder::Encoder::receive_available) to
+//!   [`Decoder::decode`](crate::av::decoder::Decoder::decode).
+//! - [`TcpProxy`] and [`UdpProxy`] sit in front of a real socket, such as the gRPC channel or
+//!   the video port.
+//!
+//! Byte streams model loss as TCP does: the data still arrives, but only after a retransmission
+//! timeout, and holds up everything behind it.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+
+use crate::av::packet::{PacketSink, VideoPacket};
+use crate::av::AvError;
+use crate::prelude::*;
+
+/// How long a byte stream takes to resend lost data
+const RETRANSMIT_AFTER: Duration = Duration::from_millis(200);
+const CHUNK_LEN: usize = 16 * 1024;
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// What's wrong with a direction of a link.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Impairment {
+    /// Added to everything sent
+    pub latency: Duration,
+    /// Up to this much more latency, chosen uniformly for each packet
+    pub jitter: Duration,
+    /// Bytes per second, or None for unlimited
+    pub bandwidth: Option<u64>,
+    /// Chance of losing each packet, from 0 to 1
+    pub loss: f64,
+    /// Makes which packets are lost and how late they are repeatable
+    pub seed: u64,
+}
+
+/// Decides when each packet sent over an impaired link arrives.
+#[derive(Debug)]
+pub(crate) struct Link {
+    impairment: Impairment,
+    rng: StdRng,
+    /// When the link will have finished sending everything so far
+    free_at: Instant,
+}
+
+impl Link {
+    pub fn new(impairment: Impairment) -> Self {
+        Self {
+            impairment,
+            rng: StdRng::seed_from_u64(impairment.seed),
+            free_at: Instant::now(),
+        }
+    }
+
+    /// When a packet of `len` bytes sent at `now` arrives, or None if it's lost. Packets wait
+    /// for the ones before them to be sent, but jitter can still reorder them.
+    pub fn send(&mut self, len: usize, now: Instant) -> Option<Instant> {
+        let sending = match self.impairment.bandwidth {
+            Some(bandwidth) => Duration::from_secs_f64(len as f64 / bandwidth as f64),
+            None => Duration::ZERO,
+        };
+        self.free_at = self.free_at.max(now) + sending;
+
+        if self.rng.gen_bool(self.impairment.loss) {
+            return None;
+        }
+        let jitter = self.impairment.jitter.mul_f64(self.rng.gen());
+        Some(self.free_at + self.impairment.latency + jitter)
+    }
+
+    /// When data sent at `now` arrives if lost attempts are resent after [`RETRANSMIT_AFTER`].
+    pub fn send_reliably(&mut self, len: usize, mut now: Instant) -> Instant {
+        assert!(self.impairment.loss < 1.0, "Nothing would ever arrive");
+        loop {
+            match self.send(len, now) {
+                Some(arrival) => return arrival,
+                None => now += RETRANSMIT_AFTER,
+            }
+        }
+    }
+}
+
+/// Delivers packets to the inner sink late, or not at all.
+#[derive(Debug)]
+pub(crate) struct ImpairedSink {
+    link: Link,
+    packets: Option<mpsc::UnboundedSender<(Instant, VideoPacket)>>,
+    keyframe_requested: Arc<AtomicBool>,
+    delivery: Option<JoinHandle<Result<(), AvError>>>,
+}
+
+impl ImpairedSink {
+    pub fn new<S: PacketSink + 'static>(inner: S, impairment: Impairment) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let keyframe_requested = Arc::new(AtomicBool::new(false));
+        let delivery = tokio::spawn(deliver(inner, rx, keyframe_requested.clone()));
+        Self {
+            link: Link::new(impairment),
+            packets: Some(tx),
+            keyframe_requested,
+            delivery: Some(delivery),
+        }
+    }
+
+    /// Why delivery stopped early.
+    async fn delivery_error(&mut self) -> AvError {
+        match self.delivery.take() {
+            Some(delivery) => match delivery.await {
+                Ok(Err(err)) => err,
+                _ => io::Error::new(io::ErrorKind::BrokenPipe, "Delivery stopped").into(),
+            },
+            None => io::Error::new(io::ErrorKind::BrokenPipe, "Already finished").into(),
+        }
+    }
+}
+
+/// Write packets to `inner` in the order they were sent, each no earlier than its arrival.
+async fn deliver<S: PacketSink>(
+    mut inner: S,
+    mut packets: mpsc::UnboundedReceiver<(Instant, VideoPacket)>,
+    keyframe_requested: Arc<AtomicBool>,
+) -> Result<(), AvError> {
+    while let Some((arrival, packet)) = packets.recv().await {
+        sleep_until(arrival).await;
+        inner.write_packet(&packet).await?;
+        if inner.take_keyframe_request() {
+            keyframe_requested.store(true, Ordering::Relaxed);
+        }
+    }
+    inner.finish().await
+}
+
+#[async_trait]
+impl PacketSink for ImpairedSink {
+    async fn write_packet(&mut self, packet: &VideoPacket) -> Result<(), AvError> {
+        let arrival = match self.link.send(packet.data.len(), Instant::now()) {
+            Some(arrival) => arrival,
+            None => {
+                trace!(?packet, "Impaired link lost packet");
+                return Ok(());
+            }
+        };
+        let sent = match &self.packets {
+            Some(packets) => packets.send((arrival, packet.clone())).is_ok(),
+            None => false,
+        };
+        if sent {
+            Ok(())
+        } else {
+            Err(self.delivery_error().await)
+        }
+    }
+
+    fn take_keyframe_request(&mut self) -> bool {
+        self.keyframe_requested.swap(false, Ordering::Relaxed)
+    }
+
+    async fn finish(&mut self) -> Result<(), AvError> {
+        self.packets = None;
+        match self.delivery.take() {
+            Some(delivery) => delivery
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ImpairedSink {
+    fn drop(&mut self) {
+        if let Some(delivery) = &self.delivery {
+            delivery.abort();
+        }
+    }
+}
+
+/// Copy `input` to `output` as if over an impaired link, then shut `output` down.
+async fn pipe<R, W>(mut input: R, mut output: W, impairment: Impairment) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut link = Link::new(impairment);
+    let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+    let writer = tokio::spawn(async move {
+        while let Some((arrival, chunk)) = rx.recv().await {
+            sleep_until(arrival).await;
+            output.write_all(&chunk).await?;
+        }
+        output.shutdown().await
+    });
+
+    let mut buf = vec![0u8; CHUNK_LEN];
+    // Retransmitted data still holds up everything behind it
+    let mut last_arrival = Instant::now();
+    loop {
+        let len = input.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        last_arrival = link.send_reliably(len, Instant::now()).max(last_arrival);
+        if tx.send((last_arrival, buf[..len].to_vec())).is_err() {
+            break;
+        }
+    }
+    drop(tx);
+    writer
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+}
+
+/// An in-memory byte stream. Returns the end to write to and the end to read from.
+pub(crate) fn impaired_pipe(impairment: Impairment) -> (DuplexStream, DuplexStream) {
+    let (writer, from_writer) = tokio::io::duplex(CHUNK_LEN);
+    let (to_reader, reader) = tokio::io::duplex(CHUNK_LEN);
+    tokio::spawn(async move {
+        if let Err(err) = pipe(from_writer, to_reader, impairment).await {
+            debug!(?err, "Impaired pipe stopped");
+        }
+    });
+    (writer, reader)
+}
+
+/// Forwards TCP connections to `target` on loopback through an impaired link.
+#[derive(Debug)]
+pub(crate) struct TcpProxy {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl TcpProxy {
+    pub async fn start(
+        target: SocketAddr,
+        to_target: Impairment,
+        from_target: Impairment,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let server = match TcpStream::connect(target).await {
+                    Ok(server) => server,
+                    Err(err) => {
+                        debug!(?err, "Proxy failed to connect to target");
+                        continue;
+                    }
+                };
+                let (client_read, client_write) = client.into_split();
+                let (server_read, server_write) = server.into_split();
+                tokio::spawn(pipe(client_read, server_write, to_target));
+                tokio::spawn(pipe(server_read, client_write, from_target));
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    /// Where to connect instead of the target.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for TcpProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Forwards datagrams between `target` and the last address to send to the proxy, through an
+/// impaired link.
+#[derive(Debug)]
+pub(crate) struct UdpProxy {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl UdpProxy {
+    pub async fn start(
+        target: SocketAddr,
+        to_target: Impairment,
+        from_target: Impairment,
+    ) -> io::Result<Self> {
+        let outside = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?);
+        let inside = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?);
+        let addr = outside.local_addr()?;
+        let task = tokio::spawn(async move {
+            let mut to_link = Link::new(to_target);
+            let mut from_link = Link::new(from_target);
+            let mut peer = None;
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            let mut back = vec![0u8; MAX_DATAGRAM];
+            loop {
+                tokio::select! {
+                    Ok((len, from)) = outside.recv_from(&mut buf) => {
+                        peer = Some(from);
+                        if let Some(arrival) = to_link.send(len, Instant::now()) {
+                            send_at(inside.clone(), buf[..len].to_vec(), target, arrival);
+                        }
+                    }
+                    Ok((len, from)) = inside.recv_from(&mut back) => {
+                        match peer {
+                            Some(peer) if from == target => {
+                                if let Some(arrival) = from_link.send(len, Instant::now()) {
+                                    send_at(outside.clone(), back[..len].to_vec(), peer, arrival);
+                                }
+                            }
+                            _ => debug!(%from, "Proxy ignoring datagram"),
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    /// Where to send instead of the target.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// Each datagram is sent separately, so jitter can reorder them.
+fn send_at(socket: Arc<UdpSocket>, datagram: Vec<u8>, to: SocketAddr, arrival: Instant) {
+    tokio::spawn(async move {
+        sleep_until(arrival).await;
+        if let Err(err) = socket.send_to(&datagram, to).await {
+            trace!(%to, ?err, "Proxy failed to send datagram");
+        }
+    });
+}
+
+impl Drop for UdpProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::Mutex;
+
+    use futures::Stream;
+    use tokio::time::timeout;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::transport::{Endpoint, Server};
+    use tonic::{Request, Response, Status, Streaming};
+
+    use super::*;
+    use crate::av::decoder::Decoder;
+    use crate::av::encoder::tests::{framebuf_fixture, mode_fixture};
+    use crate::av::encoder::Encoder;
+    use crate::proto::display_control_client::DisplayControlClient;
+    use crate::proto::display_control_server::{DisplayControl, DisplayControlServer};
+    use crate::proto::*;
+
+    fn bad_link(seed: u64) -> Impairment {
+        Impairment {
+            latency: Duration::from_millis(30),
+            jitter: Duration::from_millis(20),
+            bandwidth: Some(2 * 1024 * 1024),
+            loss: 0.1,
+            seed,
+        }
+    }
+
+    fn packet_fixture(n: u64) -> VideoPacket {
+        VideoPacket {
+            timestamp: Duration::from_millis(40 * n),
+            dts_offset: Duration::ZERO,
+            key: n % 10 == 0,
+            data: vec![n as u8; 1000].into(),
+        }
+    }
+
+    #[ltest(atest)]
+    async fn delays_and_loses_packets() {
+        let mut link = Link::new(Impairment {
+            latency: Duration::from_millis(50),
+            bandwidth: Some(1000),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        // Each waits for the one before to be sent
+        assert_eq!(
+            link.send(100, start),
+            Some(start + Duration::from_millis(150))
+        );
+        assert_eq!(
+            link.send(100, start),
+            Some(start + Duration::from_millis(250))
+        );
+
+        let mut lossy = Link::new(Impairment {
+            loss: 0.5,
+            ..Default::default()
+        });
+        let lost = (0..1000).filter(|_| lossy.send(1, start).is_none()).count();
+        assert!((400..600).contains(&lost), "Lost {} of 1000", lost);
+        assert!(lossy.send_reliably(1, start) >= start);
+    }
+
+    /// Remembers what was written to it.
+    #[derive(Debug, Clone, Default)]
+    struct RecordingSink {
+        packets: Arc<Mutex<Vec<(Instant, VideoPacket)>>>,
+        finished: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl PacketSink for RecordingSink {
+        async fn write_packet(&mut self, packet: &VideoPacket) -> Result<(), AvError> {
+            let mut packets = self.packets.lock().unwrap();
+            packets.push((Instant::now(), packet.clone()));
+            Ok(())
+        }
+
+        async fn finish(&mut self) -> Result<(), AvError> {
+            self.finished.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[ltest(atest)]
+    async fn impaired_sink_loses_and_delays_in_order() {
+        let recording = RecordingSink::default();
+        let impairment = bad_link(1);
+        let mut sink = ImpairedSink::new(recording.clone(), impairment);
+
+        let start = Instant::now();
+        let sent: Vec<_> = (0..100).map(packet_fixture).collect();
+        for packet in &sent {
+            sink.write_packet(packet).await.unwrap();
+        }
+        timeout(TIMEOUT, sink.finish()).await.unwrap().unwrap();
+        assert!(recording.finished.load(Ordering::Relaxed));
+
+        let received = recording.packets.lock().unwrap();
+        assert!(received.len() > 70 && received.len() < 100);
+        assert!(received[0].0 >= start + impairment.latency);
+        let mut sent = sent.iter();
+        for (_, packet) in received.iter() {
+            assert!(sent.any(|sent| sent == packet), "Out of order {:?}", packet);
+        }
+    }
+
+    async fn decoded_frames(impairment: Option<Impairment>) -> usize {
+        let (mut input, output) = match impairment {
+            Some(impairment) => impaired_pipe(impairment),
+            None => tokio::io::duplex(CHUNK_LEN),
+        };
+        let encoding = tokio::spawn(async move {
+            let mut encoder = Encoder::new(mode_fixture()).unwrap();
+            for n in 0..10 {
+                encoder.send_frame(&framebuf_fixture(n)).unwrap();
+                encoder.receive_available(&mut input).await.unwrap();
+            }
+            encoder.flush().unwrap();
+            encoder.receive_available(&mut input).await.unwrap();
+            input.shutdown().await.unwrap();
+        });
+
+        let mut frames = 0;
+        Decoder::new()
+            .unwrap()
+            .decode(output, |_frame| frames += 1)
+            .await
+            .unwrap();
+        encoding.await.unwrap();
+        frames
+    }
+
+    #[ltest(atest)]
+    async fn decodes_through_impaired_pipe() {
+        let expected = decoded_frames(None).await;
+        assert!(expected > 0);
+        let frames = timeout(TIMEOUT, decoded_frames(Some(bad_link(2))))
+            .await
+            .unwrap();
+        assert_eq!(frames, expected);
+    }
+
+    /// Answers hellos and echoes heartbeats.
+    #[derive(Debug)]
+    struct EchoDisplay;
+
+    #[tonic::async_trait]
+    impl DisplayControl for EchoDisplay {
+        async fn hello(&self, req: Request<HelloRequest>) -> Result<Response<HelloReply>, Status> {
+            Ok(Response::new(HelloReply {
+                version: req.into_inner().version,
+                ..Default::default()
+            }))
+        }
+
+        type AttachStream =
+            Pin<Box<dyn Stream<Item = Result<DisplayEvent, Status>> + Send + Sync + 'static>>;
+
+        async fn attach(
+            &self,
+            request: Request<Streaming<ControlEvent>>,
+        ) -> Result<Response<Self::AttachStream>, Status> {
+            let mut recv = request.into_inner();
+            let (tx, rx) = mpsc::channel(4);
+            tokio::spawn(async move {
+                while let Ok(Some(event)) = recv.message().await {
+                    if let Some(control_event::ControlEvent::Heartbeat(heartbeat)) =
+                        event.control_event
+                    {
+                        if tx.send(Ok(heartbeat.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+            Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+        }
+
+        async fn start_pairing(
+            &self,
+            _request: Request<StartPairingRequest>,
+        ) -> Result<Response<StartPairingReply>, Status> {
+            Err(Status::unimplemented("Not pairing"))
+        }
+
+        async fn finish_pairing(
+            &self,
+            _request: Request<FinishPairingRequest>,
+        ) -> Result<Response<FinishPairingReply>, Status> {
+            Err(Status::unimplemented("Not pairing"))
+        }
+
+        async fn confirm_pairing(
+            &self,
+            _request: Request<ConfirmPairingRequest>,
+        ) -> Result<Response<ConfirmPairingReply>, Status> {
+            Err(Status::unimplemented("Not pairing"))
+        }
+    }
+
+    #[ltest(atest)]
+    async fn grpc_survives_impaired_link() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let display_addr = listener.local_addr().unwrap();
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let accepted = listener.accept().await.map(|(stream, _)| stream);
+            Some((accepted, listener))
+        });
+        tokio::spawn(
+            Server::builder()
+                .add_service(DisplayControlServer::new(EchoDisplay))
+                .serve_with_incoming(incoming),
+        );
+        let proxy = TcpProxy::start(display_addr, bad_link(3), bad_link(4))
+            .await
+            .unwrap();
+
+        let channel = Endpoint::new(format!("http://{}", proxy.addr()))
+            .unwrap()
+            .timeout(TIMEOUT)
+            .connect()
+            .await
+            .unwrap();
+        let mut client = DisplayControlClient::new(channel);
+        for _ in 0..10 {
+            let reply = client
+                .hello(HelloRequest {
+                    version: VERSION.to_string(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(reply.into_inner().version, VERSION);
+        }
+
+        let (tx, rx) = mpsc::channel(4);
+        let mut events = client
+            .attach(ReceiverStream::new(rx))
+            .await
+            .unwrap()
+            .into_inner();
+        for _ in 0..10 {
+            tx.send(Heartbeat {}.into()).await.unwrap();
+            let event = timeout(TIMEOUT, events.message()).await.unwrap().unwrap();
+            assert!(matches!(
+                event,
+                Some(DisplayEvent {
+                    display_event: Some(display_event::DisplayEvent::Heartbeat(_))
+                })
+            ));
+        }
+    }
+}
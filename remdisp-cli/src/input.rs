@@ -0,0 +1,120 @@
+use std::convert::TryFrom;
+
+use crate::prelude::*;
+use crate::proto::display_event::input::{self, mouse_button::Button};
+use crate::proto::display_event::Input;
+
+/// Keyboard or mouse input captured by the display, in stream coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// `usage` is a USB HID keyboard usage id
+    Key { usage: u32, pressed: bool },
+    MouseMove { x: u32, y: u32 },
+    MouseButton { button: MouseButton, pressed: bool },
+    /// In wheel detents. Positive y is away from the user, positive x is to the right.
+    Scroll { x: i32, y: i32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    X1,
+    X2,
+}
+
+impl From<InputEvent> for Input {
+    fn from(event: InputEvent) -> Self {
+        let event = match event {
+            InputEvent::Key { usage, pressed } => input::Event::Key(input::Key { usage, pressed }),
+            InputEvent::MouseMove { x, y } => input::Event::MouseMove(input::MouseMove { x, y }),
+            InputEvent::MouseButton { button, pressed } => {
+                let button = match button {
+                    MouseButton::Left => Button::Left,
+                    MouseButton::Middle => Button::Middle,
+                    MouseButton::Right => Button::Right,
+                    MouseButton::X1 => Button::X1,
+                    MouseButton::X2 => Button::X2,
+                };
+                input::Event::MouseButton(input::MouseButton {
+                    button: button as i32,
+                    pressed,
+                })
+            }
+            InputEvent::Scroll { x, y } => input::Event::Scroll(input::Scroll { x, y }),
+        };
+        Input { event: Some(event) }
+    }
+}
+
+impl TryFrom<Input> for InputEvent {
+    type Error = InvalidInputEvent;
+
+    fn try_from(input: Input) -> Result<Self, Self::Error> {
+        Ok(match input.event.ok_or(InvalidInputEvent::Empty)? {
+            input::Event::Key(input::Key { usage, pressed }) => InputEvent::Key { usage, pressed },
+            input::Event::MouseMove(input::MouseMove { x, y }) => InputEvent::MouseMove { x, y },
+            input::Event::MouseButton(input::MouseButton { button, pressed }) => {
+                let button = match Button::from_i32(button) {
+                    Some(Button::Left) => MouseButton::Left,
+                    Some(Button::Middle) => MouseButton::Middle,
+                    Some(Button::Right) => MouseButton::Right,
+                    Some(Button::X1) => MouseButton::X1,
+                    Some(Button::X2) => MouseButton::X2,
+                    None => return Err(InvalidInputEvent::UnknownButton(button)),
+                };
+                InputEvent::MouseButton { button, pressed }
+            }
+            input::Event::Scroll(input::Scroll { x, y }) => InputEvent::Scroll { x, y },
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidInputEvent {
+    #[error("Input event has no contents")]
+    Empty,
+    #[error("Unknown mouse button {0}")]
+    UnknownButton(i32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ltest]
+    fn round_trips_through_proto() {
+        let events = [
+            InputEvent::Key {
+                usage: 0x04,
+                pressed: true,
+            },
+            InputEvent::MouseMove { x: 10, y: 1079 },
+            InputEvent::MouseButton {
+                button: MouseButton::X2,
+                pressed: false,
+            },
+            InputEvent::Scroll { x: -1, y: 2 },
+        ];
+
+        for event in events.iter().copied() {
+            let proto = Input::from(event);
+            assert_eq!(InputEvent::try_from(proto).unwrap(), event);
+        }
+    }
+
+    #[ltest]
+    fn rejects_unknown_button() {
+        let proto = Input {
+            event: Some(input::Event::MouseButton(input::MouseButton {
+                button: 42,
+                pressed: true,
+            })),
+        };
+        assert!(matches!(
+            InputEvent::try_from(proto),
+            Err(InvalidInputEvent::UnknownButton(42))
+        ));
+    }
+}
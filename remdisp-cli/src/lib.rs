@@ -9,8 +9,17 @@ pub const VERSION: &str = built_info::PKG_VERSION;
 
 #[macro_use]
 mod status_helpers;
+pub mod adjust;
+pub mod auth;
 pub mod av;
+pub mod cursor;
+pub mod discovery;
+pub mod heartbeat;
+pub mod input;
+pub mod net;
 pub mod prelude;
+#[cfg(test)]
+mod impair;
 mod send_or_log;
 
 #[cfg(feature = "display")]
@@ -1,13 +1,22 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::{App, Arg, ArgMatches, SubCommand};
 
+use remdisp_cli::auth::default_config_dir;
+use remdisp_cli::discovery::discover;
+use remdisp_cli::heartbeat::HeartbeatOptions;
 use remdisp_cli::*;
 use tokio_stream::StreamExt;
-use tracing::{debug, error, info, instrument, span, warn};
+use tracing::{debug, error, info, instrument, span, warn, Instrument, Level};
 
 const DEFAULT_PORT: &str = "48611";
+const DEFAULT_FPS: &str = "25";
+const DEFAULT_RECONNECT_SECS: &str = "60";
+const DEFAULT_DISCOVER_SECS: &str = "3";
+/// `--host` to find the display on the LAN
+const AUTO_HOST: &str = "auto";
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -23,15 +32,152 @@ async fn main() -> Result<()> {
             .help("Communicate on a custom port. You must provide the same port to the display and control.")
             .takes_value(true)
             .default_value(DEFAULT_PORT))
+        .arg(Arg::with_name("config-dir")
+            .long("config-dir")
+            .help("Where to keep this machine's identity and the peers it has paired with. Defaults to remdisp in your config directory.")
+            .takes_value(true))
+        .arg(Arg::with_name("token-file")
+            .long("token-file")
+            .help("Authenticate with the token in this file instead of pairing, for trusted networks. Give the display and control the same token. Connections aren't encrypted.")
+            .takes_value(true))
+        .arg(Arg::with_name("heartbeat-interval")
+            .long("heartbeat-interval")
+            .help("Seconds between heartbeats sent to the other side. Defaults to 2.")
+            .takes_value(true))
+        .arg(Arg::with_name("heartbeat-timeout")
+            .long("heartbeat-timeout")
+            .help("Seconds without hearing from the other side before giving up on it. Should be several of the other side's heartbeat intervals. Defaults to 10.")
+            .takes_value(true))
         .subcommand(SubCommand::with_name("display")
-            .about("Create a remote display that can be output to"))
+            .about("Create a remote display that can be output to")
+            .arg(Arg::with_name("monitor")
+                .long("monitor")
+                .help("Offer this monitor as an output, by index or name. Repeat to offer several. Defaults to every monitor.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("list-monitors")
+                .long("list-monitors")
+                .help("List the monitors that can be used with --monitor and exit."))
+            .arg(Arg::with_name("when-busy")
+                .long("when-busy")
+                .help("What to do when a control attaches to an output that is in use. A control can always take over with --force.")
+                .takes_value(true)
+                .possible_values(&["reject", "queue"])
+                .default_value("reject"))
+            .arg(Arg::with_name("resume-timeout")
+                .long("resume-timeout")
+                .help("Seconds to keep the window open for a control that lost its connection to come back. 0 closes it immediately.")
+                .takes_value(true)
+                .default_value(DEFAULT_RECONNECT_SECS))
+            .arg(Arg::with_name("windowed")
+                .long("windowed")
+                .help("Start in a resizable window instead of fullscreen. Ctrl+Alt+F toggles fullscreen."))
+            .arg(Arg::with_name("window-size")
+                .long("window-size")
+                .help("Initial size of the window with --windowed, such as 1280x720.")
+                .takes_value(true))
+            .arg(Arg::with_name("bind")
+                .long("bind")
+                .help("Listen on this IPv4 or IPv6 address, or the address of this network interface. Use :: for IPv6, which usually includes IPv4.")
+                .takes_value(true)
+                .default_value("0.0.0.0"))
+            .arg(Arg::with_name("video-port")
+                .long("video-port")
+                .help("Accept video streams on this port, or the first free one in a range like 5000-5010. Each output streaming at once needs its own. Defaults to any free port.")
+                .takes_value(true))
+            .arg(Arg::with_name("no-announce")
+                .long("no-announce")
+                .help("Don't announce this display on the LAN. Controls will need its address.")))
         .subcommand(SubCommand::with_name("control")
             .about("Output to remote displays")
             .arg(Arg::with_name("host")
                 .long("host")
                 .short("h")
+                .help("A display to output to, optionally followed by /OUTPUT to pick one of its monitors by index or name. IPv6 addresses can be bracketed, as in [::1]/0. Use auto to find the only display on the LAN. Repeat to output to several displays at once, each with its own virtual monitor.")
+                .required_unless("record")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("record")
+                .long("record")
+                .help("Record to a file, such as out.mkv or out.mp4. Without --host a virtual monitor with a sample configuration is created just for the recording. Only supported with at most one --host.")
+                .takes_value(true))
+            .arg(Arg::with_name("fps")
+                .long("fps")
+                .help("Target frame rate to capture at. With --vfr this is the maximum.")
+                .takes_value(true)
+                .default_value(DEFAULT_FPS))
+            .arg(Arg::with_name("vfr")
+                .long("vfr")
+                .help("Only capture frames when the screen changes."))
+            .arg(Arg::with_name("no-input")
+                .long("no-input")
+                .help("Ignore keyboard and mouse input from the display."))
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Take over displays that another control is using."))
+            .arg(Arg::with_name("video-transport")
+                .long("video-transport")
+                .help("Send video over TCP, or UDP which recovers from lost packets without stalling. UDP video isn't encrypted even when paired.")
+                .takes_value(true)
+                .possible_values(&["tcp", "udp"])
+                .default_value("tcp"))
+            .arg(Arg::with_name("audio")
+                .long("audio")
+                .help("Also send audio: `pulse` for whatever this machine is playing, `pulse:SOURCE` for a PulseAudio or PipeWire source such as a sink's .monitor, `null` for silence, or a file.")
+                .takes_value(true)
+                .value_name("SOURCE"))
+            .arg(Arg::with_name("reconnect-timeout")
+                .long("reconnect-timeout")
+                .help("Seconds to keep trying to get back to a display after losing the connection. The virtual monitor stays connected meanwhile. 0 gives up immediately.")
+                .takes_value(true)
+                .default_value(DEFAULT_RECONNECT_SECS))
+            .arg(Arg::with_name("brightness")
+                .long("brightness")
+                .help("Set the brightness of the displays' monitors, as a percentage. While streaming you can also type commands like `brightness 40`, `contrast 60`, `blank`, `sleep` or `on` to adjust every display.")
+                .takes_value(true))
+            .arg(Arg::with_name("contrast")
+                .long("contrast")
+                .help("Set the contrast of the displays' monitors, as a percentage.")
+                .takes_value(true))
+            .arg(Arg::with_name("power")
+                .long("power")
+                .help("Blank the displays' monitors, or put them to sleep. Monitors that can't be controlled are blanked instead of sleeping.")
+                .takes_value(true)
+                .possible_values(&["on", "blank", "sleep"])))
+        .subcommand(SubCommand::with_name("pair")
+            .about("Pair with a display so this machine can control it. Both show a code, check they match here and on the display.")
+            .arg(Arg::with_name("host")
+                .long("host")
+                .short("h")
+                .help("The display to pair with. Use the same host with `control --host`.")
                 .required(true)
                 .takes_value(true)))
+        .subcommand(SubCommand::with_name("discover")
+            .about("List the displays announcing themselves on the LAN")
+            .arg(Arg::with_name("wait")
+                .long("wait")
+                .help("Seconds to listen for displays.")
+                .takes_value(true)
+                .default_value(DEFAULT_DISCOVER_SECS)))
+        .subcommand(SubCommand::with_name("replay")
+            .about("Stream a recording to a remote display, for debugging")
+            .arg(Arg::with_name("file")
+                .help("A recording made with `control --record`, or a raw .h264 stream")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("host")
+                .long("host")
+                .short("h")
+                .help("The display to stream to, as HOST or HOST/OUTPUT. HOST can be auto, as with control.")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("fps")
+                .long("fps")
+                .help("Frame rate to assume for files without timestamps.")
+                .takes_value(true)
+                .default_value(DEFAULT_FPS)))
         .get_matches();
 
     let port: u16 = args
@@ -39,46 +185,601 @@ async fn main() -> Result<()> {
         .unwrap()
         .parse()
         .context("Failed to parse port")?;
+    let heartbeat = parse_heartbeat(&args)?;
+    let auth = AuthArgs {
+        config_dir: match args.value_of("config-dir") {
+            Some(dir) => PathBuf::from(dir),
+            None => default_config_dir()?,
+        },
+        token_file: args.value_of("token-file").map(PathBuf::from),
+    };
 
     if args.is_present("features") {
         info!("Built with features: {}", built_info::FEATURES.join(", "));
         Ok(())
     } else if let Some(sub_args) = args.subcommand_matches("display") {
-        subcommand_display(port, sub_args).await
+        subcommand_display(port, heartbeat, &auth, sub_args).await
     } else if let Some(sub_args) = args.subcommand_matches("control") {
-        subcommand_control(port, sub_args).await
+        subcommand_control(port, heartbeat, &auth, sub_args).await
+    } else if let Some(sub_args) = args.subcommand_matches("pair") {
+        subcommand_pair(port, &auth, sub_args).await
+    } else if let Some(sub_args) = args.subcommand_matches("discover") {
+        subcommand_discover(port, sub_args).await
+    } else if let Some(sub_args) = args.subcommand_matches("replay") {
+        subcommand_replay(port, heartbeat, &auth, sub_args).await
     } else {
         Ok(())
     }
 }
 
+/// Where to find credentials
+struct AuthArgs {
+    config_dir: PathBuf,
+    /// Use a shared token instead of pairing
+    token_file: Option<PathBuf>,
+}
+
+fn parse_heartbeat(args: &ArgMatches<'_>) -> Result<HeartbeatOptions> {
+    let default = HeartbeatOptions::default();
+    let interval = match args.value_of("heartbeat-interval") {
+        Some(interval) => parse_secs(interval).context("Failed to parse heartbeat interval")?,
+        None => default.interval,
+    };
+    let timeout = match args.value_of("heartbeat-timeout") {
+        Some(timeout) => parse_secs(timeout).context("Failed to parse heartbeat timeout")?,
+        None => default.timeout,
+    };
+    Ok(HeartbeatOptions::new(interval, timeout)?)
+}
+
+/// Parses possibly fractional seconds like 2.5
+fn parse_secs(secs: &str) -> Result<Duration> {
+    let secs: f64 = secs.parse()?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(anyhow!("Expected a non-negative number of seconds"));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
 #[cfg(not(feature = "display"))]
-async fn subcommand_display(_port: u16, _sub_args: &ArgMatches<'_>) -> Result<()> {
+async fn subcommand_display(
+    _port: u16,
+    _heartbeat: HeartbeatOptions,
+    _auth: &AuthArgs,
+    _sub_args: &ArgMatches<'_>,
+) -> Result<()> {
     Err(anyhow!("Not built with feature `display`"))
 }
 
 #[cfg(feature = "display")]
-async fn subcommand_display(port: u16, _sub_args: &ArgMatches<'_>) -> Result<()> {
-    use display::DisplayServer;
+async fn subcommand_display(
+    port: u16,
+    heartbeat: HeartbeatOptions,
+    auth: &AuthArgs,
+    sub_args: &ArgMatches<'_>,
+) -> Result<()> {
+    use display::window::{list_monitors, WindowOptions};
+    use display::{DisplayAuth, DisplayOptions, DisplayServer};
+    use remdisp_cli::auth::token::load_token;
+    use remdisp_cli::auth::{Identity, PairedPeers, PAIRED_CONTROLS_FILE};
+
+    if sub_args.is_present("list-monitors") {
+        for monitor in list_monitors()? {
+            println!("{}", monitor);
+        }
+        return Ok(());
+    }
+
+    let addr = net::resolve_bind(sub_args.value_of("bind").unwrap(), port)?;
+    // Keeps the scope of link-local addresses
+    let mut video_addr = addr;
+    video_addr.set_port(0);
+    let window_size = match sub_args.value_of("window-size") {
+        Some(size) => Some(parse_size(size).context("Failed to parse window size")?),
+        None => None,
+    };
+    let options = DisplayOptions {
+        monitors: sub_args
+            .values_of("monitor")
+            .map(|monitors| monitors.map(|monitor| monitor.parse().unwrap()).collect())
+            .unwrap_or_default(),
+        window: WindowOptions {
+            monitor: None,
+            windowed: sub_args.is_present("windowed"),
+            window_size,
+        },
+        busy_policy: sub_args.value_of("when-busy").unwrap().parse()?,
+        heartbeat,
+        resume_timeout: parse_secs(sub_args.value_of("resume-timeout").unwrap())
+            .context("Failed to parse resume timeout")?,
+        announce: !sub_args.is_present("no-announce"),
+        video_addr,
+        video_ports: match sub_args.value_of("video-port") {
+            Some(ports) => Some(ports.parse().context("Failed to parse video port")?),
+            None => None,
+        },
+    };
+
+    let auth = match &auth.token_file {
+        Some(token_file) => DisplayAuth::Token(load_token(token_file)?),
+        None => DisplayAuth::Pairing {
+            identity: Identity::load_or_create(&auth.config_dir)?,
+            paired: PairedPeers::load(&auth.config_dir.join(PAIRED_CONTROLS_FILE))?,
+        },
+    };
+
+    DisplayServer::new(options, auth)?.serve(addr).await?;
+
+    Ok(())
+}
+
+/// Parses sizes like 1280x720
+#[cfg(feature = "display")]
+fn parse_size(size: &str) -> Result<(u32, u32)> {
+    let (width, height) = size
+        .split_once('x')
+        .ok_or_else(|| anyhow!("Expected WIDTHxHEIGHT"))?;
+    Ok((width.parse()?, height.parse()?))
+}
+
+#[cfg(not(feature = "control"))]
+async fn subcommand_control(
+    _port: u16,
+    _heartbeat: HeartbeatOptions,
+    _auth: &AuthArgs,
+    _sub_args: &ArgMatches<'_>,
+) -> Result<()> {
+    Err(anyhow!("Not built with feature `control`"))
+}
+
+#[cfg(feature = "control")]
+async fn subcommand_control(
+    port: u16,
+    heartbeat: HeartbeatOptions,
+    auth: &AuthArgs,
+    sub_args: &ArgMatches<'_>,
+) -> Result<()> {
+    use av::frame_rate::FrameRate;
+    use control::capture::{capture, CaptureOptions, CaptureSource};
+    use evdi::prelude::DeviceConfig;
+    use futures::FutureExt;
+    use std::time::Instant;
+
+    let fps: u32 = sub_args
+        .value_of("fps")
+        .unwrap()
+        .parse()
+        .context("Failed to parse fps")?;
+    let options = CaptureOptions {
+        frame_rate: FrameRate::new(fps, sub_args.is_present("vfr"))?,
+        record_to: sub_args.value_of("record").map(Into::into),
+    };
+
+    let hosts: Vec<&str> = sub_args
+        .values_of("host")
+        .map(Iterator::collect)
+        .unwrap_or_default();
+    if hosts.len() > 1 && options.record_to.is_some() {
+        return Err(anyhow!("--record can only be used with one --host"));
+    }
+
+    // Each display gets its own virtual monitor. Open them all up front so we fail before
+    // connecting to anything if there aren't enough.
+    let handles = open_evdi(hosts.len().max(1))?;
+
+    let stop = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!(?err, "Failed to listen for ctrl-c");
+            // Run until the session ends by itself, rather than stopping straight away
+            futures::future::pending::<()>().await;
+        }
+    }
+    .shared();
+
+    if hosts.is_empty() {
+        let handle = handles.into_iter().next().expect("Opened one device");
+        let mut handle = handle.connect(&DeviceConfig::sample());
+        let source = CaptureSource::new(&mut handle).await?;
+        let start = Instant::now();
+        capture(&mut handle, &source, &options, &mut [], None, start, stop).await?;
+        return Ok(());
+    }
+
+    let credentials = std::sync::Arc::new(Credentials::load(auth)?);
+    let no_input = sub_args.is_present("no-input");
+    let force = sub_args.is_present("force");
+    let video_transport = match sub_args.value_of("video-transport").unwrap() {
+        "udp" => proto::VideoTransport::Udp,
+        _ => proto::VideoTransport::Tcp,
+    };
+    let audio = sub_args
+        .value_of("audio")
+        .map(str::parse::<av::audio::input::AudioSource>)
+        .transpose()?;
+    let reconnect_timeout = parse_secs(sub_args.value_of("reconnect-timeout").unwrap())
+        .context("Failed to parse reconnect timeout")?;
+    let adjustments = read_adjustments(parse_adjustments(sub_args)?);
+    // Each display gets a task of its own, so capturing and encoding for one doesn't hold up
+    // the others
+    let sessions = hosts.iter().zip(handles).map(|(&host, handle)| {
+        let input = if no_input {
+            None
+        } else {
+            create_input_sink(host, hosts.len() > 1)
+        };
+        let host = host.to_string();
+        let options = options.clone();
+        let credentials = credentials.clone();
+        let audio = audio.clone();
+        let stop = stop.clone();
+        let mut adjustments = adjustments.clone();
+        let span = span!(Level::INFO, "display", host = host.as_str());
+        tokio::spawn(
+            async move {
+                let mut control = connect(&host, port, &credentials, force, heartbeat).await?;
+                control.set_reconnect_timeout(reconnect_timeout);
+                control.set_video_transport(video_transport)?;
+                control.set_audio(audio)?;
+
+                let adjuster = control.adjuster();
+                adjuster.adjust(*adjustments.borrow());
+                let forward_adjustments = tokio::spawn(async move {
+                    while adjustments.changed().await.is_ok() {
+                        adjuster.adjust(*adjustments.borrow());
+                    }
+                });
+                let result = control.attach(handle, &options, input, stop).await;
+                forward_adjustments.abort();
+                result?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .instrument(span),
+        )
+    });
+    let results = futures::future::join_all(sessions).await;
+
+    // Displays are independent, so one failing doesn't stop the others
+    let mut failed = 0;
+    for (host, result) in hosts.iter().zip(results) {
+        let result = result.context("Session task failed").and_then(|result| result);
+        match result {
+            Ok(()) => info!(host, "Finished streaming to display"),
+            Err(err) => {
+                error!(host, ?err, "Error streaming to display");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} of {} displays failed", failed, hosts.len()));
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "control")]
+fn parse_adjustments(sub_args: &ArgMatches<'_>) -> Result<adjust::Adjustments> {
+    let percent = |name: &str| -> Result<Option<u32>> {
+        match sub_args.value_of(name) {
+            Some(percent) => Ok(Some(adjust::parse_percent(percent)?)),
+            None => Ok(None),
+        }
+    };
+    Ok(adjust::Adjustments {
+        brightness: percent("brightness").context("Failed to parse brightness")?,
+        contrast: percent("contrast").context("Failed to parse contrast")?,
+        power: match sub_args.value_of("power") {
+            Some(power) => Some(power.parse()?),
+            None => None,
+        },
+    })
+}
+
+/// Adjustments to make to every display, starting with `initial` and then whatever is typed on
+/// stdin.
+#[cfg(feature = "control")]
+fn read_adjustments(
+    initial: adjust::Adjustments,
+) -> tokio::sync::watch::Receiver<adjust::Adjustments> {
+    use std::io::BufRead;
 
-    let addr: SocketAddr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into();
-    DisplayServer::default().serve(addr).await?;
+    let (tx, rx) = tokio::sync::watch::channel(initial);
+    // Reading stdin blocks, and doesn't stop us exiting
+    std::thread::spawn(move || {
+        let mut adjustments = initial;
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => line,
+                Err(err) => {
+                    warn!(?err, "Failed to read stdin, ignoring commands");
+                    return;
+                }
+            };
+            match line.parse() {
+                Ok(adjustment) => {
+                    adjustments.merge(adjustment);
+                    if tx.send(adjustments).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => warn!("{}", err),
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(feature = "control")]
+enum Credentials {
+    /// Our identity and the displays we've paired with
+    Paired {
+        identity: std::sync::Arc<auth::Identity>,
+        displays: auth::PairedPeers,
+    },
+    Token(String),
+}
+
+#[cfg(feature = "control")]
+impl Credentials {
+    fn load(args: &AuthArgs) -> Result<Self> {
+        use auth::{Identity, PairedPeers, PAIRED_DISPLAYS_FILE};
+
+        if let Some(token_file) = &args.token_file {
+            return Ok(Self::Token(auth::token::load_token(token_file)?));
+        }
+        Ok(Self::Paired {
+            identity: std::sync::Arc::new(Identity::load_or_create(&args.config_dir)?),
+            displays: PairedPeers::load(&args.config_dir.join(PAIRED_DISPLAYS_FILE))?,
+        })
+    }
+
+    fn for_host(&self, host: &str) -> Result<control::ControlAuth> {
+        match self {
+            Self::Paired { identity, displays } => {
+                let display = displays.find_by_name(host).ok_or_else(|| {
+                    anyhow!(
+                        "Not paired with {}, run `remdisp pair --host {}` first",
+                        host,
+                        host
+                    )
+                })?;
+                Ok(control::ControlAuth::Paired {
+                    identity: identity.clone(),
+                    display: display.fingerprint,
+                })
+            }
+            Self::Token(token) => Ok(control::ControlAuth::Token(token.clone())),
+        }
+    }
+
+    /// How to authenticate to a display found on the LAN, if we can.
+    fn for_discovered(&self, display: &discovery::DiscoveredDisplay) -> Option<control::ControlAuth> {
+        match self {
+            Self::Paired { identity, displays } => {
+                let fingerprint = display.beacon.fingerprint.parse().ok()?;
+                displays.find(&fingerprint)?;
+                Some(control::ControlAuth::Paired {
+                    identity: identity.clone(),
+                    display: fingerprint,
+                })
+            }
+            // Displays using a token don't have a fingerprint
+            Self::Token(token) if display.beacon.fingerprint.is_empty() => {
+                Some(control::ControlAuth::Token(token.clone()))
+            }
+            Self::Token(_) => None,
+        }
+    }
+}
+
+/// Find the one display on the LAN we can connect to, returning its host and port.
+#[cfg(feature = "control")]
+async fn find_display(
+    port: u16,
+    credentials: &Credentials,
+) -> Result<(String, u16, control::ControlAuth)> {
+    let displays = discover(port, discovery::DEFAULT_DISCOVER_WAIT)
+        .await
+        .context("Failed to listen for displays")?;
+    let mut usable = displays
+        .iter()
+        .filter(|display| display.is_compatible())
+        .filter_map(|display| Some((display, credentials.for_discovered(display)?)));
+
+    let (display, auth) = usable.next().ok_or_else(|| {
+        anyhow!(
+            "Found no displays to connect to out of {} on the LAN, see `remdisp discover`",
+            displays.len()
+        )
+    })?;
+    if usable.next().is_some() {
+        return Err(anyhow!(
+            "Found several displays, pick one with --host, see `remdisp discover`"
+        ));
+    }
+    info!(%display, "Found display");
+    Ok((display.ip.to_string(), display.beacon.port as u16, auth))
+}
+
+/// Connect to a display given as HOST or HOST/OUTPUT.
+#[cfg(feature = "control")]
+async fn connect(
+    spec: &str,
+    port: u16,
+    credentials: &Credentials,
+    force: bool,
+    heartbeat: HeartbeatOptions,
+) -> Result<control::ControlClient> {
+    let (host, output) = match spec.split_once('/') {
+        Some((host, output)) => (net::unbracket(host), Some(output)),
+        None => (net::unbracket(spec), None),
+    };
+
+    let (host, port, auth) = if host == AUTO_HOST {
+        find_display(port, credentials).await?
+    } else {
+        (host.to_string(), port, credentials.for_host(host)?)
+    };
+    let mut control = control::ControlClient::connect(&host, port, auth).await?;
+    if let Some(output) = output {
+        control.select_output(output)?;
+    }
+    control.set_force(force);
+    control.set_heartbeat(heartbeat);
+    Ok(control)
+}
+
+#[cfg(feature = "control")]
+/// Opens `count` distinct evdi devices, adding more if there aren't enough.
+fn open_evdi(count: usize) -> Result<Vec<evdi::prelude::UnconnectedHandle>> {
+    use evdi::prelude::DeviceNode;
+
+    let mut nodes = DeviceNode::list_available().context("Failed to list evdi devices")?;
+    while nodes.len() < count {
+        let before = nodes.len();
+        if DeviceNode::add() {
+            nodes = DeviceNode::list_available().context("Failed to list evdi devices")?;
+        }
+        if nodes.len() <= before {
+            return Err(anyhow!(
+                "Need {} evdi devices but only {} are available. Add more as root with \
+                 `echo {} > /sys/devices/evdi/add`",
+                count,
+                before,
+                count - before
+            ));
+        }
+    }
 
+    nodes
+        .iter()
+        .take(count)
+        .map(|node| node.open().context("Failed to open evdi device"))
+        .collect()
+}
+
+/// `distinct` gives the device a name including the host, for when there are several.
+#[cfg(all(feature = "control", target_os = "linux"))]
+fn create_input_sink(host: &str, distinct: bool) -> Option<Box<dyn control::input::InputSink>> {
+    use control::input::UinputSink;
+
+    let sink = if distinct {
+        UinputSink::with_name(&format!("remdisp virtual input ({})", host))
+    } else {
+        UinputSink::new()
+    };
+    match sink {
+        Ok(sink) => Some(Box::new(sink)),
+        Err(err) => {
+            warn!(?err, "Failed to create virtual input device, input from the display will be ignored. Check you can write to /dev/uinput");
+            None
+        }
+    }
+}
+
+#[cfg(all(feature = "control", not(target_os = "linux")))]
+fn create_input_sink(_host: &str, _distinct: bool) -> Option<Box<dyn control::input::InputSink>> {
+    warn!("Input forwarding is only supported on Linux");
+    None
+}
+
+#[cfg(not(feature = "control"))]
+async fn subcommand_pair(_port: u16, _auth: &AuthArgs, _sub_args: &ArgMatches<'_>) -> Result<()> {
+    Err(anyhow!("Not built with feature `control`"))
+}
+
+#[cfg(feature = "control")]
+async fn subcommand_pair(port: u16, auth: &AuthArgs, sub_args: &ArgMatches<'_>) -> Result<()> {
+    use control::pair::pair;
+    use remdisp_cli::discovery::local_name;
+    use remdisp_cli::auth::{Identity, PairedPeer, PairedPeers, PAIRED_DISPLAYS_FILE};
+    use std::io::{self, Write};
+
+    if auth.token_file.is_some() {
+        return Err(anyhow!("Pairing isn't needed with --token-file"));
+    }
+    let host = net::unbracket(sub_args.value_of("host").unwrap());
+    let identity = Identity::load_or_create(&auth.config_dir)?;
+    let mut displays = PairedPeers::load(&auth.config_dir.join(PAIRED_DISPLAYS_FILE))?;
+
+    let confirm_code = |code: String| async move {
+        let read = tokio::task::spawn_blocking(move || {
+            println!("Pairing code: {}", code);
+            print!("Does the display show the same code? Answer on the display too [y/n]: ");
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            let answer = answer.trim().to_ascii_lowercase();
+            Ok(answer == "y" || answer == "yes")
+        });
+        read.await.unwrap_or_else(|err| Err(io::Error::new(io::ErrorKind::Other, err)))
+    };
+    let fingerprint = pair(host, port, &identity, &local_name(), confirm_code).await?;
+
+    displays.add(PairedPeer {
+        fingerprint,
+        name: host.to_string(),
+    })?;
+    println!("Paired with {}", host);
+    Ok(())
+}
+
+async fn subcommand_discover(port: u16, sub_args: &ArgMatches<'_>) -> Result<()> {
+    let wait = parse_secs(sub_args.value_of("wait").unwrap()).context("Failed to parse wait")?;
+    let displays = discover(port, wait)
+        .await
+        .context("Failed to listen for displays")?;
+    if displays.is_empty() {
+        println!("No displays found");
+    }
+    for display in displays {
+        println!("{}", display);
+    }
     Ok(())
 }
 
 #[cfg(not(feature = "control"))]
-async fn subcommand_control(_port: u16, _sub_args: &ArgMatches<'_>) -> Result<()> {
+async fn subcommand_replay(
+    _port: u16,
+    _heartbeat: HeartbeatOptions,
+    _auth: &AuthArgs,
+    _sub_args: &ArgMatches<'_>,
+) -> Result<()> {
     Err(anyhow!("Not built with feature `control`"))
 }
 
 #[cfg(feature = "control")]
-async fn subcommand_control(port: u16, sub_args: &ArgMatches<'_>) -> Result<()> {
-    use control::ControlClient;
+async fn subcommand_replay(
+    port: u16,
+    heartbeat: HeartbeatOptions,
+    auth: &AuthArgs,
+    sub_args: &ArgMatches<'_>,
+) -> Result<()> {
+    use av::frame_rate::FrameRate;
+    use control::replay::replay;
 
     let host = sub_args.value_of("host").unwrap();
+    let path = Path::new(sub_args.value_of("file").unwrap());
+
+    let fps: u32 = sub_args
+        .value_of("fps")
+        .unwrap()
+        .parse()
+        .context("Failed to parse fps")?;
+    let fallback_frame_rate = FrameRate::new(fps, false)?;
+
+    let stop = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!(?err, "Failed to listen for ctrl-c");
+            // Replay until the recording ends, rather than stopping straight away
+            futures::future::pending::<()>().await;
+        }
+    };
 
-    let _control = ControlClient::connect(host, port).await?;
+    let credentials = Credentials::load(auth)?;
+    let mut control = connect(host, port, &credentials, false, heartbeat).await?;
+    replay(&mut control, path, fallback_frame_rate, stop).await?;
 
     Ok(())
 }
@@ -0,0 +1,191 @@
+//! Addresses given on the command line.
+
+use std::fmt::{self, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV6};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::prelude::*;
+
+/// Parse an IPv4 or IPv6 address, or the name of a network interface to use the address of.
+///
+/// IPv4 is preferred for interfaces that have both.
+pub fn resolve_bind(spec: &str, port: u16) -> Result<SocketAddr, NetError> {
+    if let Ok(ip) = unbracket(spec).parse::<IpAddr>() {
+        return Ok((ip, port).into());
+    }
+
+    let mut addrs = interface_addrs(spec)?;
+    if addrs.is_empty() {
+        return Err(NetError::UnknownInterface(spec.to_string()));
+    }
+    addrs.sort_by_key(|addr| addr.is_ipv6());
+    let mut addr = addrs[0];
+    addr.set_port(port);
+    debug!(interface = spec, %addr, "Resolved interface");
+    Ok(addr)
+}
+
+/// Strip the brackets from an IPv6 literal like `[::1]`.
+pub fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// `host:port` for a URI, bracketing IPv6 literals.
+pub fn authority(host: &str, port: u16) -> String {
+    let host = unbracket(host);
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Ports to try listening on, such as 5000 or 5000-5010.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl PortRange {
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.first..=self.last
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = InvalidPortRange;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidPortRange(s.to_string());
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let first: u16 = first.trim().parse().map_err(|_| invalid())?;
+        let last: u16 = last.trim().parse().map_err(|_| invalid())?;
+        // Port 0 would have the OS choose, which isn't what anyone giving a range wants
+        if first == 0 || last < first {
+            return Err(invalid());
+        }
+        Ok(Self { first, last })
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid port range {0}, expected a port like 5000 or a range like 5000-5010")]
+pub struct InvalidPortRange(String);
+
+/// Addresses of the interface, including the scope of IPv6 link-local ones.
+#[cfg(unix)]
+fn interface_addrs(name: &str) -> Result<Vec<SocketAddr>, NetError> {
+    use std::ffi::CStr;
+
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: On success the list is freed below
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(NetError::ListInterfaces(io::Error::last_os_error()));
+    }
+
+    let mut addrs = vec![];
+    let mut curr = ifaddrs;
+    while !curr.is_null() {
+        // SAFETY: getifaddrs returns a valid linked list, with nul-terminated names and addresses
+        //  of the size their family implies
+        unsafe {
+            let ifaddr = &*curr;
+            curr = ifaddr.ifa_next;
+            let matches = CStr::from_ptr(ifaddr.ifa_name).to_bytes() == name.as_bytes();
+            if !matches || ifaddr.ifa_addr.is_null() {
+                continue;
+            }
+            match (*ifaddr.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+                    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                    addrs.push((ip, 0).into());
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in6);
+                    let ip = addr.sin6_addr.s6_addr.into();
+                    addrs.push(SocketAddrV6::new(ip, 0, 0, addr.sin6_scope_id).into());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // SAFETY: Allocated by getifaddrs above, and nothing borrowed from it is kept
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+#[cfg(not(unix))]
+fn interface_addrs(name: &str) -> Result<Vec<SocketAddr>, NetError> {
+    Err(NetError::UnknownInterface(name.to_string()))
+}
+
+#[derive(Debug, Error)]
+pub enum NetError {
+    #[error("{0} is neither an IP address nor a network interface with an address")]
+    UnknownInterface(String),
+    #[error("Error listing network interfaces")]
+    ListInterfaces(#[source] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ltest]
+    fn resolves_addresses() {
+        assert_eq!(
+            resolve_bind("127.0.0.1", 1).unwrap(),
+            "127.0.0.1:1".parse().unwrap()
+        );
+        assert_eq!(resolve_bind("::1", 1).unwrap(), "[::1]:1".parse().unwrap());
+        assert_eq!(resolve_bind("[::1]", 1).unwrap(), "[::1]:1".parse().unwrap());
+        assert!(resolve_bind("no-such-interface0", 1).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[ltest]
+    fn resolves_interfaces() {
+        assert_eq!(resolve_bind("lo", 1).unwrap(), "127.0.0.1:1".parse().unwrap());
+    }
+
+    #[ltest]
+    fn parses_port_ranges() {
+        let single: PortRange = "5000".parse().unwrap();
+        assert_eq!(single.ports(), 5000..=5000);
+        assert_eq!(single.to_string(), "5000");
+
+        let range: PortRange = "5000-5010".parse().unwrap();
+        assert_eq!(range.ports(), 5000..=5010);
+        assert_eq!(range.to_string(), "5000-5010");
+
+        assert!("5010-5000".parse::<PortRange>().is_err());
+        assert!("0".parse::<PortRange>().is_err());
+        assert!("5000-".parse::<PortRange>().is_err());
+        assert!("70000".parse::<PortRange>().is_err());
+    }
+
+    #[ltest]
+    fn brackets_ipv6() {
+        assert_eq!(authority("example.com", 80), "example.com:80");
+        assert_eq!(authority("10.0.0.1", 80), "10.0.0.1:80");
+        assert_eq!(authority("fe80::1", 80), "[fe80::1]:80");
+        assert_eq!(authority("[fe80::1]", 80), "[fe80::1]:80");
+    }
+}